@@ -48,6 +48,9 @@ pub struct AcmeInfo {
     #[serde(serialize_with = "serialize_challenge_type")]
     #[schema(value_type = String, example = "http-01")]
     pub challenge_type: ChallengeType,
+    /// Error of the last failed renewal hook, cleared when a hook succeeds.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hook_error: Option<String>,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize, ToSchema)]
@@ -1,6 +1,9 @@
+use crate::cert::{CertTrustRule, KeyPolicy};
+use crate::error::Error;
 use serde_default::DefaultFromSerde;
 use serde_derive::{Deserialize, Serialize};
-use std::{path::PathBuf, time::Duration};
+use std::{collections::HashMap, path::PathBuf, time::Duration};
+use url::Url;
 use utoipa::ToSchema;
 
 #[derive(Debug, DefaultFromSerde, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
@@ -12,6 +15,107 @@ pub struct AppConfig {
     #[serde(with = "humantime_serde", default = "default_admin_session_expiry")]
     #[schema(value_type = String, example = "1d")]
     pub admin_session_expiry: Duration,
+
+    #[serde(with = "humantime_serde", default = "default_acme_self_check_timeout")]
+    #[schema(value_type = String, example = "5s")]
+    pub acme_self_check_timeout: Duration,
+
+    #[serde(default = "default_acme_self_check_retries")]
+    #[schema(example = "3")]
+    pub acme_self_check_retries: u32,
+
+    /// Self-check responses larger than this many bytes are treated as serving the wrong content.
+    #[serde(default = "default_acme_self_check_max_body_size")]
+    #[schema(example = "4096")]
+    pub acme_self_check_max_body_size: usize,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Option<String>, example = "https://hooks.example.com/taxy")]
+    pub webhook_url: Option<Url>,
+
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    #[schema(example = json!({"backend": 1024}))]
+    pub connection_limit_groups: HashMap<String, usize>,
+
+    /// Abort startup when any keyring cert fails to load instead of skipping it.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub keyring_fail_fast: bool,
+
+    /// Stop serving TLS on ports whose refreshed certificate fails to load instead of keeping the previous one.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub strict_cert_refresh: bool,
+
+    /// Reject new ACME entries whose identifiers overlap with an existing entry instead of warning.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub reject_overlapping_acme: bool,
+
+    /// Maximum number of identifiers of a new ACME entry, each of which becomes a SAN of its certificates.
+    #[serde(default = "default_max_acme_identifiers")]
+    #[schema(example = "100")]
+    pub max_acme_identifiers: usize,
+
+    #[serde(default, skip_serializing_if = "KeyPolicy::is_default")]
+    pub key_policy: KeyPolicy,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub cert_trust_rules: Vec<CertTrustRule>,
+
+    /// Hostnames redacted from the host and SNI fields of access logs. `*` matches any sequence of characters.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    #[schema(example = json!(["*.internal"]))]
+    pub redacted_hosts: Vec<String>,
+
+    #[serde(default, skip_serializing_if = "RenewalHooks::is_default")]
+    pub renewal_hooks: RenewalHooks,
+
+    /// On shutdown, connections which transfer nothing for this period are closed.
+    #[serde(with = "humantime_serde", default = "default_shutdown_grace_period")]
+    #[schema(value_type = String, example = "5s")]
+    pub shutdown_grace_period: Duration,
+
+    /// On shutdown, connections still open after this period are force-closed.
+    #[serde(with = "humantime_serde", default = "default_shutdown_deadline")]
+    #[schema(value_type = String, example = "30s")]
+    pub shutdown_deadline: Duration,
+}
+
+impl AppConfig {
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.acme_self_check_max_body_size == 0 {
+            return Err(Error::InvalidAcmeSelfCheckMaxBodySize);
+        }
+        Ok(())
+    }
+}
+
+/// Commands run around ACME certificate issuance.
+///
+/// The pre-renewal hook receives `TAXY_ACME_ID` and `TAXY_IDENTIFIERS`, and the renewal
+/// is skipped if it fails. The post-renewal hook receives `TAXY_ACME_ID`, `TAXY_CERT_ID`
+/// and `TAXY_CERT_SAN`. Lists are comma-separated.
+#[derive(Debug, DefaultFromSerde, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct RenewalHooks {
+    /// Program and arguments run before requesting a certificate.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    #[schema(example = json!(["/usr/local/bin/open-firewall"]))]
+    pub pre: Vec<String>,
+    /// Program and arguments run after a certificate has been issued.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    #[schema(example = json!(["/usr/local/bin/deploy-cert"]))]
+    pub post: Vec<String>,
+    #[serde(with = "humantime_serde", default = "default_renewal_hook_timeout")]
+    #[schema(value_type = String, example = "30s")]
+    pub timeout: Duration,
+}
+
+impl RenewalHooks {
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+fn default_renewal_hook_timeout() -> Duration {
+    Duration::from_secs(30)
 }
 
 fn default_background_task_interval() -> Duration {
@@ -22,9 +126,35 @@ fn default_admin_session_expiry() -> Duration {
     Duration::from_secs(60 * 60)
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+fn default_acme_self_check_timeout() -> Duration {
+    Duration::from_secs(5)
+}
+
+fn default_acme_self_check_retries() -> u32 {
+    3
+}
+
+fn default_acme_self_check_max_body_size() -> usize {
+    4096
+}
+
+/// The number of names per certificate allowed by Let's Encrypt.
+fn default_max_acme_identifiers() -> usize {
+    100
+}
+
+fn default_shutdown_grace_period() -> Duration {
+    Duration::from_secs(5)
+}
+
+fn default_shutdown_deadline() -> Duration {
+    Duration::from_secs(30)
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
 #[serde(rename_all = "snake_case")]
 pub enum Source {
+    #[default]
     File,
     Api,
 }
@@ -1,4 +1,5 @@
 use serde_derive::{Deserialize, Serialize};
+use std::time::{Duration, SystemTime};
 use utoipa::ToSchema;
 
 #[derive(Deserialize, ToSchema)]
@@ -14,3 +15,37 @@ pub struct LoginResult {
     #[schema(example = "nidhmyh9c7txiyqe53ttsxyq")]
     pub token: String,
 }
+
+#[derive(Deserialize, ToSchema)]
+pub struct ApiTokenRequest {
+    #[serde(with = "humantime_serde", default)]
+    #[schema(value_type = Option<String>, example = "30d")]
+    pub expiry: Option<Duration>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ApiTokenResult {
+    #[schema(example = "c56yqmqcvpmp49n14s2lexxl")]
+    pub id: String,
+    #[schema(example = "nidhmyh9c7txiyqe53ttsxyq")]
+    pub token: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct ApiTokenInfo {
+    #[schema(example = "c56yqmqcvpmp49n14s2lexxl")]
+    pub id: String,
+    #[serde(
+        serialize_with = "crate::cert::serialize_created_at",
+        deserialize_with = "crate::cert::deserialize_created_at"
+    )]
+    #[schema(value_type = u64)]
+    pub created_at: SystemTime,
+    #[serde(
+        with = "humantime_serde",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    #[schema(value_type = Option<String>, example = "30d")]
+    pub expiry: Option<Duration>,
+}
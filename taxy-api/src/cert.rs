@@ -1,7 +1,8 @@
 use crate::{acme::AcmeInfo, subject_name::SubjectName};
 use serde_derive::{Deserialize, Serialize};
+use std::path::PathBuf;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
-use utoipa::ToSchema;
+use utoipa::{IntoParams, ToSchema};
 
 #[derive(Debug, Clone, Serialize, ToSchema)]
 #[serde(rename_all = "snake_case", tag = "type")]
@@ -45,6 +46,8 @@ pub struct SelfSignedCertRequest {
 
 #[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize, ToSchema)]
 pub struct CertMetadata {
+    /// Id of the ACME entry which issued the certificate. Empty for other certificates.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub acme_id: String,
     #[serde(
         serialize_with = "serialize_created_at",
@@ -54,9 +57,14 @@ pub struct CertMetadata {
     pub created_at: SystemTime,
     #[serde(default)]
     pub is_trusted: bool,
+    /// DER-encoded OCSP response to staple, instead of fetching one from the OCSP responder
+    /// of the issuer.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Option<String>, example = "/etc/taxy/ocsp.der")]
+    pub ocsp_response_path: Option<PathBuf>,
 }
 
-fn serialize_created_at<S>(time: &SystemTime, serializer: S) -> Result<S::Ok, S::Error>
+pub(crate) fn serialize_created_at<S>(time: &SystemTime, serializer: S) -> Result<S::Ok, S::Error>
 where
     S: serde::Serializer,
 {
@@ -66,7 +74,7 @@ where
     serializer.serialize_u64(timestamp.as_secs())
 }
 
-fn deserialize_created_at<'de, D>(deserializer: D) -> Result<SystemTime, D::Error>
+pub(crate) fn deserialize_created_at<'de, D>(deserializer: D) -> Result<SystemTime, D::Error>
 where
     D: serde::Deserializer<'de>,
 {
@@ -82,3 +90,106 @@ pub struct CertPostBody {
     #[schema(format = Binary)]
     pub key: String,
 }
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum KeyAlgorithm {
+    Rsa,
+    Ecdsa,
+    Ed25519,
+    Unknown,
+}
+
+/// Marks matching certificates as trusted when they are added to the keyring.
+/// A certificate matches when all of the specified conditions match.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct CertTrustRule {
+    /// Provider of the ACME entry which issued the certificate.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(example = "Let's Encrypt")]
+    pub acme_provider: Option<String>,
+    /// Issuer distinguished name of the leaf certificate.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(example = "C=US, O=Let's Encrypt, CN=R3")]
+    pub issuer: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, ToSchema)]
+pub struct CertTrustRequest {
+    pub is_trusted: bool,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct CertListQuery {
+    /// Sorts by id if omitted.
+    pub sort: Option<CertSortKey>,
+    #[serde(default)]
+    pub order: SortOrder,
+    pub offset: Option<usize>,
+    pub limit: Option<usize>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum CertSortKey {
+    /// The `not_after` time.
+    Expiry,
+    /// The first subject alternative name.
+    Subject,
+    Issuer,
+    /// Untrusted certificates come first in ascending order.
+    Trust,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum SortOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
+/// Restricts the keys of the certificates added to the keyring.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct KeyPolicy {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(example = "2048")]
+    pub min_rsa_bits: Option<usize>,
+    /// Allowed key algorithms. All algorithms are allowed if empty.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    #[schema(example = json!(["rsa", "ecdsa"]))]
+    pub allowed_algorithms: Vec<KeyAlgorithm>,
+}
+
+impl KeyPolicy {
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// Result of validating a certificate chain without adding it to the keyring.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, ToSchema)]
+pub struct CertValidation {
+    pub info: CertInfo,
+    pub warnings: Vec<CertWarning>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case", tag = "type")]
+pub enum CertWarning {
+    /// The leaf certificate has expired.
+    Expired { not_after: i64 },
+    /// The leaf certificate is not valid yet.
+    NotYetValid { not_before: i64 },
+    /// The certificate is not signed by the next certificate in the chain.
+    BrokenChain { subject: String },
+    /// The private key does not match the leaf certificate.
+    KeyMismatch,
+    /// The private key cannot be used for signing.
+    UnusableKey,
+    /// The RSA key is smaller than the policy or the recommended minimum.
+    WeakKey { bits: usize, min: usize },
+    /// The key algorithm is not allowed by the key policy.
+    KeyAlgorithmNotAllowed { algorithm: KeyAlgorithm },
+}
@@ -1,3 +1,4 @@
+use crate::cert::KeyAlgorithm;
 use hyper::StatusCode;
 use multiaddr::Multiaddr;
 use serde_derive::Serialize;
@@ -20,18 +21,82 @@ pub enum Error {
         addr: Multiaddr,
     },
 
+    #[error("duplicate upstream server: {addr}")]
+    DuplicateUpstream {
+        #[schema(value_type = [String])]
+        addr: Multiaddr,
+    },
+
+    #[error("unsupported protocol {protocol} in address: {addr}")]
+    UnsupportedProtocol {
+        #[schema(value_type = [String])]
+        addr: Multiaddr,
+        protocol: String,
+    },
+
+    #[error("invalid upstream rewrite: {upstream}")]
+    InvalidUpstreamRewrite { upstream: String },
+
+    #[error("invalid source port range: {start}-{end}")]
+    InvalidSourcePortRange { start: u16, end: u16 },
+
+    #[error("no source address matches the address family of upstream {addr}")]
+    SourceAddressFamilyMismatch { addr: Multiaddr },
+
     #[error("invalid subject name: {name}")]
     InvalidSubjectName { name: String },
 
+    #[error("invalid ip network: {network}")]
+    InvalidIpNetwork { network: String },
+
+    #[error("invalid header name: {name}")]
+    InvalidHeaderName { name: String },
+
+    #[error("connection rate must be greater than zero")]
+    InvalidConnectionRate,
+
+    #[error("retry budget must be greater than zero")]
+    InvalidRetryBudget,
+
+    #[error("unsupported TLS cipher suite: {name}")]
+    UnsupportedCipherSuite { name: String },
+
+    #[error("unsupported TLS key exchange group: {name}")]
+    UnsupportedKxGroup { name: String },
+
+    #[error("invalid TLS parameters: {reason}")]
+    InvalidTlsParameters { reason: String },
+
+    #[error("invalid upstream CA bundle: {reason}")]
+    InvalidCaBundle { reason: String },
+
+    #[error("invalid ALPN protocol: {protocol}")]
+    InvalidAlpnProtocol { protocol: String },
+
+    #[error("encrypted client hello is not supported by the TLS backend")]
+    EchNotSupported,
+
+    #[error("certificate compression is not supported by the TLS backend")]
+    CertCompressionNotSupported,
+
     #[error("missing TLS termination config")]
     TlsTerminationConfigMissing,
 
+    #[error("tls mode can only be set on tcp ports with tls termination")]
+    TlsModeNotSupported,
+
     #[error("failed to generate self-signed certificate")]
     FailedToGerateSelfSignedCertificate,
 
     #[error("failed to read certificate")]
     FailedToReadCertificate,
 
+    #[error("key algorithm is not allowed: {algorithm:?}")]
+    KeyAlgorithmNotAllowed { algorithm: KeyAlgorithm },
+
+    #[error("rsa key size {bits} is smaller than {min} bits")]
+    KeyTooSmall { bits: usize, min: usize },
+
     #[error("failed to read private key")]
     FailedToReadPrivateKey,
 
@@ -44,18 +109,33 @@ pub enum Error {
     #[error("port id not found: {id}")]
     IdNotFound { id: String },
 
+    #[error("upstream not found: {addr}")]
+    UpstreamNotFound { addr: Multiaddr },
+
     #[error("port id already exists: {id}")]
     IdAlreadyExists { id: String },
 
     #[error("acme account creation failed")]
     AcmeAccountCreationFailed,
 
+    #[error("identifier {name} overlaps with acme entry: {id}")]
+    AcmeIdentifierOverlap { name: String, id: String },
+
+    #[error("acme entry has {count} identifiers, exceeding max_acme_identifiers of {max}")]
+    TooManyAcmeIdentifiers { count: usize, max: usize },
+
+    #[error("acme self-check max body size must be greater than zero")]
+    InvalidAcmeSelfCheckMaxBodySize,
+
     #[error("unauthorized")]
     Unauthorized,
 
     #[error("invalid login credentials")]
     InvalidLoginCredentials,
 
+    #[error("failed to save api tokens")]
+    FailedToSaveApiTokens,
+
     #[error("failed to fetch log")]
     FailedToFetchLog,
 
@@ -80,7 +160,9 @@ impl Reject for Error {}
 impl Error {
     pub fn status_code(&self) -> StatusCode {
         match self {
-            Self::KeyringItemNotFound { .. } | Self::IdNotFound { .. } => StatusCode::NOT_FOUND,
+            Self::KeyringItemNotFound { .. }
+            | Self::IdNotFound { .. }
+            | Self::UpstreamNotFound { .. } => StatusCode::NOT_FOUND,
             Self::Unauthorized => StatusCode::UNAUTHORIZED,
             Self::WaitingLogTimedOut => StatusCode::REQUEST_TIMEOUT,
             _ => StatusCode::BAD_REQUEST,
@@ -1,7 +1,7 @@
 use crate::acme::AcmeInfo;
 use crate::app::{AppConfig, Source};
 use crate::cert::CertInfo;
-use crate::port::PortStatus;
+use crate::port::{ConnectionStats, PortStatus};
 use crate::{port::PortEntry, site::SiteEntry};
 use serde_derive::Serialize;
 use utoipa::ToSchema;
@@ -9,11 +9,30 @@ use utoipa::ToSchema;
 #[derive(Debug, Clone, Serialize, ToSchema)]
 #[serde(rename_all = "snake_case", tag = "event")]
 pub enum ServerEvent {
-    AppConfigUpdated { config: AppConfig, source: Source },
-    PortTableUpdated { entries: Vec<PortEntry> },
-    PortStatusUpdated { id: String, status: PortStatus },
-    ServerCertsUpdated { items: Vec<CertInfo> },
-    SitesUpdated { items: Vec<SiteEntry> },
-    AcmeUpdated { items: Vec<AcmeInfo> },
+    AppConfigUpdated {
+        config: Box<AppConfig>,
+        source: Source,
+    },
+    PortTableUpdated {
+        entries: Vec<PortEntry>,
+    },
+    PortStatusUpdated {
+        id: String,
+        status: PortStatus,
+    },
+    /// The counters of a port just before they were reset by `stats_reset_interval`.
+    PortStatsReset {
+        id: String,
+        stats: ConnectionStats,
+    },
+    ServerCertsUpdated {
+        items: Vec<CertInfo>,
+    },
+    SitesUpdated {
+        items: Vec<SiteEntry>,
+    },
+    AcmeUpdated {
+        items: Vec<AcmeInfo>,
+    },
     Shutdown,
 }
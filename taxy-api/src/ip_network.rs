@@ -0,0 +1,97 @@
+use crate::error::Error;
+use serde::{Deserialize, Serialize};
+use std::{fmt, net::IpAddr, str::FromStr};
+
+/// An IP network in CIDR notation, e.g. `10.0.0.0/8`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpNetwork {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl IpNetwork {
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        let addr = match addr {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(addr),
+            _ => addr,
+        };
+        match (self.addr, addr) {
+            (IpAddr::V4(net), IpAddr::V4(addr)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(addr) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(addr)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(addr) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl Serialize for IpNetwork {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for IpNetwork {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        Self::from_str(&s).map_err(serde::de::Error::custom)
+    }
+}
+
+impl fmt::Display for IpNetwork {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+impl FromStr for IpNetwork {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || Error::InvalidIpNetwork {
+            network: s.to_owned(),
+        };
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (
+                IpAddr::from_str(addr).map_err(|_| err())?,
+                Some(u8::from_str(prefix).map_err(|_| err())?),
+            ),
+            None => (IpAddr::from_str(s).map_err(|_| err())?, None),
+        };
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = prefix.unwrap_or(max);
+        if prefix > max {
+            return Err(err());
+        }
+        Ok(Self { addr, prefix })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_ip_network() {
+        let net = |s| IpNetwork::from_str(s).unwrap();
+        assert!(net("10.0.0.0/8").contains([10, 1, 2, 3].into()));
+        assert!(!net("10.0.0.0/8").contains([11, 0, 0, 1].into()));
+        assert!(net("0.0.0.0/0").contains([192, 168, 0, 1].into()));
+        assert!(net("192.168.0.1").contains([192, 168, 0, 1].into()));
+        assert!(net("10.0.0.0/8").contains("::ffff:10.0.0.1".parse().unwrap()));
+        assert!(net("2001:db8::/32").contains("2001:db8::1".parse().unwrap()));
+        assert!(!net("2001:db8::/32").contains([10, 0, 0, 1].into()));
+        assert!(IpNetwork::from_str("10.0.0.0/33").is_err());
+        assert!(IpNetwork::from_str("example.com/8").is_err());
+    }
+}
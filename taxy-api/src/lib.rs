@@ -4,8 +4,11 @@ pub mod auth;
 pub mod cert;
 pub mod error;
 pub mod event;
+pub mod ip_network;
 pub mod log;
+pub mod metrics;
 pub mod port;
 pub mod site;
 pub mod subject_name;
 pub mod tls;
+pub mod webhook;
@@ -0,0 +1,33 @@
+use serde_derive::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use utoipa::ToSchema;
+
+/// A metric family as returned by `GET /api/metrics` with `Accept: application/json`.
+///
+/// The JSON form is stable: families are sorted by `name`, counter names omit
+/// the `_total` suffix used by the text formats, and samples of a family are
+/// distinguished only by their labels.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ToSchema)]
+pub struct MetricFamily {
+    #[schema(example = "taxy_port_connections")]
+    pub name: String,
+    #[schema(example = "Total number of accepted connections.")]
+    pub help: String,
+    pub kind: MetricKind,
+    pub samples: Vec<MetricSample>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum MetricKind {
+    Counter,
+    Gauge,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ToSchema)]
+pub struct MetricSample {
+    #[schema(example = json!({"port": "f9cq8k3ag6vcfmxnsywyvj4s"}))]
+    pub labels: BTreeMap<String, String>,
+    #[schema(example = 42.0)]
+    pub value: f64,
+}
@@ -1,8 +1,12 @@
-use crate::tls::{TlsState, TlsTermination};
+use crate::app::Source;
+use crate::ip_network::IpNetwork;
+use crate::tls::{TlsMode, TlsParameters, TlsState, TlsTermination};
 use multiaddr::Multiaddr;
 use serde_derive::{Deserialize, Serialize};
-use std::time::SystemTime;
-use utoipa::ToSchema;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+use utoipa::{IntoParams, ToSchema};
 
 #[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
 #[serde(rename_all = "snake_case")]
@@ -11,6 +15,7 @@ pub enum SocketState {
     PortAlreadyInUse,
     PermissionDenied,
     AddressNotAvailable,
+    IdleUnbound,
     Error,
     #[default]
     Unknown,
@@ -22,6 +27,18 @@ pub struct PortStatus {
     #[serde(serialize_with = "serialize_started_at")]
     #[schema(value_type = Option<u64>)]
     pub started_at: Option<SystemTime>,
+    pub source: Source,
+    /// Connections currently counted against `max_connections`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub connections_in_use: Option<usize>,
+    /// Connections rejected because `max_connections` was reached.
+    pub connection_limit_rejections: u64,
+    /// Upstreams currently ejected by `outlier_detection`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ejected_upstreams: Option<usize>,
+    /// How new connections are handled, on raw TCP ports with TLS termination.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tls_mode: Option<TlsMode>,
 }
 
 #[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
@@ -30,6 +47,129 @@ pub struct PortState {
     pub tls: Option<TlsState>,
 }
 
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, ToSchema)]
+pub struct ConnectionInfo {
+    #[schema(example = "127.0.0.1:52000")]
+    pub remote: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(example = "client.example.com")]
+    pub remote_name: Option<String>,
+    #[schema(example = "127.0.0.1:8443")]
+    pub local: String,
+    #[serde(serialize_with = "serialize_timestamp")]
+    #[schema(value_type = u64)]
+    pub started_at: SystemTime,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub served_cert: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(example = "CN=client.example.com")]
+    pub client_cert: Option<String>,
+}
+
+/// A snapshot of the status and counters of a port.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, ToSchema)]
+pub struct PortStats {
+    pub id: String,
+    #[serde(flatten)]
+    pub status: PortStatus,
+    pub connections: ConnectionStats,
+    pub upstreams: Vec<UpstreamInfo>,
+}
+
+/// Counters of the connections of a port since it was created.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, ToSchema)]
+pub struct ConnectionStats {
+    pub active: usize,
+    /// Highest number of connections active at once.
+    pub peak: usize,
+    pub total: u64,
+    /// Bytes received from the clients.
+    pub bytes_received: u64,
+    /// Bytes sent to the clients.
+    pub bytes_sent: u64,
+    /// The last error which failed a connection.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, ToSchema)]
+pub struct UpstreamInfo {
+    #[schema(example = "example.com:8080")]
+    pub addr: String,
+    /// False once the health checker has marked the upstream as down.
+    pub healthy: bool,
+    pub active: usize,
+    pub connections: u64,
+    pub connect_failures: u64,
+    pub connect_timeouts: u64,
+    /// True while outlier detection keeps the upstream out of selection.
+    pub ejected: bool,
+}
+
+/// The share of the recent connections of a port picked for an upstream, against its weight.
+#[derive(Debug, Clone, PartialEq, Serialize, ToSchema)]
+pub struct UpstreamDistribution {
+    #[schema(example = "example.com:8080")]
+    pub addr: String,
+    pub weight: u32,
+    /// Share of the total weight of the enabled upstreams.
+    #[schema(example = 0.75)]
+    pub expected: f64,
+    /// Share of the recent connections for which the upstream was picked.
+    #[schema(example = 0.74)]
+    pub observed: f64,
+    /// Number of the recent connections for which the upstream was picked.
+    pub selections: usize,
+}
+
+/// Summary of a recently closed connection.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, ToSchema)]
+pub struct ClosedConnectionInfo {
+    #[serde(flatten)]
+    pub connection: ConnectionInfo,
+    #[serde(serialize_with = "serialize_timestamp")]
+    #[schema(value_type = u64)]
+    pub closed_at: SystemTime,
+    pub outcome: ConnectionOutcome,
+    /// Bytes received from the client.
+    pub bytes_received: u64,
+    /// Bytes sent to the client.
+    pub bytes_sent: u64,
+    /// Whether the connection was classified as a probe by `probe_detection`.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub probe: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ConnectionOutcome {
+    ClientClosed,
+    UpstreamClosed,
+    FirstByteTimeout,
+    /// No bytes were transferred in either direction within `idle_timeout`.
+    IdleTimeout,
+    /// Still reading from the client at `read_deadline`.
+    ReadDeadline,
+    /// Still writing to the client at `write_deadline`.
+    WriteDeadline,
+    /// Closed by resetting the port or shutting down.
+    Stopped,
+    /// Closed by draining the upstream.
+    Drained,
+    Error,
+}
+
+fn serialize_timestamp<S>(timestamp: &SystemTime, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    let timestamp = timestamp
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    serializer.serialize_u64(timestamp)
+}
+
 fn serialize_started_at<S>(
     started_at: &Option<SystemTime>,
     serializer: S,
@@ -52,6 +192,112 @@ where
 pub struct UpstreamServer {
     #[schema(value_type = String, example = "/dns/example.com/tcp/8080")]
     pub addr: Multiaddr,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source_ports: Option<PortRange>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    #[schema(value_type = [String], example = json!(["192.168.0.2"]))]
+    pub source_addrs: Vec<IpAddr>,
+    /// Disabled upstreams are not selected for new connections.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub disabled: bool,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    #[schema(example = json!(["us"]))]
+    pub tags: Vec<String>,
+    /// Relative share of new connections. Upstreams with zero weight are never selected.
+    #[serde(
+        default = "default_upstream_weight",
+        skip_serializing_if = "is_default_upstream_weight"
+    )]
+    #[schema(example = 1)]
+    pub weight: u32,
+    /// Omits the SNI extension from the TLS handshake, for upstreams which reject it.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub disable_sni: bool,
+    /// Accepts any certificate of a TLS upstream without verifying it, such as a self-signed one.
+    /// This is insecure, as the connections can be intercepted, and each handshake is logged as a warning.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub insecure_skip_verify: bool,
+    /// Name sent in the SNI of a TLS upstream and verified against its certificate,
+    /// in place of the host of `addr`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(example = "backend.internal")]
+    pub server_name_override: Option<String>,
+    /// Sends a PROXY protocol header announcing the client address to the upstream.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub proxy_protocol: Option<ProxyProtocol>,
+    /// Host patterns of the TLS clients routed to this upstream on ports with `sni_routing`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    #[schema(example = json!(["*.example.com"]))]
+    pub server_names: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ProxyProtocol {
+    /// The human-readable header.
+    V1,
+    /// The binary header, carrying the ALPN protocol negotiated with TLS clients.
+    V2,
+}
+
+impl UpstreamServer {
+    /// Returns an upstream at `addr` with the default options.
+    pub fn new(addr: Multiaddr) -> Self {
+        Self {
+            addr,
+            source_ports: None,
+            source_addrs: vec![],
+            disabled: false,
+            tags: vec![],
+            weight: default_upstream_weight(),
+            disable_sni: false,
+            insecure_skip_verify: false,
+            server_name_override: None,
+            proxy_protocol: None,
+            server_names: vec![],
+        }
+    }
+}
+
+fn default_upstream_weight() -> u32 {
+    1
+}
+
+fn is_default_upstream_weight(weight: &u32) -> bool {
+    *weight == default_upstream_weight()
+}
+
+#[derive(Debug, Clone, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct DrainQuery {
+    /// Connections still open after this period are closed. Defaults to 30s.
+    #[serde(with = "humantime_serde", default = "default_drain_timeout")]
+    #[param(value_type = Option<String>, example = "30s")]
+    pub timeout: Duration,
+}
+
+fn default_drain_timeout() -> Duration {
+    Duration::from_secs(30)
+}
+
+/// Administratively disables or re-enables an upstream of a port.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct UpstreamState {
+    #[schema(value_type = String, example = "/dns/example.com/tcp/8080")]
+    pub addr: Multiaddr,
+    pub disabled: bool,
+    /// Close the existing connections to the upstream when disabling it.
+    #[serde(default)]
+    pub drain: bool,
+}
+
+/// An inclusive range of local ports used for outbound connections.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct PortRange {
+    #[schema(example = 40000)]
+    pub start: u16,
+    #[schema(example = 40999)]
+    pub end: u16,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
@@ -88,4 +334,681 @@ pub struct PortOptions {
     pub upstream_servers: Vec<UpstreamServer>,
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub tls_termination: Option<TlsTermination>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(example = "backend")]
+    pub connection_limit_group: Option<String>,
+    #[serde(
+        with = "humantime_serde",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    #[schema(value_type = Option<String>, example = "10s")]
+    pub tls_client_hello_timeout: Option<Duration>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(example = 16384)]
+    pub tls_client_hello_max_size: Option<usize>,
+    /// Detects TLS, HTTP or raw TCP from the first bytes on HTTP ports.
+    /// Raw TCP connections are forwarded to `upstream_servers`.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub protocol_detection: bool,
+    /// How long protocol detection waits for the first bytes before closing the connection.
+    /// Defaults to 10 seconds. The TLS handshake is bounded separately by `tls_client_hello_timeout`.
+    #[serde(
+        with = "humantime_serde",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    #[schema(value_type = Option<String>, example = "5s")]
+    pub protocol_detection_timeout: Option<Duration>,
+    #[serde(
+        with = "humantime_serde",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    #[schema(value_type = Option<String>, example = "30s")]
+    pub upstream_first_byte_timeout: Option<Duration>,
+    /// Gives up connecting to an upstream of a raw TCP port after this period,
+    /// and tries the next upstream if any. Defaults to 10 seconds.
+    #[serde(
+        with = "humantime_serde",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    #[schema(value_type = Option<String>, example = "10s")]
+    pub upstream_connect_timeout: Option<Duration>,
+    /// How long a connection attempt to an upstream address may take before an attempt to
+    /// the next address, such as one of the other IP family, is raced against it,
+    /// as in Happy Eyeballs (RFC 8305). Defaults to 250ms.
+    #[serde(
+        with = "humantime_serde",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    #[schema(value_type = Option<String>, example = "250ms")]
+    pub happy_eyeballs_delay: Option<Duration>,
+    /// Excludes an upstream of a raw TCP port from selection for this period after
+    /// a failed connection or TLS handshake, unless all of the upstreams are excluded.
+    #[serde(
+        with = "humantime_serde",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    #[schema(value_type = Option<String>, example = "5s")]
+    pub upstream_failure_cooldown: Option<Duration>,
+    /// Ejects upstreams of a raw TCP port which fail repeatedly from selection.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub outlier_detection: Option<OutlierDetection>,
+    /// Local addresses bound by outbound connections to upstreams without `source_addrs`
+    /// of their own, such as to route them through a particular interface.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    #[schema(value_type = [String], example = json!(["192.168.0.2"]))]
+    pub source_addrs: Vec<IpAddr>,
+    /// Closes connections on raw TCP ports once no bytes have been transferred
+    /// in either direction for this duration. On UDP ports, closes flows once no
+    /// datagrams have been relayed for this duration, defaulting to 30s.
+    #[serde(
+        with = "humantime_serde",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    #[schema(value_type = Option<String>, example = "5m")]
+    pub idle_timeout: Option<Duration>,
+    /// Closes connections on raw TCP ports which are still reading from the client
+    /// this long after being accepted, regardless of their activity. Zero means unlimited.
+    #[serde(
+        with = "humantime_serde",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    #[schema(value_type = Option<String>, example = "1h")]
+    pub read_deadline: Option<Duration>,
+    /// Closes connections on raw TCP ports which are still writing to the client
+    /// this long after being accepted, regardless of their activity. Zero means unlimited.
+    #[serde(
+        with = "humantime_serde",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    #[schema(value_type = Option<String>, example = "1h")]
+    pub write_deadline: Option<Duration>,
+    /// How long closing a connection on a raw TCP port waits for each side to be shut down,
+    /// including sending `close_notify` on TLS connections. Defaults to 5 seconds.
+    #[serde(
+        with = "humantime_serde",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    #[schema(value_type = Option<String>, example = "5s")]
+    pub tls_close_timeout: Option<Duration>,
+    /// Treats a TLS peer of a raw TCP port closing the connection without `close_notify`
+    /// as a truncation error. By default, such a close ends the stream like a clean one.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub tls_strict_close: bool,
+    /// Reconnects and retries the upstream TLS handshake on raw TCP ports when the connection
+    /// is lost during the handshake. Handshakes rejected by TLS errors are not retried.
+    #[serde(default, skip_serializing_if = "is_zero")]
+    #[schema(example = "2")]
+    pub upstream_tls_handshake_retries: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry_budget: Option<RetryBudget>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub reverse_dns: bool,
+    #[serde(default, skip_serializing_if = "BufferingMode::is_default")]
+    pub buffering: BufferingMode,
+    /// How raw TCP ports and HTTP fallback servers pick an upstream for each connection.
+    #[serde(default, skip_serializing_if = "LoadBalanceMode::is_default")]
+    pub load_balance: LoadBalanceMode,
+    /// Logs each lifecycle stage of raw TCP connections at info level.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub lifecycle_events: bool,
+    /// Deflates the bytes tunneled over TLS on raw TCP ports, for links between two Taxy instances.
+    /// Compression is negotiated with the `taxy-deflate` ALPN protocol, both with TLS upstreams
+    /// and with clients of TLS-terminated ports, and peers which do not negotiate it are
+    /// served in plaintext as usual.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub tunnel_compression: bool,
+    /// Adds `Strict-Transport-Security` to responses on TLS-terminated HTTP ports.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hsts: Option<Hsts>,
+    /// Prefers upstreams tagged with the tag of the first network containing the client address,
+    /// falling back to the other upstreams when none of them are enabled.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tag_affinity: Vec<TagAffinity>,
+    /// Propagates W3C trace context to upstream servers in the `traceparent` header,
+    /// starting a new trace when the request carries none. Raw TCP connections log a new trace id.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub trace_context: bool,
+    /// Rejects a fraction of new connections while the load exceeds the threshold.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub overload_shedding: Option<OverloadShedding>,
+    /// Unbinds the listener after no connections have been accepted for this period.
+    /// The port stays unbound until it is rebound via `/api/ports/{id}/rebind`.
+    #[serde(
+        with = "humantime_serde",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    #[schema(value_type = Option<String>, example = "10m")]
+    pub idle_unbind: Option<Duration>,
+    /// Maximum number of pending connections queued by the listener, applied when it is bound.
+    /// Defaults to 1024. The OS caps it, e.g. at `net.core.somaxconn` on Linux.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(example = 4096)]
+    pub listen_backlog: Option<u32>,
+    /// Number of sockets bound to the listen address with `SO_REUSEPORT`, among which the kernel
+    /// balances new connections. Only supported on Unix platforms. Defaults to 1.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(example = 4)]
+    pub listen_sockets: Option<usize>,
+    /// Accepts data in the SYN of clients with TCP Fast Open cookies on the listener,
+    /// applied when it is bound. Only supported on Linux, where `net.ipv4.tcp_fastopen` must also enable the server side.
+    /// Ignored with a warning elsewhere.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub listen_fast_open: bool,
+    /// Number of recently closed connections kept for `/api/ports/{id}/connections/recent`.
+    /// Defaults to 32.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(example = 32)]
+    pub recent_connections: Option<usize>,
+    /// Resets the connection and byte counters of `/api/ports/stats` at every multiple of
+    /// this interval since the UNIX epoch, e.g. daily at midnight UTC. The peak number of
+    /// connections is kept. A `port_stats_reset` event carries the counters before each reset.
+    #[serde(
+        with = "humantime_serde",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    #[schema(value_type = Option<String>, example = "1d")]
+    pub stats_reset_interval: Option<Duration>,
+    /// Limits the rate of new connections to the port, regardless of their source.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub connection_rate: Option<ConnectionRate>,
+    /// Caches the resolved addresses of DNS upstreams and tracks their health,
+    /// instead of resolving the name for each connection. The addresses are
+    /// cached with the default settings if unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dns_resolution: Option<DnsResolution>,
+    /// Periodically connects to each upstream of raw TCP ports and skips the ones which are down.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub health_check: Option<HealthCheck>,
+    /// How the certificates of TLS upstreams are verified.
+    #[serde(default, skip_serializing_if = "UpstreamTlsVerification::is_default")]
+    pub upstream_tls_verification: UpstreamTlsVerification,
+    /// Root certificates trusted by the `full` verification of TLS upstreams,
+    /// such as the ones of a private CA.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub upstream_ca_bundle: Option<UpstreamCaBundle>,
+    /// TCP keepalive for the client sockets accepted by raw TCP ports.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub inbound_keepalive: Option<TcpKeepalive>,
+    /// TCP keepalive for the sockets connected to upstream servers by raw TCP ports.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub outbound_keepalive: Option<TcpKeepalive>,
+    /// Disables Nagle's algorithm on both the client and upstream sockets of raw TCP ports.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub tcp_nodelay: bool,
+    /// Sends the first bytes to upstreams of raw TCP ports in the SYN with TCP Fast Open
+    /// (`TCP_FASTOPEN_CONNECT`), once the kernel holds a cookie for the upstream. The connection
+    /// completes only once the client sends something, so this suits protocols where the
+    /// client speaks first. Only supported on Linux. Ignored with a warning elsewhere.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub upstream_fast_open: bool,
+    /// Size of the send buffer (`SO_SNDBUF`) of the client and upstream sockets of raw TCP ports.
+    /// The OS caps it, e.g. at `net.core.wmem_max` on Linux, which also doubles the requested size.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(example = 262144)]
+    pub send_buffer_size: Option<usize>,
+    /// Size of the receive buffer (`SO_RCVBUF`) of the client and upstream sockets of raw TCP ports.
+    /// The OS caps it, e.g. at `net.core.rmem_max` on Linux, which also doubles the requested size.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(example = 262144)]
+    pub recv_buffer_size: Option<usize>,
+    /// Maximum number of concurrent connections on raw TCP ports.
+    /// New connections beyond the limit are closed immediately.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(example = 1024)]
+    pub max_connections: Option<usize>,
+    /// Counts connections which transferred almost nothing separately, as probes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub probe_detection: Option<ProbeDetection>,
+    /// Proxies clients which do not start with a TLS handshake as plaintext on raw TCP ports
+    /// with TLS termination, instead of closing them. These clients bypass TLS entirely,
+    /// so this is meant for migrating existing clients to TLS only.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub plaintext_fallback: Option<PlaintextFallback>,
+    /// Expects each connection to start with a PROXY protocol v1 or v2 header, as sent by
+    /// a load balancer in front of the port, and takes the client address from it.
+    /// Connections without a valid header are closed.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub accept_proxy_protocol: bool,
+    /// Routes TLS clients on raw TCP ports without TLS termination to the upstreams whose
+    /// `server_names` match the SNI of their ClientHello, which is peeked without decrypting
+    /// the connection. Other clients are balanced over the upstreams without `server_names`,
+    /// or over all upstreams if each of them has some. Clients not speaking TLS are closed.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub sni_routing: bool,
+    /// Rules computing the upstream of connections on raw TCP ports from their SNI or local port,
+    /// such as `x.internal` to `10.0.0.x:8080`. The first matching rule wins, and connections
+    /// matching none are proxied to `upstream_servers`. Rules on the SNI peek the ClientHello,
+    /// closing clients not speaking TLS, and are ignored if `plaintext_fallback` is enabled.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub upstream_rewrites: Vec<UpstreamRewrite>,
+    /// How raw TCP ports handle upstream servers listed more than once with the same host and port.
+    #[serde(default, skip_serializing_if = "DuplicateUpstreams::is_default")]
+    pub duplicate_upstreams: DuplicateUpstreams,
+    /// TLS versions, cipher suites and key exchange groups allowed for TLS termination
+    /// and TLS upstreams. Defaults to all of those supported.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tls_parameters: Option<TlsParameters>,
+}
+
+fn is_zero(n: &u32) -> bool {
+    *n == 0
+}
+
+/// Classifies closed connections as probes, such as those of scanners which
+/// connect and disconnect right away.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct ProbeDetection {
+    /// Connections which transferred fewer bytes in both directions combined are probes.
+    /// Defaults to 1, so only connections which transferred nothing are probes.
+    #[serde(default = "default_probe_min_bytes")]
+    #[schema(example = 64)]
+    pub min_bytes: u64,
+    /// Connections which closed sooner are probes, regardless of the bytes transferred.
+    #[serde(
+        with = "humantime_serde",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    #[schema(value_type = Option<String>, example = "100ms")]
+    pub min_duration: Option<Duration>,
+}
+
+fn default_probe_min_bytes() -> u64 {
+    1
+}
+
+impl ProbeDetection {
+    pub fn is_probe(&self, bytes: u64, duration: Duration) -> bool {
+        bytes < self.min_bytes || self.min_duration.is_some_and(|min| duration < min)
+    }
+}
+
+/// Rewrites the upstream of the connections matching all of the given conditions.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct UpstreamRewrite {
+    /// Host pattern matched against the SNI of TLS clients.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(example = "*.internal")]
+    pub server_name: Option<String>,
+    /// Local port the connection was accepted on.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(example = 8443)]
+    pub port: Option<u16>,
+    /// Address of the upstream, in which `{sni}` is replaced with the SNI, `{label}` with
+    /// its first label and `{port}` with the local port.
+    #[schema(example = "/ip4/10.0.0.{label}/tcp/8080")]
+    pub upstream: String,
+}
+
+/// Detects plaintext clients from their first byte, which are then proxied
+/// without TLS termination.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct PlaintextFallback {
+    /// Upstreams of the plaintext clients. Defaults to the `upstream_servers` of the port.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub upstream_servers: Vec<UpstreamServer>,
+}
+
+/// Token bucket refilled at `per_second` tokens per second, holding up to `burst` tokens.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct ConnectionRate {
+    #[schema(example = 100)]
+    pub per_second: u32,
+    /// Defaults to `per_second`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(example = 200)]
+    pub burst: Option<u32>,
+    #[serde(default)]
+    pub exceeded: RateExceededAction,
+    /// Connections which would be delayed longer than this are rejected.
+    #[serde(with = "humantime_serde", default = "default_rate_max_delay")]
+    #[schema(value_type = String, example = "1s")]
+    pub max_delay: Duration,
+}
+
+fn default_rate_max_delay() -> Duration {
+    Duration::from_secs(1)
+}
+
+/// Token bucket shared by the connections of a raw TCP port, taken from whenever a connection
+/// fails over to another upstream or reconnects for `upstream_tls_handshake_retries`.
+/// Once it is empty, connections fail instead of retrying, so that an outage of the
+/// upstreams is not amplified by retries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct RetryBudget {
+    #[schema(example = 10)]
+    pub per_second: u32,
+    /// Defaults to `per_second`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(example = 20)]
+    pub burst: Option<u32>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum RateExceededAction {
+    /// Close connections beyond the rate.
+    #[default]
+    Reject,
+    /// Hold connections beyond the rate until a token is available.
+    Delay,
+}
+
+/// Resolved addresses are refreshed every `refresh_interval`, dropping the ones which
+/// disappeared from the record set. An address which fails to connect is avoided for
+/// `unhealthy_cooldown` as long as other addresses are healthy.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct DnsResolution {
+    #[serde(with = "humantime_serde", default = "default_dns_refresh_interval")]
+    #[schema(value_type = String, example = "30s")]
+    pub refresh_interval: Duration,
+    #[serde(with = "humantime_serde", default = "default_dns_unhealthy_cooldown")]
+    #[schema(value_type = String, example = "30s")]
+    pub unhealthy_cooldown: Duration,
+}
+
+impl Default for DnsResolution {
+    fn default() -> Self {
+        Self {
+            refresh_interval: default_dns_refresh_interval(),
+            unhealthy_cooldown: default_dns_unhealthy_cooldown(),
+        }
+    }
+}
+
+fn default_dns_refresh_interval() -> Duration {
+    Duration::from_secs(30)
+}
+
+fn default_dns_unhealthy_cooldown() -> Duration {
+    Duration::from_secs(30)
+}
+
+/// Connects to each upstream every `interval`. An upstream is marked down after
+/// `unhealthy_threshold` consecutive checks failed or exceeded `timeout`, and up again
+/// after a successful check. If all upstreams are down, they are tried anyway.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct HealthCheck {
+    #[serde(with = "humantime_serde", default = "default_health_check_interval")]
+    #[schema(value_type = String, example = "10s")]
+    pub interval: Duration,
+    #[serde(with = "humantime_serde", default = "default_health_check_timeout")]
+    #[schema(value_type = String, example = "3s")]
+    pub timeout: Duration,
+    #[serde(default = "default_health_check_unhealthy_threshold")]
+    #[schema(example = 3)]
+    pub unhealthy_threshold: u32,
+    /// Also completes a TLS handshake with TLS upstreams.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub tls_handshake: bool,
+}
+
+fn default_health_check_interval() -> Duration {
+    Duration::from_secs(10)
+}
+
+fn default_health_check_timeout() -> Duration {
+    Duration::from_secs(3)
+}
+
+fn default_health_check_unhealthy_threshold() -> u32 {
+    3
+}
+
+/// Ejects an upstream from selection once `consecutive_failures` connections to it have
+/// failed in a row, each within `interval` of the previous one. Failed connections or TLS
+/// handshakes count as failures, as do connections reset by the upstream before it sent
+/// anything. The upstream is tried again after the ejection time, which starts at
+/// `base_ejection_time` and doubles each time the first connection after an ejection fails,
+/// up to `max_ejection_time`. If all upstreams are ejected, they are tried anyway.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct OutlierDetection {
+    #[serde(default = "default_outlier_consecutive_failures")]
+    #[schema(example = 5)]
+    pub consecutive_failures: u32,
+    #[serde(with = "humantime_serde", default = "default_outlier_interval")]
+    #[schema(value_type = String, example = "10s")]
+    pub interval: Duration,
+    #[serde(
+        with = "humantime_serde",
+        default = "default_outlier_base_ejection_time"
+    )]
+    #[schema(value_type = String, example = "30s")]
+    pub base_ejection_time: Duration,
+    #[serde(
+        with = "humantime_serde",
+        default = "default_outlier_max_ejection_time"
+    )]
+    #[schema(value_type = String, example = "5m")]
+    pub max_ejection_time: Duration,
+}
+
+impl Default for OutlierDetection {
+    fn default() -> Self {
+        Self {
+            consecutive_failures: default_outlier_consecutive_failures(),
+            interval: default_outlier_interval(),
+            base_ejection_time: default_outlier_base_ejection_time(),
+            max_ejection_time: default_outlier_max_ejection_time(),
+        }
+    }
+}
+
+fn default_outlier_consecutive_failures() -> u32 {
+    5
+}
+
+fn default_outlier_interval() -> Duration {
+    Duration::from_secs(10)
+}
+
+fn default_outlier_base_ejection_time() -> Duration {
+    Duration::from_secs(30)
+}
+
+fn default_outlier_max_ejection_time() -> Duration {
+    Duration::from_secs(300)
+}
+
+/// Sends the first keepalive probe once the connection has been idle for `time`, then one
+/// every `interval`. The connection is dropped after `retries` unanswered probes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct TcpKeepalive {
+    #[serde(with = "humantime_serde", default = "default_keepalive_time")]
+    #[schema(value_type = String, example = "60s")]
+    pub time: Duration,
+    #[serde(with = "humantime_serde", default = "default_keepalive_interval")]
+    #[schema(value_type = String, example = "10s")]
+    pub interval: Duration,
+    /// Ignored on Windows, which always sends 10 probes.
+    #[serde(default = "default_keepalive_retries")]
+    #[schema(example = 3)]
+    pub retries: u32,
+}
+
+fn default_keepalive_time() -> Duration {
+    Duration::from_secs(60)
+}
+
+fn default_keepalive_interval() -> Duration {
+    Duration::from_secs(10)
+}
+
+fn default_keepalive_retries() -> u32 {
+    3
+}
+
+/// Sheds new connections with a probability rising linearly from 0 at `threshold`
+/// to 1 at `ceiling`. Both are percentages of the load signal.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct OverloadShedding {
+    #[serde(default)]
+    pub signal: LoadSignal,
+    #[serde(default = "default_shedding_threshold")]
+    #[schema(example = 80)]
+    pub threshold: u32,
+    #[serde(default = "default_shedding_ceiling")]
+    #[schema(example = 100)]
+    pub ceiling: u32,
+}
+
+fn default_shedding_threshold() -> u32 {
+    80
+}
+
+fn default_shedding_ceiling() -> u32 {
+    100
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case", tag = "type")]
+pub enum LoadSignal {
+    /// One-minute load average divided by the number of CPUs.
+    #[default]
+    SystemLoad,
+    /// Active connections of the port divided by `max`.
+    ActiveConnections { max: usize },
+}
+
+/// Maps clients in a network to an upstream tag.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct TagAffinity {
+    #[schema(value_type = String, example = "10.0.0.0/8")]
+    pub network: IpNetwork,
+    #[schema(example = "us")]
+    pub tag: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct Hsts {
+    #[serde(with = "humantime_serde", default = "default_hsts_max_age")]
+    #[schema(value_type = String, example = "1y")]
+    pub max_age: Duration,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub include_subdomains: bool,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub preload: bool,
+}
+
+fn default_hsts_max_age() -> Duration {
+    Duration::from_secs(365 * 24 * 60 * 60)
+}
+
+impl Hsts {
+    pub fn header_value(&self) -> String {
+        let mut value = format!("max-age={}", self.max_age.as_secs());
+        if self.include_subdomains {
+            value.push_str("; includeSubDomains");
+        }
+        if self.preload {
+            value.push_str("; preload");
+        }
+        value
+    }
+}
+
+/// Controls how client data is copied on raw TCP ports.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum BufferingMode {
+    /// Reads the client stream through a buffer. This batches small reads and writes,
+    /// at the cost of an extra copy per transfer.
+    #[default]
+    Buffered,
+    /// Copies directly between the client and upstream sockets. This avoids the extra
+    /// copy for bulk transfers, but each small read turns into its own write.
+    /// Ports which peek at the first bytes, such as HTTP ports with protocol detection
+    /// or ports accepting the PROXY protocol, always buffer.
+    Direct,
+}
+
+impl BufferingMode {
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// Controls how an upstream is picked for each new connection.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum LoadBalanceMode {
+    /// Cycles through the upstreams in proportion to their weights.
+    #[default]
+    RoundRobin,
+    /// Picks the upstream with the fewest open connections relative to its weight.
+    /// Suits long-lived connections whose durations vary widely.
+    /// Ties are broken in round-robin order.
+    LeastConnections,
+    /// Picks the upstream by a hash of the client IP address, weighted by the upstream weights,
+    /// so that a client keeps hitting the same upstream as long as the upstreams are unchanged.
+    /// The hash is not seeded and stays stable across restarts.
+    IpHash,
+}
+
+impl LoadBalanceMode {
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum DuplicateUpstreams {
+    /// Balances over each entry separately, so that a repeated upstream gets a larger share.
+    #[default]
+    Keep,
+    /// Rejects the port config.
+    Error,
+    /// Keeps the first entry, with the sum of the weights of all entries.
+    Merge,
+}
+
+impl DuplicateUpstreams {
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum UpstreamTlsVerification {
+    /// Verifies the certificate chain against the native root certificates,
+    /// and the ones of `upstream_ca_bundle`.
+    #[default]
+    Full,
+    /// Accepts any certificate, including a self-signed one, whose leaf SAN covers
+    /// the upstream hostname, regardless of its issuer.
+    NameMatch,
+}
+
+impl UpstreamTlsVerification {
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// PEM certificates trusted as roots, read from `path`, given inline as `pem`, or both.
+/// An invalid bundle fails loading the port configuration.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct UpstreamCaBundle {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Option<String>, example = "/etc/taxy/internal-ca.pem")]
+    pub path: Option<PathBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pem: Option<String>,
+    /// Trusts the bundle only, without the native root certificates.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub exclude_native_roots: bool,
 }
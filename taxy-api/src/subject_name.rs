@@ -24,6 +24,18 @@ impl SubjectName {
             },
         }
     }
+
+    /// Returns true if both names can match the same host, taking wildcards into account.
+    pub fn overlaps(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::DnsName(a), Self::DnsName(b)) => a.eq_ignore_ascii_case(b),
+            (Self::WildcardDnsName(a), Self::WildcardDnsName(b)) => a.eq_ignore_ascii_case(b),
+            (wildcard @ Self::WildcardDnsName(_), Self::DnsName(name))
+            | (Self::DnsName(name), wildcard @ Self::WildcardDnsName(_)) => wildcard.test(name),
+            (Self::IPAddress(a), Self::IPAddress(b)) => a == b,
+            _ => false,
+        }
+    }
 }
 
 impl Serialize for SubjectName {
@@ -108,4 +120,16 @@ mod test {
             .unwrap()
             .test("127.0.0.1"));
     }
+
+    #[test]
+    fn test_subject_name_overlaps() {
+        let name = |s| SubjectName::from_str(s).unwrap();
+        assert!(name("*.example.com").overlaps(&name("app.example.com")));
+        assert!(name("app.example.com").overlaps(&name("*.example.com")));
+        assert!(name("*.example.com").overlaps(&name("*.example.com")));
+        assert!(!name("*.example.com").overlaps(&name("example.com")));
+        assert!(!name("*.example.com").overlaps(&name("a.app.example.com")));
+        assert!(!name("app.example.com").overlaps(&name("www.example.com")));
+        assert!(!name("example.org").overlaps(&name("*.example.com")));
+    }
 }
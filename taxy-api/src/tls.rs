@@ -5,10 +5,162 @@ use utoipa::ToSchema;
 #[serde(rename_all = "snake_case")]
 pub enum TlsState {
     Active,
+    /// No valid certificate covers the server names, and an expired ACME certificate is served instead.
+    Degraded,
+    /// The refreshed certificate failed to load, and the previous one is served instead.
+    Stale,
+    /// The certificate failed to load, and TLS handshakes fail.
+    Failed,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+/// How a port with TLS termination handles new connections.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum TlsMode {
+    /// TLS is terminated with the certificates of the port.
+    #[default]
+    Terminate,
+    /// Connections are proxied as they are, leaving TLS to the upstreams.
+    Passthrough,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
 pub struct TlsTermination {
     #[schema(example = json!(["*.example.com"]))]
     pub server_names: Vec<String>,
+    #[serde(default, skip_serializing_if = "CertSelection::is_default")]
+    pub cert_selection: CertSelection,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub self_signed_fallback: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_auth: Option<ClientAuth>,
+    /// Keep serving an expired ACME certificate when its renewal has failed and no valid
+    /// certificate is available, instead of failing the handshake.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub serve_expired_acme_certs: bool,
+    /// Maximum number of sessions kept for resumption. The oldest sessions are evicted
+    /// once it is reached. Defaults to 256.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub session_cache_size: Option<usize>,
+    /// ALPN protocols offered to clients, in order of preference.
+    /// Defaults to none on TCP ports and to `h2` and `http/1.1` on HTTP ports.
+    /// Clients offering only other protocols are rejected.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    #[schema(example = json!(["h2", "http/1.1"]))]
+    pub alpn: Vec<String>,
+    /// Accepts Encrypted Client Hello (ECH). Not supported yet, as the TLS backend (rustls 0.21)
+    /// cannot decrypt the inner ClientHello, so ports enabling it are rejected. Until then,
+    /// clients offering ECH are served based on the SNI of their outer ClientHello.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub ech: bool,
+    /// Compresses the certificate chain sent to clients which support it (RFC 8879).
+    /// Not supported yet, as it needs a newer TLS backend than rustls 0.21,
+    /// so ports enabling it are rejected.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub cert_compression: bool,
+}
+
+/// Restricts the TLS handshakes with clients and TLS upstreams of a port.
+/// Empty lists allow everything supported.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct TlsParameters {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub versions: Vec<TlsVersion>,
+    /// IANA names of the cipher suites, in order of preference.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    #[schema(example = json!(["TLS13_AES_256_GCM_SHA384", "TLS13_CHACHA20_POLY1305_SHA256"]))]
+    pub cipher_suites: Vec<String>,
+    /// IANA names of the key exchange groups, in order of preference.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    #[schema(example = json!(["X25519", "secp384r1"]))]
+    pub kx_groups: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum TlsVersion {
+    Tls12,
+    Tls13,
+}
+
+/// Requests a certificate from TLS clients.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct ClientAuth {
+    #[serde(default)]
+    pub mode: ClientAuthMode,
+    /// Ids of the keyring certificates whose root certificates may issue client certificates.
+    #[schema(example = json!(["f9cf7e3faa1aca8d8a2d"]))]
+    pub trusted_certs: Vec<String>,
+    #[serde(default, skip_serializing_if = "ClientCertHeaders::is_default")]
+    pub forward_headers: ClientCertHeaders,
+    /// Overrides `mode` for the clients requesting one of the server names in their SNI.
+    /// The first matching entry applies.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sni_modes: Vec<SniClientAuthMode>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct SniClientAuthMode {
+    #[schema(example = json!(["admin.example.com"]))]
+    pub server_names: Vec<String>,
+    pub mode: ClientAuthMode,
+}
+
+/// Names of the request headers used to forward the verified client certificate
+/// to upstream servers on HTTP ports. Headers with these names sent by the client are removed.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct ClientCertHeaders {
+    /// The URL-encoded PEM of the certificate.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(example = "X-Client-Cert")]
+    pub cert: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(example = "X-Client-Cert-Subject")]
+    pub subject: Option<String>,
+    /// Comma-separated DNS names and IP addresses.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(example = "X-Client-Cert-San")]
+    pub san: Option<String>,
+    /// Hex-encoded SHA-256 fingerprint of the certificate.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(example = "X-Client-Cert-Fingerprint")]
+    pub fingerprint: Option<String>,
+}
+
+impl ClientCertHeaders {
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ClientAuthMode {
+    /// Accept clients without a certificate, but verify the certificate if presented.
+    Optional,
+    /// Reject clients without a valid certificate.
+    #[default]
+    Required,
+    /// Don't request a certificate.
+    None,
+}
+
+/// Decides which certificate is served when several valid certificates
+/// cover the requested server name.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum CertSelection {
+    /// Prefer trusted certificates, then the most recently issued one.
+    #[default]
+    NewestNotBefore,
+    /// Prefer the certificate which expires last.
+    LongestValidity,
+    /// Prefer the certificate with the given id, falling back to `newest_not_before`.
+    Pinned(String),
+}
+
+impl CertSelection {
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
 }
@@ -0,0 +1,37 @@
+use crate::port::SocketState;
+use serde_derive::Serialize;
+use utoipa::ToSchema;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case", tag = "event")]
+pub enum WebhookEvent {
+    PortBindFailed {
+        id: String,
+        #[schema(example = "0.0.0.0:443")]
+        addr: String,
+        state: SocketState,
+    },
+    UpstreamDown {
+        port_id: String,
+        #[schema(example = "example.com:8080")]
+        addr: String,
+    },
+    UpstreamUp {
+        port_id: String,
+        #[schema(example = "example.com:8080")]
+        addr: String,
+    },
+    CertRenewed {
+        acme_id: String,
+        cert_id: String,
+    },
+    CertRenewalFailed {
+        acme_id: String,
+        reason: String,
+    },
+    CertNearExpiry {
+        cert_id: String,
+        #[schema(example = "67090118400")]
+        not_after: i64,
+    },
+}
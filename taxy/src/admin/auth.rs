@@ -1,12 +1,14 @@
 use super::{with_state, AppState};
+use crate::auth::{hash_api_token, ApiToken};
 use std::{
     collections::HashMap,
-    time::{Duration, Instant},
+    time::{Duration, Instant, SystemTime},
 };
 use taxy_api::{
-    auth::{LoginRequest, LoginResult},
+    auth::{ApiTokenInfo, ApiTokenRequest, ApiTokenResult, LoginRequest, LoginResult},
     error::Error,
 };
+use tracing::error;
 use warp::{filters::BoxedFilter, Filter, Rejection, Reply};
 
 const MINIMUM_SESSION_EXPIRY: Duration = Duration::from_secs(60 * 5); // 5 minutes
@@ -21,13 +23,38 @@ pub fn api(app_state: AppState) -> BoxedFilter<(impl Reply,)> {
         .and_then(login);
 
     let api_logout = warp::get().and(warp::path("logout")).and(
-        with_state(app_state)
+        with_state(app_state.clone())
             .and(warp::header::optional("authorization"))
             .and(warp::path::end())
             .and_then(logout),
     );
 
-    api_login.or(api_logout).boxed()
+    let api_token_list = warp::get().and(warp::path("tokens")).and(
+        with_state(app_state.clone())
+            .and(warp::path::end())
+            .and_then(list_tokens),
+    );
+
+    let api_token_create = warp::post().and(warp::path("tokens")).and(
+        with_state(app_state.clone())
+            .and(warp::body::json())
+            .and(warp::path::end())
+            .and_then(create_token),
+    );
+
+    let api_token_revoke = warp::delete().and(warp::path("tokens")).and(
+        with_state(app_state)
+            .and(warp::path::param())
+            .and(warp::path::end())
+            .and_then(revoke_token),
+    );
+
+    api_login
+        .or(api_logout)
+        .or(api_token_list)
+        .or(api_token_create)
+        .or(api_token_revoke)
+        .boxed()
 }
 
 /// Login.
@@ -70,6 +97,79 @@ pub async fn logout(state: AppState, header: Option<String>) -> Result<impl Repl
     Ok(warp::reply::reply())
 }
 
+/// List API tokens.
+#[utoipa::path(
+    get,
+    path = "/api/tokens",
+    responses(
+        (status = 200, body = [ApiTokenInfo]),
+        (status = 401),
+    ),
+    security(
+        ("authorization"=[])
+    )
+)]
+pub async fn list_tokens(state: AppState) -> Result<impl Reply, Rejection> {
+    let mut data = state.data.lock().await;
+    Ok(warp::reply::json(
+        &data.sessions.api_tokens(SystemTime::now()),
+    ))
+}
+
+/// Issue a new API token.
+#[utoipa::path(
+    post,
+    path = "/api/tokens",
+    request_body = ApiTokenRequest,
+    responses(
+        (status = 200, body = ApiTokenResult),
+        (status = 401),
+    ),
+    security(
+        ("authorization"=[])
+    )
+)]
+pub async fn create_token(state: AppState, req: ApiTokenRequest) -> Result<impl Reply, Rejection> {
+    let mut data = state.data.lock().await;
+    let (id, token) = data.sessions.new_api_token(req.expiry);
+    let tokens = data.sessions.api_token_entries();
+    if let Err(err) = crate::auth::save_api_tokens(&data.app_info.config_path, tokens).await {
+        error!("failed to save api tokens: {err}");
+        data.sessions.revoke_api_token(&id);
+        return Err(warp::reject::custom(Error::FailedToSaveApiTokens));
+    }
+    Ok(warp::reply::json(&ApiTokenResult { id, token }))
+}
+
+/// Revoke an API token.
+#[utoipa::path(
+    delete,
+    path = "/api/tokens/{id}",
+    params(
+        ("id" = String, Path, description = "API token id")
+    ),
+    responses(
+        (status = 200),
+        (status = 404),
+        (status = 401),
+    ),
+    security(
+        ("authorization"=[])
+    )
+)]
+pub async fn revoke_token(state: AppState, id: String) -> Result<impl Reply, Rejection> {
+    let mut data = state.data.lock().await;
+    if !data.sessions.revoke_api_token(&id) {
+        return Err(warp::reject::custom(Error::IdNotFound { id }));
+    }
+    let tokens = data.sessions.api_token_entries();
+    if let Err(err) = crate::auth::save_api_tokens(&data.app_info.config_path, tokens).await {
+        error!("failed to save api tokens: {err}");
+        return Err(warp::reject::custom(Error::FailedToSaveApiTokens));
+    }
+    Ok(warp::reply::reply())
+}
+
 pub fn get_auth_token(header: &Option<String>) -> Option<&str> {
     if let Some(header) = header {
         let parts: Vec<&str> = header.split(' ').collect();
@@ -85,9 +185,28 @@ pub fn get_auth_token(header: &Option<String>) -> Option<&str> {
 #[derive(Default)]
 pub struct SessionStore {
     tokens: HashMap<String, Instant>,
+    /// API tokens keyed by the hash of the token.
+    api_tokens: HashMap<String, ApiToken>,
+}
+
+fn is_expired(token: &ApiToken, now: SystemTime) -> bool {
+    token.info.expiry.is_some_and(|expiry| {
+        now.duration_since(token.info.created_at)
+            .is_ok_and(|elapsed| elapsed >= expiry)
+    })
 }
 
 impl SessionStore {
+    pub fn with_api_tokens(tokens: Vec<ApiToken>) -> Self {
+        Self {
+            tokens: HashMap::new(),
+            api_tokens: tokens
+                .into_iter()
+                .map(|token| (token.hash.clone(), token))
+                .collect(),
+        }
+    }
+
     pub fn new_token(&mut self) -> String {
         let token = cuid2::cuid();
         self.tokens.insert(token.clone(), Instant::now());
@@ -95,16 +214,129 @@ impl SessionStore {
     }
 
     pub fn verify(&mut self, token: &str, expiry: Duration) -> bool {
+        self.verify_session(token, expiry, Instant::now())
+            || self.verify_api_token(token, SystemTime::now())
+    }
+
+    fn verify_session(&mut self, token: &str, expiry: Duration, now: Instant) -> bool {
         let expiry = expiry.max(MINIMUM_SESSION_EXPIRY);
         self.tokens = self
             .tokens
             .drain()
-            .filter(|(_, t)| t.elapsed() < expiry)
+            .filter(|(_, t)| now.saturating_duration_since(*t) < expiry)
             .collect();
         self.tokens.contains_key(token)
     }
 
+    fn verify_api_token(&mut self, token: &str, now: SystemTime) -> bool {
+        self.api_tokens.retain(|_, t| !is_expired(t, now));
+        self.api_tokens.contains_key(&hash_api_token(token))
+    }
+
     pub fn remove(&mut self, token: &str) {
         self.tokens.remove(token);
     }
+
+    pub fn new_api_token(&mut self, expiry: Option<Duration>) -> (String, String) {
+        let id = cuid2::cuid();
+        let token = cuid2::cuid();
+        let hash = hash_api_token(&token);
+        self.api_tokens.insert(
+            hash.clone(),
+            ApiToken {
+                info: ApiTokenInfo {
+                    id: id.clone(),
+                    created_at: SystemTime::now(),
+                    expiry,
+                },
+                hash,
+            },
+        );
+        (id, token)
+    }
+
+    pub fn revoke_api_token(&mut self, id: &str) -> bool {
+        let len = self.api_tokens.len();
+        self.api_tokens.retain(|_, t| t.info.id != id);
+        self.api_tokens.len() < len
+    }
+
+    /// Returns the unexpired API tokens, oldest first.
+    pub fn api_tokens(&mut self, now: SystemTime) -> Vec<ApiTokenInfo> {
+        self.api_tokens.retain(|_, t| !is_expired(t, now));
+        let mut tokens = self
+            .api_tokens
+            .values()
+            .map(|t| t.info.clone())
+            .collect::<Vec<_>>();
+        tokens.sort_by_key(|t| t.created_at);
+        tokens
+    }
+
+    pub fn api_token_entries(&self) -> Vec<ApiToken> {
+        let mut tokens = self.api_tokens.values().cloned().collect::<Vec<_>>();
+        tokens.sort_by_key(|t| t.info.created_at);
+        tokens
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_api_token_outlives_session() {
+        let mut store = SessionStore::default();
+        let session = store.new_token();
+        let (_, token) = store.new_api_token(None);
+
+        let expiry = Duration::from_secs(60 * 60);
+        assert!(!store.verify_session(&session, expiry, Instant::now() + expiry * 2));
+        assert!(store.verify_api_token(&token, SystemTime::now() + expiry * 2));
+    }
+
+    #[test]
+    fn test_api_token_expiry() {
+        let mut store = SessionStore::default();
+        let (_, token) = store.new_api_token(Some(Duration::from_secs(60 * 60 * 24)));
+
+        let hour = Duration::from_secs(60 * 60);
+        let now = SystemTime::now();
+        assert!(store.verify_api_token(&token, now + hour * 2));
+        assert!(!store.verify_api_token(&token, now + hour * 25));
+        assert!(store.api_tokens(now).is_empty());
+    }
+
+    #[test]
+    fn test_api_token_revoke() {
+        let mut store = SessionStore::default();
+        let (id_a, token_a) = store.new_api_token(None);
+        let (id_b, token_b) = store.new_api_token(None);
+
+        assert!(store.revoke_api_token(&id_a));
+        assert!(!store.revoke_api_token(&id_a));
+
+        let expiry = Duration::from_secs(60 * 60);
+        assert!(!store.verify(&token_a, expiry));
+        assert!(store.verify(&token_b, expiry));
+
+        let ids = store
+            .api_tokens(SystemTime::now())
+            .into_iter()
+            .map(|t| t.id)
+            .collect::<Vec<_>>();
+        assert_eq!(ids, [id_b]);
+    }
+
+    #[test]
+    fn test_api_token_reload() {
+        let mut store = SessionStore::default();
+        let (id, token) = store.new_api_token(None);
+        let entries = store.api_token_entries();
+        assert!(entries.iter().all(|t| t.hash != token));
+
+        let mut store = SessionStore::with_api_tokens(entries);
+        assert!(store.verify(&token, Duration::from_secs(60 * 60)));
+        assert!(store.revoke_api_token(&id));
+    }
 }
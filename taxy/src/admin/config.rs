@@ -8,10 +8,20 @@ pub fn api(app_state: AppState) -> BoxedFilter<(impl Reply,)> {
         .and(warp::path::end())
         .and(with_state(app_state.clone()).and_then(get));
 
-    let api_put = warp::put()
+    let api_put = warp::put().and(warp::path::end()).and(
+        with_state(app_state.clone())
+            .and(warp::body::json())
+            .and_then(put),
+    );
+
+    let api_reload = warp::post()
+        .and(warp::path("reload"))
         .and(warp::path::end())
-        .and(with_state(app_state).and(warp::body::json()).and_then(put));
-    warp::path("config").and(api_get.or(api_put)).boxed()
+        .and(with_state(app_state).and_then(reload));
+
+    warp::path("config")
+        .and(api_get.or(api_put).or(api_reload))
+        .boxed()
 }
 
 /// Get the application configuration.
@@ -47,3 +57,22 @@ pub async fn get(state: AppState) -> Result<impl Reply, Rejection> {
 pub async fn put(state: AppState, config: AppConfig) -> Result<impl Reply, Rejection> {
     Ok(warp::reply::json(&state.call(SetConfig { config }).await?))
 }
+
+/// Reload the configuration files.
+///
+/// Resources defined in the files override changes made through the API,
+/// while resources created through the API and missing from the files are kept.
+#[utoipa::path(
+    post,
+    path = "/api/config/reload",
+    responses(
+        (status = 200),
+        (status = 401),
+    ),
+    security(
+        ("authorization"=[])
+    )
+)]
+pub async fn reload(state: AppState) -> Result<impl Reply, Rejection> {
+    Ok(warp::reply::json(&state.call(ReloadConfig).await?))
+}
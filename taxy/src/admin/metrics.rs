@@ -0,0 +1,211 @@
+use super::{with_state, AppState};
+use crate::server::rpc::metrics::GetMetrics;
+use std::fmt::Write;
+use taxy_api::metrics::{MetricFamily, MetricKind};
+use warp::{filters::BoxedFilter, Filter, Rejection, Reply};
+
+const PROMETHEUS_CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+const OPENMETRICS_CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+const JSON_CONTENT_TYPE: &str = "application/json";
+
+pub fn api(app_state: AppState) -> BoxedFilter<(impl Reply,)> {
+    warp::path("metrics")
+        .and(warp::get())
+        .and(
+            with_state(app_state)
+                .and(warp::header::optional::<String>("accept"))
+                .and(warp::path::end())
+                .and_then(get),
+        )
+        .boxed()
+}
+
+/// Get metrics in the Prometheus text, OpenMetrics or JSON format depending on the `Accept` header.
+#[utoipa::path(
+    get,
+    path = "/api/metrics",
+    responses(
+        (status = 200, body = [MetricFamily], content_type = "application/json"),
+        (status = 200, body = String, content_type = "text/plain"),
+        (status = 200, body = String, content_type = "application/openmetrics-text"),
+        (status = 401),
+    ),
+    security(
+        ("authorization"=[])
+    )
+)]
+pub async fn get(state: AppState, accept: Option<String>) -> Result<impl Reply, Rejection> {
+    let metrics = state.call(GetMetrics).await?;
+    let format = MetricsFormat::negotiate(accept.as_deref());
+    Ok(warp::reply::with_header(
+        format.encode(&metrics),
+        "content-type",
+        format.content_type(),
+    ))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum MetricsFormat {
+    Prometheus,
+    OpenMetrics,
+    Json,
+}
+
+impl MetricsFormat {
+    /// Picks the first supported media type in the `Accept` header,
+    /// falling back to the Prometheus text format.
+    fn negotiate(accept: Option<&str>) -> Self {
+        accept
+            .into_iter()
+            .flat_map(|accept| accept.split(','))
+            .filter_map(|media| media.split(';').next())
+            .find_map(|media| match media.trim() {
+                "application/openmetrics-text" => Some(Self::OpenMetrics),
+                "application/json" => Some(Self::Json),
+                "text/plain" => Some(Self::Prometheus),
+                _ => None,
+            })
+            .unwrap_or(Self::Prometheus)
+    }
+
+    fn content_type(&self) -> &'static str {
+        match self {
+            Self::Prometheus => PROMETHEUS_CONTENT_TYPE,
+            Self::OpenMetrics => OPENMETRICS_CONTENT_TYPE,
+            Self::Json => JSON_CONTENT_TYPE,
+        }
+    }
+
+    fn encode(&self, metrics: &[MetricFamily]) -> String {
+        match self {
+            Self::Prometheus => encode_text(metrics, false),
+            Self::OpenMetrics => encode_text(metrics, true),
+            Self::Json => serde_json::to_string(metrics).unwrap_or_default(),
+        }
+    }
+}
+
+fn encode_text(metrics: &[MetricFamily], openmetrics: bool) -> String {
+    let mut out = String::new();
+    for family in metrics {
+        let (kind, suffix) = match family.kind {
+            MetricKind::Counter => ("counter", "_total"),
+            MetricKind::Gauge => ("gauge", ""),
+        };
+        // OpenMetrics names the counter family without the `_total` suffix of its samples.
+        let family_name = if openmetrics {
+            family.name.clone()
+        } else {
+            format!("{}{suffix}", family.name)
+        };
+        let _ = writeln!(out, "# HELP {family_name} {}", family.help);
+        let _ = writeln!(out, "# TYPE {family_name} {kind}");
+        for sample in &family.samples {
+            let labels = sample
+                .labels
+                .iter()
+                .map(|(key, value)| format!("{key}=\"{}\"", escape_label(value)))
+                .collect::<Vec<_>>();
+            let _ = write!(out, "{}{suffix}", family.name);
+            if !labels.is_empty() {
+                let _ = write!(out, "{{{}}}", labels.join(","));
+            }
+            let _ = writeln!(out, " {}", sample.value);
+        }
+    }
+    if openmetrics {
+        out.push_str("# EOF\n");
+    }
+    out
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::BTreeMap;
+    use taxy_api::metrics::MetricSample;
+
+    fn metrics() -> Vec<MetricFamily> {
+        let labels = BTreeMap::from([("port".to_string(), "web".to_string())]);
+        vec![
+            MetricFamily {
+                name: "taxy_port_active_connections".into(),
+                help: "Number of connections currently being proxied.".into(),
+                kind: MetricKind::Gauge,
+                samples: vec![MetricSample {
+                    labels: labels.clone(),
+                    value: 2.0,
+                }],
+            },
+            MetricFamily {
+                name: "taxy_port_connections".into(),
+                help: "Total number of accepted connections.".into(),
+                kind: MetricKind::Counter,
+                samples: vec![MetricSample { labels, value: 5.0 }],
+            },
+        ]
+    }
+
+    #[test]
+    fn test_negotiate() {
+        assert_eq!(MetricsFormat::negotiate(None), MetricsFormat::Prometheus);
+        assert_eq!(
+            MetricsFormat::negotiate(Some("*/*")),
+            MetricsFormat::Prometheus
+        );
+        assert_eq!(
+            MetricsFormat::negotiate(Some(
+                "application/openmetrics-text; version=1.0.0,text/plain;q=0.5"
+            )),
+            MetricsFormat::OpenMetrics
+        );
+        assert_eq!(
+            MetricsFormat::negotiate(Some("application/json")),
+            MetricsFormat::Json
+        );
+    }
+
+    #[test]
+    fn test_encode() {
+        let metrics = metrics();
+        assert_eq!(
+            MetricsFormat::Prometheus.encode(&metrics),
+            "# HELP taxy_port_active_connections Number of connections currently being proxied.\n\
+             # TYPE taxy_port_active_connections gauge\n\
+             taxy_port_active_connections{port=\"web\"} 2\n\
+             # HELP taxy_port_connections_total Total number of accepted connections.\n\
+             # TYPE taxy_port_connections_total counter\n\
+             taxy_port_connections_total{port=\"web\"} 5\n"
+        );
+        assert_eq!(
+            MetricsFormat::OpenMetrics.encode(&metrics),
+            "# HELP taxy_port_active_connections Number of connections currently being proxied.\n\
+             # TYPE taxy_port_active_connections gauge\n\
+             taxy_port_active_connections{port=\"web\"} 2\n\
+             # HELP taxy_port_connections Total number of accepted connections.\n\
+             # TYPE taxy_port_connections counter\n\
+             taxy_port_connections_total{port=\"web\"} 5\n\
+             # EOF\n"
+        );
+
+        let json: Vec<MetricFamily> =
+            serde_json::from_str(&MetricsFormat::Json.encode(&metrics)).unwrap();
+        assert_eq!(json, metrics);
+        assert_eq!(
+            serde_json::to_value(&json[1]).unwrap(),
+            serde_json::json!({
+                "name": "taxy_port_connections",
+                "help": "Total number of accepted connections.",
+                "kind": "counter",
+                "samples": [{ "labels": { "port": "web" }, "value": 5.0 }],
+            })
+        );
+    }
+}
@@ -21,12 +21,14 @@ use warp::{sse::Event, Filter, Rejection, Reply};
 
 use self::auth::SessionStore;
 use self::log::LogReader;
+pub use self::static_file::StaticSource;
 
 mod acme;
 mod app_info;
 mod auth;
 mod config;
 mod log;
+mod metrics;
 mod ports;
 mod server_certs;
 mod sites;
@@ -39,6 +41,7 @@ pub async fn start_admin(
     command: mpsc::Sender<ServerCommand>,
     mut callback: mpsc::Receiver<RpcCallback>,
     event: broadcast::Sender<ServerEvent>,
+    static_source: static_file::StaticSource,
 ) -> anyhow::Result<()> {
     let data = Data::new(app_info).await?;
     let data = Arc::new(Mutex::new(data));
@@ -62,7 +65,7 @@ pub async fn start_admin(
         loop {
             match event_recv.recv().await {
                 Ok(ServerEvent::AppConfigUpdated { config, .. }) => {
-                    data.lock().await.config = config;
+                    data.lock().await.config = *config;
                 }
                 Ok(ServerEvent::Shutdown) => break,
                 Err(RecvError::Lagged(n)) => {
@@ -74,6 +77,7 @@ pub async fn start_admin(
     });
 
     let static_file = warp::get()
+        .and(warp::any().map(move || static_source.clone()))
         .and(warp::path::full())
         .and_then(static_file::get);
 
@@ -128,6 +132,7 @@ pub async fn start_admin(
             .or(server_certs::api(app_state.clone()))
             .or(acme::api(app_state.clone()))
             .or(auth::api(app_state.clone()))
+            .or(metrics::api(app_state.clone()))
             .or(log::api(app_state))
             .or(api_events)
             .or(api_doc)
@@ -225,10 +230,17 @@ impl AppState {
 impl Data {
     async fn new(app_info: AppInfo) -> anyhow::Result<Self> {
         let log = app_info.log_path.join("log.db");
+        let api_tokens = match crate::auth::load_api_tokens(&app_info.config_path).await {
+            Ok(tokens) => tokens,
+            Err(err) => {
+                error!("failed to load api tokens: {err}");
+                Vec::new()
+            }
+        };
         Ok(Self {
             app_info,
             config: AppConfig::default(),
-            sessions: Default::default(),
+            sessions: SessionStore::with_api_tokens(api_tokens),
             log: Arc::new(LogReader::new(&log).await?),
             rpc_counter: 0,
             rpc_callbacks: HashMap::new(),
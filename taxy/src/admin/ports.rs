@@ -1,6 +1,7 @@
 use super::{with_state, AppState};
 use crate::server::rpc::ports::*;
-use taxy_api::port::Port;
+use taxy_api::port::{DrainQuery, Port, UpstreamState};
+use taxy_api::tls::TlsMode;
 use warp::{filters::BoxedFilter, Filter, Rejection, Reply};
 
 pub fn api(app_state: AppState) -> BoxedFilter<(impl Reply,)> {
@@ -8,6 +9,11 @@ pub fn api(app_state: AppState) -> BoxedFilter<(impl Reply,)> {
         .and(warp::path::end())
         .and(with_state(app_state.clone()).and_then(list));
 
+    let ports_stats = warp::get()
+        .and(warp::path("stats"))
+        .and(warp::path::end())
+        .and(with_state(app_state.clone()).and_then(stats));
+
     let ports_status = warp::get()
         .and(with_state(app_state.clone()))
         .and(warp::path::param())
@@ -15,6 +21,21 @@ pub fn api(app_state: AppState) -> BoxedFilter<(impl Reply,)> {
         .and(warp::path::end())
         .and_then(status);
 
+    let ports_connections = warp::get()
+        .and(with_state(app_state.clone()))
+        .and(warp::path::param())
+        .and(warp::path("connections"))
+        .and(warp::path::end())
+        .and_then(connections);
+
+    let ports_recent_connections = warp::get()
+        .and(with_state(app_state.clone()))
+        .and(warp::path::param())
+        .and(warp::path("connections"))
+        .and(warp::path("recent"))
+        .and(warp::path::end())
+        .and_then(recent_connections);
+
     let ports_delete = warp::delete().and(
         with_state(app_state.clone())
             .and(warp::path::param())
@@ -37,19 +58,70 @@ pub fn api(app_state: AppState) -> BoxedFilter<(impl Reply,)> {
             .and_then(post),
     );
 
+    let ports_upstream_state = warp::post().and(
+        with_state(app_state.clone())
+            .and(warp::body::json())
+            .and(warp::path::param())
+            .and(warp::path("upstreams"))
+            .and(warp::path("state"))
+            .and(warp::path::end())
+            .and_then(upstream_state),
+    );
+
+    let ports_upstream_distribution = warp::get()
+        .and(with_state(app_state.clone()))
+        .and(warp::path::param())
+        .and(warp::path("upstreams"))
+        .and(warp::path("distribution"))
+        .and(warp::path::end())
+        .and_then(upstream_distribution);
+
+    let ports_tls_mode = warp::post().and(
+        with_state(app_state.clone())
+            .and(warp::body::json())
+            .and(warp::path::param())
+            .and(warp::path("tls"))
+            .and(warp::path("mode"))
+            .and(warp::path::end())
+            .and_then(tls_mode),
+    );
+
     let ports_reset = warp::get()
-        .and(with_state(app_state))
+        .and(with_state(app_state.clone()))
         .and(warp::path::param())
         .and(warp::path("reset"))
         .and(warp::path::end())
         .and_then(reset);
 
+    let ports_drain = warp::get()
+        .and(with_state(app_state.clone()))
+        .and(warp::path::param())
+        .and(warp::path("drain"))
+        .and(warp::query())
+        .and(warp::path::end())
+        .and_then(drain);
+
+    let ports_rebind = warp::get()
+        .and(with_state(app_state))
+        .and(warp::path::param())
+        .and(warp::path("rebind"))
+        .and(warp::path::end())
+        .and_then(rebind);
+
     warp::path("ports")
         .and(
             ports_delete
                 .or(ports_put)
+                .or(ports_stats)
                 .or(ports_status)
+                .or(ports_connections)
+                .or(ports_recent_connections)
+                .or(ports_upstream_state)
+                .or(ports_upstream_distribution)
+                .or(ports_tls_mode)
                 .or(ports_reset)
+                .or(ports_drain)
+                .or(ports_rebind)
                 .or(ports_list)
                 .or(ports_post),
         )
@@ -94,6 +166,66 @@ pub async fn status(state: AppState, id: String) -> Result<impl Reply, Rejection
     Ok(warp::reply::json(&state.call(GetPortStatus { id }).await?))
 }
 
+/// Get a snapshot of the status and counters of every port.
+#[utoipa::path(
+    get,
+    path = "/api/ports/stats",
+    responses(
+        (status = 200, body = [PortStats]),
+        (status = 401),
+    ),
+    security(
+        ("authorization"=[])
+    )
+)]
+pub async fn stats(state: AppState) -> Result<impl Reply, Rejection> {
+    Ok(warp::reply::json(&state.call(GetPortStats).await?))
+}
+
+/// Get the active connections of a port.
+#[utoipa::path(
+    get,
+    path = "/api/ports/{id}/connections",
+    params(
+        ("id" = String, Path, description = "Port configuration id")
+    ),
+    responses(
+        (status = 200, body = [ConnectionInfo]),
+        (status = 404),
+        (status = 401),
+    ),
+    security(
+        ("authorization"=[])
+    )
+)]
+pub async fn connections(state: AppState, id: String) -> Result<impl Reply, Rejection> {
+    Ok(warp::reply::json(
+        &state.call(GetPortConnections { id }).await?,
+    ))
+}
+
+/// Get the recently closed connections of a port, newest first.
+#[utoipa::path(
+    get,
+    path = "/api/ports/{id}/connections/recent",
+    params(
+        ("id" = String, Path, description = "Port configuration id")
+    ),
+    responses(
+        (status = 200, body = [ClosedConnectionInfo]),
+        (status = 404),
+        (status = 401),
+    ),
+    security(
+        ("authorization"=[])
+    )
+)]
+pub async fn recent_connections(state: AppState, id: String) -> Result<impl Reply, Rejection> {
+    Ok(warp::reply::json(
+        &state.call(GetRecentPortConnections { id }).await?,
+    ))
+}
+
 /// Delete a port configuration.
 #[utoipa::path(
     delete,
@@ -161,6 +293,85 @@ pub async fn put(state: AppState, entry: Port, id: String) -> Result<impl Reply,
     Ok(warp::reply::json(&state.call(UpdatePort { entry }).await?))
 }
 
+/// Disable or re-enable an upstream of a port.
+#[utoipa::path(
+    post,
+    path = "/api/ports/{id}/upstreams/state",
+    params(
+        ("id" = String, Path, description = "Port configuration id")
+    ),
+    request_body = UpstreamState,
+    responses(
+        (status = 200),
+        (status = 404),
+        (status = 401),
+    ),
+    security(
+        ("authorization"=[])
+    )
+)]
+pub async fn upstream_state(
+    state: AppState,
+    upstream: UpstreamState,
+    id: String,
+) -> Result<impl Reply, Rejection> {
+    Ok(warp::reply::json(
+        &state
+            .call(SetUpstreamState {
+                id,
+                state: upstream,
+            })
+            .await?,
+    ))
+}
+
+/// Get the share of the recent connections of a port picked for each upstream, against its weight.
+#[utoipa::path(
+    get,
+    path = "/api/ports/{id}/upstreams/distribution",
+    params(
+        ("id" = String, Path, description = "Port configuration id")
+    ),
+    responses(
+        (status = 200, body = [UpstreamDistribution]),
+        (status = 404),
+        (status = 401),
+    ),
+    security(
+        ("authorization"=[])
+    )
+)]
+pub async fn upstream_distribution(state: AppState, id: String) -> Result<impl Reply, Rejection> {
+    Ok(warp::reply::json(
+        &state.call(GetUpstreamDistribution { id }).await?,
+    ))
+}
+
+/// Switch a raw TCP port with TLS termination between terminating and passing through TLS.
+/// Existing connections are kept in their mode, and the mode is kept across configuration updates.
+#[utoipa::path(
+    post,
+    path = "/api/ports/{id}/tls/mode",
+    params(
+        ("id" = String, Path, description = "Port configuration id")
+    ),
+    request_body = TlsMode,
+    responses(
+        (status = 200),
+        (status = 400),
+        (status = 404),
+        (status = 401),
+    ),
+    security(
+        ("authorization"=[])
+    )
+)]
+pub async fn tls_mode(state: AppState, mode: TlsMode, id: String) -> Result<impl Reply, Rejection> {
+    Ok(warp::reply::json(
+        &state.call(SetTlsMode { id, mode }).await?,
+    ))
+}
+
 /// Close all existing connections.
 #[utoipa::path(
     get,
@@ -180,3 +391,56 @@ pub async fn put(state: AppState, entry: Port, id: String) -> Result<impl Reply,
 pub async fn reset(state: AppState, id: String) -> Result<impl Reply, Rejection> {
     Ok(warp::reply::json(&state.call(ResetPort { id }).await?))
 }
+
+/// Reject new connections, and close the existing ones once the timeout has elapsed.
+/// Updating the port configuration accepts new connections again.
+#[utoipa::path(
+    get,
+    path = "/api/ports/{id}/drain",
+    params(
+        ("id" = String, Path, description = "Port configuration id"),
+        DrainQuery
+    ),
+    responses(
+        (status = 200),
+        (status = 404),
+        (status = 401),
+    ),
+    security(
+        ("authorization"=[])
+    )
+)]
+pub async fn drain(
+    state: AppState,
+    id: String,
+    query: DrainQuery,
+) -> Result<impl Reply, Rejection> {
+    Ok(warp::reply::json(
+        &state
+            .call(DrainPort {
+                id,
+                timeout: query.timeout,
+            })
+            .await?,
+    ))
+}
+
+/// Bind a listener unbound for being idle again.
+#[utoipa::path(
+    get,
+    path = "/api/ports/{id}/rebind",
+    params(
+        ("id" = String, Path, description = "Port configuration id")
+    ),
+    responses(
+        (status = 200),
+        (status = 404),
+        (status = 401),
+    ),
+    security(
+        ("authorization"=[])
+    )
+)]
+pub async fn rebind(state: AppState, id: String) -> Result<impl Reply, Rejection> {
+    Ok(warp::reply::json(&state.call(RebindPort { id }).await?))
+}
@@ -1,14 +1,19 @@
 use super::{with_state, AppState};
 use crate::{keyring::certs::Cert, server::rpc::server_certs::*};
 use std::io::Read;
-use taxy_api::{cert::SelfSignedCertRequest, error::Error};
+use taxy_api::{
+    cert::{CertListQuery, CertTrustRequest, SelfSignedCertRequest},
+    error::Error,
+};
 use tokio_stream::StreamExt;
 use warp::{filters::BoxedFilter, multipart::FormData, Buf, Filter, Rejection, Reply};
 
 pub fn api(app_state: AppState) -> BoxedFilter<(impl Reply,)> {
-    let api_list = warp::get()
-        .and(warp::path::end())
-        .and(with_state(app_state.clone()).and_then(list));
+    let api_list = warp::get().and(warp::path::end()).and(
+        with_state(app_state.clone())
+            .and(warp::query())
+            .and_then(list),
+    );
 
     let api_self_sign = warp::post().and(warp::path("self_sign")).and(
         with_state(app_state.clone())
@@ -24,6 +29,30 @@ pub fn api(app_state: AppState) -> BoxedFilter<(impl Reply,)> {
             .and_then(upload),
     );
 
+    let api_validate = warp::post().and(warp::path("validate")).and(
+        with_state(app_state.clone())
+            .and(warp::multipart::form())
+            .and(warp::path::end())
+            .and_then(validate),
+    );
+
+    let api_trust = warp::put().and(
+        with_state(app_state.clone())
+            .and(warp::body::json())
+            .and(warp::path::param())
+            .and(warp::path("trust"))
+            .and(warp::path::end())
+            .and_then(trust),
+    );
+
+    let api_ports = warp::get().and(
+        with_state(app_state.clone())
+            .and(warp::path::param())
+            .and(warp::path("ports"))
+            .and(warp::path::end())
+            .and_then(ports),
+    );
+
     let api_delete = warp::delete().and(
         with_state(app_state)
             .and(warp::path::param())
@@ -32,7 +61,15 @@ pub fn api(app_state: AppState) -> BoxedFilter<(impl Reply,)> {
     );
 
     warp::path("server_certs")
-        .and(api_delete.or(api_self_sign).or(api_upload).or(api_list))
+        .and(
+            api_delete
+                .or(api_trust)
+                .or(api_ports)
+                .or(api_self_sign)
+                .or(api_upload)
+                .or(api_validate)
+                .or(api_list),
+        )
         .boxed()
 }
 
@@ -40,6 +77,7 @@ pub fn api(app_state: AppState) -> BoxedFilter<(impl Reply,)> {
 #[utoipa::path(
     get,
     path = "/api/server_certs",
+    params(CertListQuery),
     responses(
         (status = 200, body = [CertInfo]),
         (status = 401),
@@ -48,8 +86,10 @@ pub fn api(app_state: AppState) -> BoxedFilter<(impl Reply,)> {
         ("authorization"=[])
     )
 )]
-pub async fn list(state: AppState) -> Result<impl Reply, Rejection> {
-    Ok(warp::reply::json(&state.call(GetServerCertList).await?))
+pub async fn list(state: AppState, query: CertListQuery) -> Result<impl Reply, Rejection> {
+    Ok(warp::reply::json(
+        &state.call(GetServerCertList { query }).await?,
+    ))
 }
 
 /// Generate a self-signed certificate.
@@ -70,9 +110,8 @@ pub async fn self_sign(
     state: AppState,
     request: SelfSignedCertRequest,
 ) -> Result<impl Reply, Rejection> {
-    let cert = Cert::new_self_signed(&request)?;
     Ok(warp::reply::json(
-        &state.call(AddServerCert { cert }).await?,
+        &state.call(SelfSignServerCert { request }).await?,
     ))
 }
 
@@ -90,7 +129,35 @@ pub async fn self_sign(
         ("authorization"=[])
     )
 )]
-pub async fn upload(state: AppState, mut form: FormData) -> Result<impl Reply, Rejection> {
+pub async fn upload(state: AppState, form: FormData) -> Result<impl Reply, Rejection> {
+    let cert = read_cert_form(form).await?;
+    Ok(warp::reply::json(
+        &state.call(AddServerCert { cert }).await?,
+    ))
+}
+
+/// Validate a certificate and key pair without adding it to the keyring.
+#[utoipa::path(
+    post,
+    path = "/api/server_certs/validate",
+    request_body(content = CertPostBody, content_type = "multipart/form-data"),
+    responses(
+        (status = 200, body = CertValidation),
+        (status = 400, body = Error),
+        (status = 401),
+    ),
+    security(
+        ("authorization"=[])
+    )
+)]
+pub async fn validate(state: AppState, form: FormData) -> Result<impl Reply, Rejection> {
+    let cert = read_cert_form(form).await?;
+    Ok(warp::reply::json(
+        &state.call(ValidateServerCert { cert }).await?,
+    ))
+}
+
+async fn read_cert_form(mut form: FormData) -> Result<Cert, Error> {
     let mut chain = Vec::new();
     let mut key = Vec::new();
     while let Some(part) = form.next().await {
@@ -111,9 +178,62 @@ pub async fn upload(state: AppState, mut form: FormData) -> Result<impl Reply, R
         }
     }
 
-    let cert = Cert::new(chain, key)?;
+    Cert::new(chain, key)
+}
+
+/// Mark a certificate as trusted or untrusted. Trusted certificates take
+/// precedence in certificate selection.
+#[utoipa::path(
+    put,
+    path = "/api/server_certs/{id}/trust",
+    params(
+        ("id" = String, Path, description = "Certification ID")
+    ),
+    request_body = CertTrustRequest,
+    responses(
+        (status = 200),
+        (status = 404),
+        (status = 401),
+    ),
+    security(
+        ("authorization"=[])
+    )
+)]
+pub async fn trust(
+    state: AppState,
+    request: CertTrustRequest,
+    id: String,
+) -> Result<impl Reply, Rejection> {
+    Ok(warp::reply::json(
+        &state
+            .call(SetServerCertTrust {
+                id,
+                is_trusted: request.is_trusted,
+            })
+            .await?,
+    ))
+}
+
+/// List the ids of the ports which may serve a certificate, because it is pinned
+/// or because it or its ACME entry covers one of their server names.
+#[utoipa::path(
+    get,
+    path = "/api/server_certs/{id}/ports",
+    params(
+        ("id" = String, Path, description = "Certification ID")
+    ),
+    responses(
+        (status = 200, body = [String]),
+        (status = 404),
+        (status = 401),
+    ),
+    security(
+        ("authorization"=[])
+    )
+)]
+pub async fn ports(state: AppState, id: String) -> Result<impl Reply, Rejection> {
     Ok(warp::reply::json(
-        &state.call(AddServerCert { cert }).await?,
+        &state.call(GetServerCertPorts { id }).await?,
     ))
 }
 
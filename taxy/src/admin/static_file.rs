@@ -1,12 +1,47 @@
 use include_dir::{include_dir, Dir};
-use std::path::Path;
+use std::borrow::Cow;
+use std::path::{Path, PathBuf};
 use warp::{path::FullPath, Rejection, Reply};
 
 static STATIC_DIR: Dir<'_> = include_dir!("$CARGO_MANIFEST_DIR/../webui/dist");
 
-pub async fn get(path: FullPath) -> Result<impl Reply, Rejection> {
+/// Where the web UI files are served from.
+#[derive(Debug, Clone, Default)]
+pub enum StaticSource {
+    /// The bundle embedded in the binary at build time.
+    #[default]
+    Embedded,
+    /// A `webui/dist` directory on disk, read on every request so that
+    /// rebuilt files are picked up without recompiling.
+    Dir(PathBuf),
+}
+
+impl StaticSource {
+    pub fn new(dir: Option<PathBuf>) -> Self {
+        dir.map(Self::Dir).unwrap_or_default()
+    }
+
+    async fn get_file(&self, path: &str) -> Option<(Cow<'static, [u8]>, bool)> {
+        let gz = Path::new("webui").join(format!("{path}.gz"));
+        match self {
+            Self::Embedded => STATIC_DIR
+                .get_file(gz)
+                .map(|file| (Cow::Borrowed(file.contents()), true)),
+            Self::Dir(dir) => {
+                if let Ok(data) = tokio::fs::read(dir.join(gz)).await {
+                    Some((Cow::Owned(data), true))
+                } else {
+                    let data = tokio::fs::read(dir.join("webui").join(path)).await.ok()?;
+                    Some((Cow::Owned(data), false))
+                }
+            }
+        }
+    }
+}
+
+pub async fn get(source: StaticSource, path: FullPath) -> Result<impl Reply, Rejection> {
     let path = path.as_str();
-    if path.starts_with("/api/") {
+    if path.starts_with("/api/") || path.split('/').any(|segment| segment == "..") {
         return Err(warp::reject::not_found());
     }
     let path_has_extension = path
@@ -18,20 +53,51 @@ pub async fn get(path: FullPath) -> Result<impl Reply, Rejection> {
     } else {
         path.trim_start_matches('/')
     };
-    let path = Path::new("webui").join(format!("{path}.gz"));
-    if let Some(file) = STATIC_DIR.get_file(path) {
-        let ext = file
-            .path()
-            .file_stem()
-            .and_then(|x| x.to_str())
-            .unwrap_or_default();
-        let mime = mime_guess::from_path(ext).first_or_octet_stream();
-        Ok(warp::reply::with_header(
-            warp::reply::with_header(file.contents(), "Content-Encoding", "gzip"),
-            "Content-Type",
-            mime.to_string(),
-        ))
+    if let Some((contents, gzip)) = source.get_file(path).await {
+        let mime = mime_guess::from_path(path).first_or_octet_stream();
+        let reply =
+            warp::reply::with_header(contents.into_owned(), "Content-Type", mime.to_string());
+        if gzip {
+            Ok(warp::reply::with_header(reply, "Content-Encoding", "gzip").into_response())
+        } else {
+            Ok(reply.into_response())
+        }
     } else {
         Err(warp::reject::not_found())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use warp::Filter;
+
+    #[tokio::test]
+    async fn test_dir_source() {
+        let dir = std::env::temp_dir().join(cuid2::cuid());
+        std::fs::create_dir_all(dir.join("webui")).unwrap();
+        let source = StaticSource::new(Some(dir.clone()));
+        let filter = warp::path::full().and_then(move |path| get(source.clone(), path));
+
+        let res = warp::test::request().path("/app.js").reply(&filter).await;
+        assert_eq!(res.status(), 404);
+
+        std::fs::write(dir.join("webui/app.js"), "v1").unwrap();
+        let res = warp::test::request().path("/app.js").reply(&filter).await;
+        assert_eq!(res.status(), 200);
+        assert_eq!(res.body(), "v1");
+        assert!(res.headers().get("content-encoding").is_none());
+
+        std::fs::write(dir.join("webui/app.js"), "v2").unwrap();
+        let res = warp::test::request().path("/app.js").reply(&filter).await;
+        assert_eq!(res.body(), "v2");
+
+        std::fs::write(dir.join("webui/index.html.gz"), "index").unwrap();
+        let res = warp::test::request().path("/ports").reply(&filter).await;
+        assert_eq!(res.body(), "index");
+        assert_eq!(res.headers()["content-encoding"], "gzip");
+        assert_eq!(res.headers()["content-type"], "text/html");
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,19 +1,36 @@
-use super::{acme, app_info, auth, config, log, ports, server_certs, sites};
+use super::{acme, app_info, auth, config, log, metrics, ports, server_certs, sites};
 use hyper::{Response, StatusCode, Uri};
 use std::sync::Arc;
 use taxy_api::acme::AcmeInfo;
 use taxy_api::acme::{AcmeRequest, ExternalAccountBinding};
-use taxy_api::app::{AppConfig, AppInfo, Source};
-use taxy_api::auth::{LoginRequest, LoginResult};
-use taxy_api::cert::{CertInfo, CertMetadata, CertPostBody, SelfSignedCertRequest};
+use taxy_api::app::{AppConfig, AppInfo, RenewalHooks, Source};
+use taxy_api::auth::{ApiTokenInfo, ApiTokenRequest, ApiTokenResult, LoginRequest, LoginResult};
+use taxy_api::cert::{
+    CertInfo, CertMetadata, CertPostBody, CertSortKey, CertTrustRequest, CertTrustRule,
+    CertValidation, CertWarning, KeyAlgorithm, KeyPolicy, SelfSignedCertRequest, SortOrder,
+};
 use taxy_api::error::Error;
 use taxy_api::event::ServerEvent;
 use taxy_api::log::SystemLogRow;
-use taxy_api::port::{PortEntry, PortOptions, UpstreamServer};
-use taxy_api::port::{PortState, PortStatus, SocketState};
+use taxy_api::metrics::{MetricFamily, MetricKind, MetricSample};
+use taxy_api::port::{
+    BufferingMode, ConnectionRate, DnsResolution, DuplicateUpstreams, HealthCheck, Hsts,
+    LoadBalanceMode, LoadSignal, OutlierDetection, OverloadShedding, PlaintextFallback, PortEntry,
+    PortOptions, PortRange, ProbeDetection, ProxyProtocol, RateExceededAction, RetryBudget,
+    TagAffinity, TcpKeepalive, UpstreamCaBundle, UpstreamRewrite, UpstreamServer, UpstreamState,
+    UpstreamTlsVerification,
+};
+use taxy_api::port::{
+    ClosedConnectionInfo, ConnectionInfo, ConnectionOutcome, ConnectionStats, PortState, PortStats,
+    PortStatus, SocketState, UpstreamDistribution, UpstreamInfo,
+};
 use taxy_api::site::{Route, Server, SiteEntry};
-use taxy_api::tls::TlsState;
-use taxy_api::tls::TlsTermination;
+use taxy_api::tls::{
+    CertSelection, ClientAuth, ClientAuthMode, ClientCertHeaders, SniClientAuthMode, TlsParameters,
+    TlsTermination, TlsVersion,
+};
+use taxy_api::tls::{TlsMode, TlsState};
+use taxy_api::webhook::WebhookEvent;
 use utoipa::openapi::security::{ApiKey, ApiKeyValue, SecurityScheme};
 use utoipa::{Modify, OpenApi};
 use utoipa_swagger_ui::Config;
@@ -24,14 +41,26 @@ use warp::{Rejection, Reply};
     paths(
         auth::login,
         auth::logout,
+        auth::list_tokens,
+        auth::create_token,
+        auth::revoke_token,
         ports::list,
         ports::status,
+        ports::stats,
+        ports::connections,
+        ports::recent_connections,
         ports::delete,
         ports::post,
         ports::put,
+        ports::upstream_state,
+        ports::upstream_distribution,
+        ports::tls_mode,
         ports::reset,
+        ports::drain,
+        ports::rebind,
         config::get,
         config::put,
+        config::reload,
         app_info::get,
         acme::list,
         acme::delete,
@@ -41,37 +70,94 @@ use warp::{Rejection, Reply};
         sites::post,
         sites::put,
         log::get,
+        metrics::get,
         server_certs::list,
         server_certs::delete,
         server_certs::self_sign,
         server_certs::upload,
+        server_certs::validate,
+        server_certs::trust,
+        server_certs::ports,
     ),
     components(schemas(
         AppInfo,
         AppConfig,
+        RenewalHooks,
         PortEntry,
         PortOptions,
         UpstreamServer,
+        PortRange,
+        Hsts,
+        TagAffinity,
+        OverloadShedding,
+        ConnectionRate,
+        ProbeDetection,
+        PlaintextFallback,
+        UpstreamRewrite,
+        ProxyProtocol,
+        DuplicateUpstreams,
+        DnsResolution,
+        HealthCheck,
+        OutlierDetection,
+        TcpKeepalive,
+        RateExceededAction,
+        RetryBudget,
+        LoadSignal,
+        BufferingMode,
+        LoadBalanceMode,
+        UpstreamTlsVerification,
+        UpstreamCaBundle,
+        UpstreamState,
         TlsTermination,
+        TlsParameters,
+        TlsVersion,
+        CertSelection,
+        ClientAuth,
+        ClientAuthMode,
+        SniClientAuthMode,
+        ClientCertHeaders,
         PortStatus,
+        PortStats,
+        ConnectionStats,
+        UpstreamInfo,
+        UpstreamDistribution,
+        ConnectionInfo,
+        ClosedConnectionInfo,
+        ConnectionOutcome,
         PortState,
         SocketState,
         TlsState,
+        TlsMode,
         CertInfo,
         CertMetadata,
+        CertTrustRule,
+        CertTrustRequest,
+        CertSortKey,
+        SortOrder,
         AcmeInfo,
         SelfSignedCertRequest,
+        KeyPolicy,
+        KeyAlgorithm,
         AcmeRequest,
         ExternalAccountBinding,
         CertPostBody,
+        CertValidation,
+        CertWarning,
         Error,
         ServerEvent,
+        WebhookEvent,
+        MetricFamily,
+        MetricKind,
+        MetricSample,
         Source,
         SiteEntry,
         Route,
         Server,
         LoginRequest,
         LoginResult,
+        ApiTokenInfo,
+        ApiTokenRequest,
+        ApiTokenResult,
         SystemLogRow
     )),
     modifiers(&SecurityAddon)
@@ -15,6 +15,10 @@ pub enum Command {
     Start(StartArgs),
     /// Add user
     AddUser(AddUserArgs),
+    /// Export the keyring as an encrypted archive
+    ExportKeyring(KeyringArchiveArgs),
+    /// Import the keyring items of an encrypted archive
+    ImportKeyring(KeyringArchiveArgs),
 }
 
 #[derive(Args)]
@@ -64,9 +68,24 @@ pub struct StartArgs {
     #[clap(long, short, env = "TAXY_NO_WEBUI", conflicts_with = "webui")]
     pub no_webui: bool,
 
+    /// Serve the web UI from a `webui/dist` directory on disk instead of the
+    /// embedded bundle. Intended for UI development.
+    #[clap(
+        long,
+        value_name = "DIR",
+        env = "TAXY_WEBUI_DIR",
+        conflicts_with = "no_webui"
+    )]
+    pub webui_dir: Option<PathBuf>,
+
     #[clap(long, short, value_name = "DIR", env = "TAXY_CONFIG_DIR")]
     pub config_dir: Option<PathBuf>,
 
+    /// Store the keyring in this directory instead of the config directory.
+    /// Instances pointing at the same directory share their certs and ACME accounts.
+    #[clap(long, value_name = "DIR", env = "TAXY_KEYRING_DIR")]
+    pub keyring_dir: Option<PathBuf>,
+
     #[clap(long, short = 'd', value_name = "DIR", env = "TAXY_LOG_DIR")]
     pub log_dir: Option<PathBuf>,
 }
@@ -81,3 +100,18 @@ pub struct AddUserArgs {
     #[clap(long, short, value_name = "DIR", env = "TAXY_CONFIG_DIR")]
     pub config_dir: Option<PathBuf>,
 }
+
+#[derive(Args)]
+pub struct KeyringArchiveArgs {
+    #[clap(value_name = "FILE")]
+    pub archive: PathBuf,
+
+    #[clap(long, short, value_name = "PASSPHRASE")]
+    pub passphrase: Option<String>,
+
+    #[clap(long, short, value_name = "DIR", env = "TAXY_CONFIG_DIR")]
+    pub config_dir: Option<PathBuf>,
+
+    #[clap(long, value_name = "DIR", env = "TAXY_KEYRING_DIR")]
+    pub keyring_dir: Option<PathBuf>,
+}
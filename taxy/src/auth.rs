@@ -3,7 +3,9 @@ use argon2::{
     Argon2, PasswordHash, PasswordVerifier,
 };
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, path::Path};
+use sha2::{Digest, Sha256};
+use std::{collections::HashMap, io::ErrorKind, path::Path};
+use taxy_api::auth::ApiTokenInfo;
 use tokio::fs;
 use toml_edit::Document;
 use tracing::info;
@@ -82,3 +84,81 @@ pub async fn verify_account(config_dir: &Path, name: &str, password: &str) -> bo
 pub struct Account {
     pub password: String,
 }
+
+pub async fn save_api_tokens(config_dir: &Path, tokens: Vec<ApiToken>) -> anyhow::Result<()> {
+    fs::create_dir_all(&config_dir).await?;
+
+    let path = config_dir.join("api_tokens.toml");
+    info!(?path, "save api tokens");
+
+    let file = ApiTokenFile { tokens };
+    fs::write(&path, toml::to_string(&file)?).await?;
+    Ok(())
+}
+
+pub async fn load_api_tokens(config_dir: &Path) -> anyhow::Result<Vec<ApiToken>> {
+    let path = config_dir.join("api_tokens.toml");
+    info!(?path, "load api tokens");
+    let content = match fs::read_to_string(&path).await {
+        Ok(content) => content,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err.into()),
+    };
+    Ok(toml::from_str::<ApiTokenFile>(&content)?.tokens)
+}
+
+/// Returns the hex-encoded SHA-256 digest under which an API token is stored.
+pub fn hash_api_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct ApiTokenFile {
+    #[serde(default)]
+    tokens: Vec<ApiToken>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ApiToken {
+    #[serde(flatten)]
+    pub info: ApiTokenInfo,
+    pub hash: String,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[tokio::test]
+    async fn test_api_tokens_roundtrip() {
+        let dir = std::env::temp_dir().join(cuid2::cuid());
+        assert!(load_api_tokens(&dir).await.unwrap().is_empty());
+
+        let tokens = vec![
+            ApiToken {
+                info: ApiTokenInfo {
+                    id: "a".into(),
+                    created_at: UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+                    expiry: Some(Duration::from_secs(60 * 60 * 24 * 30)),
+                },
+                hash: hash_api_token("token-a"),
+            },
+            ApiToken {
+                info: ApiTokenInfo {
+                    id: "b".into(),
+                    created_at: UNIX_EPOCH + Duration::from_secs(1_700_000_001),
+                    expiry: None,
+                },
+                hash: hash_api_token("token-b"),
+            },
+        ];
+        save_api_tokens(&dir, tokens.clone()).await.unwrap();
+
+        let content = fs::read_to_string(dir.join("api_tokens.toml"))
+            .await
+            .unwrap();
+        assert!(!content.contains("token-a"));
+        assert_eq!(load_api_tokens(&dir).await.unwrap(), tokens);
+    }
+}
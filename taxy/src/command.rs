@@ -1,15 +1,39 @@
-use crate::keyring::KeyringItem;
+use crate::keyring::{ocsp::OcspResponse, KeyringItem};
+use crate::proxy::socket::SocketStream;
 use crate::server::rpc::ErasedRpcMethod;
+use std::{net::SocketAddr, sync::Arc};
+use tokio::io::BufStream;
 
 pub enum ServerCommand {
     AddKeyringItem {
         item: KeyringItem,
     },
     StopHttpChallenges,
+    SetOcspResponse {
+        cert_id: String,
+        response: Arc<OcspResponse>,
+    },
+    SetRenewalHookError {
+        acme_id: String,
+        error: Option<String>,
+    },
+    ResetPortStats {
+        id: String,
+    },
     CallMethod {
         id: usize,
         arg: Box<dyn ErasedRpcMethod>,
     },
+    SetUpstreamHealth {
+        port_id: String,
+        addr: String,
+        healthy: bool,
+    },
+    ProxyConnection {
+        id: String,
+        stream: BufStream<SocketStream>,
+        client_addr: Option<SocketAddr>,
+    },
 }
 
 impl std::fmt::Debug for ServerCommand {
@@ -20,9 +44,36 @@ impl std::fmt::Debug for ServerCommand {
                 .field("item", item)
                 .finish(),
             Self::StopHttpChallenges => f.debug_struct("StopHttpChallenges").finish(),
-            Self::CallMethod { id, .. } => {
-                f.debug_struct("CallMethod").field("id", id).finish()
+            Self::SetOcspResponse { cert_id, .. } => f
+                .debug_struct("SetOcspResponse")
+                .field("cert_id", cert_id)
+                .finish(),
+            Self::SetRenewalHookError { acme_id, error } => f
+                .debug_struct("SetRenewalHookError")
+                .field("acme_id", acme_id)
+                .field("error", error)
+                .finish(),
+            Self::ResetPortStats { id } => {
+                f.debug_struct("ResetPortStats").field("id", id).finish()
             }
+            Self::CallMethod { id, .. } => f.debug_struct("CallMethod").field("id", id).finish(),
+            Self::SetUpstreamHealth {
+                port_id,
+                addr,
+                healthy,
+            } => f
+                .debug_struct("SetUpstreamHealth")
+                .field("port_id", port_id)
+                .field("addr", addr)
+                .field("healthy", healthy)
+                .finish(),
+            Self::ProxyConnection {
+                id, client_addr, ..
+            } => f
+                .debug_struct("ProxyConnection")
+                .field("id", id)
+                .field("client_addr", client_addr)
+                .finish(),
         }
     }
 }
@@ -0,0 +1,320 @@
+use crate::keyring::{
+    acme::{AcmeAccount, AcmeEntry},
+    certs::Cert,
+    KeyringItem,
+};
+use async_trait::async_trait;
+use indexmap::map::IndexMap;
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tokio::fs;
+use tokio::io::AsyncReadExt;
+use toml_edit::Document;
+use tracing::{error, info, warn};
+
+/// Persists the keyring items.
+///
+/// Implementations must make each save and delete atomic, so that a concurrent
+/// load by another instance sharing the store never observes a partial item.
+#[async_trait]
+pub trait KeyringStore: Send + Sync {
+    /// Loads the keyring items, skipping certs which fail to load.
+    ///
+    /// Returns the locations of the skipped certs along with the items.
+    async fn load(&self) -> anyhow::Result<(Vec<KeyringItem>, Vec<String>)>;
+
+    async fn save_cert(&self, cert: &Cert) -> anyhow::Result<()>;
+
+    async fn delete_cert(&self, id: &str) -> anyhow::Result<()>;
+
+    async fn save_acme(&self, acme: &AcmeEntry) -> anyhow::Result<()>;
+
+    async fn delete_acme(&self, id: &str) -> anyhow::Result<()>;
+}
+
+/// Stores certs as `certs/<id>/{cert,key}.pem` and ACME accounts in `acme.toml`
+/// under the config directory.
+///
+/// Files are written to a staging directory and renamed into place.
+pub struct FileKeyringStore {
+    dir: PathBuf,
+}
+
+impl FileKeyringStore {
+    pub fn new(dir: &Path) -> Self {
+        Self {
+            dir: dir.to_owned(),
+        }
+    }
+
+    async fn staging_path(&self) -> anyhow::Result<PathBuf> {
+        let staging = self.dir.join(".staging");
+        fs::create_dir_all(&staging).await?;
+        Ok(staging.join(cuid2::cuid()))
+    }
+
+    async fn write_atomic(&self, path: &Path, contents: &[u8]) -> anyhow::Result<()> {
+        let tmp = self.staging_path().await?;
+        fs::write(&tmp, contents).await?;
+        if let Err(err) = fs::rename(&tmp, path).await {
+            let _ = fs::remove_file(&tmp).await;
+            return Err(err.into());
+        }
+        Ok(())
+    }
+
+    async fn load_document(&self, path: &Path) -> Document {
+        let content = match fs::read_to_string(path).await {
+            Ok(content) => content,
+            Err(err) => {
+                warn!(?path, ?err, "failed to load config");
+                return Document::new();
+            }
+        };
+        match content.parse::<Document>() {
+            Ok(doc) => doc,
+            Err(err) => {
+                warn!(?path, ?err, "failed to load config");
+                Document::new()
+            }
+        }
+    }
+
+    async fn load_certs(&self, path: &Path) -> anyhow::Result<(Vec<KeyringItem>, Vec<String>)> {
+        let walker = globwalk::GlobWalkerBuilder::from_patterns(path, &["*/cert.pem"])
+            .build()?
+            .filter_map(Result::ok);
+
+        let mut certs = Vec::new();
+        let mut failed = Vec::new();
+        for pem in walker {
+            let chain = pem.path();
+            let key = pem.path().parent().unwrap().join("key.pem");
+            let mut chain_data = Vec::new();
+            let mut key_data = Vec::new();
+
+            match fs::File::open(&chain).await {
+                Ok(mut file) => {
+                    if let Err(err) = file.read_to_end(&mut chain_data).await {
+                        error!(path = ?chain, "failed to load: {err}");
+                    }
+                }
+                Err(err) => {
+                    error!(path = ?chain, "failed to load: {err}");
+                }
+            }
+
+            match fs::File::open(&key).await {
+                Ok(mut file) => {
+                    if let Err(err) = file.read_to_end(&mut key_data).await {
+                        error!(path = ?key, "failed to load: {err}");
+                    }
+                }
+                Err(err) => {
+                    error!(path = ?key, "failed to load: {err}");
+                }
+            }
+
+            match Cert::new(chain_data, key_data) {
+                Ok(cert) => certs.push(KeyringItem::ServerCert(Arc::new(cert))),
+                Err(err) => {
+                    error!(path = ?chain, "failed to load cert, skipping: {err}");
+                    failed.push(chain.display().to_string());
+                }
+            }
+        }
+        Ok((certs, failed))
+    }
+
+    async fn load_acmes(&self, path: &Path) -> anyhow::Result<Vec<KeyringItem>> {
+        info!(?path, "load acmes");
+        let content = fs::read_to_string(path).await?;
+        let table: IndexMap<String, AcmeAccount> = toml::from_str(&content)?;
+        Ok(table
+            .into_iter()
+            .map(|entry| KeyringItem::Acme(Arc::new(entry.into())))
+            .collect())
+    }
+}
+
+#[async_trait]
+impl KeyringStore for FileKeyringStore {
+    async fn load(&self) -> anyhow::Result<(Vec<KeyringItem>, Vec<String>)> {
+        let mut items = Vec::new();
+        let mut failed = Vec::new();
+
+        let path = self.dir.join("certs");
+        match self.load_certs(&path).await {
+            Ok((mut certs, mut failed_certs)) => {
+                items.append(&mut certs);
+                failed.append(&mut failed_certs);
+            }
+            Err(err) => {
+                warn!(?path, "failed to load certs: {err}");
+            }
+        }
+
+        let path = self.dir.join("acme.toml");
+        match self.load_acmes(&path).await {
+            Ok(mut certs) => items.append(&mut certs),
+            Err(err) => {
+                warn!(?path, "failed to load acme config: {err}");
+            }
+        }
+
+        Ok((items, failed))
+    }
+
+    async fn save_cert(&self, cert: &Cert) -> anyhow::Result<()> {
+        let path = self.dir.join("certs").join(cert.id());
+        info!(?path, "save cert");
+
+        let tmp = self.staging_path().await?;
+        fs::create_dir_all(&tmp).await?;
+        fs::write(tmp.join("cert.pem"), &cert.raw_chain).await?;
+        fs::write(tmp.join("key.pem"), &cert.raw_key).await?;
+
+        fs::create_dir_all(path.parent().unwrap()).await?;
+        if fs::metadata(&path).await.is_ok() {
+            self.delete_cert(cert.id()).await?;
+        }
+        if let Err(err) = fs::rename(&tmp, &path).await {
+            let _ = fs::remove_dir_all(&tmp).await;
+            return Err(err.into());
+        }
+        Ok(())
+    }
+
+    async fn delete_cert(&self, id: &str) -> anyhow::Result<()> {
+        let path = self.dir.join("certs").join(id);
+        info!(?path, "delete cert");
+
+        let tmp = self.staging_path().await?;
+        fs::rename(&path, &tmp).await?;
+        fs::remove_dir_all(&tmp).await?;
+        Ok(())
+    }
+
+    async fn save_acme(&self, acme: &AcmeEntry) -> anyhow::Result<()> {
+        let path = self.dir.join("acme.toml");
+        info!(?path, "save acme");
+
+        let mut doc = self.load_document(&path).await;
+        let (id, entry): (String, AcmeAccount) = acme.clone().into();
+        doc[&id] = toml_edit::ser::to_document(&entry)?.as_item().clone();
+        self.write_atomic(&path, doc.to_string().as_bytes()).await
+    }
+
+    async fn delete_acme(&self, id: &str) -> anyhow::Result<()> {
+        let path = self.dir.join("acme.toml");
+        info!(?path, "delete acme");
+
+        let mut doc = self.load_document(&path).await;
+        doc.remove(id);
+        self.write_atomic(&path, doc.to_string().as_bytes()).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::storage::ConfigStorage;
+    use crate::testing::self_signed;
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+
+    type CertPems = HashMap<String, (Vec<u8>, Vec<u8>)>;
+
+    #[derive(Clone, Default)]
+    struct MemoryKeyringStore {
+        certs: Arc<Mutex<CertPems>>,
+        acmes: Arc<Mutex<IndexMap<String, AcmeAccount>>>,
+    }
+
+    #[async_trait]
+    impl KeyringStore for MemoryKeyringStore {
+        async fn load(&self) -> anyhow::Result<(Vec<KeyringItem>, Vec<String>)> {
+            let mut items = Vec::new();
+            let mut failed = Vec::new();
+            for (id, (chain, key)) in self.certs.lock().unwrap().iter() {
+                match Cert::new(chain.clone(), key.clone()) {
+                    Ok(cert) => items.push(KeyringItem::ServerCert(Arc::new(cert))),
+                    Err(_) => failed.push(id.clone()),
+                }
+            }
+            for entry in self.acmes.lock().unwrap().clone() {
+                items.push(KeyringItem::Acme(Arc::new(entry.into())));
+            }
+            Ok((items, failed))
+        }
+
+        async fn save_cert(&self, cert: &Cert) -> anyhow::Result<()> {
+            self.certs.lock().unwrap().insert(
+                cert.id().to_string(),
+                (cert.raw_chain.clone(), cert.raw_key.clone()),
+            );
+            Ok(())
+        }
+
+        async fn delete_cert(&self, id: &str) -> anyhow::Result<()> {
+            self.certs.lock().unwrap().remove(id);
+            Ok(())
+        }
+
+        async fn save_acme(&self, acme: &AcmeEntry) -> anyhow::Result<()> {
+            let (id, entry): (String, AcmeAccount) = acme.clone().into();
+            self.acmes.lock().unwrap().insert(id, entry);
+            Ok(())
+        }
+
+        async fn delete_acme(&self, id: &str) -> anyhow::Result<()> {
+            self.acmes.lock().unwrap().remove(id);
+            Ok(())
+        }
+    }
+
+    fn cert_ids(certs: Vec<Arc<Cert>>) -> Vec<String> {
+        let mut ids = certs
+            .iter()
+            .map(|cert| cert.id().to_string())
+            .collect::<Vec<_>>();
+        ids.sort();
+        ids
+    }
+
+    #[tokio::test]
+    async fn test_custom_keyring_store() {
+        let dir = std::env::temp_dir().join(cuid2::cuid());
+        let store = MemoryKeyringStore::default();
+        let storage = ConfigStorage::new(&dir).with_keyring_store(store.clone());
+
+        let a = self_signed("a.example.com");
+        let b = self_signed("b.example.com");
+        storage.save_cert(&a).await;
+        storage.save_cert(&b).await;
+
+        let (keyring, failed) = storage.load_keychain().await;
+        let mut ids = vec![a.id().to_string(), b.id().to_string()];
+        ids.sort();
+        assert_eq!(cert_ids(keyring.certs()), ids);
+        assert!(failed.is_empty());
+
+        storage.delete_cert(a.id()).await;
+
+        // Another instance sharing the store sees the same items.
+        let other = ConfigStorage::new(&dir).with_keyring_store(store);
+        let (keyring, _) = other.load_keychain().await;
+        assert_eq!(cert_ids(keyring.certs()), vec![b.id().to_string()]);
+
+        let c = self_signed("c.example.com");
+        other.save_cert(&c).await;
+        let (keyring, _) = storage.load_keychain().await;
+        let mut ids = vec![b.id().to_string(), c.id().to_string()];
+        ids.sort();
+        assert_eq!(cert_ids(keyring.certs()), ids);
+
+        assert!(fs::metadata(dir.join("certs")).await.is_err());
+    }
+}
@@ -2,6 +2,7 @@ use std::path::Path;
 
 use taxy_api::app::AppInfo;
 
+pub mod keyring_store;
 pub mod storage;
 
 mod build_info {
@@ -1,13 +1,9 @@
-use crate::keyring::{
-    acme::{AcmeAccount, AcmeEntry},
-    certs::Cert,
-    {Keyring, KeyringItem},
-};
+use super::keyring_store::{FileKeyringStore, KeyringStore};
+use crate::keyring::{acme::AcmeEntry, certs::Cert, Keyring};
 use indexmap::map::IndexMap;
 use std::{
     collections::HashSet,
     path::{Path, PathBuf},
-    sync::Arc,
 };
 use taxy_api::app::AppConfig;
 use taxy_api::{
@@ -15,21 +11,28 @@ use taxy_api::{
     site::{Site, SiteEntry},
 };
 use tokio::fs;
-use tokio::io::AsyncReadExt;
 use toml_edit::Document;
 use tracing::{error, info, warn};
 
 pub struct ConfigStorage {
     dir: PathBuf,
+    keyring: Box<dyn KeyringStore>,
 }
 
 impl ConfigStorage {
     pub fn new(dir: &Path) -> Self {
         Self {
             dir: dir.to_owned(),
+            keyring: Box::new(FileKeyringStore::new(dir)),
         }
     }
 
+    /// Replaces the default file-based keyring persistence.
+    pub fn with_keyring_store<S: KeyringStore + 'static>(mut self, store: S) -> Self {
+        self.keyring = Box::new(store);
+        self
+    }
+
     pub async fn save_app_config(&self, config: &AppConfig) {
         let dir = &self.dir;
         let path = dir.join("config.toml");
@@ -60,18 +63,33 @@ impl ConfigStorage {
     async fn load_app_config_impl(&self, path: &Path) -> anyhow::Result<AppConfig> {
         info!(?path, "load config");
         let content = fs::read_to_string(path).await?;
-        Ok(toml::from_str(&content)?)
+        let config: AppConfig = toml::from_str(&content)?;
+        config.validate()?;
+        Ok(config)
     }
 
-    pub async fn save_entries(&self, entries: &[PortEntry]) {
+    /// Saves file-defined entries to `ports.toml` and API-defined ones to `ports.api.toml`.
+    ///
+    /// File entries overridden through the API are left untouched in `ports.toml`.
+    pub async fn save_entries(&self, entries: &[PortEntry], api: &[PortEntry]) {
         let dir = &self.dir;
+        let overrides = api.iter().map(|entry| entry.id.clone()).collect();
         let path = dir.join("ports.toml");
-        if let Err(err) = self.save_entries_impl(&path, entries).await {
+        if let Err(err) = self.save_entries_impl(&path, entries, &overrides).await {
+            error!(?path, "failed to save: {err}");
+        }
+        let path = dir.join("ports.api.toml");
+        if let Err(err) = self.save_entries_impl(&path, api, &HashSet::new()).await {
             error!(?path, "failed to save: {err}");
         }
     }
 
-    async fn save_entries_impl(&self, path: &Path, ports: &[PortEntry]) -> anyhow::Result<()> {
+    async fn save_entries_impl(
+        &self,
+        path: &Path,
+        ports: &[PortEntry],
+        keep: &HashSet<String>,
+    ) -> anyhow::Result<()> {
         fs::create_dir_all(path.parent().unwrap()).await?;
         info!(?path, "save config");
         let mut doc = match self.load_document(path).await {
@@ -92,8 +110,8 @@ impl ConfigStorage {
             doc[&id] = toml_edit::ser::to_document(&entry)?.as_item().clone();
             unused.remove(&id);
         }
-        for key in unused {
-            doc.remove(&key);
+        for key in unused.difference(keep) {
+            doc.remove(key);
         }
 
         fs::write(path, doc.to_string()).await?;
@@ -107,8 +125,16 @@ impl ConfigStorage {
     }
 
     pub async fn load_entries(&self) -> Vec<PortEntry> {
+        self.load_entries_from("ports.toml").await
+    }
+
+    pub async fn load_api_entries(&self) -> Vec<PortEntry> {
+        self.load_entries_from("ports.api.toml").await
+    }
+
+    async fn load_entries_from(&self, name: &str) -> Vec<PortEntry> {
         let dir = &self.dir;
-        let path = dir.join("ports.toml");
+        let path = dir.join(name);
         match self.load_entries_impl(&path).await {
             Ok(ports) => ports,
             Err(err) => {
@@ -126,8 +152,16 @@ impl ConfigStorage {
     }
 
     pub async fn load_sites(&self) -> Vec<SiteEntry> {
+        self.load_sites_from("sites.toml").await
+    }
+
+    pub async fn load_api_sites(&self) -> Vec<SiteEntry> {
+        self.load_sites_from("sites.api.toml").await
+    }
+
+    async fn load_sites_from(&self, name: &str) -> Vec<SiteEntry> {
         let dir = &self.dir;
-        let path = dir.join("sites.toml");
+        let path = dir.join(name);
         match self.load_sites_impl(&path).await {
             Ok(sites) => sites,
             Err(err) => {
@@ -144,15 +178,28 @@ impl ConfigStorage {
         Ok(table.into_iter().map(|entry| entry.into()).collect())
     }
 
-    pub async fn save_sites(&self, sites: &[SiteEntry]) {
+    /// Saves file-defined sites to `sites.toml` and API-defined ones to `sites.api.toml`.
+    ///
+    /// File sites overridden through the API are left untouched in `sites.toml`.
+    pub async fn save_sites(&self, sites: &[SiteEntry], api: &[SiteEntry]) {
         let dir = &self.dir;
+        let overrides = api.iter().map(|entry| entry.id.clone()).collect();
         let path = dir.join("sites.toml");
-        if let Err(err) = self.save_sites_impl(&path, sites).await {
+        if let Err(err) = self.save_sites_impl(&path, sites, &overrides).await {
+            error!(?path, "failed to save: {err}");
+        }
+        let path = dir.join("sites.api.toml");
+        if let Err(err) = self.save_sites_impl(&path, api, &HashSet::new()).await {
             error!(?path, "failed to save: {err}");
         }
     }
 
-    async fn save_sites_impl(&self, path: &Path, sites: &[SiteEntry]) -> anyhow::Result<()> {
+    async fn save_sites_impl(
+        &self,
+        path: &Path,
+        sites: &[SiteEntry],
+        keep: &HashSet<String>,
+    ) -> anyhow::Result<()> {
         fs::create_dir_all(path.parent().unwrap()).await?;
         info!(?path, "save config");
         let mut doc = match self.load_document(path).await {
@@ -173,8 +220,8 @@ impl ConfigStorage {
             doc[&id] = toml_edit::ser::to_document(&entry)?.as_item().clone();
             unused.remove(&id);
         }
-        for key in unused {
-            doc.remove(&key);
+        for key in unused.difference(keep) {
+            doc.remove(key);
         }
 
         fs::write(path, doc.to_string()).await?;
@@ -182,147 +229,83 @@ impl ConfigStorage {
     }
 
     pub async fn save_cert(&self, cert: &Cert) {
-        let dir = &self.dir;
-        let path = dir.join("certs").join(cert.id());
-        if let Err(err) = self.save_cert_impl(&path, cert).await {
-            error!(?path, "failed to save: {err}");
+        if let Err(err) = self.keyring.save_cert(cert).await {
+            error!(id = cert.id(), "failed to save cert: {err}");
         }
     }
 
-    async fn save_cert_impl(&self, path: &Path, cert: &Cert) -> anyhow::Result<()> {
-        fs::create_dir_all(path).await?;
-        info!(?path, "save cert");
-        fs::write(path.join("cert.pem"), &cert.raw_chain).await?;
-        fs::write(path.join("key.pem"), &cert.raw_key).await?;
-        Ok(())
-    }
-
     pub async fn save_acme(&self, acme: &AcmeEntry) {
-        let path = self.dir.join("acme.toml");
-        if let Err(err) = self.save_acme_impl(&path, acme).await {
-            error!(?path, "failed to save: {err}");
+        if let Err(err) = self.keyring.save_acme(acme).await {
+            error!(id = acme.id(), "failed to save acme: {err}");
         }
     }
 
-    async fn save_acme_impl(&self, path: &Path, acme: &AcmeEntry) -> anyhow::Result<()> {
-        fs::create_dir_all(path.parent().unwrap()).await?;
-        info!(?path, "save config");
-        let mut doc = match self.load_document(path).await {
-            Ok(doc) => doc,
-            Err(err) => {
-                warn!(?path, ?err, "failed to load config");
-                Document::new()
-            }
-        };
-
-        let (id, entry): (String, AcmeAccount) = acme.clone().into();
-        doc[&id] = toml_edit::ser::to_document(&entry)?.as_item().clone();
-
-        fs::write(path, doc.to_string()).await?;
-        Ok(())
-    }
-
     pub async fn delete_acme(&self, id: &str) {
-        let path = self.dir.join("acme.toml");
-        if let Err(err) = self.delete_acme_impl(&path, id).await {
-            error!(?path, "failed to save: {err}");
+        if let Err(err) = self.keyring.delete_acme(id).await {
+            error!(id, "failed to delete acme: {err}");
         }
     }
 
-    async fn delete_acme_impl(&self, path: &Path, id: &str) -> anyhow::Result<()> {
-        info!(?path, "delete acme");
-        let mut doc = match self.load_document(path).await {
-            Ok(doc) => doc,
-            Err(err) => {
-                warn!(?path, ?err, "failed to load config");
-                Document::new()
-            }
-        };
-
-        doc.remove(id);
-        fs::write(path, doc.to_string()).await?;
-        Ok(())
-    }
-
     pub async fn delete_cert(&self, id: &str) {
-        let dir = &self.dir;
-        let path = dir.join("certs").join(id);
-        if let Err(err) = fs::remove_dir_all(&path).await {
-            error!(?path, "failed to delete: {err}");
+        if let Err(err) = self.keyring.delete_cert(id).await {
+            error!(id, "failed to delete cert: {err}");
         }
     }
 
-    pub async fn load_keychain(&self) -> Keyring {
-        let mut items = Vec::new();
-
-        let path = self.dir.join("certs");
-        match self.load_certs_impl(&path).await {
-            Ok(mut certs) => items.append(&mut certs),
-            Err(err) => {
-                warn!(?path, "failed to load certs: {err}");
-            }
-        }
-
-        let path = self.dir.join("acme.toml");
-        match self.load_acmes_impl(&path).await {
-            Ok(mut certs) => items.append(&mut certs),
+    /// Loads the keyring, skipping certs which fail to load.
+    ///
+    /// Returns the locations of the skipped certs along with the keyring.
+    pub async fn load_keychain(&self) -> (Keyring, Vec<String>) {
+        match self.keyring.load().await {
+            Ok((items, failed)) => (Keyring::new(items), failed),
             Err(err) => {
-                warn!(?path, "failed to load acme config: {err}");
+                warn!("failed to load keyring: {err}");
+                (Keyring::default(), Vec::new())
             }
         }
-
-        Keyring::new(items)
     }
+}
 
-    pub async fn load_certs_impl(&self, path: &Path) -> anyhow::Result<Vec<KeyringItem>> {
-        let walker = globwalk::GlobWalkerBuilder::from_patterns(path, &["*/cert.pem"])
-            .build()?
-            .filter_map(Result::ok);
-
-        let mut certs = Vec::new();
-        for pem in walker {
-            let chain = pem.path();
-            let key = pem.path().parent().unwrap().join("key.pem");
-            let mut chain_data = Vec::new();
-            let mut key_data = Vec::new();
-
-            match fs::File::open(&chain).await {
-                Ok(mut file) => {
-                    if let Err(err) = file.read_to_end(&mut chain_data).await {
-                        error!(path = ?chain, "failed to load: {err}");
-                    }
-                }
-                Err(err) => {
-                    error!(path = ?chain, "failed to load: {err}");
-                }
-            }
-
-            match fs::File::open(&key).await {
-                Ok(mut file) => {
-                    if let Err(err) = file.read_to_end(&mut key_data).await {
-                        error!(path = ?key, "failed to load: {err}");
-                    }
-                }
-                Err(err) => {
-                    error!(path = ?key, "failed to load: {err}");
-                }
-            }
-
-            match Cert::new(chain_data, key_data) {
-                Ok(cert) => certs.push(KeyringItem::ServerCert(Arc::new(cert))),
-                Err(err) => error!(?path, "failed to load: {err}"),
-            }
+#[cfg(test)]
+mod test {
+    use super::*;
+    use taxy_api::{cert::SelfSignedCertRequest, subject_name::SubjectName};
+
+    #[tokio::test]
+    async fn test_skip_corrupt_certs() {
+        let dir = std::env::temp_dir().join(cuid2::cuid());
+        let storage = ConfigStorage::new(&dir);
+
+        let mut ids = Vec::new();
+        for name in ["a.example.com", "b.example.com"] {
+            let cert = Cert::new_self_signed(&SelfSignedCertRequest {
+                san: vec![name.parse::<SubjectName>().unwrap()],
+            })
+            .unwrap();
+            storage.save_cert(&cert).await;
+            ids.push(cert.id().to_string());
         }
-        Ok(certs)
-    }
 
-    pub async fn load_acmes_impl(&self, path: &Path) -> anyhow::Result<Vec<KeyringItem>> {
-        info!(?path, "load acmes");
-        let content = fs::read_to_string(path).await?;
-        let table: IndexMap<String, AcmeAccount> = toml::from_str(&content)?;
-        Ok(table
-            .into_iter()
-            .map(|entry| KeyringItem::Acme(Arc::new(entry.into())))
-            .collect())
+        let corrupt = dir.join("certs").join("corrupt");
+        fs::create_dir_all(&corrupt).await.unwrap();
+        fs::write(corrupt.join("cert.pem"), "not a cert")
+            .await
+            .unwrap();
+        fs::write(corrupt.join("key.pem"), "not a key")
+            .await
+            .unwrap();
+
+        let (keyring, failed) = storage.load_keychain().await;
+        let mut loaded = keyring
+            .certs()
+            .iter()
+            .map(|cert| cert.id().to_string())
+            .collect::<Vec<_>>();
+        loaded.sort();
+        ids.sort();
+        assert_eq!(loaded, ids);
+        assert_eq!(failed, vec![corrupt.join("cert.pem").display().to_string()]);
+
+        fs::remove_dir_all(&dir).await.unwrap();
     }
 }
@@ -1,6 +1,7 @@
 use crate::keyring::certs::Cert;
 use anyhow::bail;
 use backoff::{backoff::Backoff, ExponentialBackoff};
+use hyper::{body::HttpBody, client::HttpConnector, Client, StatusCode, Uri};
 use instant_acme::{
     Account, AccountCredentials, AuthorizationStatus, ChallengeType, ExternalAccountKey,
     Identifier, NewAccount, NewOrder, Order, OrderStatus,
@@ -10,12 +11,34 @@ use serde_derive::{Deserialize, Serialize};
 use std::{
     collections::HashMap,
     fmt,
+    net::SocketAddr,
     time::{Duration, SystemTime},
 };
-use taxy_api::{acme::Acme, cert::CertMetadata};
+use taxy_api::{acme::Acme, app::AppConfig, cert::CertMetadata};
 use taxy_api::{acme::AcmeInfo, subject_name::SubjectName};
 use taxy_api::{acme::AcmeRequest, error::Error};
-use tracing::{error, info};
+use thiserror::Error;
+use tracing::{error, info, warn};
+
+const SELF_CHECK_RETRY_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Limits of the http-01 self-check.
+#[derive(Debug, Clone, Copy)]
+pub struct SelfCheckOptions {
+    pub timeout: Duration,
+    pub retries: u32,
+    pub max_body_size: usize,
+}
+
+impl From<&AppConfig> for SelfCheckOptions {
+    fn from(config: &AppConfig) -> Self {
+        Self {
+            timeout: config.acme_self_check_timeout,
+            retries: config.acme_self_check_retries,
+            max_body_size: config.acme_self_check_max_body_size,
+        }
+    }
+}
 
 #[derive(Clone, Serialize, Deserialize)]
 pub struct AcmeEntry {
@@ -89,8 +112,53 @@ impl AcmeEntry {
                 .map(|id| id.to_string())
                 .collect(),
             challenge_type: self.acme.challenge_type,
+            hook_error: None,
+        }
+    }
+}
+
+/// Checks the identifiers of a new ACME entry against the existing entries, so that
+/// two entries never renew certificates for the same name.
+///
+/// Overlapping identifiers are rejected if `reject` is true, and returned after
+/// logging a warning otherwise.
+pub fn check_overlapping_identifiers<'a, I>(
+    acme: &Acme,
+    entries: I,
+    reject: bool,
+) -> Result<Vec<SubjectName>, Error>
+where
+    I: IntoIterator<Item = (&'a str, &'a Acme)>,
+{
+    let mut overlapping = Vec::new();
+    for (id, entry) in entries {
+        for name in &acme.identifiers {
+            if entry.identifiers.iter().any(|other| name.overlaps(other)) {
+                if reject {
+                    return Err(Error::AcmeIdentifierOverlap {
+                        name: name.to_string(),
+                        id: id.to_string(),
+                    });
+                }
+                warn!(
+                    id,
+                    name = name.to_string(),
+                    "acme identifier overlaps with an existing entry"
+                );
+                overlapping.push(name.clone());
+            }
         }
     }
+    Ok(overlapping)
+}
+
+/// Rejects ACME entries with more than `max` identifiers.
+pub fn check_identifier_limit(acme: &Acme, max: usize) -> Result<(), Error> {
+    let count = acme.identifiers.len();
+    if count > max {
+        return Err(Error::TooManyAcmeIdentifiers { count, max });
+    }
+    Ok(())
 }
 
 #[derive(Clone, Serialize, Deserialize)]
@@ -192,6 +260,17 @@ impl AcmeOrder {
         })
     }
 
+    pub async fn self_check(
+        &self,
+        addr: SocketAddr,
+        opts: SelfCheckOptions,
+    ) -> Result<(), SelfCheckError> {
+        for (token, key_auth) in &self.http_challenges {
+            self_check(addr, token, key_auth, opts).await?;
+        }
+        Ok(())
+    }
+
     pub async fn start_challenge(&mut self) -> anyhow::Result<Cert> {
         for (_, url) in &self.challenges {
             self.order.set_challenge_ready(url).await?;
@@ -240,6 +319,7 @@ impl AcmeOrder {
             acme_id: self.id.clone(),
             created_at: SystemTime::now(),
             is_trusted: self.is_trusted,
+            ocsp_response_path: None,
         };
         let metadata = serde_qs::to_string(&metadata).unwrap_or_default();
         let cert_chain_pem = format!("# {}\r\n\r\n{}", metadata, cert_chain_pem);
@@ -253,6 +333,79 @@ impl AcmeOrder {
     }
 }
 
+#[derive(Debug, Error)]
+pub enum SelfCheckError {
+    #[error("http-01 responder is not ready: {reason}")]
+    NotReady { reason: String },
+
+    #[error("wrong content served for http-01 token: {token}")]
+    ContentMismatch { token: String },
+}
+
+/// Fetches the challenge through the local HTTP-01 responder and compares the
+/// served content with the expected key authorization.
+///
+/// Only unreachable or unresponsive responders are retried; serving the wrong
+/// content fails immediately.
+pub async fn self_check(
+    addr: SocketAddr,
+    token: &str,
+    key_auth: &str,
+    opts: SelfCheckOptions,
+) -> Result<(), SelfCheckError> {
+    let uri: Uri = format!("http://{addr}/.well-known/acme-challenge/{token}")
+        .parse()
+        .map_err(|err| SelfCheckError::NotReady {
+            reason: format!("{err}"),
+        })?;
+    let client = Client::new();
+
+    let mut attempt = 0;
+    loop {
+        let result = tokio::time::timeout(
+            opts.timeout,
+            fetch_challenge(&client, uri.clone(), opts.max_body_size),
+        )
+        .await;
+        let reason = match result {
+            Ok(Ok(Some(body))) if body == key_auth.as_bytes() => return Ok(()),
+            Ok(Ok(_)) => {
+                return Err(SelfCheckError::ContentMismatch {
+                    token: token.to_string(),
+                })
+            }
+            Ok(Err(err)) => err.to_string(),
+            Err(_) => format!("timed out after {:?}", opts.timeout),
+        };
+        if attempt >= opts.retries {
+            return Err(SelfCheckError::NotReady { reason });
+        }
+        attempt += 1;
+        warn!(%addr, attempt, "http-01 self-check failed: {reason}");
+        tokio::time::sleep(SELF_CHECK_RETRY_INTERVAL).await;
+    }
+}
+
+async fn fetch_challenge(
+    client: &Client<HttpConnector>,
+    uri: Uri,
+    max_body_size: usize,
+) -> hyper::Result<Option<Vec<u8>>> {
+    let mut res = client.get(uri).await?;
+    if res.status() != StatusCode::OK {
+        return Ok(None);
+    }
+    let body = res.body_mut();
+    let mut buf = Vec::new();
+    while let Some(chunk) = body.data().await {
+        buf.extend_from_slice(&chunk?);
+        if buf.len() > max_body_size {
+            return Ok(None);
+        }
+    }
+    Ok(Some(buf))
+}
+
 fn serialize_account<S>(account: &Account, serializer: S) -> Result<S::Ok, S::Error>
 where
     S: serde::Serializer,
@@ -269,3 +422,132 @@ where
     let creds = AccountCredentials::deserialize(deserializer)?;
     Account::from_credentials(creds).map_err(serde::de::Error::custom)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use hyper::{service::service_fn, Body, Response, Server};
+    use std::convert::Infallible;
+    use tokio::net::TcpListener;
+
+    fn acme(identifiers: &[&str]) -> Acme {
+        Acme {
+            provider: "Let's Encrypt".into(),
+            identifiers: identifiers.iter().map(|s| s.parse().unwrap()).collect(),
+            challenge_type: ChallengeType::Http01,
+            renewal_days: 60,
+            is_trusted: true,
+        }
+    }
+
+    #[test]
+    fn test_overlapping_identifiers() {
+        let existing = acme(&["*.example.com"]);
+        let entries = [("existing", &existing)];
+
+        let new = acme(&["app.example.com", "example.org"]);
+        assert_eq!(
+            check_overlapping_identifiers(&new, entries, false).unwrap(),
+            vec!["app.example.com".parse::<SubjectName>().unwrap()]
+        );
+        assert!(matches!(
+            check_overlapping_identifiers(&new, entries, true),
+            Err(Error::AcmeIdentifierOverlap { name, id }) if name == "app.example.com" && id == "existing"
+        ));
+
+        let new = acme(&["example.com"]);
+        assert!(check_overlapping_identifiers(&new, entries, true)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_identifier_limit() {
+        let within = acme(&["example.com", "www.example.com"]);
+        assert!(check_identifier_limit(&within, 2).is_ok());
+
+        let exceeding = acme(&["example.com", "www.example.com", "api.example.com"]);
+        assert!(matches!(
+            check_identifier_limit(&exceeding, 2),
+            Err(Error::TooManyAcmeIdentifiers { count: 3, max: 2 })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_self_check_content_mismatch() {
+        let server = Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(
+            hyper::service::make_service_fn(|_| async {
+                Ok::<_, Infallible>(service_fn(|_| async {
+                    Ok::<_, Infallible>(Response::new(Body::from("it works!")))
+                }))
+            }),
+        );
+        let addr = server.local_addr();
+        tokio::spawn(server);
+
+        let opts = SelfCheckOptions {
+            timeout: Duration::from_secs(1),
+            retries: 3,
+            max_body_size: 4096,
+        };
+        let result = self_check(addr, "token", "token.key", opts).await;
+        assert!(matches!(
+            result,
+            Err(SelfCheckError::ContentMismatch { token }) if token == "token"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_self_check_max_body_size() {
+        let server = Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(
+            hyper::service::make_service_fn(|_| async {
+                Ok::<_, Infallible>(service_fn(|_| async {
+                    Ok::<_, Infallible>(Response::new(Body::from("token.key")))
+                }))
+            }),
+        );
+        let addr = server.local_addr();
+        tokio::spawn(server);
+
+        let opts = SelfCheckOptions {
+            timeout: Duration::from_secs(1),
+            retries: 0,
+            max_body_size: 9,
+        };
+        assert!(self_check(addr, "token", "token.key", opts).await.is_ok());
+
+        let opts = SelfCheckOptions {
+            max_body_size: 8,
+            ..opts
+        };
+        assert!(matches!(
+            self_check(addr, "token", "token.key", opts).await,
+            Err(SelfCheckError::ContentMismatch { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_self_check_not_ready() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut conns = Vec::new();
+            while let Ok((sock, _)) = listener.accept().await {
+                conns.push(sock);
+            }
+        });
+
+        let opts = SelfCheckOptions {
+            timeout: Duration::from_millis(100),
+            retries: 1,
+            max_body_size: 4096,
+        };
+        let result = tokio::time::timeout(
+            Duration::from_secs(5),
+            self_check(addr, "token", "token.key", opts),
+        )
+        .await
+        .unwrap();
+        assert!(matches!(result, Err(SelfCheckError::NotReady { .. })));
+    }
+}
@@ -0,0 +1,140 @@
+use super::{acme::AcmeEntry, certs::Cert, Keyring, KeyringItem};
+use argon2::Argon2;
+use rand::RngCore;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN};
+use serde_derive::{Deserialize, Serialize};
+use std::sync::Arc;
+
+const MAGIC: &[u8] = b"TAXYKR01";
+const SALT_LEN: usize = 16;
+const HEADER_LEN: usize = MAGIC.len() + SALT_LEN + NONCE_LEN;
+
+#[derive(Serialize, Deserialize)]
+struct Archive {
+    certs: Vec<ArchivedCert>,
+    acme: Vec<AcmeEntry>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ArchivedCert {
+    chain: String,
+    key: String,
+}
+
+/// Serializes all keyring items, including private keys, into an archive encrypted with
+/// a key derived from `passphrase`.
+///
+/// The archive consists of a header holding the format magic, the Argon2id salt and
+/// the nonce, followed by the ChaCha20-Poly1305 ciphertext. The header is authenticated
+/// along with the ciphertext.
+pub fn export(keyring: &Keyring, passphrase: &str) -> anyhow::Result<Vec<u8>> {
+    let mut archive = Archive {
+        certs: Vec::new(),
+        acme: Vec::new(),
+    };
+    for item in keyring.iter() {
+        match item {
+            KeyringItem::ServerCert(cert) => archive.certs.push(ArchivedCert {
+                chain: String::from_utf8(cert.raw_chain.clone())?,
+                key: String::from_utf8(cert.raw_key.clone())?,
+            }),
+            KeyringItem::Acme(acme) => archive.acme.push(AcmeEntry::clone(acme)),
+        }
+    }
+    let mut data = serde_json::to_vec(&archive)?;
+
+    let mut header = [0; HEADER_LEN];
+    header[..MAGIC.len()].copy_from_slice(MAGIC);
+    rand::thread_rng().fill_bytes(&mut header[MAGIC.len()..]);
+    let (salt, nonce) = header[MAGIC.len()..].split_at(SALT_LEN);
+
+    let key = derive_key(passphrase, salt)?;
+    let nonce =
+        Nonce::try_assume_unique_for_key(nonce).map_err(|_| anyhow::anyhow!("invalid nonce"))?;
+    key.seal_in_place_append_tag(nonce, Aad::from(&header), &mut data)
+        .map_err(|_| anyhow::anyhow!("failed to encrypt the archive"))?;
+
+    let mut out = header.to_vec();
+    out.append(&mut data);
+    Ok(out)
+}
+
+/// Decrypts an archive created by [`export`] and restores its items.
+///
+/// Fails if the passphrase is wrong or the archive has been tampered with.
+pub fn import(archive: &[u8], passphrase: &str) -> anyhow::Result<Vec<KeyringItem>> {
+    if archive.len() < HEADER_LEN || !archive.starts_with(MAGIC) {
+        anyhow::bail!("not a keyring archive");
+    }
+    let (header, data) = archive.split_at(HEADER_LEN);
+    let (salt, nonce) = header[MAGIC.len()..].split_at(SALT_LEN);
+
+    let key = derive_key(passphrase, salt)?;
+    let nonce =
+        Nonce::try_assume_unique_for_key(nonce).map_err(|_| anyhow::anyhow!("invalid nonce"))?;
+    let mut data = data.to_vec();
+    let data = key
+        .open_in_place(nonce, Aad::from(header), &mut data)
+        .map_err(|_| {
+            anyhow::anyhow!("failed to decrypt the archive: wrong passphrase or corrupted data")
+        })?;
+    let archive: Archive = serde_json::from_slice(data)?;
+
+    let mut items = Vec::new();
+    for cert in archive.certs {
+        let cert = Cert::new(cert.chain.into_bytes(), cert.key.into_bytes())?;
+        items.push(KeyringItem::ServerCert(Arc::new(cert)));
+    }
+    for acme in archive.acme {
+        items.push(KeyringItem::Acme(Arc::new(acme)));
+    }
+    Ok(items)
+}
+
+fn derive_key(passphrase: &str, salt: &[u8]) -> anyhow::Result<LessSafeKey> {
+    let mut key = [0; 32];
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|err| anyhow::anyhow!("failed to derive the archive key: {err}"))?;
+    let key = UnboundKey::new(&CHACHA20_POLY1305, &key)
+        .map_err(|_| anyhow::anyhow!("failed to derive the archive key"))?;
+    Ok(LessSafeKey::new(key))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::self_signed;
+
+    fn cert_ids(keyring: &Keyring) -> Vec<(String, String)> {
+        keyring
+            .certs()
+            .iter()
+            .map(|cert| (cert.id().to_string(), cert.fingerprint.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn test_export_import() {
+        let keyring = Keyring::new([
+            KeyringItem::ServerCert(self_signed("a.example.com")),
+            KeyringItem::ServerCert(self_signed("b.example.com")),
+        ]);
+        let archive = export(&keyring, "correct horse").unwrap();
+
+        let restored = Keyring::new(import(&archive, "correct horse").unwrap());
+        assert_eq!(cert_ids(&restored), cert_ids(&keyring));
+        assert_eq!(cert_ids(&restored).len(), 2);
+
+        assert!(import(&archive, "wrong horse").is_err());
+
+        let mut tampered = archive.clone();
+        let last = tampered.len() - 1;
+        tampered[last] ^= 1;
+        assert!(import(&tampered, "correct horse").is_err());
+
+        let mut tampered = archive;
+        tampered[MAGIC.len()] ^= 1;
+        assert!(import(&tampered, "correct horse").is_err());
+    }
+}
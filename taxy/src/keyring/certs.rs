@@ -1,19 +1,30 @@
+use super::ocsp::OcspResponse;
 use pkcs8::{PrivateKeyInfo, SecretDocument};
 use rcgen::{BasicConstraints, CertificateParams, DistinguishedName, DnType, IsCa, SanType};
+use ring::signature;
 use sha2::{Digest, Sha256};
 use std::fmt;
 use std::io::{BufRead, BufReader};
 use std::str::FromStr;
-use taxy_api::cert::{CertInfo, CertMetadata, SelfSignedCertRequest};
+use std::sync::Arc;
+use std::time::SystemTime;
+use taxy_api::cert::{
+    CertInfo, CertMetadata, CertTrustRule, CertWarning, KeyAlgorithm, KeyPolicy,
+    SelfSignedCertRequest,
+};
 use taxy_api::error::Error;
 use taxy_api::subject_name::SubjectName;
 use tokio_rustls::rustls::sign::CertifiedKey;
-use tokio_rustls::rustls::{sign, Certificate, PrivateKey};
+use tokio_rustls::rustls::{sign, Certificate, PrivateKey, SignatureScheme};
 use tracing::error;
+use x509_parser::oid_registry::OID_SIG_ED25519;
+use x509_parser::public_key::PublicKey;
 use x509_parser::{extensions::GeneralName, time::ASN1Time};
 use x509_parser::{parse_x509_certificate, prelude::X509Certificate};
 
 const CERT_ID_LENGTH: usize = 20;
+const MIN_RECOMMENDED_RSA_BITS: usize = 2048;
+const KEY_PROBE_MESSAGE: &[u8] = b"taxy key probe";
 
 #[derive(Clone)]
 pub struct Cert {
@@ -28,6 +39,10 @@ pub struct Cert {
     pub not_after: ASN1Time,
     pub not_before: ASN1Time,
     pub metadata: Option<CertMetadata>,
+    pub key_algorithm: KeyAlgorithm,
+    pub key_size: usize,
+    /// The OCSP response stapled to the handshakes, if available.
+    pub ocsp: Option<Arc<OcspResponse>>,
 }
 
 impl PartialEq for Cert {
@@ -49,6 +64,8 @@ impl fmt::Debug for Cert {
             .field("not_after", &self.not_after)
             .field("not_before", &self.not_before)
             .field("metadata", &self.metadata)
+            .field("key_algorithm", &self.key_algorithm)
+            .field("key_size", &self.key_size)
             .finish()
     }
 }
@@ -106,20 +123,7 @@ impl Cert {
     }
 
     pub fn has_subject_name(&self, name: &SubjectName) -> bool {
-        for san in &self.san {
-            if match (san, name) {
-                (SubjectName::DnsName(c), SubjectName::DnsName(n)) => c == n,
-                (SubjectName::WildcardDnsName(c), SubjectName::DnsName(n)) => {
-                    c == n.trim_start_matches(|c| c != '.').trim_start_matches('.')
-                }
-                (SubjectName::WildcardDnsName(c), SubjectName::WildcardDnsName(n)) => c == n,
-                (SubjectName::IPAddress(c), SubjectName::IPAddress(n)) => c == n,
-                _ => false,
-            } {
-                return true;
-            }
-        }
-        false
+        san_matches(&self.san, name)
     }
 
     pub fn new(raw_chain: Vec<u8>, raw_key: Vec<u8>) -> Result<Self, Error> {
@@ -154,20 +158,19 @@ impl Cert {
 
         let parsed_chain = parse_chain(&chain)?;
         let x509 = parsed_chain.first().ok_or(Error::FailedToReadCertificate)?;
-        let san = x509
-            .subject_alternative_name()
-            .into_iter()
-            .flatten()
-            .flat_map(|name| &name.value.general_names)
-            .filter_map(|name| match name {
-                GeneralName::DNSName(name) => SubjectName::from_str(name).ok(),
-                _ => None,
-            })
-            .collect();
+        let san = subject_alt_names(x509);
 
         let not_after = x509.validity().not_after;
         let not_before = x509.validity().not_before;
 
+        let public_key = x509.public_key();
+        let (key_algorithm, key_size) = match public_key.parsed() {
+            Ok(PublicKey::RSA(key)) => (KeyAlgorithm::Rsa, key.key_size()),
+            Ok(PublicKey::EC(key)) => (KeyAlgorithm::Ecdsa, key.key_size()),
+            _ if public_key.algorithm.algorithm == OID_SIG_ED25519 => (KeyAlgorithm::Ed25519, 256),
+            _ => (KeyAlgorithm::Unknown, 0),
+        };
+
         let issuer = x509.issuer().to_string();
         let root_cert = parsed_chain
             .last()
@@ -186,10 +189,189 @@ impl Cert {
             not_after,
             not_before,
             metadata,
+            key_algorithm,
+            key_size,
+            ocsp: None,
         })
     }
 
+    pub fn is_trusted(&self) -> bool {
+        self.metadata.as_ref().is_some_and(|meta| meta.is_trusted)
+    }
+
+    pub fn acme_id(&self) -> Option<&str> {
+        self.metadata
+            .as_ref()
+            .map(|meta| meta.acme_id.as_str())
+            .filter(|id| !id.is_empty())
+    }
+
+    /// Returns a copy of the certificate with the trust flag set, rewriting the
+    /// metadata comment at the head of the PEM chain.
+    pub fn with_trusted(&self, is_trusted: bool) -> Result<Self, Error> {
+        let mut metadata = self.metadata.clone().unwrap_or_else(|| CertMetadata {
+            acme_id: String::new(),
+            created_at: SystemTime::now(),
+            is_trusted,
+            ocsp_response_path: None,
+        });
+        metadata.is_trusted = is_trusted;
+        let metadata =
+            serde_qs::to_string(&metadata).map_err(|_| Error::FailedToReadCertificate)?;
+
+        let chain =
+            std::str::from_utf8(&self.raw_chain).map_err(|_| Error::FailedToReadCertificate)?;
+        let pem = chain
+            .find("-----BEGIN")
+            .map(|start| &chain[start..])
+            .ok_or(Error::FailedToReadCertificate)?;
+        Self::new(
+            format!("# {metadata}\r\n\r\n{pem}").into_bytes(),
+            self.raw_key.clone(),
+        )
+    }
+
+    pub fn matches_trust_rule(&self, rule: &CertTrustRule, acme_provider: Option<&str>) -> bool {
+        rule.acme_provider
+            .as_ref()
+            .is_none_or(|provider| Some(provider.as_str()) == acme_provider)
+            && rule
+                .issuer
+                .as_ref()
+                .is_none_or(|issuer| *issuer == self.issuer)
+    }
+
+    /// Checks the key of the leaf certificate against the policy.
+    pub fn check_key_policy(&self, policy: &KeyPolicy) -> Result<(), Error> {
+        if !policy.allowed_algorithms.is_empty()
+            && !policy.allowed_algorithms.contains(&self.key_algorithm)
+        {
+            return Err(Error::KeyAlgorithmNotAllowed {
+                algorithm: self.key_algorithm,
+            });
+        }
+        match policy.min_rsa_bits {
+            Some(min) if self.key_algorithm == KeyAlgorithm::Rsa && self.key_size < min => {
+                Err(Error::KeyTooSmall {
+                    bits: self.key_size,
+                    min,
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Runs the validation checks against the chain and key without storing them.
+    pub fn validate(&self, policy: &KeyPolicy) -> Vec<CertWarning> {
+        let mut warnings = Vec::new();
+
+        let now = ASN1Time::now();
+        if self.not_after < now {
+            warnings.push(CertWarning::Expired {
+                not_after: self.not_after.timestamp(),
+            });
+        } else if now < self.not_before {
+            warnings.push(CertWarning::NotYetValid {
+                not_before: self.not_before.timestamp(),
+            });
+        }
+
+        let mut chain = self.raw_chain.as_slice();
+        let chain = rustls_pemfile::certs(&mut chain)
+            .unwrap_or_default()
+            .into_iter()
+            .map(Certificate)
+            .collect::<Vec<_>>();
+        if let Ok(parsed_chain) = parse_chain(&chain) {
+            for pair in parsed_chain.windows(2) {
+                if pair[0]
+                    .verify_signature(Some(pair[1].public_key()))
+                    .is_err()
+                {
+                    warnings.push(CertWarning::BrokenChain {
+                        subject: pair[0].subject().to_string(),
+                    });
+                }
+            }
+            if let Some(leaf) = parsed_chain.first() {
+                match self.key_matches(leaf) {
+                    Some(true) => (),
+                    Some(false) => warnings.push(CertWarning::KeyMismatch),
+                    None => warnings.push(CertWarning::UnusableKey),
+                }
+            }
+        }
+
+        match self.check_key_policy(policy) {
+            Err(Error::KeyAlgorithmNotAllowed { algorithm }) => {
+                warnings.push(CertWarning::KeyAlgorithmNotAllowed { algorithm })
+            }
+            Err(Error::KeyTooSmall { bits, min }) => {
+                warnings.push(CertWarning::WeakKey { bits, min })
+            }
+            _ if self.key_algorithm == KeyAlgorithm::Rsa
+                && self.key_size < MIN_RECOMMENDED_RSA_BITS =>
+            {
+                warnings.push(CertWarning::WeakKey {
+                    bits: self.key_size,
+                    min: MIN_RECOMMENDED_RSA_BITS,
+                })
+            }
+            _ => (),
+        }
+
+        warnings
+    }
+
+    /// Signs a probe message with the private key and verifies it with the public key
+    /// of the leaf certificate. Returns `None` if the key cannot be used for signing.
+    fn key_matches(&self, leaf: &X509Certificate) -> Option<bool> {
+        let certified = self.certified_impl().ok()?;
+        let signer = certified.key.choose_scheme(&[
+            SignatureScheme::ECDSA_NISTP256_SHA256,
+            SignatureScheme::ECDSA_NISTP384_SHA384,
+            SignatureScheme::ED25519,
+            SignatureScheme::RSA_PKCS1_SHA256,
+        ])?;
+        let algorithm: &dyn signature::VerificationAlgorithm = match signer.scheme() {
+            SignatureScheme::ECDSA_NISTP256_SHA256 => &signature::ECDSA_P256_SHA256_ASN1,
+            SignatureScheme::ECDSA_NISTP384_SHA384 => &signature::ECDSA_P384_SHA384_ASN1,
+            SignatureScheme::ED25519 => &signature::ED25519,
+            SignatureScheme::RSA_PKCS1_SHA256 => &signature::RSA_PKCS1_2048_8192_SHA256,
+            _ => return None,
+        };
+        let sig = signer.sign(KEY_PROBE_MESSAGE).ok()?;
+        let public_key = signature::UnparsedPublicKey::new(
+            algorithm,
+            &leaf.public_key().subject_public_key.data,
+        );
+        Some(public_key.verify(KEY_PROBE_MESSAGE, &sig).is_ok())
+    }
+
+    /// Generates a self-signed certificate with an ECDSA P-256 key.
     pub fn new_self_signed(req: &SelfSignedCertRequest) -> Result<Self, Error> {
+        Self::new_self_signed_with_policy(req, &KeyPolicy::default())
+    }
+
+    /// Generates a self-signed certificate with an ECDSA key, or an Ed25519 key if
+    /// the policy only allows the latter.
+    pub fn new_self_signed_with_policy(
+        req: &SelfSignedCertRequest,
+        policy: &KeyPolicy,
+    ) -> Result<Self, Error> {
+        let allowed = |algorithm| {
+            policy.allowed_algorithms.is_empty() || policy.allowed_algorithms.contains(&algorithm)
+        };
+        let alg = if allowed(KeyAlgorithm::Ecdsa) {
+            &rcgen::PKCS_ECDSA_P256_SHA256
+        } else if allowed(KeyAlgorithm::Ed25519) {
+            &rcgen::PKCS_ED25519
+        } else {
+            return Err(Error::KeyAlgorithmNotAllowed {
+                algorithm: KeyAlgorithm::Ecdsa,
+            });
+        };
+
         let mut distinguished_name = DistinguishedName::new();
         distinguished_name.push(DnType::CommonName, "Taxy CA");
         let mut ca_params = CertificateParams::default();
@@ -205,6 +387,7 @@ impl Cert {
         };
 
         let mut params = CertificateParams::default();
+        params.alg = alg;
         params.subject_alt_names = req
             .san
             .iter()
@@ -246,7 +429,9 @@ impl Cert {
         let raw_chain = format!("{}\r\n{}", cert_pem, ca_pem).into_bytes();
         let raw_key = cert.serialize_private_key_pem().into_bytes();
 
-        Self::new(raw_chain, raw_key)
+        let cert = Self::new(raw_chain, raw_key)?;
+        cert.check_key_policy(policy)?;
+        Ok(cert)
     }
 
     pub fn certified(&self) -> Result<CertifiedKey, Error> {
@@ -263,18 +448,58 @@ impl Cert {
         let key = self
             .key
             .decode_msg::<PrivateKeyInfo>()
-            .map_err(|err| anyhow::anyhow!("{err}"))?;
-        let signing_key = sign::any_supported_type(&PrivateKey(key.private_key.to_vec()))
-            .map_err(|err| anyhow::anyhow!("{err}"))?;
+            .map_err(|err| anyhow::anyhow!("{err}"))
+            .and_then(|key| {
+                sign::any_supported_type(&PrivateKey(key.private_key.to_vec()))
+                    .map_err(|err| anyhow::anyhow!("{err}"))
+            });
+        // Ed25519 keys are only loaded from the whole PKCS#8 document.
+        let signing_key = match key {
+            Ok(key) => key,
+            Err(err) => sign::any_supported_type(&PrivateKey(self.key.as_bytes().to_vec()))
+                .map_err(|_| err)?,
+        };
 
         let mut chain = self.raw_chain.as_slice();
         let chain =
             rustls_pemfile::certs(&mut chain).map_err(|_| Error::FailedToReadCertificate)?;
         let chain = chain.into_iter().map(Certificate).collect::<Vec<_>>();
-        Ok(CertifiedKey::new(chain, signing_key))
+        let mut certified = CertifiedKey::new(chain, signing_key);
+        certified.ocsp = self
+            .ocsp
+            .as_ref()
+            .filter(|ocsp| ocsp.is_valid(SystemTime::now()))
+            .map(|ocsp| ocsp.der.clone());
+        Ok(certified)
     }
 }
 
+/// Returns the DNS names in the subject alternative names of the certificate.
+pub fn subject_alt_names(x509: &X509Certificate) -> Vec<SubjectName> {
+    x509.subject_alternative_name()
+        .into_iter()
+        .flatten()
+        .flat_map(|name| &name.value.general_names)
+        .filter_map(|name| match name {
+            GeneralName::DNSName(name) => SubjectName::from_str(name).ok(),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Returns true if one of the subject alternative names covers the name.
+pub fn san_matches(san: &[SubjectName], name: &SubjectName) -> bool {
+    san.iter().any(|san| match (san, name) {
+        (SubjectName::DnsName(c), SubjectName::DnsName(n)) => c == n,
+        (SubjectName::WildcardDnsName(c), SubjectName::DnsName(n)) => {
+            c == n.trim_start_matches(|c| c != '.').trim_start_matches('.')
+        }
+        (SubjectName::WildcardDnsName(c), SubjectName::WildcardDnsName(n)) => c == n,
+        (SubjectName::IPAddress(c), SubjectName::IPAddress(n)) => c == n,
+        _ => false,
+    })
+}
+
 fn parse_chain(chain: &[Certificate]) -> Result<Vec<X509Certificate>, Error> {
     let mut certs = Vec::new();
     for data in chain {
@@ -287,7 +512,6 @@ fn parse_chain(chain: &[Certificate]) -> Result<Vec<X509Certificate>, Error> {
 
 #[cfg(test)]
 mod test {
-    
 
     #[test]
     fn test_self_signed() {
@@ -298,5 +522,199 @@ mod test {
         };
         let cert = Cert::new_self_signed(&req).unwrap();
         assert_eq!(cert.san, req.san);
+        assert_eq!(cert.key_algorithm, KeyAlgorithm::Ecdsa);
+    }
+
+    #[test]
+    fn test_key_policy() {
+        use super::*;
+
+        let policy = KeyPolicy {
+            min_rsa_bits: Some(2048),
+            allowed_algorithms: vec![KeyAlgorithm::Rsa],
+        };
+
+        let cert = Cert::new(
+            include_bytes!("testdata/rsa1024.pem").to_vec(),
+            include_bytes!("testdata/rsa1024.key.pem").to_vec(),
+        )
+        .unwrap();
+        assert_eq!(cert.key_algorithm, KeyAlgorithm::Rsa);
+        assert!(matches!(
+            cert.check_key_policy(&policy),
+            Err(Error::KeyTooSmall {
+                bits: 1024,
+                min: 2048
+            })
+        ));
+
+        let cert = Cert::new(
+            include_bytes!("testdata/rsa2048.pem").to_vec(),
+            include_bytes!("testdata/rsa2048.key.pem").to_vec(),
+        )
+        .unwrap();
+        assert!(cert.check_key_policy(&policy).is_ok());
+
+        let req = SelfSignedCertRequest {
+            san: vec![SubjectName::from_str("localhost").unwrap()],
+        };
+        let cert = Cert::new_self_signed(&req).unwrap();
+        assert!(matches!(
+            cert.check_key_policy(&policy),
+            Err(Error::KeyAlgorithmNotAllowed {
+                algorithm: KeyAlgorithm::Ecdsa
+            })
+        ));
+    }
+
+    #[test]
+    fn test_self_signed_key_policy() {
+        use super::*;
+
+        let req = SelfSignedCertRequest {
+            san: vec![SubjectName::from_str("localhost").unwrap()],
+        };
+        let policy = KeyPolicy {
+            min_rsa_bits: None,
+            allowed_algorithms: vec![KeyAlgorithm::Ed25519],
+        };
+        let cert = Cert::new_self_signed_with_policy(&req, &policy).unwrap();
+        assert_eq!(cert.key_algorithm, KeyAlgorithm::Ed25519);
+        assert!(cert.certified().is_ok());
+
+        let policy = KeyPolicy {
+            min_rsa_bits: None,
+            allowed_algorithms: vec![KeyAlgorithm::Rsa],
+        };
+        assert!(matches!(
+            Cert::new_self_signed_with_policy(&req, &policy),
+            Err(Error::KeyAlgorithmNotAllowed {
+                algorithm: KeyAlgorithm::Ecdsa
+            })
+        ));
+    }
+
+    #[test]
+    fn test_validate() {
+        use super::*;
+
+        let policy = KeyPolicy::default();
+        let req = SelfSignedCertRequest {
+            san: vec![SubjectName::from_str("localhost").unwrap()],
+        };
+        let cert = Cert::new_self_signed(&req).unwrap();
+        assert_eq!(cert.validate(&policy), vec![]);
+
+        let other = Cert::new_self_signed(&req).unwrap();
+        let mismatch = Cert::new(cert.raw_chain.clone(), other.raw_key.clone()).unwrap();
+        assert_eq!(mismatch.validate(&policy), vec![CertWarning::KeyMismatch]);
+
+        let mut chain = rustls_pemfile::certs(&mut cert.raw_chain.as_slice()).unwrap();
+        let other_chain = rustls_pemfile::certs(&mut other.raw_chain.as_slice()).unwrap();
+        chain[1] = other_chain[1].clone();
+        let raw_chain = chain
+            .iter()
+            .map(|der| pem_encode(der))
+            .collect::<String>()
+            .into_bytes();
+        let broken = Cert::new(raw_chain, cert.raw_key.clone()).unwrap();
+        assert_eq!(
+            broken.validate(&policy),
+            vec![CertWarning::BrokenChain {
+                subject: "CN=localhost".into()
+            }]
+        );
+
+        let mut params = CertificateParams::new(vec!["localhost".into()]);
+        params.not_before = time::OffsetDateTime::from_unix_timestamp(1_000_000_000).unwrap();
+        params.not_after = time::OffsetDateTime::from_unix_timestamp(1_100_000_000).unwrap();
+        let expired = rcgen::Certificate::from_params(params).unwrap();
+        let expired = Cert::new(
+            expired.serialize_pem().unwrap().into_bytes(),
+            expired.serialize_private_key_pem().into_bytes(),
+        )
+        .unwrap();
+        assert_eq!(
+            expired.validate(&policy),
+            vec![CertWarning::Expired {
+                not_after: 1_100_000_000
+            }]
+        );
+
+        let weak = Cert::new(
+            include_bytes!("testdata/rsa1024.pem").to_vec(),
+            include_bytes!("testdata/rsa1024.key.pem").to_vec(),
+        )
+        .unwrap();
+        let warnings = weak.validate(&policy);
+        assert!(warnings.contains(&CertWarning::WeakKey {
+            bits: 1024,
+            min: MIN_RECOMMENDED_RSA_BITS
+        }));
+
+        let rsa = Cert::new(
+            include_bytes!("testdata/rsa2048.pem").to_vec(),
+            include_bytes!("testdata/rsa2048.key.pem").to_vec(),
+        )
+        .unwrap();
+        assert!(!rsa.validate(&policy).contains(&CertWarning::KeyMismatch));
+        let policy = KeyPolicy {
+            min_rsa_bits: Some(4096),
+            allowed_algorithms: vec![KeyAlgorithm::Rsa],
+        };
+        assert!(rsa.validate(&policy).contains(&CertWarning::WeakKey {
+            bits: 2048,
+            min: 4096
+        }));
+        assert!(cert
+            .validate(&policy)
+            .contains(&CertWarning::KeyAlgorithmNotAllowed {
+                algorithm: KeyAlgorithm::Ecdsa
+            }));
+    }
+
+    #[test]
+    fn test_with_trusted() {
+        use super::*;
+
+        let req = SelfSignedCertRequest {
+            san: vec![SubjectName::from_str("localhost").unwrap()],
+        };
+        let cert = Cert::new_self_signed(&req).unwrap();
+        assert!(!cert.is_trusted());
+        assert_eq!(cert.acme_id(), None);
+
+        let trusted = cert.with_trusted(true).unwrap();
+        assert_eq!(trusted.id(), cert.id());
+        assert!(trusted.is_trusted());
+        assert_eq!(trusted.acme_id(), None);
+
+        let reparsed = Cert::new(trusted.raw_chain.clone(), trusted.raw_key).unwrap();
+        assert!(reparsed.is_trusted());
+        assert!(!reparsed.with_trusted(false).unwrap().is_trusted());
+
+        let rule = CertTrustRule {
+            acme_provider: Some("Let's Encrypt".into()),
+            issuer: None,
+        };
+        assert!(cert.matches_trust_rule(&rule, Some("Let's Encrypt")));
+        assert!(!cert.matches_trust_rule(&rule, None));
+        let rule = CertTrustRule {
+            acme_provider: None,
+            issuer: Some(cert.issuer.clone()),
+        };
+        assert!(cert.matches_trust_rule(&rule, None));
+    }
+
+    fn pem_encode(der: &[u8]) -> String {
+        use base64::Engine;
+        let body = base64::engine::general_purpose::STANDARD.encode(der);
+        let lines = body
+            .as_bytes()
+            .chunks(64)
+            .map(|line| std::str::from_utf8(line).unwrap())
+            .collect::<Vec<_>>()
+            .join("\n");
+        format!("-----BEGIN CERTIFICATE-----\n{lines}\n-----END CERTIFICATE-----\n")
     }
 }
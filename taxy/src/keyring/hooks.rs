@@ -0,0 +1,149 @@
+use super::certs::Cert;
+use crate::command::ServerCommand;
+use std::{process::Stdio, time::Duration};
+use taxy_api::app::RenewalHooks;
+use tokio::{process::Command, sync::mpsc};
+use tracing::{error, info};
+
+/// Runs the renewal hooks around ACME issuance and reports their results to the server.
+#[derive(Debug, Clone)]
+pub struct RenewalHookRunner {
+    hooks: RenewalHooks,
+    command: mpsc::Sender<ServerCommand>,
+}
+
+impl RenewalHookRunner {
+    pub fn new(hooks: RenewalHooks, command: mpsc::Sender<ServerCommand>) -> Self {
+        Self { hooks, command }
+    }
+
+    /// Returns false if the hook failed, in which case the renewal should be skipped.
+    pub async fn pre_renewal(&self, acme_id: &str, identifiers: &[String]) -> bool {
+        let env = [
+            ("TAXY_ACME_ID", acme_id.to_string()),
+            ("TAXY_IDENTIFIERS", identifiers.join(",")),
+        ];
+        self.run("pre-renewal", &self.hooks.pre, acme_id, &env)
+            .await
+    }
+
+    pub async fn post_renewal(&self, acme_id: &str, cert: &Cert) -> bool {
+        let san = cert
+            .san
+            .iter()
+            .map(|name| name.to_string())
+            .collect::<Vec<_>>();
+        let env = [
+            ("TAXY_ACME_ID", acme_id.to_string()),
+            ("TAXY_CERT_ID", cert.id().to_string()),
+            ("TAXY_CERT_SAN", san.join(",")),
+        ];
+        self.run("post-renewal", &self.hooks.post, acme_id, &env)
+            .await
+    }
+
+    async fn run(
+        &self,
+        name: &str,
+        command: &[String],
+        acme_id: &str,
+        env: &[(&str, String)],
+    ) -> bool {
+        if command.is_empty() {
+            return true;
+        }
+        let error = match run_command(command, env, self.hooks.timeout).await {
+            Ok(()) => {
+                info!(hook = name, "renewal hook completed");
+                None
+            }
+            Err(err) => {
+                error!(hook = name, "renewal hook failed: {err}");
+                Some(format!("{name} hook failed: {err}"))
+            }
+        };
+        let succeeded = error.is_none();
+        let _ = self
+            .command
+            .send(ServerCommand::SetRenewalHookError {
+                acme_id: acme_id.to_string(),
+                error,
+            })
+            .await;
+        succeeded
+    }
+}
+
+async fn run_command(
+    command: &[String],
+    env: &[(&str, String)],
+    timeout: Duration,
+) -> anyhow::Result<()> {
+    let mut child = Command::new(&command[0])
+        .args(&command[1..])
+        .envs(env.iter().map(|(key, value)| (key, value)))
+        .stdin(Stdio::null())
+        .kill_on_drop(true)
+        .spawn()?;
+    let status = tokio::time::timeout(timeout, child.wait())
+        .await
+        .map_err(|_| anyhow::anyhow!("timed out after {timeout:?}"))??;
+    if !status.success() {
+        anyhow::bail!("{status}");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::str::FromStr;
+    use taxy_api::{cert::SelfSignedCertRequest, subject_name::SubjectName};
+
+    fn runner(post: Vec<String>) -> (RenewalHookRunner, mpsc::Receiver<ServerCommand>) {
+        let (command, recv) = mpsc::channel(1);
+        let hooks = RenewalHooks {
+            post,
+            timeout: Duration::from_secs(5),
+            ..Default::default()
+        };
+        (RenewalHookRunner::new(hooks, command), recv)
+    }
+
+    #[tokio::test]
+    async fn test_post_renewal_hook() {
+        let cert = Cert::new_self_signed(&SelfSignedCertRequest {
+            san: vec![
+                SubjectName::from_str("example.com").unwrap(),
+                SubjectName::from_str("*.example.com").unwrap(),
+            ],
+        })
+        .unwrap();
+
+        let output = std::env::temp_dir().join(cuid2::cuid());
+        let (hooks, mut recv) = runner(vec![
+            "sh".into(),
+            "-c".into(),
+            format!(
+                "echo \"$TAXY_ACME_ID $TAXY_CERT_ID $TAXY_CERT_SAN\" > {}",
+                output.display()
+            ),
+        ]);
+        assert!(hooks.post_renewal("acme", &cert).await);
+        assert_eq!(
+            tokio::fs::read_to_string(&output).await.unwrap().trim(),
+            format!("acme {} example.com,*.example.com", cert.id())
+        );
+        assert!(matches!(
+            recv.recv().await,
+            Some(ServerCommand::SetRenewalHookError { acme_id, error: None }) if acme_id == "acme"
+        ));
+
+        let (hooks, mut recv) = runner(vec!["sh".into(), "-c".into(), "exit 3".into()]);
+        assert!(!hooks.post_renewal("acme", &cert).await);
+        assert!(matches!(
+            recv.recv().await,
+            Some(ServerCommand::SetRenewalHookError { error: Some(_), .. })
+        ));
+    }
+}
@@ -1,10 +1,13 @@
-use taxy_api::cert::KeyringInfo;
+use taxy_api::cert::{CertInfo, CertListQuery, CertSortKey, KeyringInfo, SortOrder};
 
 use self::{acme::AcmeEntry, certs::Cert};
-use std::{collections::HashMap, sync::Arc};
+use std::{cmp::Ordering, collections::HashMap, sync::Arc};
 
 pub mod acme;
+pub mod backup;
 pub mod certs;
+pub mod hooks;
+pub mod ocsp;
 
 #[derive(Debug, Default)]
 pub struct Keyring {
@@ -81,16 +84,19 @@ impl Keyring {
                 KeyringItem::ServerCert(cert) => Some(cert),
                 _ => None,
             })
-            .filter(|cert| {
-                cert.metadata
-                    .as_ref()
-                    .map_or(false, |meta| meta.acme_id == acme)
-            })
+            .filter(|cert| cert.acme_id() == Some(acme))
             .collect::<Vec<_>>();
         certs.sort();
         certs
     }
 
+    pub fn find_server_cert(&self, id: &str) -> Option<&Arc<Cert>> {
+        match self.certs.get(id) {
+            Some(KeyringItem::ServerCert(cert)) => Some(cert),
+            _ => None,
+        }
+    }
+
     pub fn add(&mut self, item: KeyringItem) {
         self.certs.insert(item.id().to_string(), item);
     }
@@ -108,4 +114,131 @@ impl Keyring {
         list.sort_unstable_by_key(|cert| cert.id().to_string());
         list
     }
+
+    /// Lists the server certificates in the order and range requested by `query`.
+    pub fn list_server_certs(&self, query: &CertListQuery) -> Vec<CertInfo> {
+        let mut list = self
+            .certs
+            .values()
+            .filter_map(|item| match item {
+                KeyringItem::ServerCert(cert) => Some(cert.info()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        list.sort_unstable_by(|a, b| {
+            query
+                .sort
+                .map_or(Ordering::Equal, |key| compare_certs(key, a, b))
+                .then_with(|| a.id.cmp(&b.id))
+        });
+        if query.order == SortOrder::Desc {
+            list.reverse();
+        }
+        list.into_iter()
+            .skip(query.offset.unwrap_or_default())
+            .take(query.limit.unwrap_or(usize::MAX))
+            .collect()
+    }
+}
+
+fn compare_certs(key: CertSortKey, a: &CertInfo, b: &CertInfo) -> Ordering {
+    match key {
+        CertSortKey::Expiry => a.not_after.cmp(&b.not_after),
+        CertSortKey::Subject => {
+            let subject = |cert: &CertInfo| cert.san.first().map(|name| name.to_string());
+            subject(a).cmp(&subject(b))
+        }
+        CertSortKey::Issuer => a.issuer.cmp(&b.issuer),
+        CertSortKey::Trust => {
+            let trusted = |cert: &CertInfo| cert.metadata.as_ref().is_some_and(|m| m.is_trusted);
+            trusted(a).cmp(&trusted(b))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::SystemTime;
+    use taxy_api::cert::{CertMetadata, SelfSignedCertRequest};
+    use x509_parser::time::ASN1Time;
+
+    fn cert(name: &str, issuer: &str, not_after: i64, is_trusted: bool) -> KeyringItem {
+        let mut cert = Cert::new_self_signed(&SelfSignedCertRequest {
+            san: vec![name.parse().unwrap()],
+        })
+        .unwrap();
+        cert.issuer = issuer.into();
+        cert.not_after = ASN1Time::from_timestamp(not_after).unwrap();
+        cert.metadata = Some(CertMetadata {
+            acme_id: String::new(),
+            created_at: SystemTime::now(),
+            is_trusted,
+            ocsp_response_path: None,
+        });
+        KeyringItem::ServerCert(Arc::new(cert))
+    }
+
+    #[test]
+    fn test_list_server_certs() {
+        let keyring = Keyring::new([
+            cert("b.example.com", "CN=c", 2_000_000_000, true),
+            cert("c.example.com", "CN=a", 1_900_000_000, false),
+            cert("a.example.com", "CN=b", 2_100_000_000, false),
+        ]);
+        let names = |sort, order| {
+            keyring
+                .list_server_certs(&CertListQuery {
+                    sort: Some(sort),
+                    order,
+                    ..Default::default()
+                })
+                .into_iter()
+                .map(|cert| cert.san[0].to_string())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            names(CertSortKey::Expiry, SortOrder::Asc),
+            ["c.example.com", "b.example.com", "a.example.com"]
+        );
+        assert_eq!(
+            names(CertSortKey::Subject, SortOrder::Asc),
+            ["a.example.com", "b.example.com", "c.example.com"]
+        );
+        assert_eq!(
+            names(CertSortKey::Subject, SortOrder::Desc),
+            ["c.example.com", "b.example.com", "a.example.com"]
+        );
+        assert_eq!(
+            names(CertSortKey::Issuer, SortOrder::Asc),
+            ["c.example.com", "a.example.com", "b.example.com"]
+        );
+        assert_eq!(
+            names(CertSortKey::Trust, SortOrder::Desc)[0],
+            "b.example.com"
+        );
+        assert_eq!(
+            names(CertSortKey::Trust, SortOrder::Asc)[2],
+            "b.example.com"
+        );
+
+        let page = keyring.list_server_certs(&CertListQuery {
+            sort: Some(CertSortKey::Subject),
+            offset: Some(1),
+            limit: Some(1),
+            ..Default::default()
+        });
+        assert_eq!(page.len(), 1);
+        assert_eq!(page[0].san[0].to_string(), "b.example.com");
+
+        let ids = keyring
+            .list_server_certs(&Default::default())
+            .into_iter()
+            .map(|cert| cert.id)
+            .collect::<Vec<_>>();
+        let mut sorted = ids.clone();
+        sorted.sort();
+        assert_eq!(ids, sorted);
+    }
 }
@@ -0,0 +1,348 @@
+use super::certs::Cert;
+use hyper::{body::HttpBody, header::CONTENT_TYPE, Body, Client, Request, StatusCode};
+use ring::digest::{digest, SHA1_FOR_LEGACY_USE_ONLY};
+use std::time::{Duration, SystemTime};
+use time::{Date, Month, OffsetDateTime, PrimitiveDateTime, Time};
+use x509_parser::{
+    extensions::{GeneralName, ParsedExtension},
+    parse_x509_certificate,
+    prelude::X509Certificate,
+};
+
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_RESPONSE_SIZE: usize = 64 * 1024;
+
+/// Refresh interval of responses without a `nextUpdate` time.
+const DEFAULT_REFRESH_INTERVAL: Duration = Duration::from_secs(60 * 60 * 12);
+
+const OID_OCSP: &str = "1.3.6.1.5.5.7.48.1";
+const OID_SHA1: &[u8] = &[0x2b, 0x0e, 0x03, 0x02, 0x1a];
+
+const TAG_INTEGER: u8 = 0x02;
+const TAG_OCTET_STRING: u8 = 0x04;
+const TAG_NULL: u8 = 0x05;
+const TAG_OID: u8 = 0x06;
+const TAG_ENUMERATED: u8 = 0x0a;
+const TAG_GENERALIZED_TIME: u8 = 0x18;
+const TAG_SEQUENCE: u8 = 0x30;
+const TAG_EXPLICIT_0: u8 = 0xa0;
+const TAG_CERT_STATUS_GOOD: u8 = 0x80;
+
+/// A successful OCSP response reporting the certificate as good, ready to be stapled.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OcspResponse {
+    pub der: Vec<u8>,
+    pub this_update: SystemTime,
+    pub next_update: Option<SystemTime>,
+}
+
+impl OcspResponse {
+    pub fn parse(der: Vec<u8>) -> anyhow::Result<Self> {
+        let (this_update, next_update) =
+            parse_response(&der).ok_or_else(|| anyhow::anyhow!("malformed ocsp response"))??;
+        Ok(Self {
+            der,
+            this_update,
+            next_update,
+        })
+    }
+
+    /// Returns false once the response has expired.
+    pub fn is_valid(&self, now: SystemTime) -> bool {
+        self.next_update.is_none_or(|next| now < next)
+    }
+
+    /// Returns true once half of the validity period has elapsed, so that a failed
+    /// refresh can be retried before the response expires.
+    pub fn needs_refresh(&self, now: SystemTime) -> bool {
+        let period = self
+            .next_update
+            .and_then(|next| next.duration_since(self.this_update).ok())
+            .map_or(DEFAULT_REFRESH_INTERVAL, |period| period / 2);
+        now >= self.this_update + period
+    }
+}
+
+/// Loads the OCSP response of the certificate from the file in its metadata, or fetches it
+/// from the OCSP responder of its issuer. Returns `None` if neither is available.
+pub async fn load(cert: &Cert) -> anyhow::Result<Option<OcspResponse>> {
+    if let Some(path) = cert
+        .metadata
+        .as_ref()
+        .and_then(|meta| meta.ocsp_response_path.as_ref())
+    {
+        let der = tokio::fs::read(path).await?;
+        return OcspResponse::parse(der).map(Some);
+    }
+
+    let chain = rustls_pemfile::certs(&mut cert.raw_chain.as_slice())?;
+    let (Some(leaf), Some(issuer)) = (chain.first(), chain.get(1)) else {
+        return Ok(None);
+    };
+    let (leaf, issuer) = (parse_cert(leaf)?, parse_cert(issuer)?);
+    let Some(url) = responder_url(&leaf) else {
+        return Ok(None);
+    };
+    let der = tokio::time::timeout(FETCH_TIMEOUT, fetch(&url, request(&leaf, &issuer)))
+        .await
+        .map_err(|_| anyhow::anyhow!("ocsp request timed out after {FETCH_TIMEOUT:?}"))??;
+    OcspResponse::parse(der).map(Some)
+}
+
+fn parse_cert(der: &[u8]) -> anyhow::Result<X509Certificate<'_>> {
+    parse_x509_certificate(der)
+        .map(|(_, cert)| cert)
+        .map_err(|err| anyhow::anyhow!("{err}"))
+}
+
+async fn fetch(url: &str, body: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+    let req = Request::post(url)
+        .header(CONTENT_TYPE, "application/ocsp-request")
+        .body(Body::from(body))?;
+    let mut res = Client::new().request(req).await?;
+    if res.status() != StatusCode::OK {
+        anyhow::bail!("ocsp responder returned {}", res.status());
+    }
+    let body = res.body_mut();
+    let mut buf = Vec::new();
+    while let Some(chunk) = body.data().await {
+        buf.extend_from_slice(&chunk?);
+        if buf.len() > MAX_RESPONSE_SIZE {
+            anyhow::bail!("ocsp response exceeds {MAX_RESPONSE_SIZE} bytes");
+        }
+    }
+    Ok(buf)
+}
+
+/// Returns the URL of the OCSP responder in the authority information access extension.
+pub fn responder_url(cert: &X509Certificate) -> Option<String> {
+    cert.extensions()
+        .iter()
+        .filter_map(|ext| match ext.parsed_extension() {
+            ParsedExtension::AuthorityInfoAccess(aia) => Some(aia),
+            _ => None,
+        })
+        .flat_map(|aia| &aia.accessdescs)
+        .filter(|desc| desc.access_method.to_id_string() == OID_OCSP)
+        .find_map(|desc| match desc.access_location {
+            GeneralName::URI(uri) => Some(uri.to_string()),
+            _ => None,
+        })
+}
+
+/// Encodes an OCSP request for the certificate, identified by SHA-1 hashes as most
+/// responders expect.
+pub fn request(cert: &X509Certificate, issuer: &X509Certificate) -> Vec<u8> {
+    let algorithm = der(
+        TAG_SEQUENCE,
+        &[der(TAG_OID, OID_SHA1), der(TAG_NULL, &[])].concat(),
+    );
+    let name_hash = digest(&SHA1_FOR_LEGACY_USE_ONLY, issuer.subject().as_raw());
+    let key_hash = digest(
+        &SHA1_FOR_LEGACY_USE_ONLY,
+        &issuer.public_key().subject_public_key.data,
+    );
+    let cert_id = der(
+        TAG_SEQUENCE,
+        &[
+            algorithm,
+            der(TAG_OCTET_STRING, name_hash.as_ref()),
+            der(TAG_OCTET_STRING, key_hash.as_ref()),
+            der(TAG_INTEGER, cert.raw_serial()),
+        ]
+        .concat(),
+    );
+    let request = der(TAG_SEQUENCE, &cert_id);
+    let request_list = der(TAG_SEQUENCE, &request);
+    let tbs_request = der(TAG_SEQUENCE, &request_list);
+    der(TAG_SEQUENCE, &tbs_request)
+}
+
+fn der(tag: u8, content: &[u8]) -> Vec<u8> {
+    let mut out = vec![tag];
+    let len = content.len();
+    if len < 0x80 {
+        out.push(len as u8);
+    } else {
+        let bytes = len.to_be_bytes();
+        let bytes = &bytes[bytes.iter().take_while(|&&b| b == 0).count()..];
+        out.push(0x80 | bytes.len() as u8);
+        out.extend_from_slice(bytes);
+    }
+    out.extend_from_slice(content);
+    out
+}
+
+/// Returns the `thisUpdate` and `nextUpdate` times of the first single response,
+/// or `None` if the response is malformed.
+fn parse_response(der: &[u8]) -> Option<anyhow::Result<(SystemTime, Option<SystemTime>)>> {
+    let mut response = Reader(der).expect(TAG_SEQUENCE)?;
+    let status = response.expect(TAG_ENUMERATED)?;
+    if status.0 != [0] {
+        return Some(Err(anyhow::anyhow!(
+            "ocsp responder returned status {:?}",
+            status.0
+        )));
+    }
+    let mut bytes = response.expect(TAG_EXPLICIT_0)?.expect(TAG_SEQUENCE)?;
+    bytes.expect(TAG_OID)?;
+    let mut basic = bytes.expect(TAG_OCTET_STRING)?.expect(TAG_SEQUENCE)?;
+    let mut data = basic.expect(TAG_SEQUENCE)?;
+    if data.peek() == Some(TAG_EXPLICIT_0) {
+        data.read()?; // version
+    }
+    data.read()?; // responderID
+    data.expect(TAG_GENERALIZED_TIME)?; // producedAt
+    let mut single = data.expect(TAG_SEQUENCE)?.expect(TAG_SEQUENCE)?;
+    single.expect(TAG_SEQUENCE)?; // certID
+    let (status, _) = single.read()?;
+    if status != TAG_CERT_STATUS_GOOD {
+        return Some(Err(anyhow::anyhow!("certificate status is not good")));
+    }
+    let this_update = generalized_time(single.expect(TAG_GENERALIZED_TIME)?.0)?;
+    let next_update = match single.peek() {
+        Some(TAG_EXPLICIT_0) => Some(generalized_time(
+            single
+                .expect(TAG_EXPLICIT_0)?
+                .expect(TAG_GENERALIZED_TIME)?
+                .0,
+        )?),
+        _ => None,
+    };
+    Some(Ok((this_update, next_update)))
+}
+
+/// Parses a `YYYYMMDDHHMMSSZ` time, ignoring fractional seconds.
+fn generalized_time(value: &[u8]) -> Option<SystemTime> {
+    let value = std::str::from_utf8(value).ok()?;
+    let field = |range: std::ops::Range<usize>| value.get(range)?.parse::<u16>().ok();
+    let date = Date::from_calendar_date(
+        field(0..4)? as i32,
+        Month::try_from(field(4..6)? as u8).ok()?,
+        field(6..8)? as u8,
+    )
+    .ok()?;
+    let time = Time::from_hms(
+        field(8..10)? as u8,
+        field(10..12)? as u8,
+        field(12..14)? as u8,
+    )
+    .ok()?;
+    let time: OffsetDateTime = PrimitiveDateTime::new(date, time).assume_utc();
+    Some(time.into())
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn peek(&self) -> Option<u8> {
+        self.0.first().copied()
+    }
+
+    /// Reads a TLV, returning its tag and content.
+    fn read(&mut self) -> Option<(u8, Reader<'a>)> {
+        let (&tag, rest) = self.0.split_first()?;
+        let (&first, mut rest) = rest.split_first()?;
+        let len = if first < 0x80 {
+            first as usize
+        } else {
+            let n = (first & 0x7f) as usize;
+            if n == 0 || n > std::mem::size_of::<usize>() || rest.len() < n {
+                return None;
+            }
+            let (bytes, tail) = rest.split_at(n);
+            rest = tail;
+            bytes.iter().fold(0, |len, &b| (len << 8) | b as usize)
+        };
+        if rest.len() < len {
+            return None;
+        }
+        let (content, rest) = rest.split_at(len);
+        self.0 = rest;
+        Some((tag, Reader(content)))
+    }
+
+    fn expect(&mut self, tag: u8) -> Option<Reader<'a>> {
+        match self.read()? {
+            (t, content) if t == tag => Some(content),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn response(status: u8, next_update: Option<&str>) -> Vec<u8> {
+        let time = |value: &str| der(TAG_GENERALIZED_TIME, value.as_bytes());
+        let mut single = [
+            der(TAG_SEQUENCE, &[]),
+            der(status, &[]),
+            time("20300101000000Z"),
+        ]
+        .concat();
+        if let Some(next) = next_update {
+            single.extend(der(TAG_EXPLICIT_0, &time(next)));
+        }
+        let data = der(
+            TAG_SEQUENCE,
+            &[
+                der(0xa2, &der(TAG_OCTET_STRING, &[0; 20])),
+                time("20300101000000Z"),
+                der(TAG_SEQUENCE, &der(TAG_SEQUENCE, &single)),
+            ]
+            .concat(),
+        );
+        let basic = der(TAG_SEQUENCE, &data);
+        let bytes = der(
+            TAG_SEQUENCE,
+            &[
+                der(
+                    TAG_OID,
+                    &[0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x30, 0x01, 0x01],
+                ),
+                der(TAG_OCTET_STRING, &basic),
+            ]
+            .concat(),
+        );
+        der(
+            TAG_SEQUENCE,
+            &[der(TAG_ENUMERATED, &[0]), der(TAG_EXPLICIT_0, &bytes)].concat(),
+        )
+    }
+
+    #[test]
+    fn test_parse_response() {
+        let this_update = SystemTime::UNIX_EPOCH + Duration::from_secs(1_893_456_000);
+        let day = Duration::from_secs(60 * 60 * 24);
+
+        let res =
+            OcspResponse::parse(response(TAG_CERT_STATUS_GOOD, Some("20300103000000Z"))).unwrap();
+        assert_eq!(res.this_update, this_update);
+        assert_eq!(res.next_update, Some(this_update + day * 2));
+        assert!(!res.needs_refresh(this_update + day / 2));
+        assert!(res.needs_refresh(this_update + day));
+        assert!(res.is_valid(this_update + day));
+        assert!(!res.is_valid(this_update + day * 2));
+
+        let res = OcspResponse::parse(response(TAG_CERT_STATUS_GOOD, None)).unwrap();
+        assert_eq!(res.next_update, None);
+        assert!(res.needs_refresh(this_update + DEFAULT_REFRESH_INTERVAL));
+
+        // Revoked
+        assert!(OcspResponse::parse(response(0xa1, None)).is_err());
+        assert!(OcspResponse::parse(vec![TAG_SEQUENCE, 0x10]).is_err());
+    }
+
+    #[test]
+    fn test_der_length() {
+        assert_eq!(der(TAG_NULL, &[]), [TAG_NULL, 0]);
+        let long = der(TAG_OCTET_STRING, &[0; 300]);
+        assert_eq!(&long[..4], [TAG_OCTET_STRING, 0x82, 0x01, 0x2c]);
+        let mut reader = Reader(&long);
+        let content = reader.expect(TAG_OCTET_STRING).unwrap();
+        assert_eq!(content.0.len(), 300);
+        assert!(reader.0.is_empty());
+    }
+}
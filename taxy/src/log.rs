@@ -4,6 +4,7 @@ use sqlx::{sqlite::SqliteConnectOptions, ConnectOptions, SqlitePool};
 use std::{
     collections::HashMap,
     path::{Path, PathBuf},
+    sync::RwLock,
 };
 use time::OffsetDateTime;
 use tokio::runtime::Handle;
@@ -27,6 +28,40 @@ pub enum LogFormat {
     Json,
 }
 
+pub const REDACTED_PLACEHOLDER: &str = "[REDACTED]";
+
+static REDACTED_HOSTS: RwLock<Vec<String>> = RwLock::new(Vec::new());
+
+/// Replaces the host patterns redacted from access logs.
+pub fn set_redacted_hosts(patterns: &[String]) {
+    *REDACTED_HOSTS.write().unwrap() = patterns.to_vec();
+}
+
+/// Returns the placeholder in place of a host matching any of the redaction patterns.
+pub fn redact_host(host: String) -> String {
+    let patterns = REDACTED_HOSTS.read().unwrap();
+    if patterns
+        .iter()
+        .any(|pattern| matches_host(pattern.as_bytes(), host.as_bytes()))
+    {
+        REDACTED_PLACEHOLDER.to_string()
+    } else {
+        host
+    }
+}
+
+/// Case-insensitive match where `*` stands for any sequence of characters.
+fn matches_host(pattern: &[u8], host: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => host.is_empty(),
+        Some((b'*', rest)) => (0..=host.len()).any(|i| matches_host(rest, &host[i..])),
+        Some((c, rest)) => match host.split_first() {
+            Some((h, host)) => h.eq_ignore_ascii_case(c) && matches_host(rest, host),
+            None => false,
+        },
+    }
+}
+
 pub fn create_layer<S>(
     file: Option<PathBuf>,
     default_file: &str,
@@ -192,3 +227,17 @@ impl Visit for KeyValueVisitor {
             .insert(field.name().to_string(), value.to_string());
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_matches_host() {
+        assert!(matches_host(b"db.internal", b"DB.Internal"));
+        assert!(matches_host(b"*.internal", b"db.internal"));
+        assert!(matches_host(b"db-*.example.com", b"db-1.example.com"));
+        assert!(!matches_host(b"*.internal", b"internal"));
+        assert!(!matches_host(b"db.internal", b"db.internal.example.com"));
+    }
+}
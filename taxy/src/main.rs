@@ -1,8 +1,10 @@
 #![forbid(unsafe_code)]
 
 use crate::args::Command;
+use crate::config::keyring_store::{FileKeyringStore, KeyringStore};
 use crate::config::new_appinfo;
 use crate::config::storage::ConfigStorage;
+use crate::keyring::{Keyring, KeyringItem};
 use crate::log::DatabaseLayer;
 use args::StartArgs;
 use clap::Parser;
@@ -23,6 +25,9 @@ mod keyring;
 mod log;
 mod proxy;
 mod server;
+#[cfg(test)]
+mod testing;
+mod webhook;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
@@ -31,6 +36,8 @@ async fn main() -> anyhow::Result<()> {
     match args.command {
         Command::Start(args) => start(args).await?,
         Command::AddUser(args) => add_user(args).await?,
+        Command::ExportKeyring(args) => export_keyring(args).await?,
+        Command::ImportKeyring(args) => import_keyring(args).await?,
     }
 
     Ok(())
@@ -70,13 +77,17 @@ async fn start(args: StartArgs) -> anyhow::Result<()> {
     let config_dir = get_config_dir(args.config_dir)?;
     fs::create_dir_all(&config_dir)?;
 
-    let config = ConfigStorage::new(&config_dir);
+    let mut config = ConfigStorage::new(&config_dir);
+    if let Some(keyring_dir) = &args.keyring_dir {
+        fs::create_dir_all(keyring_dir)?;
+        config = config.with_keyring_store(FileKeyringStore::new(keyring_dir));
+    }
     let app_info = new_appinfo(&config_dir, &log_dir);
 
     let (event_send, _) = broadcast::channel(16);
     let (command_send, command_recv) = mpsc::channel(1);
     let (callback_send, callback_recv) = mpsc::channel(16);
-    let server_task = tokio::spawn(server::start_server(
+    let mut server_task = tokio::spawn(server::start_server(
         config,
         command_send.clone(),
         command_recv,
@@ -85,12 +96,16 @@ async fn start(args: StartArgs) -> anyhow::Result<()> {
     ));
 
     let webui_enabled = !args.no_webui;
+    let static_source = admin::StaticSource::new(args.webui_dir);
     tokio::select! {
-        r = admin::start_admin(app_info, args.webui, command_send, callback_recv, event_send.clone()), if webui_enabled => {
+        r = admin::start_admin(app_info, args.webui, command_send, callback_recv, event_send.clone(), static_source), if webui_enabled => {
             if let Err(err) = r {
                 error!("admin error: {}", err);
             }
         }
+        r = &mut server_task => {
+            return r?;
+        }
         _ =  tokio::signal::ctrl_c() => {
             info!("received ctrl-c signal");
         }
@@ -113,6 +128,42 @@ async fn add_user(args: args::AddUserArgs) -> anyhow::Result<()> {
     Ok(())
 }
 
+async fn export_keyring(args: args::KeyringArchiveArgs) -> anyhow::Result<()> {
+    let config_dir = get_config_dir(args.config_dir)?;
+    let passphrase = if let Some(passphrase) = args.passphrase {
+        passphrase
+    } else {
+        rpassword::prompt_password("passphrase?: ")?
+    };
+    let keyring_dir = args.keyring_dir.unwrap_or(config_dir);
+    let (items, failed) = FileKeyringStore::new(&keyring_dir).load().await?;
+    if !failed.is_empty() {
+        anyhow::bail!("some keyring certs failed to load: {failed:?}");
+    }
+    let archive = keyring::backup::export(&Keyring::new(items), &passphrase)?;
+    fs::write(&args.archive, archive)?;
+    Ok(())
+}
+
+/// Restores the items into the keyring directory. A running server loads them on restart.
+async fn import_keyring(args: args::KeyringArchiveArgs) -> anyhow::Result<()> {
+    let config_dir = get_config_dir(args.config_dir)?;
+    let passphrase = if let Some(passphrase) = args.passphrase {
+        passphrase
+    } else {
+        rpassword::prompt_password("passphrase?: ")?
+    };
+    let items = keyring::backup::import(&fs::read(&args.archive)?, &passphrase)?;
+    let store = FileKeyringStore::new(&args.keyring_dir.unwrap_or(config_dir));
+    for item in items {
+        match item {
+            KeyringItem::ServerCert(cert) => store.save_cert(&cert).await?,
+            KeyringItem::Acme(acme) => store.save_acme(&acme).await?,
+        }
+    }
+    Ok(())
+}
+
 fn get_config_dir(dir: Option<PathBuf>) -> anyhow::Result<PathBuf> {
     if let Some(dir) = dir {
         Ok(dir)
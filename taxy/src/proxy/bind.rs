@@ -0,0 +1,210 @@
+use super::socket;
+use multiaddr::Protocol;
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    ops::RangeInclusive,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use taxy_api::{error::Error, port::UpstreamServer};
+use tokio::net::TcpStream;
+use tracing::debug;
+
+/// Local addresses and ports used for outbound connections to an upstream.
+///
+/// Each connection takes the next address and port in turn, which widens
+/// the available 4-tuple space under heavy connection churn. Only the addresses
+/// of the family of the upstream are used, and connecting fails if there are none.
+#[derive(Debug)]
+pub struct SourceBinding {
+    addrs: Vec<IpAddr>,
+    ports: RangeInclusive<u16>,
+    cursor: AtomicUsize,
+}
+
+impl SourceBinding {
+    /// `default_addrs` are used if the upstream has no source addresses of its own.
+    pub fn new(server: &UpstreamServer, default_addrs: &[IpAddr]) -> Result<Option<Self>, Error> {
+        let addrs = if server.source_addrs.is_empty() {
+            default_addrs
+        } else {
+            &server.source_addrs[..]
+        };
+        if server.source_ports.is_none() && addrs.is_empty() {
+            return Ok(None);
+        }
+        // Upstreams with a literal address are checked here, the others once resolved.
+        let ipv4 = match server.addr.iter().next() {
+            Some(Protocol::Ip4(_)) => Some(true),
+            Some(Protocol::Ip6(_)) => Some(false),
+            _ => None,
+        };
+        if let Some(ipv4) = ipv4.filter(|_| !addrs.is_empty()) {
+            if !addrs.iter().any(|addr| addr.is_ipv4() == ipv4) {
+                return Err(Error::SourceAddressFamilyMismatch {
+                    addr: server.addr.clone(),
+                });
+            }
+        }
+        let ports = match server.source_ports {
+            Some(range) if range.start > range.end || range.start == 0 => {
+                return Err(Error::InvalidSourcePortRange {
+                    start: range.start,
+                    end: range.end,
+                });
+            }
+            Some(range) => range.start..=range.end,
+            None => 0..=0,
+        };
+        Ok(Some(Self {
+            addrs: addrs.to_vec(),
+            ports,
+            cursor: AtomicUsize::new(0),
+        }))
+    }
+
+    pub async fn connect(&self, remote: SocketAddr, fast_open: bool) -> io::Result<TcpStream> {
+        let addrs = self
+            .addrs
+            .iter()
+            .copied()
+            .filter(|addr| addr.is_ipv4() == remote.is_ipv4())
+            .collect::<Vec<_>>();
+        if addrs.is_empty() && !self.addrs.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrNotAvailable,
+                SourceFamilyMismatch(remote),
+            ));
+        }
+        let addrs = if addrs.is_empty() {
+            vec![if remote.is_ipv4() {
+                IpAddr::V4(Ipv4Addr::UNSPECIFIED)
+            } else {
+                IpAddr::V6(Ipv6Addr::UNSPECIFIED)
+            }]
+        } else {
+            addrs
+        };
+
+        let port_count = self.ports.len();
+        let attempts = addrs.len() * port_count;
+        let mut last_err = None;
+        for _ in 0..attempts {
+            let next = self.cursor.fetch_add(1, Ordering::Relaxed);
+            let addr = addrs[next % addrs.len()];
+            let port = self.ports.start() + ((next / addrs.len()) % port_count) as u16;
+            let local = SocketAddr::new(addr, port);
+
+            let sock = socket::new_tcp_socket(remote, fast_open)?;
+            let result = match sock.bind(local) {
+                Ok(()) => sock.connect(remote).await,
+                Err(err) => Err(err),
+            };
+            match result {
+                Ok(stream) => return Ok(stream),
+                Err(err)
+                    if matches!(
+                        err.kind(),
+                        io::ErrorKind::AddrInUse | io::ErrorKind::AddrNotAvailable
+                    ) =>
+                {
+                    debug!(%local, "source address unavailable: {err}");
+                    last_err = Some(err);
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        Err(last_err.unwrap_or_else(|| io::ErrorKind::AddrNotAvailable.into()))
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("no source address matches the address family of {0}")]
+struct SourceFamilyMismatch(SocketAddr);
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use taxy_api::port::PortRange;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_source_port_range() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let remote = listener.local_addr().unwrap();
+
+        // Find a free range by binding its first port ourselves, which also
+        // exercises the retry on ports that are already in use.
+        let occupied = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let start = occupied.local_addr().unwrap().port();
+        let server = UpstreamServer {
+            source_ports: Some(PortRange {
+                start,
+                end: start.saturating_add(3),
+            }),
+            source_addrs: vec![IpAddr::V4(Ipv4Addr::LOCALHOST)],
+            ..UpstreamServer::new("/ip4/127.0.0.1/tcp/8080".parse().unwrap())
+        };
+        let binding = SourceBinding::new(&server, &[]).unwrap().unwrap();
+
+        let mut streams = Vec::new();
+        for _ in 0..2 {
+            let stream = binding.connect(remote, false).await.unwrap();
+            let (accepted, _) = listener.accept().await.unwrap();
+            let port = accepted.peer_addr().unwrap().port();
+            assert!(port > start && port <= start.saturating_add(3));
+            streams.push(stream);
+        }
+    }
+
+    #[test]
+    fn test_invalid_range() {
+        let server = UpstreamServer {
+            source_ports: Some(PortRange {
+                start: 2000,
+                end: 1000,
+            }),
+            ..UpstreamServer::new("/ip4/127.0.0.1/tcp/8080".parse().unwrap())
+        };
+        assert!(SourceBinding::new(&server, &[]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_source_family_mismatch() {
+        let server = |addr: &str, source_addrs: Vec<IpAddr>| UpstreamServer {
+            source_addrs,
+            ..UpstreamServer::new(addr.parse().unwrap())
+        };
+        let v4 = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let v6 = IpAddr::V6(Ipv6Addr::LOCALHOST);
+
+        assert!(matches!(
+            SourceBinding::new(&server("/ip6/::1/tcp/8080", vec![v4]), &[]),
+            Err(Error::SourceAddressFamilyMismatch { .. })
+        ));
+        // The addresses of the port apply to upstreams without their own.
+        assert!(matches!(
+            SourceBinding::new(&server("/ip6/::1/tcp/8080", vec![]), &[v4]),
+            Err(Error::SourceAddressFamilyMismatch { .. })
+        ));
+        assert!(
+            SourceBinding::new(&server("/ip6/::1/tcp/8080", vec![v6]), &[v4])
+                .unwrap()
+                .is_some()
+        );
+
+        // Names are checked once resolved.
+        let binding = SourceBinding::new(&server("/dns/localhost/tcp/8080", vec![v6]), &[])
+            .unwrap()
+            .unwrap();
+        let err = binding
+            .connect("127.0.0.1:8080".parse().unwrap(), false)
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AddrNotAvailable);
+        assert_eq!(
+            err.to_string(),
+            "no source address matches the address family of 127.0.0.1:8080"
+        );
+    }
+}
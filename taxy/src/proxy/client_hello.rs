@@ -0,0 +1,344 @@
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+use taxy_api::port::PortOptions;
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
+
+const DEFAULT_CLIENT_HELLO_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_CLIENT_HELLO_MAX_SIZE: usize = 16 * 1024;
+
+const RECORD_HEADER_LEN: usize = 5;
+const HANDSHAKE_HEADER_LEN: usize = 4;
+const CONTENT_TYPE_HANDSHAKE: u8 = 0x16;
+const HANDSHAKE_TYPE_CLIENT_HELLO: u8 = 0x01;
+const EXTENSION_SERVER_NAME: u16 = 0x0000;
+const SERVER_NAME_TYPE_HOST_NAME: u8 = 0x00;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientHelloLimits {
+    pub timeout: Duration,
+    pub max_size: usize,
+}
+
+impl Default for ClientHelloLimits {
+    fn default() -> Self {
+        Self {
+            timeout: DEFAULT_CLIENT_HELLO_TIMEOUT,
+            max_size: DEFAULT_CLIENT_HELLO_MAX_SIZE,
+        }
+    }
+}
+
+impl From<&PortOptions> for ClientHelloLimits {
+    fn from(opts: &PortOptions) -> Self {
+        let default = Self::default();
+        Self {
+            timeout: opts.tls_client_hello_timeout.unwrap_or(default.timeout),
+            max_size: opts.tls_client_hello_max_size.unwrap_or(default.max_size),
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum ClientHelloError {
+    #[error("client hello timed out after {0:?}")]
+    Timeout(Duration),
+
+    #[error("client hello exceeds {0} bytes")]
+    TooLarge(usize),
+
+    #[error("not a tls client hello")]
+    NotClientHello,
+
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+/// Reads the TLS records carrying the ClientHello, which may be fragmented
+/// across several records, within the given size and time bounds.
+///
+/// The returned bytes must be replayed to the TLS acceptor, e.g. with [`PrefixedStream`].
+pub async fn read_client_hello<S>(
+    stream: &mut S,
+    limits: &ClientHelloLimits,
+) -> Result<Vec<u8>, ClientHelloError>
+where
+    S: AsyncRead + Unpin,
+{
+    tokio::time::timeout(
+        limits.timeout,
+        read_client_hello_records(stream, limits.max_size),
+    )
+    .await
+    .map_err(|_| ClientHelloError::Timeout(limits.timeout))?
+}
+
+async fn read_client_hello_records<S>(
+    stream: &mut S,
+    max_size: usize,
+) -> Result<Vec<u8>, ClientHelloError>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut raw = Vec::new();
+    let mut handshake = Vec::new();
+    loop {
+        let mut header = [0; RECORD_HEADER_LEN];
+        stream.read_exact(&mut header).await?;
+        if header[0] != CONTENT_TYPE_HANDSHAKE {
+            return Err(ClientHelloError::NotClientHello);
+        }
+        let len = u16::from_be_bytes([header[3], header[4]]) as usize;
+        if raw.len() + RECORD_HEADER_LEN + len > max_size {
+            return Err(ClientHelloError::TooLarge(max_size));
+        }
+
+        let mut payload = vec![0; len];
+        stream.read_exact(&mut payload).await?;
+        raw.extend_from_slice(&header);
+        raw.extend_from_slice(&payload);
+        handshake.extend_from_slice(&payload);
+
+        if handshake.len() >= HANDSHAKE_HEADER_LEN {
+            if handshake[0] != HANDSHAKE_TYPE_CLIENT_HELLO {
+                return Err(ClientHelloError::NotClientHello);
+            }
+            let body_len =
+                u32::from_be_bytes([0, handshake[1], handshake[2], handshake[3]]) as usize;
+            if HANDSHAKE_HEADER_LEN + body_len > max_size {
+                return Err(ClientHelloError::TooLarge(max_size));
+            }
+            if handshake.len() >= HANDSHAKE_HEADER_LEN + body_len {
+                return Ok(raw);
+            }
+        }
+    }
+}
+
+/// Extracts the SNI host name from the records returned by [`read_client_hello`],
+/// without validating the rest of the handshake.
+pub fn server_name(records: &[u8]) -> Option<String> {
+    let mut handshake = Vec::new();
+    let mut records = records;
+    while records.len() >= RECORD_HEADER_LEN {
+        let len = u16::from_be_bytes([records[3], records[4]]) as usize;
+        let end = (RECORD_HEADER_LEN + len).min(records.len());
+        handshake.extend_from_slice(&records[RECORD_HEADER_LEN..end]);
+        records = &records[end..];
+    }
+
+    let mut body = Reader(handshake.get(HANDSHAKE_HEADER_LEN..)?);
+    body.skip(2 + 32)?; // legacy_version, random
+    let len = body.u8()? as usize;
+    body.skip(len)?; // legacy_session_id
+    let len = body.u16()? as usize;
+    body.skip(len)?; // cipher_suites
+    let len = body.u8()? as usize;
+    body.skip(len)?; // legacy_compression_methods
+    let len = body.u16()? as usize;
+    let mut extensions = Reader(body.take(len)?);
+    while !extensions.0.is_empty() {
+        let ty = extensions.u16()?;
+        let len = extensions.u16()? as usize;
+        let data = extensions.take(len)?;
+        if ty != EXTENSION_SERVER_NAME {
+            continue;
+        }
+        let mut list = Reader(data);
+        let len = list.u16()? as usize;
+        let mut list = Reader(list.take(len)?);
+        while !list.0.is_empty() {
+            let ty = list.u8()?;
+            let len = list.u16()? as usize;
+            let name = list.take(len)?;
+            if ty == SERVER_NAME_TYPE_HOST_NAME {
+                return std::str::from_utf8(name).ok().map(str::to_string);
+            }
+        }
+        return None;
+    }
+    None
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.0.len() < len {
+            return None;
+        }
+        let (head, tail) = self.0.split_at(len);
+        self.0 = tail;
+        Some(head)
+    }
+
+    fn skip(&mut self, len: usize) -> Option<()> {
+        self.take(len).map(|_| ())
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+}
+
+/// A stream which yields the given prefix before reading from the inner stream.
+#[derive(Debug)]
+pub struct PrefixedStream<S> {
+    prefix: Vec<u8>,
+    pos: usize,
+    inner: S,
+}
+
+impl<S> PrefixedStream<S> {
+    pub fn new(prefix: Vec<u8>, inner: S) -> Self {
+        Self {
+            prefix,
+            pos: 0,
+            inner,
+        }
+    }
+}
+
+impl<S> AsyncRead for PrefixedStream<S>
+where
+    S: AsyncRead + Unpin,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if self.pos < self.prefix.len() {
+            let len = buf.remaining().min(self.prefix.len() - self.pos);
+            let pos = self.pos;
+            buf.put_slice(&self.prefix[pos..pos + len]);
+            self.pos += len;
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S> AsyncWrite for PrefixedStream<S>
+where
+    S: AsyncWrite + Unpin,
+{
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::{sync::Arc, time::Instant};
+    use tokio::io::AsyncWriteExt;
+
+    fn record(payload: &[u8]) -> Vec<u8> {
+        let mut record = vec![CONTENT_TYPE_HANDSHAKE, 0x03, 0x01];
+        record.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+        record.extend_from_slice(payload);
+        record
+    }
+
+    #[tokio::test]
+    async fn test_fragmented_client_hello() {
+        let handshake = [HANDSHAKE_TYPE_CLIENT_HELLO, 0, 0, 4, 1, 2, 3, 4];
+        let mut data = record(&handshake[..3]);
+        data.extend(record(&handshake[3..]));
+
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        client.write_all(&data).await.unwrap();
+        client.write_all(b"rest").await.unwrap();
+
+        let limits = ClientHelloLimits::default();
+        let hello = read_client_hello(&mut server, &limits).await.unwrap();
+        assert_eq!(hello, data);
+
+        let mut stream = PrefixedStream::new(hello, server);
+        let mut buf = vec![0; data.len() + 4];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf[..data.len()], &data[..]);
+        assert_eq!(&buf[data.len()..], b"rest");
+    }
+
+    fn client_hello(sni: Option<&str>) -> Vec<u8> {
+        use tokio_rustls::rustls::{ClientConfig, ClientConnection, RootCertStore};
+        let mut config = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(RootCertStore::empty())
+            .with_no_client_auth();
+        config.enable_sni = sni.is_some();
+        let name = sni.unwrap_or("localhost").try_into().unwrap();
+        let mut conn = ClientConnection::new(Arc::new(config), name).unwrap();
+        let mut hello = Vec::new();
+        conn.write_tls(&mut hello).unwrap();
+        hello
+    }
+
+    #[test]
+    fn test_server_name() {
+        let hello = client_hello(Some("a.example.com"));
+        assert_eq!(server_name(&hello).as_deref(), Some("a.example.com"));
+
+        // Splits the handshake over two records.
+        let payload = &hello[RECORD_HEADER_LEN..];
+        let (first, second) = payload.split_at(payload.len() / 2);
+        let fragmented = [record(first), record(second)].concat();
+        assert_eq!(server_name(&fragmented).as_deref(), Some("a.example.com"));
+
+        assert_eq!(server_name(&client_hello(None)), None);
+        assert_eq!(server_name(&hello[..40]), None);
+    }
+
+    #[tokio::test]
+    async fn test_incomplete_client_hello() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        let data = record(&[HANDSHAKE_TYPE_CLIENT_HELLO, 0, 0, 100]);
+        client.write_all(&data).await.unwrap();
+
+        let limits = ClientHelloLimits {
+            timeout: Duration::from_millis(100),
+            ..Default::default()
+        };
+        let start = Instant::now();
+        let result = read_client_hello(&mut server, &limits).await;
+        assert!(matches!(result, Err(ClientHelloError::Timeout(_))));
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn test_oversized_client_hello() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        let mut data = vec![CONTENT_TYPE_HANDSHAKE, 0x03, 0x01];
+        data.extend_from_slice(&1024u16.to_be_bytes());
+        client.write_all(&data).await.unwrap();
+
+        let limits = ClientHelloLimits {
+            max_size: 512,
+            ..Default::default()
+        };
+        let result = read_client_hello(&mut server, &limits).await;
+        assert!(matches!(result, Err(ClientHelloError::TooLarge(512))));
+    }
+}
@@ -0,0 +1,206 @@
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress, Status};
+use std::{
+    io,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// ALPN protocol negotiated by both ends of a TLS tunnel to deflate the tunneled bytes.
+pub const ALPN_DEFLATE: &[u8] = b"taxy-deflate";
+
+const BUF_SIZE: usize = 8 * 1024;
+
+/// A stream which deflates the written bytes and inflates the read bytes.
+///
+/// Each write is sync-flushed so that interactive traffic is not held back
+/// waiting for a full deflate block.
+#[derive(Debug)]
+pub struct DeflateStream<S> {
+    inner: S,
+    compress: Compress,
+    decompress: Decompress,
+    input: Vec<u8>,
+    input_pos: usize,
+    output: Vec<u8>,
+    output_pos: usize,
+}
+
+impl<S> DeflateStream<S> {
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner,
+            compress: Compress::new(Compression::default(), false),
+            decompress: Decompress::new(false),
+            input: Vec::with_capacity(BUF_SIZE),
+            input_pos: 0,
+            output: Vec::with_capacity(BUF_SIZE),
+            output_pos: 0,
+        }
+    }
+}
+
+impl<S> DeflateStream<S>
+where
+    S: AsyncWrite + Unpin,
+{
+    /// Writes out the compressed bytes of the previous writes.
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.output_pos < self.output.len() {
+            let len =
+                ready!(Pin::new(&mut self.inner).poll_write(cx, &self.output[self.output_pos..]))?;
+            if len == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.output_pos += len;
+        }
+        self.output.clear();
+        self.output_pos = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S> AsyncRead for DeflateStream<S>
+where
+    S: AsyncRead + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        while buf.remaining() > 0 {
+            let total_in = this.decompress.total_in();
+            let total_out = this.decompress.total_out();
+            let status = this
+                .decompress
+                .decompress(
+                    &this.input[this.input_pos..],
+                    buf.initialize_unfilled(),
+                    FlushDecompress::Sync,
+                )
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+            this.input_pos += (this.decompress.total_in() - total_in) as usize;
+            let len = (this.decompress.total_out() - total_out) as usize;
+            if len > 0 || status == Status::StreamEnd {
+                buf.advance(len);
+                return Poll::Ready(Ok(()));
+            }
+
+            // Nothing could be inflated from the buffered input, so read more.
+            this.input.drain(..this.input_pos);
+            this.input_pos = 0;
+            let filled = this.input.len();
+            this.input.resize(filled + BUF_SIZE, 0);
+            let mut read_buf = ReadBuf::new(&mut this.input[filled..]);
+            let result = Pin::new(&mut this.inner).poll_read(cx, &mut read_buf);
+            let read = read_buf.filled().len();
+            this.input.truncate(filled + read);
+            ready!(result)?;
+            if read == 0 {
+                return Poll::Ready(Ok(()));
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S> AsyncWrite for DeflateStream<S>
+where
+    S: AsyncWrite + Unpin,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        let mut consumed = 0;
+        loop {
+            this.output.reserve(buf.len() - consumed + 64);
+            let total_in = this.compress.total_in();
+            this.compress
+                .compress_vec(&buf[consumed..], &mut this.output, FlushCompress::Sync)
+                .map_err(io::Error::other)?;
+            consumed += (this.compress.total_in() - total_in) as usize;
+            // The flush is complete once the compressor leaves spare room in the output.
+            if consumed == buf.len() && this.output.len() < this.output.capacity() {
+                break;
+            }
+        }
+
+        if let Poll::Ready(Err(err)) = this.poll_drain(cx) {
+            return Poll::Ready(Err(err));
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_round_trip() {
+        let (client, server) = tokio::io::duplex(1024);
+        let mut client = DeflateStream::new(client);
+        let mut server = DeflateStream::new(server);
+
+        // Small writes are delivered without waiting for more data.
+        client.write_all(b"ping").await.unwrap();
+        client.flush().await.unwrap();
+        let mut buf = [0; 4];
+        server.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+
+        let data = b"taxy".repeat(64 * 1024);
+        let expected = data.clone();
+        let writer = tokio::spawn(async move {
+            client.write_all(&data).await.unwrap();
+            client.shutdown().await.unwrap();
+        });
+        let mut received = Vec::new();
+        server.read_to_end(&mut received).await.unwrap();
+        writer.await.unwrap();
+        assert_eq!(received, expected);
+    }
+
+    #[tokio::test]
+    async fn test_compressed_size() {
+        let (client, mut server) = tokio::io::duplex(64 * 1024);
+        let mut client = DeflateStream::new(client);
+        client.write_all(&b"taxy".repeat(16 * 1024)).await.unwrap();
+        client.shutdown().await.unwrap();
+        drop(client);
+
+        let mut wire = Vec::new();
+        server.read_to_end(&mut wire).await.unwrap();
+        assert!(wire.len() < 1024);
+
+        let mut inflated = Vec::new();
+        DeflateStream::new(wire.as_slice())
+            .read_to_end(&mut inflated)
+            .await
+            .unwrap();
+        assert_eq!(inflated, b"taxy".repeat(16 * 1024));
+    }
+}
@@ -0,0 +1,126 @@
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Caps the concurrent connections of a port.
+///
+/// The limit can be resized while connections hold permits. When shrinking below the
+/// number of permits in use, the surplus is retired as those permits are released.
+#[derive(Debug)]
+pub struct ConnectionLimit {
+    limit: usize,
+    semaphore: Arc<Semaphore>,
+    surplus: Arc<AtomicUsize>,
+}
+
+impl ConnectionLimit {
+    pub fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            semaphore: Arc::new(Semaphore::new(limit)),
+            surplus: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    /// Reserves a connection slot which is released when the permit is dropped.
+    pub fn try_acquire(&self) -> Option<ConnectionLimitPermit> {
+        let permit = self.semaphore.clone().try_acquire_owned().ok()?;
+        Some(ConnectionLimitPermit {
+            permit: Some(permit),
+            surplus: self.surplus.clone(),
+        })
+    }
+
+    pub fn in_use(&self) -> usize {
+        (self.limit + self.surplus.load(Ordering::Acquire))
+            .saturating_sub(self.semaphore.available_permits())
+    }
+
+    pub fn resize(&mut self, limit: usize) {
+        if limit > self.limit {
+            let added = limit - self.limit;
+            let retired = self
+                .surplus
+                .fetch_update(Ordering::AcqRel, Ordering::Acquire, |surplus| {
+                    Some(surplus.saturating_sub(added))
+                })
+                .unwrap_or_default()
+                .min(added);
+            self.semaphore.add_permits(added - retired);
+        } else {
+            let removed = self.limit - limit;
+            let mut taken = 0;
+            while taken < removed {
+                match self.semaphore.try_acquire() {
+                    Ok(permit) => permit.forget(),
+                    Err(_) => break,
+                }
+                taken += 1;
+            }
+            self.surplus.fetch_add(removed - taken, Ordering::AcqRel);
+        }
+        self.limit = limit;
+    }
+}
+
+#[derive(Debug)]
+pub struct ConnectionLimitPermit {
+    permit: Option<OwnedSemaphorePermit>,
+    surplus: Arc<AtomicUsize>,
+}
+
+impl Drop for ConnectionLimitPermit {
+    fn drop(&mut self) {
+        let retire = self
+            .surplus
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |surplus| {
+                surplus.checked_sub(1)
+            })
+            .is_ok();
+        if let (true, Some(permit)) = (retire, self.permit.take()) {
+            permit.forget();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_resize() {
+        let mut limit = ConnectionLimit::new(3);
+        let permits = (0..3)
+            .map(|_| limit.try_acquire().unwrap())
+            .collect::<Vec<_>>();
+        assert!(limit.try_acquire().is_none());
+        assert_eq!(limit.in_use(), 3);
+
+        limit.resize(1);
+        assert_eq!(limit.in_use(), 3);
+        let mut permits = permits.into_iter();
+        drop(permits.next());
+        drop(permits.next());
+        assert_eq!(limit.in_use(), 1);
+        assert!(limit.try_acquire().is_none());
+
+        limit.resize(2);
+        let second = limit.try_acquire().unwrap();
+        assert!(limit.try_acquire().is_none());
+        assert_eq!(limit.in_use(), 2);
+
+        drop(permits);
+        drop(second);
+        assert_eq!(limit.in_use(), 0);
+        let _permits = (0..2)
+            .map(|_| limit.try_acquire().unwrap())
+            .collect::<Vec<_>>();
+        assert!(limit.try_acquire().is_none());
+    }
+}
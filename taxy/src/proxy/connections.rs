@@ -0,0 +1,563 @@
+use super::{rate_limit::RetryLimiter, rdns::ReverseDns};
+use std::{
+    collections::{BTreeMap, VecDeque},
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, AtomicU8, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant, SystemTime},
+};
+use taxy_api::port::{
+    ClosedConnectionInfo, ConnectionInfo, ConnectionOutcome, ConnectionStats, ProbeDetection,
+};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::watch;
+use tracing::debug;
+
+pub const DEFAULT_RECENT_CONNECTIONS: usize = 32;
+
+/// Keeps track of the connections currently being proxied by a port.
+#[derive(Debug, Clone)]
+pub struct ConnectionRegistry {
+    inner: Arc<Mutex<Registry>>,
+    /// The grace period of idle connections once shutting down.
+    shutdown: Arc<watch::Sender<Option<Duration>>>,
+    /// Bytes transferred by all connections, counted as they are transferred.
+    bytes: Arc<ByteCounters>,
+}
+
+#[derive(Debug, Default)]
+struct ByteCounters {
+    received: AtomicU64,
+    sent: AtomicU64,
+}
+
+impl Default for ConnectionRegistry {
+    fn default() -> Self {
+        Self {
+            inner: Default::default(),
+            shutdown: Arc::new(watch::channel(None).0),
+            bytes: Default::default(),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Registry {
+    next_id: u64,
+    total: u64,
+    peak: usize,
+    last_error: Option<String>,
+    connections: BTreeMap<u64, ConnectionInfo>,
+    recent: VecDeque<ClosedConnectionInfo>,
+    recent_capacity: usize,
+    reverse_dns: Option<ReverseDns>,
+    probe_detection: Option<ProbeDetection>,
+    probes: u64,
+    retry_limiter: Option<RetryLimiter>,
+}
+
+impl Default for Registry {
+    fn default() -> Self {
+        Self {
+            next_id: 0,
+            total: 0,
+            peak: 0,
+            last_error: None,
+            connections: BTreeMap::new(),
+            recent: VecDeque::new(),
+            recent_capacity: DEFAULT_RECENT_CONNECTIONS,
+            reverse_dns: None,
+            probe_detection: None,
+            probes: 0,
+            retry_limiter: None,
+        }
+    }
+}
+
+impl ConnectionRegistry {
+    pub fn with_reverse_dns(reverse_dns: Option<ReverseDns>) -> Self {
+        let registry = Self::default();
+        registry.set_reverse_dns(reverse_dns);
+        registry
+    }
+
+    pub fn reverse_dns(&self) -> Option<ReverseDns> {
+        self.inner.lock().unwrap().reverse_dns.clone()
+    }
+
+    pub fn set_reverse_dns(&self, reverse_dns: Option<ReverseDns>) {
+        self.inner.lock().unwrap().reverse_dns = reverse_dns;
+    }
+
+    pub fn recent_capacity(&self) -> usize {
+        self.inner.lock().unwrap().recent_capacity
+    }
+
+    pub fn set_recent_capacity(&self, capacity: usize) {
+        let mut registry = self.inner.lock().unwrap();
+        registry.recent_capacity = capacity;
+        let excess = registry.recent.len().saturating_sub(capacity);
+        registry.recent.drain(..excess);
+    }
+
+    pub fn probe_detection(&self) -> Option<ProbeDetection> {
+        self.inner.lock().unwrap().probe_detection
+    }
+
+    pub fn set_probe_detection(&self, probe_detection: Option<ProbeDetection>) {
+        self.inner.lock().unwrap().probe_detection = probe_detection;
+    }
+
+    /// Replaces the retry budget of the port, keeping the tokens left if the budget is unchanged.
+    pub fn set_retry_limiter(&self, limiter: Option<RetryLimiter>) {
+        let mut registry = self.inner.lock().unwrap();
+        let unchanged = match (&registry.retry_limiter, &limiter) {
+            (Some(current), Some(new)) => current.budget() == new.budget(),
+            _ => false,
+        };
+        if !unchanged {
+            registry.retry_limiter = limiter;
+        }
+    }
+
+    pub fn take_retry_limiter(&self) -> Option<RetryLimiter> {
+        self.inner.lock().unwrap().retry_limiter.take()
+    }
+
+    /// Takes a token from the retry budget of the port, if it has one.
+    /// Returns `false` if the budget is exhausted.
+    pub fn acquire_retry(&self) -> bool {
+        self.inner
+            .lock()
+            .unwrap()
+            .retry_limiter
+            .as_mut()
+            .is_none_or(|limiter| limiter.acquire(tokio::time::Instant::now()))
+    }
+
+    /// Closes the connections once they have transferred nothing for `grace`.
+    pub fn shutdown(&self, grace: Duration) {
+        self.shutdown.send_replace(Some(grace));
+    }
+
+    /// Registers a connection until the returned handle is dropped.
+    pub fn register(&self, remote: SocketAddr, local: SocketAddr) -> ConnectionHandle {
+        let mut registry = self.inner.lock().unwrap();
+        let id = registry.next_id;
+        registry.next_id += 1;
+        registry.total += 1;
+        let remote_name = registry
+            .reverse_dns
+            .as_ref()
+            .and_then(|rdns| rdns.lookup(remote.ip()));
+        registry.connections.insert(
+            id,
+            ConnectionInfo {
+                remote: remote.to_string(),
+                remote_name,
+                local: local.to_string(),
+                started_at: SystemTime::now(),
+                served_cert: None,
+                client_cert: None,
+            },
+        );
+        registry.peak = registry.peak.max(registry.connections.len());
+        ConnectionHandle {
+            id,
+            registry: self.clone(),
+            traffic: Default::default(),
+            outcome: None,
+        }
+    }
+
+    pub fn snapshot(&self) -> Vec<ConnectionInfo> {
+        let registry = self.inner.lock().unwrap();
+        registry.connections.values().cloned().collect()
+    }
+
+    pub fn active_count(&self) -> usize {
+        self.inner.lock().unwrap().connections.len()
+    }
+
+    /// Returns the recently closed connections, newest first.
+    pub fn recent(&self) -> Vec<ClosedConnectionInfo> {
+        let registry = self.inner.lock().unwrap();
+        registry.recent.iter().rev().cloned().collect()
+    }
+
+    /// Returns the number of connections registered since the port was created.
+    pub fn total_count(&self) -> u64 {
+        self.inner.lock().unwrap().total
+    }
+
+    /// Returns the number of closed connections classified as probes since the port was created.
+    pub fn probe_count(&self) -> u64 {
+        self.inner.lock().unwrap().probes
+    }
+
+    /// Records an error which has failed a connection.
+    pub fn record_error(&self, err: &anyhow::Error) {
+        self.inner.lock().unwrap().last_error = Some(err.to_string());
+    }
+
+    /// Zeroes the counters, except the peak, and returns their values before the reset.
+    ///
+    /// Bytes transferred concurrently are counted either before or after the reset, never both.
+    pub fn reset_stats(&self) -> ConnectionStats {
+        let mut registry = self.inner.lock().unwrap();
+        let stats = ConnectionStats {
+            active: registry.connections.len(),
+            peak: registry.peak,
+            total: registry.total,
+            bytes_received: self.bytes.received.swap(0, Ordering::Relaxed),
+            bytes_sent: self.bytes.sent.swap(0, Ordering::Relaxed),
+            last_error: registry.last_error.take(),
+        };
+        registry.total = 0;
+        registry.probes = 0;
+        stats
+    }
+
+    pub fn stats(&self) -> ConnectionStats {
+        let registry = self.inner.lock().unwrap();
+        ConnectionStats {
+            active: registry.connections.len(),
+            peak: registry.peak,
+            total: registry.total,
+            bytes_received: self.bytes.received.load(Ordering::Relaxed),
+            bytes_sent: self.bytes.sent.load(Ordering::Relaxed),
+            last_error: registry.last_error.clone(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ConnectionHandle {
+    id: u64,
+    registry: ConnectionRegistry,
+    traffic: Arc<Traffic>,
+    outcome: Option<ConnectionOutcome>,
+}
+
+impl ConnectionHandle {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Wraps a stream to count its bytes and to detect which side closes first.
+    pub fn track<S>(&self, stream: S, side: Side) -> TrackedStream<S> {
+        TrackedStream {
+            inner: stream,
+            traffic: self.traffic.clone(),
+            bytes: self.registry.bytes.clone(),
+            side,
+        }
+    }
+
+    /// Returns how long no bytes have been transferred on the client side.
+    pub fn idle_for(&self) -> Duration {
+        let last_active =
+            Duration::from_micros(self.traffic.last_active_us.load(Ordering::Relaxed));
+        self.traffic
+            .started_at
+            .elapsed()
+            .saturating_sub(last_active)
+    }
+
+    /// Returns once the registry is shutting down and the connection
+    /// has transferred nothing for the grace period.
+    pub async fn shutdown_idle(&self) {
+        let mut shutdown = self.registry.shutdown.subscribe();
+        let grace = loop {
+            if let Some(grace) = *shutdown.borrow_and_update() {
+                break grace;
+            }
+            if shutdown.changed().await.is_err() {
+                return std::future::pending().await;
+            }
+        };
+        loop {
+            let idle = self.idle_for();
+            if idle >= grace {
+                return;
+            }
+            tokio::time::sleep(grace - idle).await;
+        }
+    }
+
+    /// Returns the side which has closed first, defaulting to the client.
+    pub fn closed_by(&self) -> ConnectionOutcome {
+        if self.traffic.first_eof.load(Ordering::Relaxed) == Side::Upstream as u8 {
+            ConnectionOutcome::UpstreamClosed
+        } else {
+            ConnectionOutcome::ClientClosed
+        }
+    }
+
+    /// Takes a token from the retry budget of the port. See [`ConnectionRegistry::acquire_retry`].
+    pub fn acquire_retry(&self) -> bool {
+        self.registry.acquire_retry()
+    }
+
+    /// Returns `true` if the upstream has reset the connection before anything was sent to the client.
+    pub fn upstream_reset_early(&self) -> bool {
+        self.traffic.first_reset.load(Ordering::Relaxed) == Side::Upstream as u8
+            && self.traffic.sent.load(Ordering::Relaxed) == 0
+    }
+
+    /// Sets the outcome reported once the handle is dropped.
+    /// Connections dropped without an outcome are reported as errors.
+    pub fn set_outcome(&mut self, outcome: ConnectionOutcome) {
+        self.outcome = Some(outcome);
+    }
+
+    pub fn remote_name(&self) -> Option<String> {
+        let registry = self.registry.inner.lock().unwrap();
+        registry
+            .connections
+            .get(&self.id)
+            .and_then(|info| info.remote_name.clone())
+    }
+
+    pub fn set_served_cert(&self, cert: &str) {
+        let mut registry = self.registry.inner.lock().unwrap();
+        if let Some(info) = registry.connections.get_mut(&self.id) {
+            info.served_cert = Some(cert.to_string());
+        }
+    }
+
+    pub fn set_client_cert(&self, subject: &str) {
+        let mut registry = self.registry.inner.lock().unwrap();
+        if let Some(info) = registry.connections.get_mut(&self.id) {
+            info.client_cert = Some(subject.to_string());
+        }
+    }
+}
+
+impl Drop for ConnectionHandle {
+    fn drop(&mut self) {
+        let mut registry = self.registry.inner.lock().unwrap();
+        let Some(connection) = registry.connections.remove(&self.id) else {
+            return;
+        };
+        let bytes_received = self.traffic.received.load(Ordering::Relaxed);
+        let bytes_sent = self.traffic.sent.load(Ordering::Relaxed);
+        let probe = registry.probe_detection.is_some_and(|detection| {
+            detection.is_probe(
+                bytes_received + bytes_sent,
+                self.traffic.started_at.elapsed(),
+            )
+        });
+        if probe {
+            registry.probes += 1;
+            debug!(
+                remote = %connection.remote,
+                bytes_received, bytes_sent, "probe connection closed"
+            );
+        }
+        if registry.recent_capacity == 0 {
+            return;
+        }
+        if registry.recent.len() >= registry.recent_capacity {
+            registry.recent.pop_front();
+        }
+        registry.recent.push_back(ClosedConnectionInfo {
+            connection,
+            closed_at: SystemTime::now(),
+            outcome: self.outcome.unwrap_or(ConnectionOutcome::Error),
+            bytes_received,
+            bytes_sent,
+            probe,
+        });
+    }
+}
+
+#[derive(Debug)]
+struct Traffic {
+    received: AtomicU64,
+    sent: AtomicU64,
+    first_eof: AtomicU8,
+    first_reset: AtomicU8,
+    started_at: Instant,
+    last_active_us: AtomicU64,
+}
+
+impl Default for Traffic {
+    fn default() -> Self {
+        Self {
+            received: Default::default(),
+            sent: Default::default(),
+            first_eof: Default::default(),
+            first_reset: Default::default(),
+            started_at: Instant::now(),
+            last_active_us: Default::default(),
+        }
+    }
+}
+
+impl Traffic {
+    fn touch(&self) {
+        let elapsed = self.started_at.elapsed().as_micros() as u64;
+        self.last_active_us.fetch_max(elapsed, Ordering::Relaxed);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    Client = 1,
+    Upstream = 2,
+}
+
+/// A stream tracked by a `ConnectionHandle`. Only the bytes of the client side are counted.
+pub struct TrackedStream<S> {
+    inner: S,
+    traffic: Arc<Traffic>,
+    bytes: Arc<ByteCounters>,
+    side: Side,
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for TrackedStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Err(err)) = &result {
+            if matches!(
+                err.kind(),
+                io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionAborted
+            ) {
+                let _ = self.traffic.first_reset.compare_exchange(
+                    0,
+                    self.side as u8,
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                );
+            }
+        }
+        if let Poll::Ready(Ok(())) = result {
+            let len = buf.filled().len() - filled;
+            if self.side == Side::Client && len > 0 {
+                self.traffic
+                    .received
+                    .fetch_add(len as u64, Ordering::Relaxed);
+                self.bytes.received.fetch_add(len as u64, Ordering::Relaxed);
+                self.traffic.touch();
+            }
+            if len == 0 && buf.remaining() > 0 {
+                let _ = self.traffic.first_eof.compare_exchange(
+                    0,
+                    self.side as u8,
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                );
+            }
+        }
+        result
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for TrackedStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let result = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let (Poll::Ready(Ok(len)), Side::Client) = (&result, self.side) {
+            self.traffic.sent.fetch_add(*len as u64, Ordering::Relaxed);
+            self.bytes.sent.fetch_add(*len as u64, Ordering::Relaxed);
+            self.traffic.touch();
+        }
+        result
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::proxy::rdns::test::StubResolver;
+    use std::{collections::HashMap, net::IpAddr, time::Duration};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_probe_detection() {
+        let remote: SocketAddr = "192.0.2.1:40000".parse().unwrap();
+        let local: SocketAddr = "127.0.0.1:8080".parse().unwrap();
+        let registry = ConnectionRegistry::default();
+        registry.set_probe_detection(Some(ProbeDetection {
+            min_bytes: 64,
+            min_duration: None,
+        }));
+
+        let mut conn = registry.register(remote, local);
+        conn.set_outcome(ConnectionOutcome::ClientClosed);
+        drop(conn);
+        assert!(registry.recent()[0].probe);
+        assert_eq!(registry.probe_count(), 1);
+
+        let mut conn = registry.register(remote, local);
+        let (mut client, server) = tokio::io::duplex(8192);
+        let mut server = conn.track(server, Side::Client);
+        client.write_all(&[0; 4096]).await.unwrap();
+        let mut buf = vec![0; 4096];
+        server.read_exact(&mut buf).await.unwrap();
+        server.write_all(&buf).await.unwrap();
+        conn.set_outcome(ConnectionOutcome::ClientClosed);
+        drop(conn);
+
+        let recent = registry.recent();
+        assert!(!recent[0].probe);
+        assert_eq!(recent[0].bytes_received, 4096);
+        assert_eq!(recent[0].bytes_sent, 4096);
+        assert_eq!(registry.probe_count(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_remote_name() {
+        let remote: SocketAddr = "192.0.2.1:40000".parse().unwrap();
+        let local: SocketAddr = "127.0.0.1:8080".parse().unwrap();
+        let resolver = StubResolver(HashMap::from([(
+            IpAddr::from([192, 0, 2, 1]),
+            "client.example.com".to_string(),
+        )]));
+        let registry =
+            ConnectionRegistry::with_reverse_dns(Some(ReverseDns::with_resolver(resolver)));
+
+        let conn = registry.register(remote, local);
+        assert_eq!(conn.remote_name(), None);
+        drop(conn);
+
+        let conn = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let conn = registry.register(remote, local);
+                if conn.remote_name().is_some() {
+                    break conn;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(conn.remote_name().as_deref(), Some("client.example.com"));
+        assert_eq!(
+            registry.snapshot()[0].remote_name.as_deref(),
+            Some("client.example.com")
+        );
+    }
+}
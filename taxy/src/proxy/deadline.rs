@@ -0,0 +1,108 @@
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time::{Instant, Sleep},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum DeadlineExceeded {
+    #[error("read deadline exceeded")]
+    Read,
+    #[error("write deadline exceeded")]
+    Write,
+}
+
+impl DeadlineExceeded {
+    /// Returns the deadline which failed an I/O operation of a [`DeadlineStream`], if any.
+    pub fn from_io_error(err: &io::Error) -> Option<Self> {
+        err.get_ref()?.downcast_ref::<Self>().copied()
+    }
+}
+
+impl From<DeadlineExceeded> for io::Error {
+    fn from(err: DeadlineExceeded) -> Self {
+        io::Error::new(io::ErrorKind::TimedOut, err)
+    }
+}
+
+/// A stream whose reads and writes fail once their deadline has passed.
+///
+/// A deadline only fails the operations still in progress, so the read deadline
+/// has no effect once the stream has reached EOF. Shutting down is always allowed.
+#[derive(Debug)]
+pub struct DeadlineStream<S> {
+    inner: S,
+    read: Option<Pin<Box<Sleep>>>,
+    write: Option<Pin<Box<Sleep>>>,
+}
+
+impl<S> DeadlineStream<S> {
+    pub fn new(inner: S, read: Option<Instant>, write: Option<Instant>) -> Self {
+        Self {
+            inner,
+            read: read.map(|deadline| Box::pin(tokio::time::sleep_until(deadline))),
+            write: write.map(|deadline| Box::pin(tokio::time::sleep_until(deadline))),
+        }
+    }
+}
+
+fn poll_deadline(
+    deadline: &mut Option<Pin<Box<Sleep>>>,
+    cx: &mut Context<'_>,
+    exceeded: DeadlineExceeded,
+) -> Poll<io::Error> {
+    if let Some(sleep) = deadline {
+        if sleep.as_mut().poll(cx).is_ready() {
+            return Poll::Ready(exceeded.into());
+        }
+    }
+    Poll::Pending
+}
+
+impl<S> AsyncRead for DeadlineStream<S>
+where
+    S: AsyncRead + Unpin,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if let Poll::Ready(err) = poll_deadline(&mut self.read, cx, DeadlineExceeded::Read) {
+            return Poll::Ready(Err(err));
+        }
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S> AsyncWrite for DeadlineStream<S>
+where
+    S: AsyncWrite + Unpin,
+{
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        if let Poll::Ready(err) = poll_deadline(&mut self.write, cx, DeadlineExceeded::Write) {
+            return Poll::Ready(Err(err));
+        }
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if let Poll::Ready(err) = poll_deadline(&mut self.write, cx, DeadlineExceeded::Write) {
+            return Poll::Ready(Err(err));
+        }
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
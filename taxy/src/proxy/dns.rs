@@ -0,0 +1,299 @@
+use std::{
+    fmt, io,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+use taxy_api::port::DnsResolution;
+use tokio::{net, time::Instant};
+use tracing::{debug, warn};
+
+#[async_trait::async_trait]
+pub trait UpstreamResolver: Send + Sync + 'static {
+    async fn resolve(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>>;
+}
+
+/// Resolves names with the system resolver.
+#[derive(Debug, Default)]
+pub struct SystemResolver;
+
+#[async_trait::async_trait]
+impl UpstreamResolver for SystemResolver {
+    async fn resolve(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        Ok(net::lookup_host((host, port)).await?.collect())
+    }
+}
+
+#[derive(Debug)]
+struct Endpoint {
+    addr: SocketAddr,
+    unhealthy_until: Option<Instant>,
+}
+
+impl Endpoint {
+    fn is_healthy(&self, now: Instant) -> bool {
+        self.unhealthy_until.is_none_or(|until| until <= now)
+    }
+}
+
+#[derive(Debug, Default)]
+struct State {
+    endpoints: Vec<Endpoint>,
+    resolved_at: Option<Instant>,
+    counter: usize,
+}
+
+/// The resolved addresses of a DNS upstream with their health.
+#[derive(Clone)]
+pub struct ResolvedEndpoints {
+    host: String,
+    port: u16,
+    config: DnsResolution,
+    resolver: Arc<dyn UpstreamResolver>,
+    state: Arc<Mutex<State>>,
+}
+
+impl fmt::Debug for ResolvedEndpoints {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ResolvedEndpoints")
+            .field("host", &self.host)
+            .field("port", &self.port)
+            .field("config", &self.config)
+            .finish()
+    }
+}
+
+impl ResolvedEndpoints {
+    pub fn new(host: &str, port: u16, config: &DnsResolution) -> Self {
+        Self::with_resolver(host, port, config, SystemResolver)
+    }
+
+    pub fn with_resolver<R: UpstreamResolver>(
+        host: &str,
+        port: u16,
+        config: &DnsResolution,
+        resolver: R,
+    ) -> Self {
+        Self {
+            host: host.to_string(),
+            port,
+            config: config.clone(),
+            resolver: Arc::new(resolver),
+            state: Default::default(),
+        }
+    }
+
+    /// Takes the resolved addresses and their health from `old`, so that they
+    /// survive config updates.
+    pub fn inherit(&mut self, old: &Self) {
+        self.state = old.state.clone();
+    }
+
+    /// Returns the next healthy address in round-robin order, re-resolving
+    /// the name first if the addresses are stale. Falls back to the address
+    /// which will recover first if none of them is healthy.
+    pub async fn select(&self, now: Instant) -> io::Result<SocketAddr> {
+        self.refresh(now).await?;
+        let mut state = self.state.lock().unwrap();
+        let healthy = state
+            .endpoints
+            .iter()
+            .filter(|endpoint| endpoint.is_healthy(now))
+            .map(|endpoint| endpoint.addr)
+            .collect::<Vec<_>>();
+        let addr = if healthy.is_empty() {
+            state
+                .endpoints
+                .iter()
+                .min_by_key(|endpoint| endpoint.unhealthy_until)
+                .map(|endpoint| endpoint.addr)
+        } else {
+            Some(healthy[state.counter % healthy.len()])
+        };
+        state.counter = state.counter.wrapping_add(1);
+        addr.ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no resolved addresses"))
+    }
+
+    /// Returns the address of [`select`](Self::select), followed by a healthy address
+    /// of the other family if there is any, to race the connections to both families.
+    pub async fn select_dual_stack(&self, now: Instant) -> io::Result<Vec<SocketAddr>> {
+        let addr = self.select(now).await?;
+        let state = self.state.lock().unwrap();
+        let other = state
+            .endpoints
+            .iter()
+            .filter(|endpoint| {
+                endpoint.is_healthy(now) && endpoint.addr.is_ipv4() != addr.is_ipv4()
+            })
+            .map(|endpoint| endpoint.addr)
+            .collect::<Vec<_>>();
+        let mut addrs = vec![addr];
+        if !other.is_empty() {
+            addrs.push(other[state.counter % other.len()]);
+        }
+        Ok(addrs)
+    }
+
+    async fn refresh(&self, now: Instant) -> io::Result<()> {
+        let has_endpoints = {
+            let mut state = self.state.lock().unwrap();
+            let fresh = state
+                .resolved_at
+                .is_some_and(|at| now < at + self.config.refresh_interval);
+            if fresh {
+                return Ok(());
+            }
+            // Concurrent connections keep using the current addresses meanwhile.
+            state.resolved_at = Some(now);
+            !state.endpoints.is_empty()
+        };
+
+        let addrs = match self.resolver.resolve(&self.host, self.port).await {
+            Ok(addrs) if !addrs.is_empty() => addrs,
+            Ok(_) | Err(_) if has_endpoints => {
+                warn!(
+                    host = self.host,
+                    "failed to refresh addresses, keeping the stale ones"
+                );
+                return Ok(());
+            }
+            Ok(_) => {
+                self.state.lock().unwrap().resolved_at = None;
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("no addresses found for {}", self.host),
+                ));
+            }
+            Err(err) => {
+                self.state.lock().unwrap().resolved_at = None;
+                return Err(err);
+            }
+        };
+
+        debug!(host = self.host, ?addrs, "resolved upstream");
+        let mut state = self.state.lock().unwrap();
+        let endpoints = addrs
+            .into_iter()
+            .map(|addr| Endpoint {
+                addr,
+                unhealthy_until: state
+                    .endpoints
+                    .iter()
+                    .find(|endpoint| endpoint.addr == addr)
+                    .and_then(|endpoint| endpoint.unhealthy_until),
+            })
+            .collect();
+        state.endpoints = endpoints;
+        Ok(())
+    }
+
+    pub fn mark_unhealthy(&self, addr: SocketAddr, now: Instant) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(endpoint) = state.endpoints.iter_mut().find(|e| e.addr == addr) {
+            endpoint.unhealthy_until = Some(now + self.config.unhealthy_cooldown);
+        }
+    }
+
+    pub fn mark_healthy(&self, addr: SocketAddr) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(endpoint) = state.endpoints.iter_mut().find(|e| e.addr == addr) {
+            endpoint.unhealthy_until = None;
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use std::time::Duration;
+
+    #[derive(Clone, Default)]
+    pub struct StubResolver(pub Arc<Mutex<Vec<SocketAddr>>>);
+
+    #[async_trait::async_trait]
+    impl UpstreamResolver for StubResolver {
+        async fn resolve(&self, _host: &str, _port: u16) -> io::Result<Vec<SocketAddr>> {
+            Ok(self.0.lock().unwrap().clone())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_select() {
+        let a: SocketAddr = "192.0.2.1:8080".parse().unwrap();
+        let b: SocketAddr = "192.0.2.2:8080".parse().unwrap();
+        let c: SocketAddr = "192.0.2.3:8080".parse().unwrap();
+        let resolver = StubResolver::default();
+        let config = DnsResolution {
+            refresh_interval: Duration::from_secs(30),
+            unhealthy_cooldown: Duration::from_secs(10),
+        };
+        let endpoints =
+            ResolvedEndpoints::with_resolver("example.com", 8080, &config, resolver.clone());
+        let mut now = Instant::now();
+        assert!(endpoints.select(now).await.is_err());
+
+        *resolver.0.lock().unwrap() = vec![a, b];
+        assert_eq!(endpoints.select(now).await.unwrap(), a);
+        assert_eq!(endpoints.select(now).await.unwrap(), b);
+
+        endpoints.mark_unhealthy(a, now);
+        assert_eq!(endpoints.select(now).await.unwrap(), b);
+        assert_eq!(endpoints.select(now).await.unwrap(), b);
+
+        // All addresses are unhealthy: the one recovering first is tried.
+        now += Duration::from_secs(1);
+        endpoints.mark_unhealthy(b, now);
+        assert_eq!(endpoints.select(now).await.unwrap(), a);
+
+        now += Duration::from_secs(10);
+        let selected = [
+            endpoints.select(now).await.unwrap(),
+            endpoints.select(now).await.unwrap(),
+        ];
+        assert!(selected.contains(&a) && selected.contains(&b));
+
+        // The record changes, but the addresses are not stale yet.
+        *resolver.0.lock().unwrap() = vec![b, c];
+        endpoints.mark_unhealthy(b, now);
+        assert_eq!(endpoints.select(now).await.unwrap(), a);
+
+        // The disappeared address is dropped and the health of the kept one survives.
+        now += Duration::from_secs(30);
+        endpoints.mark_unhealthy(b, now);
+        assert_eq!(endpoints.select(now).await.unwrap(), c);
+        assert_eq!(endpoints.select(now).await.unwrap(), c);
+        endpoints.mark_healthy(b);
+        let selected = [
+            endpoints.select(now).await.unwrap(),
+            endpoints.select(now).await.unwrap(),
+        ];
+        assert!(selected.contains(&b) && selected.contains(&c));
+
+        // Failed lookups keep the stale addresses.
+        resolver.0.lock().unwrap().clear();
+        now += Duration::from_secs(30);
+        let selected = endpoints.select(now).await.unwrap();
+        assert!(selected == b || selected == c);
+    }
+
+    #[tokio::test]
+    async fn test_select_dual_stack() {
+        let v4: SocketAddr = "192.0.2.1:8080".parse().unwrap();
+        let v6: SocketAddr = "[2001:db8::1]:8080".parse().unwrap();
+        let resolver = StubResolver(Arc::new(Mutex::new(vec![v6, v4])));
+        let endpoints =
+            ResolvedEndpoints::with_resolver("example.com", 8080, &Default::default(), resolver);
+        let now = Instant::now();
+        assert_eq!(
+            endpoints.select_dual_stack(now).await.unwrap(),
+            vec![v6, v4]
+        );
+        assert_eq!(
+            endpoints.select_dual_stack(now).await.unwrap(),
+            vec![v4, v6]
+        );
+
+        // An unhealthy address is not raced.
+        endpoints.mark_unhealthy(v6, now);
+        assert_eq!(endpoints.select_dual_stack(now).await.unwrap(), vec![v4]);
+    }
+}
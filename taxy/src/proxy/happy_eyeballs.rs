@@ -0,0 +1,136 @@
+use futures::{stream::FuturesUnordered, StreamExt};
+use std::{future::Future, io, net::SocketAddr, time::Duration};
+
+/// The connection attempt delay recommended by RFC 8305.
+pub const DEFAULT_HAPPY_EYEBALLS_DELAY: Duration = Duration::from_millis(250);
+
+/// Orders the resolved addresses for connection attempts, alternating between the
+/// address families starting with the family of the first address (RFC 8305, section 4).
+pub fn interleave(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let Some(first) = addrs.first() else {
+        return addrs;
+    };
+    let first_ipv4 = first.is_ipv4();
+    let (mut preferred, mut other): (Vec<_>, Vec<_>) = addrs
+        .into_iter()
+        .partition(|addr| addr.is_ipv4() == first_ipv4);
+    let mut interleaved = Vec::with_capacity(preferred.len() + other.len());
+    preferred.reverse();
+    other.reverse();
+    loop {
+        match (preferred.pop(), other.pop()) {
+            (None, None) => break,
+            (preferred, other) => interleaved.extend(preferred.into_iter().chain(other)),
+        }
+    }
+    interleaved
+}
+
+/// Connects to the addresses in order, as in Happy Eyeballs (RFC 8305). The next attempt
+/// starts once `delay` has elapsed since the last one or as soon as an attempt fails,
+/// without cancelling the attempts in progress. Returns the first connection established
+/// along with its address, or the error of the last attempt if all of them fail.
+pub async fn connect<A, T, E, F, Fut>(
+    addrs: Vec<A>,
+    delay: Duration,
+    mut connect: F,
+) -> Result<(A, T), E>
+where
+    A: Clone,
+    E: From<io::Error>,
+    F: FnMut(A) -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+{
+    let mut pending = addrs.into_iter();
+    let mut attempts = FuturesUnordered::new();
+    match pending.next() {
+        Some(addr) => attempts.push(attempt(addr.clone(), connect(addr))),
+        None => return Err(io::Error::new(io::ErrorKind::NotFound, "no addresses found").into()),
+    }
+    loop {
+        tokio::select! {
+            Some((addr, result)) = attempts.next() => match result {
+                Ok(stream) => return Ok((addr, stream)),
+                Err(err) => match pending.next() {
+                    Some(addr) => attempts.push(attempt(addr.clone(), connect(addr))),
+                    None if attempts.is_empty() => return Err(err),
+                    None => {}
+                },
+            },
+            _ = tokio::time::sleep(delay), if pending.len() > 0 => {
+                if let Some(addr) = pending.next() {
+                    attempts.push(attempt(addr.clone(), connect(addr)));
+                }
+            }
+        }
+    }
+}
+
+async fn attempt<A, Fut: Future>(addr: A, connect: Fut) -> (A, Fut::Output) {
+    (addr, connect.await)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::time::Instant;
+
+    #[test]
+    fn test_interleave() {
+        let v6a: SocketAddr = "[2001:db8::1]:443".parse().unwrap();
+        let v6b: SocketAddr = "[2001:db8::2]:443".parse().unwrap();
+        let v4a: SocketAddr = "192.0.2.1:443".parse().unwrap();
+        let v4b: SocketAddr = "192.0.2.2:443".parse().unwrap();
+        assert_eq!(
+            interleave(vec![v6a, v6b, v4a, v4b]),
+            vec![v6a, v4a, v6b, v4b]
+        );
+        assert_eq!(interleave(vec![v4a, v4b, v6a]), vec![v4a, v6a, v4b]);
+        assert_eq!(interleave(vec![]), vec![]);
+    }
+
+    #[tokio::test]
+    async fn test_connect() {
+        // The first address stalls, so the second one wins after the delay.
+        let started_at = Instant::now();
+        let result: io::Result<_> = connect(
+            vec!["stalled", "ok"],
+            Duration::from_millis(50),
+            |addr| async move {
+                if addr == "stalled" {
+                    std::future::pending::<()>().await;
+                }
+                Ok(addr.len())
+            },
+        )
+        .await;
+        assert_eq!(result.unwrap(), ("ok", 2));
+        assert!(started_at.elapsed() >= Duration::from_millis(50));
+
+        // A failed attempt starts the next one right away.
+        let started_at = Instant::now();
+        let result: io::Result<_> = connect(
+            vec!["refused", "ok"],
+            Duration::from_secs(10),
+            |addr| async move {
+                match addr {
+                    "refused" => Err(io::ErrorKind::ConnectionRefused.into()),
+                    _ => Ok(addr),
+                }
+            },
+        )
+        .await;
+        assert_eq!(result.unwrap().0, "ok");
+        assert!(started_at.elapsed() < Duration::from_secs(5));
+
+        let result: io::Result<((), ())> = connect(vec![(), ()], Duration::ZERO, |_| async {
+            Err(io::ErrorKind::ConnectionRefused.into())
+        })
+        .await;
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::ConnectionRefused);
+
+        let result: io::Result<((), ())> =
+            connect(vec![], Duration::ZERO, |_| async { Ok(()) }).await;
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::NotFound);
+    }
+}
@@ -0,0 +1,208 @@
+use super::{
+    socket::{self, SocketStream},
+    tcp::Connection,
+};
+use crate::command::ServerCommand;
+use std::{io, sync::atomic::Ordering, sync::Arc};
+use taxy_api::port::HealthCheck;
+use tokio::{
+    net::{self, TcpStream},
+    sync::mpsc,
+    task::JoinHandle,
+    time::{Instant, MissedTickBehavior},
+};
+use tokio_rustls::{rustls::ClientConfig, TlsConnector};
+use tracing::{debug, info, warn, Instrument, Span};
+
+/// Checks the upstreams of a port periodically until dropped.
+#[derive(Debug)]
+pub struct HealthChecker {
+    handle: JoinHandle<()>,
+}
+
+/// Reports the upstreams of a port going down or coming back up to the server.
+#[derive(Debug, Clone)]
+pub struct HealthEvents {
+    pub port_id: String,
+    pub sender: mpsc::Sender<ServerCommand>,
+}
+
+impl HealthChecker {
+    pub fn start(
+        config: &HealthCheck,
+        servers: Vec<Connection>,
+        tls_client_config: Option<Arc<ClientConfig>>,
+        events: Option<HealthEvents>,
+        span: Span,
+    ) -> Self {
+        let config = config.clone();
+        let handle = tokio::spawn(
+            async move {
+                let mut interval = tokio::time::interval(config.interval);
+                interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+                loop {
+                    interval.tick().await;
+                    let changes = check_all(&servers, &config, tls_client_config.as_ref()).await;
+                    let Some(events) = &events else {
+                        continue;
+                    };
+                    for (addr, healthy) in changes {
+                        let _ = events
+                            .sender
+                            .send(ServerCommand::SetUpstreamHealth {
+                                port_id: events.port_id.clone(),
+                                addr,
+                                healthy,
+                            })
+                            .await;
+                    }
+                }
+            }
+            .instrument(span),
+        );
+        Self { handle }
+    }
+}
+
+impl Drop for HealthChecker {
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
+
+/// Runs one round of checks on the enabled upstreams and updates their health.
+/// Returns the address and new health of the upstreams whose health changed.
+pub async fn check_all(
+    servers: &[Connection],
+    config: &HealthCheck,
+    tls_client_config: Option<&Arc<ClientConfig>>,
+) -> Vec<(String, bool)> {
+    let checks = servers
+        .iter()
+        .filter(|server| !server.disabled)
+        .map(|server| async move {
+            let result = check(server, config, tls_client_config).await;
+            record(server, result, config.unhealthy_threshold)
+                .map(|healthy| (format!("{}:{}", server.hostname(), server.port), healthy))
+        });
+    futures::future::join_all(checks)
+        .await
+        .into_iter()
+        .flatten()
+        .collect()
+}
+
+async fn check(
+    server: &Connection,
+    config: &HealthCheck,
+    tls_client_config: Option<&Arc<ClientConfig>>,
+) -> anyhow::Result<()> {
+    let check = async {
+        let stream = if let Some(path) = &server.unix {
+            socket::connect_unix(path).await?
+        } else {
+            let addr = match &server.endpoints {
+                Some(endpoints) => endpoints.select(Instant::now()).await?,
+                None => net::lookup_host((server.hostname(), server.port))
+                    .await?
+                    .next()
+                    .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no addresses found"))?,
+            };
+            SocketStream::Tcp(TcpStream::connect(addr).await?)
+        };
+        if let (true, true, Some(tls)) = (config.tls_handshake, server.tls, tls_client_config) {
+            TlsConnector::from(server.tls_client_config(tls))
+                .connect(server.tls_server_name(), stream)
+                .await?;
+        }
+        anyhow::Ok(())
+    };
+    tokio::time::timeout(config.timeout, check)
+        .await
+        .map_err(|_| anyhow::anyhow!("timed out after {:?}", config.timeout))?
+}
+
+/// Returns the new health of the upstream if it changed.
+fn record(server: &Connection, result: anyhow::Result<()>, threshold: u32) -> Option<bool> {
+    let stats = &server.stats;
+    let upstream = server.hostname();
+    match result {
+        Ok(()) => {
+            stats.health_failures.store(0, Ordering::Relaxed);
+            if stats.unhealthy.swap(false, Ordering::Relaxed) {
+                info!(upstream, port = server.port, "upstream is healthy again");
+                return Some(true);
+            }
+        }
+        Err(err) => {
+            let failures = stats.health_failures.fetch_add(1, Ordering::Relaxed) + 1;
+            debug!(
+                upstream,
+                port = server.port,
+                failures,
+                "health check failed: {err}"
+            );
+            if failures >= threshold.max(1) && !stats.unhealthy.swap(true, Ordering::Relaxed) {
+                warn!(
+                    upstream,
+                    port = server.port,
+                    failures,
+                    "upstream is unhealthy: {err}"
+                );
+                return Some(false);
+            }
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::proxy::tcp::multiaddr_to_host;
+    use std::time::Duration;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_check_all() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server = multiaddr_to_host(
+            &format!(
+                "/ip4/127.0.0.1/tcp/{}",
+                listener.local_addr().unwrap().port()
+            )
+            .parse()
+            .unwrap(),
+        )
+        .unwrap();
+        let servers = [server];
+        let config = HealthCheck {
+            interval: Duration::from_secs(10),
+            timeout: Duration::from_secs(3),
+            unhealthy_threshold: 2,
+            tls_handshake: false,
+        };
+        let unhealthy = || servers[0].stats.unhealthy.load(Ordering::SeqCst);
+
+        assert!(check_all(&servers, &config, None).await.is_empty());
+        assert!(!unhealthy());
+
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+        assert!(check_all(&servers, &config, None).await.is_empty());
+        assert!(!unhealthy());
+        assert_eq!(
+            check_all(&servers, &config, None).await,
+            [(addr.to_string(), false)]
+        );
+        assert!(unhealthy());
+
+        let _listener = TcpListener::bind(addr).await.unwrap();
+        assert_eq!(
+            check_all(&servers, &config, None).await,
+            [(addr.to_string(), true)]
+        );
+        assert!(!unhealthy());
+        assert_eq!(servers[0].stats.health_failures.load(Ordering::SeqCst), 0);
+    }
+}
@@ -1,10 +1,13 @@
+use crate::proxy::tls::ClientCert;
 use hyper::{
-    header::{FORWARDED, VIA},
+    header::{HeaderName, FORWARDED, VIA},
     http::header::Entry,
     http::HeaderValue,
     HeaderMap,
 };
-use std::{iter, net::IpAddr};
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use std::{iter, net::IpAddr, str::FromStr};
+use taxy_api::{error::Error, tls::ClientCertHeaders};
 
 #[derive(Default)]
 pub struct HeaderRewriter {
@@ -140,6 +143,57 @@ impl Builder {
     }
 }
 
+/// Sets the configured request headers to the details of the verified client certificate.
+#[derive(Debug, Default, Clone)]
+pub struct ClientCertForwarder {
+    cert: Option<HeaderName>,
+    subject: Option<HeaderName>,
+    san: Option<HeaderName>,
+    fingerprint: Option<HeaderName>,
+}
+
+impl ClientCertForwarder {
+    pub fn new(headers: &ClientCertHeaders) -> Result<Self, Error> {
+        let parse = |name: &Option<String>| {
+            name.as_ref()
+                .map(|name| {
+                    HeaderName::from_str(name)
+                        .map_err(|_| Error::InvalidHeaderName { name: name.clone() })
+                })
+                .transpose()
+        };
+        Ok(Self {
+            cert: parse(&headers.cert)?,
+            subject: parse(&headers.subject)?,
+            san: parse(&headers.san)?,
+            fingerprint: parse(&headers.fingerprint)?,
+        })
+    }
+
+    /// Removes the configured headers sent by the client, then sets them
+    /// if the client has presented a certificate.
+    pub fn apply(&self, headers: &mut HeaderMap, cert: Option<&ClientCert>) {
+        let fields = [
+            (
+                &self.cert,
+                cert.map(|cert| utf8_percent_encode(&cert.pem, NON_ALPHANUMERIC).to_string()),
+            ),
+            (&self.subject, cert.map(|cert| cert.subject.clone())),
+            (&self.san, cert.map(|cert| cert.san.join(","))),
+            (&self.fingerprint, cert.map(|cert| cert.fingerprint.clone())),
+        ];
+        for (name, value) in fields {
+            let Some(name) = name else {
+                continue;
+            };
+            headers.remove(name);
+            if let Some(value) = value.and_then(|value| HeaderValue::from_str(&value).ok()) {
+                headers.insert(name.clone(), value);
+            }
+        }
+    }
+}
+
 fn forwarded_directive(addr: IpAddr) -> String {
     if addr.is_ipv6() {
         format!("for=\"[{addr}]\"")
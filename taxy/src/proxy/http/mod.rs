@@ -1,25 +1,48 @@
 use self::route::Router;
-use super::{tls::TlsTermination, PortContextEvent};
-use crate::keyring::Keyring;
+use super::{
+    conn_limit::ConnectionLimitPermit,
+    connections::{ConnectionRegistry, Side, DEFAULT_RECENT_CONNECTIONS},
+    happy_eyeballs::{self, DEFAULT_HAPPY_EYEBALLS_DELAY},
+    rate_limit::ConnectionRateLimiter,
+    rdns::ReverseDns,
+    shedding::LoadShedder,
+    sniff::{sniff, DetectedProtocol, DEFAULT_SNIFF_TIMEOUT},
+    socket::Socket,
+    tcp::{self, multiaddr_to_host, multiaddr_to_tcp},
+    tls::{BoundedAcceptor, TlsTermination},
+    tls_params::TlsParams,
+    trace::{TraceParent, TRACEPARENT, TRACESTATE},
+    upstream_tls::{self, CaBundle},
+    PortContextEvent,
+};
+use crate::{keyring::Keyring, log::redact_host};
 use hyper::{
     client,
-    header::{HOST, UPGRADE},
+    header::{HOST, STRICT_TRANSPORT_SECURITY, UPGRADE},
     http::HeaderValue,
     server::conn::Http,
 };
 use multiaddr::{Multiaddr, Protocol};
-use std::{net::SocketAddr, sync::Arc, time::SystemTime};
+use std::{
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
 use taxy_api::error::Error;
-use taxy_api::port::{PortStatus, SocketState};
-use taxy_api::{port::PortEntry, site::SiteEntry};
-use tokio::net::{self, TcpSocket, TcpStream};
+use taxy_api::port::{
+    ConnectionOutcome, LoadBalanceMode, PortStatus, SocketState, TagAffinity,
+    UpstreamTlsVerification,
+};
+use taxy_api::{port::PortEntry, site::SiteEntry, tls::TlsState};
+use tokio::net::{self, TcpSocket};
 use tokio::{
-    io::{AsyncRead, AsyncWrite, BufStream},
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt, BufStream},
     sync::Notify,
+    time::Instant,
 };
 use tokio_rustls::{
-    rustls::{client::ServerName, Certificate, ClientConfig, RootCertStore},
-    TlsAcceptor, TlsConnector,
+    rustls::{client::ServerName, ClientConfig},
+    TlsConnector,
 };
 use tracing::{debug, error, info, span, warn, Instrument, Level, Span};
 
@@ -28,7 +51,7 @@ mod header;
 mod route;
 mod upgrade;
 
-use header::HeaderRewriter;
+use header::{ClientCertForwarder, HeaderRewriter};
 
 #[derive(Debug)]
 pub struct HttpPortContext {
@@ -37,9 +60,25 @@ pub struct HttpPortContext {
     span: Span,
     tls_termination: Option<TlsTermination>,
     tls_client_config: Option<Arc<ClientConfig>>,
+    upstream_tls_verification: UpstreamTlsVerification,
+    upstream_ca_bundle: Option<CaBundle>,
+    tls_params: TlsParams,
+    protocol_detection: bool,
+    protocol_detection_timeout: Duration,
+    fallback_servers: Vec<tcp::Connection>,
+    tag_affinity: Vec<TagAffinity>,
+    load_balance: LoadBalanceMode,
+    hsts: Option<HeaderValue>,
+    trace_context: bool,
+    happy_eyeballs_delay: Duration,
+    client_cert_forwarder: ClientCertForwarder,
+    shedder: Option<LoadShedder>,
+    rate_limiter: Option<ConnectionRateLimiter>,
     router: Arc<Router>,
     round_robin_counter: usize,
     stop_notifier: Arc<Notify>,
+    draining: bool,
+    connections: ConnectionRegistry,
 }
 
 impl HttpPortContext {
@@ -51,24 +90,140 @@ impl HttpPortContext {
         info!("initializing http proxy");
         let listen = multiaddr_to_tcp(&entry.port.listen)?;
 
+        let tls_params = entry
+            .port
+            .opts
+            .tls_parameters
+            .as_ref()
+            .map(TlsParams::new)
+            .transpose()?
+            .unwrap_or_default();
+
+        let upstream_ca_bundle = entry
+            .port
+            .opts
+            .upstream_ca_bundle
+            .as_ref()
+            .map(CaBundle::new)
+            .transpose()?;
+
         let tls_termination = if let Some(tls) = &entry.port.opts.tls_termination {
             let alpn = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
-            Some(TlsTermination::new(tls, alpn)?)
+            let mut tls = TlsTermination::new(tls, alpn.clone(), (&entry.port.opts).into())?;
+            if tls
+                .alpn_protocols
+                .iter()
+                .any(|protocol| !alpn.contains(protocol))
+            {
+                warn!("only h2 and http/1.1 are supported on http ports, ignoring the other ALPN protocols");
+                tls.alpn_protocols
+                    .retain(|protocol| alpn.contains(protocol));
+            }
+            tls.params = tls_params.clone();
+            Some(tls)
         } else if entry.port.listen.iter().any(|p| p == Protocol::Tls) {
             return Err(Error::TlsTerminationConfigMissing);
         } else {
             None
         };
 
+        let protocol_detection = entry.port.opts.protocol_detection;
+        let mut fallback_servers = Vec::new();
+        if protocol_detection {
+            for server in &entry.port.opts.upstream_servers {
+                let mut conn = multiaddr_to_host(&server.addr)?;
+                conn.disabled = server.disabled;
+                conn.tags = server.tags.clone();
+                conn.weight = server.weight;
+                conn.disable_sni = server.disable_sni;
+                conn.insecure_skip_verify = server.insecure_skip_verify;
+                conn.server_name_override = server
+                    .server_name_override
+                    .as_deref()
+                    .map(tcp::parse_server_name)
+                    .transpose()?;
+                conn.proxy_protocol = server.proxy_protocol;
+                fallback_servers.push(conn);
+            }
+        }
+
+        let hsts = entry
+            .port
+            .opts
+            .hsts
+            .as_ref()
+            .filter(|_| tls_termination.is_some())
+            .and_then(|hsts| HeaderValue::from_str(&hsts.header_value()).ok());
+
+        let client_cert_forwarder = match entry
+            .port
+            .opts
+            .tls_termination
+            .as_ref()
+            .and_then(|tls| tls.client_auth.as_ref())
+        {
+            Some(client_auth) => ClientCertForwarder::new(&client_auth.forward_headers)?,
+            None => Default::default(),
+        };
+
+        let connections = ConnectionRegistry::with_reverse_dns(
+            entry.port.opts.reverse_dns.then(ReverseDns::default),
+        );
+        connections.set_recent_capacity(
+            entry
+                .port
+                .opts
+                .recent_connections
+                .unwrap_or(DEFAULT_RECENT_CONNECTIONS),
+        );
+        connections.set_probe_detection(entry.port.opts.probe_detection);
+        let shedder = entry
+            .port
+            .opts
+            .overload_shedding
+            .as_ref()
+            .map(|config| LoadShedder::new(config, &connections));
+        let rate_limiter = entry
+            .port
+            .opts
+            .connection_rate
+            .as_ref()
+            .map(ConnectionRateLimiter::new)
+            .transpose()?;
+
         Ok(Self {
             listen,
             status: Default::default(),
             span,
             tls_termination,
             tls_client_config: None,
+            upstream_tls_verification: entry.port.opts.upstream_tls_verification,
+            upstream_ca_bundle,
+            tls_params,
+            protocol_detection,
+            protocol_detection_timeout: entry
+                .port
+                .opts
+                .protocol_detection_timeout
+                .unwrap_or(DEFAULT_SNIFF_TIMEOUT),
+            fallback_servers,
+            tag_affinity: entry.port.opts.tag_affinity.clone(),
+            load_balance: entry.port.opts.load_balance,
+            hsts,
+            trace_context: entry.port.opts.trace_context,
+            happy_eyeballs_delay: entry
+                .port
+                .opts
+                .happy_eyeballs_delay
+                .unwrap_or(DEFAULT_HAPPY_EYEBALLS_DELAY),
+            client_cert_forwarder,
+            shedder,
+            rate_limiter,
             router: Arc::new(Default::default()),
             round_robin_counter: 0,
             stop_notifier: Arc::new(Notify::new()),
+            draining: false,
+            connections,
         })
     }
 
@@ -76,27 +231,12 @@ impl HttpPortContext {
         self.router = Arc::new(Router::new(sites));
 
         if self.tls_client_config.is_none() {
-            let mut root_certs = RootCertStore::empty();
-            if let Ok(certs) =
-                tokio::task::spawn_blocking(rustls_native_certs::load_native_certs).await
-            {
-                match certs {
-                    Ok(certs) => {
-                        for certs in certs {
-                            if let Err(err) = root_certs.add(&Certificate(certs.0)) {
-                                warn!("failed to add native certs: {err}");
-                            }
-                        }
-                    }
-                    Err(err) => {
-                        warn!("failed to load native certs: {err}");
-                    }
-                }
-            }
-            let mut config = ClientConfig::builder()
-                .with_safe_defaults()
-                .with_root_certificates(root_certs)
-                .with_no_client_auth();
+            let mut config = upstream_tls::client_config(
+                self.upstream_tls_verification,
+                &self.tls_params,
+                self.upstream_ca_bundle.as_ref(),
+            )
+            .await;
             config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
             self.tls_client_config = Some(Arc::new(config));
         }
@@ -106,17 +246,41 @@ impl HttpPortContext {
         Ok(())
     }
 
-    pub async fn refresh(&mut self, certs: &Keyring) -> Result<(), Error> {
+    pub async fn refresh(&mut self, certs: &Keyring, strict: bool) -> Result<(), Error> {
         if let Some(tls) = &mut self.tls_termination {
-            self.status.state.tls = Some(tls.refresh(certs).await);
+            match tls.refresh(certs, strict).await {
+                Ok(state) => self.status.state.tls = Some(state),
+                Err(err) => {
+                    self.status.state.tls = Some(TlsState::Failed);
+                    return Err(err);
+                }
+            }
         }
         Ok(())
     }
 
-    pub fn apply(&mut self, new: Self) {
+    /// Takes the config of `new`, keeping the runtime state of the port and its unchanged upstreams.
+    pub fn apply(&mut self, mut new: Self) {
+        self.connections
+            .set_reverse_dns(new.connections.reverse_dns());
+        self.connections
+            .set_recent_capacity(new.connections.recent_capacity());
+        self.connections
+            .set_probe_detection(new.connections.probe_detection());
+        tcp::inherit_upstream_stats(&mut new.fallback_servers, &self.fallback_servers);
+        if new.listen == self.listen {
+            new.status.state.socket = self.status.state.socket;
+            new.status.started_at = self.status.started_at;
+        }
+        let shedder = new
+            .shedder
+            .take()
+            .map(|shedder| LoadShedder::new(shedder.config(), &self.connections));
         *self = Self {
+            shedder,
             round_robin_counter: self.round_robin_counter,
             stop_notifier: self.stop_notifier.clone(),
+            connections: self.connections.clone(),
             ..new
         };
     }
@@ -140,11 +304,66 @@ impl HttpPortContext {
         &self.status
     }
 
+    pub fn connections(&self) -> &ConnectionRegistry {
+        &self.connections
+    }
+
+    pub fn tls_termination(&self) -> Option<&TlsTermination> {
+        self.tls_termination.as_ref()
+    }
+
     pub fn reset(&mut self) {
         self.stop_notifier.notify_waiters();
     }
 
-    pub fn start_proxy(&mut self, stream: BufStream<TcpStream>) {
+    /// Rejects new connections, and closes the existing ones once `timeout` has elapsed.
+    /// Applying a new config accepts connections again.
+    pub fn drain(&mut self, timeout: Duration) {
+        self.draining = true;
+        tcp::stop_after(&mut self.stop_notifier, timeout);
+    }
+
+    pub fn drain_upstream(&self, addr: &Multiaddr) {
+        tcp::drain_upstream(&self.fallback_servers, addr);
+    }
+
+    pub fn upstreams(&self) -> &[tcp::Connection] {
+        &self.fallback_servers
+    }
+
+    pub fn start_proxy<S: Socket>(
+        &mut self,
+        mut stream: BufStream<S>,
+        client_addr: Option<SocketAddr>,
+        permit: Option<ConnectionLimitPermit>,
+    ) {
+        if self.draining {
+            self.span
+                .in_scope(|| warn!("connection rejected: port is draining"));
+            tokio::spawn(async move { stream.get_mut().shutdown().await });
+            return;
+        }
+
+        if let Some(load) = self.shedder.as_ref().and_then(|shedder| shedder.shed()) {
+            self.span
+                .in_scope(|| warn!(load, "connection rejected: overloaded"));
+            tokio::spawn(async move { stream.get_mut().shutdown().await });
+            return;
+        }
+
+        let delay = match &mut self.rate_limiter {
+            Some(limiter) => match limiter.acquire(Instant::now()) {
+                Some(delay) => delay,
+                None => {
+                    self.span
+                        .in_scope(|| warn!("connection rejected: rate limit exceeded"));
+                    tokio::spawn(async move { stream.get_mut().shutdown().await });
+                    return;
+                }
+            },
+            None => Duration::ZERO,
+        };
+
         let span = self.span.clone();
 
         let tls_client_config = self.tls_client_config.clone();
@@ -153,31 +372,101 @@ impl HttpPortContext {
             .as_ref()
             .and_then(|tls| tls.acceptor.clone());
 
-        let header_rewriter = HeaderRewriter::builder()
-            .trust_upstream_headers(false)
-            .use_std_forwarded(true)
-            .set_via(HeaderValue::from_static("taxy"))
-            .build();
-
         let stop_notifier = self.stop_notifier.clone();
+        let connections = self.connections.clone();
         let router = self.router.clone();
         let round_robin_counter = self.round_robin_counter;
+        let protocol_detection = self.protocol_detection;
+        let protocol_detection_timeout = self.protocol_detection_timeout;
+        let hsts = self.hsts.clone();
+        let trace_context = self.trace_context;
+        let happy_eyeballs_delay = self.happy_eyeballs_delay;
+        let client_cert_forwarder = self.client_cert_forwarder.clone();
+        let client_addr = client_addr.or_else(|| stream.get_ref().peer_addr().ok());
+        let client = client_addr.map(|addr| addr.ip());
+        let tag = tcp::client_tag(&self.tag_affinity, client);
+        let fallback =
+            tcp::select_upstreams(&self.fallback_servers, tag, client, self.load_balance);
 
         tokio::spawn(
             async move {
-                if let Err(err) = start(
-                    stream,
-                    tls_client_config,
-                    tls_acceptor,
-                    header_rewriter,
-                    router,
-                    round_robin_counter,
-                    stop_notifier,
-                )
-                .await
-                {
+                if !delay.is_zero() {
+                    tokio::time::sleep(delay).await;
+                }
+                let protocol = if protocol_detection {
+                    sniff(&mut stream, protocol_detection_timeout).await
+                } else if tls_acceptor.is_some() {
+                    Ok(DetectedProtocol::Tls)
+                } else {
+                    Ok(DetectedProtocol::Http)
+                };
+                let registry = connections.clone();
+                let result = match protocol {
+                    Ok(DetectedProtocol::Http) => {
+                        start(
+                            stream,
+                            HttpParams {
+                                client_addr,
+                                tls_client_config,
+                                trace_context,
+                                happy_eyeballs_delay,
+                                client_cert_forwarder,
+                                router,
+                                round_robin_counter,
+                                ..Default::default()
+                            },
+                            connections,
+                            stop_notifier,
+                        )
+                        .await
+                    }
+                    Ok(DetectedProtocol::Tls) if tls_acceptor.is_some() => {
+                        start(
+                            stream,
+                            HttpParams {
+                                client_addr,
+                                tls_client_config,
+                                tls_acceptor,
+                                hsts,
+                                trace_context,
+                                happy_eyeballs_delay,
+                                client_cert_forwarder,
+                                router,
+                                round_robin_counter,
+                            },
+                            connections,
+                            stop_notifier,
+                        )
+                        .await
+                    }
+                    Ok(_) => {
+                        debug!("no http or tls detected, falling back to tcp");
+                        start_fallback(
+                            stream,
+                            tcp::ProxyParams {
+                                client_addr,
+                                candidates: fallback,
+                                tls_client_config,
+                                ..Default::default()
+                            },
+                            connections,
+                            // The stream has already been peeked at, so it must stay buffered.
+                            tcp::StreamOptions {
+                                trace_context,
+                                happy_eyeballs_delay: Some(happy_eyeballs_delay),
+                                ..Default::default()
+                            },
+                            stop_notifier,
+                        )
+                        .await
+                    }
+                    Err(err) => Err(err.into()),
+                };
+                if let Err(err) = result {
                     error!("{err}");
+                    registry.record_error(&err);
                 }
+                drop(permit);
             }
             .instrument(span),
         );
@@ -185,30 +474,97 @@ impl HttpPortContext {
     }
 }
 
-pub async fn start(
-    stream: BufStream<TcpStream>,
-    tls_client_config: Option<Arc<ClientConfig>>,
-    tls_acceptor: Option<TlsAcceptor>,
-    header_rewriter: HeaderRewriter,
-    router: Arc<Router>,
-    round_robin_counter: usize,
+async fn start_fallback<S: Socket>(
+    mut stream: BufStream<S>,
+    mut params: tcp::ProxyParams,
+    connections: ConnectionRegistry,
+    opts: tcp::StreamOptions,
     stop_notifier: Arc<Notify>,
 ) -> anyhow::Result<()> {
-    let remote = stream.get_ref().peer_addr()?;
+    if params.candidates.is_empty() {
+        stream.get_mut().shutdown().await?;
+        return Ok(());
+    }
+    // The client config negotiates http protocols, which a raw tcp upstream does not expect.
+    params.tls_client_config = params.tls_client_config.map(|config| {
+        let mut config = ClientConfig::clone(&config);
+        config.alpn_protocols.clear();
+        Arc::new(config)
+    });
+    tcp::start(stream, params, connections, opts, stop_notifier).await
+}
+
+/// The client and the routing of a connection served by [`start`].
+#[derive(Default)]
+pub struct HttpParams {
+    /// Takes the place of the peer address of the stream.
+    pub client_addr: Option<SocketAddr>,
+    pub tls_client_config: Option<Arc<ClientConfig>>,
+    pub tls_acceptor: Option<BoundedAcceptor>,
+    /// Only added to responses when `tls_acceptor` is set.
+    pub hsts: Option<HeaderValue>,
+    pub trace_context: bool,
+    pub happy_eyeballs_delay: Duration,
+    pub client_cert_forwarder: ClientCertForwarder,
+    pub router: Arc<Router>,
+    pub round_robin_counter: usize,
+}
+
+/// Serves HTTP on the stream.
+pub async fn start<S: Socket>(
+    stream: BufStream<S>,
+    params: HttpParams,
+    connections: ConnectionRegistry,
+    stop_notifier: Arc<Notify>,
+) -> anyhow::Result<()> {
+    let HttpParams {
+        client_addr,
+        tls_client_config,
+        tls_acceptor,
+        hsts,
+        trace_context,
+        happy_eyeballs_delay,
+        client_cert_forwarder,
+        router,
+        round_robin_counter,
+    } = params;
+    let remote = match client_addr {
+        Some(addr) => addr,
+        None => stream.get_ref().peer_addr()?,
+    };
     let local = stream.get_ref().local_addr()?;
+    let mut active = connections.register(remote, local);
 
-    let mut stream: Box<dyn IoStream> = Box::new(stream);
+    let mut stream: Box<dyn IoStream> = Box::new(active.track(stream, Side::Client));
     let mut server_http2 = false;
     let mut sni = None;
+    let mut served_cert = None;
+    let mut client_cert = None;
+    let hsts = hsts.filter(|_| tls_acceptor.is_some());
 
     if let Some(acceptor) = tls_acceptor {
         debug!(%remote, "server: tls handshake");
-        let accepted = acceptor.accept(stream).await?;
+        let accepted;
+        (accepted, served_cert) = acceptor.accept_with_cert(stream).await?;
         let tls_conn = &accepted.get_ref().1;
         server_http2 = tls_conn.alpn_protocol() == Some(b"h2");
         sni = tls_conn.server_name().map(|sni| sni.to_string());
+        client_cert = acceptor.client_cert(&accepted);
         stream = Box::new(accepted);
     }
+    if let Some(cert) = &served_cert {
+        active.set_served_cert(cert);
+    }
+    if let Some(cert) = &client_cert {
+        active.set_client_cert(&cert.subject);
+    }
+
+    let remote_name = active.remote_name();
+    let header_rewriter = HeaderRewriter::builder()
+        .trust_upstream_headers(false)
+        .use_std_forwarded(true)
+        .set_via(HeaderValue::from_static("taxy"))
+        .build();
 
     let router = router.clone();
     let stop_notifier_clone = stop_notifier.clone();
@@ -252,29 +608,60 @@ pub async fn start(
 
         header_rewriter.pre_process(req.headers_mut(), remote.ip());
         header_rewriter.post_process(req.headers_mut());
+        client_cert_forwarder.apply(req.headers_mut(), client_cert.as_ref());
+
+        let trace_id = trace_context.then(|| {
+            let incoming = req
+                .headers()
+                .get(TRACEPARENT)
+                .and_then(|value| value.to_str().ok())
+                .and_then(TraceParent::parse);
+            let trace = match incoming {
+                Some(parent) => parent.child(),
+                None => {
+                    req.headers_mut().remove(TRACESTATE);
+                    TraceParent::generate()
+                }
+            };
+            req.headers_mut().insert(
+                TRACEPARENT,
+                HeaderValue::from_str(&trace.to_string()).unwrap(),
+            );
+            trace.trace_id()
+        });
 
         let stop_notifier = stop_notifier_clone.clone();
-        async move {
+        let served_cert = served_cert.clone();
+        let client_cert = client_cert.as_ref().map(|cert| cert.subject.clone());
+        let remote_name = remote_name.clone();
+        let hsts = hsts.clone();
+        let sni = sni.clone();
+        let response = async move {
             if hostname.is_empty() || domain_fronting {
                 let mut res = hyper::Response::new(hyper::Body::empty());
                 *res.status_mut() = hyper::StatusCode::BAD_GATEWAY;
                 return Ok::<_, anyhow::Error>(res);
             }
 
-            let resolved = net::lookup_host(&host).await?.next().unwrap();
-            debug!(host, %resolved);
-
-            info!(target: "taxy::access_log", remote = %remote, %local, %resolved);
-
-            let sock = if resolved.is_ipv4() {
-                TcpSocket::new_v4()
-            } else {
-                TcpSocket::new_v6()
-            }?;
+            let addrs = happy_eyeballs::interleave(net::lookup_host(&host).await?.collect());
+            debug!(host, ?addrs);
 
-            let out = sock.connect(resolved).await?;
+            let (resolved, out) =
+                happy_eyeballs::connect(addrs, happy_eyeballs_delay, |addr| async move {
+                    let sock = if addr.is_ipv4() {
+                        TcpSocket::new_v4()
+                    } else {
+                        TcpSocket::new_v6()
+                    }?;
+                    sock.connect(addr).await
+                })
+                .await?;
             debug!(%resolved, "connected");
 
+            let remote_name = remote_name.map(redact_host);
+            let sni = sni.map(redact_host);
+            info!(target: "taxy::access_log", remote = %remote, remote_name, %local, host = redact_host(hostname.clone()), sni, %resolved, served_cert, client_cert, trace_id);
+
             let mut client_http2 = false;
 
             let mut out: Box<dyn IoStream> = Box::new(out);
@@ -315,42 +702,44 @@ pub async fn start(
             });
 
             Result::<_, anyhow::Error>::Ok(sender.send_request(req).await?)
+        };
+        async move {
+            let mut res = response.await?;
+            if let Some(hsts) = hsts {
+                res.headers_mut().insert(STRICT_TRANSPORT_SECURITY, hsts);
+            }
+            Result::<_, anyhow::Error>::Ok(res)
         }
     });
 
-    tokio::task::spawn(async move {
-        let http = Http::new()
-            .http2_only(server_http2)
-            .serve_connection(stream, service)
-            .with_upgrades();
-        tokio::select! {
-            result = http => {
-                if let Err(err) = result {
+    let http = Http::new()
+        .http2_only(server_http2)
+        .serve_connection(stream, service)
+        .with_upgrades();
+    let outcome = tokio::select! {
+        result = http => {
+            match result {
+                Err(err) => {
                     error!("Failed to serve the connection: {:?}", err);
+                    ConnectionOutcome::Error
                 }
-            },
-            _ = stop_notifier.notified() => {
-                debug!("stop");
-            },
-        }
-    });
+                Ok(()) => ConnectionOutcome::ClientClosed,
+            }
+        },
+        _ = stop_notifier.notified() => {
+            debug!("stop");
+            ConnectionOutcome::Stopped
+        },
+        _ = active.shutdown_idle() => {
+            debug!("shutdown");
+            ConnectionOutcome::Stopped
+        },
+    };
+    active.set_outcome(outcome);
 
     Ok(())
 }
 
-fn multiaddr_to_tcp(addr: &Multiaddr) -> Result<SocketAddr, Error> {
-    let stack = addr.iter().collect::<Vec<_>>();
-    match &stack[..] {
-        [Protocol::Ip4(addr), Protocol::Tcp(port), ..] if *port > 0 => {
-            Ok(SocketAddr::new(std::net::IpAddr::V4(*addr), *port))
-        }
-        [Protocol::Ip6(addr), Protocol::Tcp(port), ..] if *port > 0 => {
-            Ok(SocketAddr::new(std::net::IpAddr::V6(*addr), *port))
-        }
-        _ => Err(Error::InvalidListeningAddress { addr: addr.clone() }),
-    }
-}
-
 pub trait IoStream: AsyncRead + AsyncWrite + Unpin + Send {}
 
 impl<S> IoStream for S where S: AsyncRead + AsyncWrite + Unpin + Send {}
@@ -361,3 +750,334 @@ pub struct Connection {
     pub port: u16,
     pub tls: bool,
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::keyring::{certs::Cert, KeyringItem};
+    use crate::testing;
+    use hyper::{service::service_fn, Body, Request, Response, Server};
+    use std::convert::Infallible;
+    use taxy_api::{
+        port::Hsts,
+        site::{Route, Site},
+        tls::{ClientAuth, ClientAuthMode, ClientCertHeaders},
+    };
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_rustls::rustls::{Certificate, PrivateKey};
+
+    async fn request(tls: bool, hsts: &Hsts) -> Response<Body> {
+        let upstream = Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(
+            hyper::service::make_service_fn(|_| async {
+                Ok::<_, Infallible>(service_fn(|_| async {
+                    Ok::<_, Infallible>(Response::new(Body::from("ok")))
+                }))
+            }),
+        );
+        let upstream_addr = upstream.local_addr();
+        tokio::spawn(upstream);
+
+        let router = Arc::new(Router::new(vec![SiteEntry {
+            id: "test".into(),
+            site: Site {
+                ports: vec![],
+                vhosts: vec![],
+                routes: vec![Route {
+                    path: "/".into(),
+                    servers: vec![taxy_api::site::Server {
+                        url: format!("http://{upstream_addr}/").parse().unwrap(),
+                    }],
+                }],
+            },
+        }]));
+
+        let cert = testing::self_signed("localhost");
+        let config = taxy_api::tls::TlsTermination {
+            server_names: vec!["localhost".into()],
+            ..Default::default()
+        };
+        let mut termination = TlsTermination::new(&config, vec![], Default::default()).unwrap();
+        termination
+            .setup(&Keyring::new([KeyringItem::ServerCert(cert.clone())]))
+            .await;
+        let tls_acceptor = termination.acceptor.filter(|_| tls);
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_addr = listener.local_addr().unwrap();
+        let hsts = HeaderValue::from_str(&hsts.header_value()).ok();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            start(
+                BufStream::new(stream),
+                HttpParams {
+                    tls_acceptor,
+                    hsts,
+                    happy_eyeballs_delay: DEFAULT_HAPPY_EYEBALLS_DELAY,
+                    router,
+                    ..Default::default()
+                },
+                Default::default(),
+                Arc::new(Notify::new()),
+            )
+            .await
+        });
+
+        let stream = TcpStream::connect(proxy_addr).await.unwrap();
+        let stream: Box<dyn IoStream> = if tls {
+            let client_config = testing::client_config(&cert);
+            Box::new(
+                TlsConnector::from(Arc::new(client_config))
+                    .connect(ServerName::try_from("localhost").unwrap(), stream)
+                    .await
+                    .unwrap(),
+            )
+        } else {
+            Box::new(stream)
+        };
+
+        let (mut sender, conn) = client::conn::handshake(stream).await.unwrap();
+        tokio::spawn(conn);
+        let req = Request::get("/")
+            .header(HOST, format!("localhost:{}", proxy_addr.port()))
+            .body(Body::empty())
+            .unwrap();
+        sender.send_request(req).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_hsts() {
+        let hsts = Hsts {
+            max_age: std::time::Duration::from_secs(3600),
+            include_subdomains: true,
+            preload: true,
+        };
+
+        let res = request(true, &hsts).await;
+        assert_eq!(res.status(), hyper::StatusCode::OK);
+        assert_eq!(
+            res.headers().get(STRICT_TRANSPORT_SECURITY).unwrap(),
+            "max-age=3600; includeSubDomains; preload"
+        );
+
+        let res = request(false, &hsts).await;
+        assert_eq!(res.status(), hyper::StatusCode::OK);
+        assert!(res.headers().get(STRICT_TRANSPORT_SECURITY).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_trace_context() {
+        let upstream = Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(
+            hyper::service::make_service_fn(|_| async {
+                Ok::<_, Infallible>(service_fn(|req: Request<Body>| async move {
+                    let traceparent = req.headers().get(TRACEPARENT).cloned();
+                    let body = traceparent
+                        .map(|value| Body::from(value.to_str().unwrap().to_string()))
+                        .unwrap_or_default();
+                    Ok::<_, Infallible>(Response::new(body))
+                }))
+            }),
+        );
+        let upstream_addr = upstream.local_addr();
+        tokio::spawn(upstream);
+
+        let router = Arc::new(Router::new(vec![SiteEntry {
+            id: "test".into(),
+            site: Site {
+                ports: vec![],
+                vhosts: vec![],
+                routes: vec![Route {
+                    path: "/".into(),
+                    servers: vec![taxy_api::site::Server {
+                        url: format!("http://{upstream_addr}/").parse().unwrap(),
+                    }],
+                }],
+            },
+        }]));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            start(
+                BufStream::new(stream),
+                HttpParams {
+                    trace_context: true,
+                    happy_eyeballs_delay: DEFAULT_HAPPY_EYEBALLS_DELAY,
+                    router,
+                    ..Default::default()
+                },
+                Default::default(),
+                Arc::new(Notify::new()),
+            )
+            .await
+        });
+
+        let stream = TcpStream::connect(proxy_addr).await.unwrap();
+        let (mut sender, conn) = client::conn::handshake(stream).await.unwrap();
+        tokio::spawn(conn);
+
+        let mut traceparent = |incoming: Option<&str>| {
+            let mut req =
+                Request::get("/").header(HOST, format!("localhost:{}", proxy_addr.port()));
+            if let Some(incoming) = incoming {
+                req = req.header(TRACEPARENT, incoming);
+            }
+            let res = sender.send_request(req.body(Body::empty()).unwrap());
+            async move {
+                let body = hyper::body::to_bytes(res.await.unwrap().into_body())
+                    .await
+                    .unwrap();
+                TraceParent::parse(std::str::from_utf8(&body).unwrap()).unwrap()
+            }
+        };
+
+        traceparent(None).await;
+
+        let incoming = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+        let propagated = traceparent(Some(incoming)).await;
+        assert_eq!(propagated.trace_id(), "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_ne!(propagated.to_string(), incoming);
+    }
+
+    async fn client_cert_request(with_cert: bool) -> (Response<Body>, Arc<Cert>) {
+        let upstream = Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(
+            hyper::service::make_service_fn(|_| async {
+                Ok::<_, Infallible>(service_fn(|req: Request<Body>| async move {
+                    let mut res = Response::new(Body::empty());
+                    for (name, value) in req.headers() {
+                        if name.as_str().starts_with("x-client-cert") {
+                            res.headers_mut().append(name, value.clone());
+                        }
+                    }
+                    Ok::<_, Infallible>(res)
+                }))
+            }),
+        );
+        let upstream_addr = upstream.local_addr();
+        tokio::spawn(upstream);
+
+        let router = Arc::new(Router::new(vec![SiteEntry {
+            id: "test".into(),
+            site: Site {
+                ports: vec![],
+                vhosts: vec![],
+                routes: vec![Route {
+                    path: "/".into(),
+                    servers: vec![taxy_api::site::Server {
+                        url: format!("http://{upstream_addr}/").parse().unwrap(),
+                    }],
+                }],
+            },
+        }]));
+
+        let server_cert = testing::self_signed("localhost");
+        let trusted_cert = testing::self_signed("client.example.com");
+        let client_auth = ClientAuth {
+            mode: ClientAuthMode::Optional,
+            trusted_certs: vec![trusted_cert.id().to_string()],
+            forward_headers: ClientCertHeaders {
+                cert: Some("X-Client-Cert".into()),
+                subject: Some("X-Client-Cert-Subject".into()),
+                san: Some("X-Client-Cert-San".into()),
+                fingerprint: Some("X-Client-Cert-Fingerprint".into()),
+            },
+            sni_modes: vec![],
+        };
+        let forwarder = ClientCertForwarder::new(&client_auth.forward_headers).unwrap();
+        let config = taxy_api::tls::TlsTermination {
+            server_names: vec!["localhost".into()],
+            client_auth: Some(client_auth),
+            ..Default::default()
+        };
+        let mut termination = TlsTermination::new(&config, vec![], Default::default()).unwrap();
+        termination
+            .setup(&Keyring::new([
+                KeyringItem::ServerCert(server_cert.clone()),
+                KeyringItem::ServerCert(trusted_cert.clone()),
+            ]))
+            .await;
+        let tls_acceptor = termination.acceptor;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            start(
+                BufStream::new(stream),
+                HttpParams {
+                    tls_acceptor,
+                    happy_eyeballs_delay: DEFAULT_HAPPY_EYEBALLS_DELAY,
+                    client_cert_forwarder: forwarder,
+                    router,
+                    ..Default::default()
+                },
+                Default::default(),
+                Arc::new(Notify::new()),
+            )
+            .await
+        });
+
+        let builder = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(testing::root_certs(&server_cert));
+        let client_config = if with_cert {
+            let chain = rustls_pemfile::certs(&mut trusted_cert.raw_chain.as_slice())
+                .unwrap()
+                .into_iter()
+                .map(Certificate)
+                .collect();
+            let key = rustls_pemfile::pkcs8_private_keys(&mut trusted_cert.raw_key.as_slice())
+                .unwrap()
+                .remove(0);
+            builder.with_single_cert(chain, PrivateKey(key)).unwrap()
+        } else {
+            builder.with_no_client_auth()
+        };
+
+        let stream = TcpStream::connect(proxy_addr).await.unwrap();
+        let stream = TlsConnector::from(Arc::new(client_config))
+            .connect(ServerName::try_from("localhost").unwrap(), stream)
+            .await
+            .unwrap();
+        let (mut sender, conn) = client::conn::handshake(stream).await.unwrap();
+        tokio::spawn(conn);
+        let req = Request::get("/")
+            .header(HOST, format!("localhost:{}", proxy_addr.port()))
+            .header("X-Client-Cert", "spoofed")
+            .header("X-Client-Cert-Subject", "CN=admin")
+            .header("X-Client-Cert-Subject", "CN=root")
+            .body(Body::empty())
+            .unwrap();
+        (sender.send_request(req).await.unwrap(), trusted_cert)
+    }
+
+    #[tokio::test]
+    async fn test_client_cert_headers() {
+        let (res, _) = client_cert_request(false).await;
+        assert_eq!(res.status(), hyper::StatusCode::OK);
+        assert!(!res
+            .headers()
+            .keys()
+            .any(|name| name.as_str().starts_with("x-client-cert")));
+
+        let (res, cert) = client_cert_request(true).await;
+        assert_eq!(res.status(), hyper::StatusCode::OK);
+        let headers = res.headers();
+        assert_eq!(headers.get_all("x-client-cert-subject").iter().count(), 1);
+        assert_eq!(headers["x-client-cert-subject"], "CN=client.example.com");
+        assert_eq!(headers["x-client-cert-san"], "client.example.com");
+        assert_eq!(
+            headers["x-client-cert-fingerprint"],
+            cert.fingerprint.as_str()
+        );
+
+        let pem = percent_encoding::percent_decode_str(headers["x-client-cert"].to_str().unwrap())
+            .decode_utf8()
+            .unwrap();
+        let leaf = rustls_pemfile::certs(&mut cert.raw_chain.as_slice()).unwrap();
+        assert_eq!(
+            rustls_pemfile::certs(&mut pem.as_bytes()).unwrap(),
+            vec![leaf[0].clone()]
+        );
+    }
+}
@@ -1,17 +1,44 @@
-use self::{http::HttpPortContext, tcp::TcpPortContext};
-use crate::keyring::Keyring;
+use self::{
+    connections::ConnectionRegistry, health::HealthEvents, http::HttpPortContext,
+    tcp::TcpPortContext, tls::TlsTermination, udp::UdpPortContext,
+};
+use crate::{command::ServerCommand, keyring::Keyring};
 use multiaddr::{Multiaddr, Protocol};
-use once_cell::sync::OnceCell;
+use std::time::Duration;
+use taxy_api::app::Source;
 use taxy_api::error::Error;
-use taxy_api::port::{PortStatus, SocketState};
+use taxy_api::port::{ConnectionInfo, PortStatus, SocketState, UpstreamDistribution};
 use taxy_api::{
     port::{Port, PortEntry},
     site::SiteEntry,
+    tls::TlsMode,
 };
+use tokio::sync::mpsc;
 
+pub mod bind;
+pub mod client_hello;
+pub mod compress;
+pub mod conn_limit;
+pub mod connections;
+pub mod deadline;
+pub mod dns;
+pub mod happy_eyeballs;
+pub mod health;
 pub mod http;
+pub mod proxy_protocol;
+pub mod rate_limit;
+pub mod rdns;
+pub mod rewrite;
+pub mod shedding;
+pub mod sniff;
+pub mod socket;
 pub mod tcp;
 pub mod tls;
+pub mod tls_close;
+pub mod tls_params;
+pub mod trace;
+pub mod udp;
+pub mod upstream_tls;
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum PortContextEvent {
@@ -22,17 +49,23 @@ pub enum PortContextEvent {
 pub struct PortContext {
     pub entry: PortEntry,
     pub kind: PortContextKind,
+    pub source: Source,
 }
 
 impl PortContext {
     pub fn new(entry: PortEntry) -> Result<Self, Error> {
         let kind = match entry.port.listen.into_iter().last() {
             Some(Protocol::Http) | Some(Protocol::Https) => {
-                PortContextKind::Http(HttpPortContext::new(&entry)?)
+                PortContextKind::Http(Box::new(HttpPortContext::new(&entry)?))
             }
-            _ => PortContextKind::Tcp(TcpPortContext::new(&entry)?),
+            Some(Protocol::Udp(_)) => PortContextKind::Udp(Box::new(UdpPortContext::new(&entry)?)),
+            _ => PortContextKind::Tcp(Box::new(TcpPortContext::new(&entry)?)),
         };
-        Ok(Self { entry, kind })
+        Ok(Self {
+            entry,
+            kind,
+            source: Source::File,
+        })
     }
 
     pub fn reserved() -> Self {
@@ -45,6 +78,7 @@ impl PortContext {
                 },
             },
             kind: PortContextKind::Reserved,
+            source: Source::File,
         }
     }
 
@@ -64,58 +98,142 @@ impl PortContext {
         match &mut self.kind {
             PortContextKind::Tcp(ctx) => ctx.setup(keyring, sites).await,
             PortContextKind::Http(ctx) => ctx.setup(keyring, sites).await,
-            PortContextKind::Reserved => Ok(()),
+            PortContextKind::Udp(_) | PortContextKind::Reserved => Ok(()),
         }
     }
 
-    pub async fn refresh(&mut self, certs: &Keyring) -> Result<(), Error> {
+    /// Reports the upstream health changes of the port to the server.
+    pub fn set_command_sender(&mut self, sender: mpsc::Sender<ServerCommand>) {
+        if let PortContextKind::Tcp(ctx) = &mut self.kind {
+            ctx.set_health_events(HealthEvents {
+                port_id: self.entry.id.clone(),
+                sender,
+            });
+        }
+    }
+
+    pub async fn refresh(&mut self, certs: &Keyring, strict: bool) -> Result<(), Error> {
         match &mut self.kind {
-            PortContextKind::Tcp(ctx) => ctx.refresh(certs).await,
-            PortContextKind::Http(ctx) => ctx.refresh(certs).await,
-            PortContextKind::Reserved => Ok(()),
+            PortContextKind::Tcp(ctx) => ctx.refresh(certs, strict).await,
+            PortContextKind::Http(ctx) => ctx.refresh(certs, strict).await,
+            PortContextKind::Udp(_) | PortContextKind::Reserved => Ok(()),
         }
     }
 
     pub fn apply(&mut self, new: Self) {
         match (&mut self.kind, new.kind) {
-            (PortContextKind::Tcp(old), PortContextKind::Tcp(new)) => old.apply(new),
-            (PortContextKind::Http(old), PortContextKind::Http(new)) => old.apply(new),
+            (PortContextKind::Tcp(old), PortContextKind::Tcp(new)) => old.apply(*new),
+            (PortContextKind::Http(old), PortContextKind::Http(new)) => old.apply(*new),
+            (PortContextKind::Udp(old), PortContextKind::Udp(new)) => old.apply(*new),
             (old, new) => *old = new,
         }
         self.entry = new.entry;
+        self.source = new.source;
     }
 
     pub fn event(&mut self, event: PortContextEvent) {
         match &mut self.kind {
             PortContextKind::Tcp(ctx) => ctx.event(event),
             PortContextKind::Http(ctx) => ctx.event(event),
+            PortContextKind::Udp(ctx) => ctx.event(event),
             PortContextKind::Reserved => (),
         }
     }
 
-    pub fn status(&self) -> &PortStatus {
-        match &self.kind {
+    pub fn status(&self) -> PortStatus {
+        let status = match &self.kind {
             PortContextKind::Tcp(ctx) => ctx.status(),
-            PortContextKind::Http(ctx) => ctx.status(),
-            PortContextKind::Reserved => {
-                static STATUS: OnceCell<PortStatus> = OnceCell::new();
-                STATUS.get_or_init(PortStatus::default)
-            }
+            PortContextKind::Http(ctx) => *ctx.status(),
+            PortContextKind::Udp(ctx) => *ctx.status(),
+            PortContextKind::Reserved => PortStatus::default(),
+        };
+        PortStatus {
+            source: self.source,
+            ..status
+        }
+    }
+
+    pub fn connection_registry(&self) -> Option<&ConnectionRegistry> {
+        match &self.kind {
+            PortContextKind::Tcp(ctx) => Some(ctx.connections()),
+            PortContextKind::Http(ctx) => Some(ctx.connections()),
+            PortContextKind::Udp(ctx) => Some(ctx.connections()),
+            PortContextKind::Reserved => None,
+        }
+    }
+
+    pub fn tls_termination(&self) -> Option<&TlsTermination> {
+        match &self.kind {
+            PortContextKind::Tcp(ctx) => ctx.tls_termination(),
+            PortContextKind::Http(ctx) => ctx.tls_termination(),
+            PortContextKind::Udp(_) | PortContextKind::Reserved => None,
         }
     }
 
+    pub fn connections(&self) -> Vec<ConnectionInfo> {
+        self.connection_registry()
+            .map(|registry| registry.snapshot())
+            .unwrap_or_default()
+    }
+
     pub fn reset(&mut self) {
         match &mut self.kind {
             PortContextKind::Tcp(ctx) => ctx.reset(),
             PortContextKind::Http(ctx) => ctx.reset(),
+            PortContextKind::Udp(ctx) => ctx.reset(),
+            PortContextKind::Reserved => (),
+        }
+    }
+
+    pub fn drain(&mut self, timeout: Duration) {
+        match &mut self.kind {
+            PortContextKind::Tcp(ctx) => ctx.drain(timeout),
+            PortContextKind::Http(ctx) => ctx.drain(timeout),
+            PortContextKind::Udp(ctx) => ctx.drain(timeout),
             PortContextKind::Reserved => (),
         }
     }
+
+    pub fn upstreams(&self) -> &[tcp::Connection] {
+        match &self.kind {
+            PortContextKind::Tcp(ctx) => ctx.upstreams(),
+            PortContextKind::Http(ctx) => ctx.upstreams(),
+            PortContextKind::Udp(_) | PortContextKind::Reserved => &[],
+        }
+    }
+
+    /// Returns the distribution of the recent connections of raw TCP ports among their upstreams.
+    pub fn upstream_distribution(&self) -> Vec<UpstreamDistribution> {
+        match &self.kind {
+            PortContextKind::Tcp(ctx) => ctx.upstream_distribution(),
+            PortContextKind::Http(_) | PortContextKind::Udp(_) | PortContextKind::Reserved => {
+                vec![]
+            }
+        }
+    }
+
+    pub fn set_tls_mode(&mut self, mode: TlsMode) -> Result<(), Error> {
+        match &mut self.kind {
+            PortContextKind::Tcp(ctx) => ctx.set_tls_mode(mode),
+            PortContextKind::Http(_) | PortContextKind::Udp(_) | PortContextKind::Reserved => {
+                Err(Error::TlsModeNotSupported)
+            }
+        }
+    }
+
+    pub fn drain_upstream(&self, addr: &Multiaddr) {
+        match &self.kind {
+            PortContextKind::Tcp(ctx) => ctx.drain_upstream(addr),
+            PortContextKind::Http(ctx) => ctx.drain_upstream(addr),
+            PortContextKind::Udp(_) | PortContextKind::Reserved => (),
+        }
+    }
 }
 
 #[derive(Debug)]
 pub enum PortContextKind {
-    Tcp(TcpPortContext),
-    Http(HttpPortContext),
+    Tcp(Box<TcpPortContext>),
+    Http(Box<HttpPortContext>),
+    Udp(Box<UdpPortContext>),
     Reserved,
 }
@@ -0,0 +1,316 @@
+use std::{
+    io,
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+use taxy_api::port::ProxyProtocol;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt};
+
+/// How long the load balancer has to send the header of an inbound connection.
+pub const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+const V1_MAX_LEN: u64 = 107;
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+const V2_LOCAL_COMMAND: u8 = 0x20;
+const V2_PROXY_COMMAND: u8 = 0x21;
+const V2_TCP4: u8 = 0x11;
+const V2_TCP6: u8 = 0x21;
+const V2_TLV_ALPN: u8 = 0x01;
+
+/// Encodes the PROXY protocol header announcing a connection from `src` to `dst`.
+///
+/// The ALPN protocol is only sent with v2, as v1 has no room for it. Mixed address families
+/// are sent as IPv6, with the IPv4 address mapped.
+pub fn encode_header(
+    version: ProxyProtocol,
+    src: SocketAddr,
+    dst: SocketAddr,
+    alpn: Option<&[u8]>,
+) -> Vec<u8> {
+    let (src_ip, dst_ip) = match (src.ip(), dst.ip()) {
+        (IpAddr::V6(src), IpAddr::V4(dst)) => (IpAddr::V6(src), IpAddr::V6(dst.to_ipv6_mapped())),
+        (IpAddr::V4(src), IpAddr::V6(dst)) => (IpAddr::V6(src.to_ipv6_mapped()), IpAddr::V6(dst)),
+        pair => pair,
+    };
+    match version {
+        ProxyProtocol::V1 => {
+            let family = if src_ip.is_ipv4() { "TCP4" } else { "TCP6" };
+            format!(
+                "PROXY {family} {src_ip} {dst_ip} {} {}\r\n",
+                src.port(),
+                dst.port()
+            )
+            .into_bytes()
+        }
+        ProxyProtocol::V2 => {
+            let mut addrs = Vec::with_capacity(36);
+            let family = match (src_ip, dst_ip) {
+                (IpAddr::V4(src), IpAddr::V4(dst)) => {
+                    addrs.extend_from_slice(&src.octets());
+                    addrs.extend_from_slice(&dst.octets());
+                    V2_TCP4
+                }
+                (IpAddr::V6(src), IpAddr::V6(dst)) => {
+                    addrs.extend_from_slice(&src.octets());
+                    addrs.extend_from_slice(&dst.octets());
+                    V2_TCP6
+                }
+                _ => unreachable!(),
+            };
+            addrs.extend_from_slice(&src.port().to_be_bytes());
+            addrs.extend_from_slice(&dst.port().to_be_bytes());
+            if let Some(alpn) = alpn {
+                addrs.push(V2_TLV_ALPN);
+                addrs.extend_from_slice(&(alpn.len() as u16).to_be_bytes());
+                addrs.extend_from_slice(alpn);
+            }
+
+            let mut header = Vec::with_capacity(16 + addrs.len());
+            header.extend_from_slice(V2_SIGNATURE);
+            header.push(V2_PROXY_COMMAND);
+            header.push(family);
+            header.extend_from_slice(&(addrs.len() as u16).to_be_bytes());
+            header.extend_from_slice(&addrs);
+            header
+        }
+    }
+}
+
+/// Reads a v1 or v2 PROXY protocol header off the stream, returning the announced client address.
+///
+/// Headers which do not announce a TCP client, such as health checks of the load balancer,
+/// return `None`. Fails with [`io::ErrorKind::InvalidData`] if the header is missing or malformed.
+pub async fn read_header<S>(stream: &mut S) -> io::Result<Option<SocketAddr>>
+where
+    S: AsyncBufRead + Unpin,
+{
+    let buf = stream.fill_buf().await?;
+    match buf.first() {
+        None => Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "connection closed before the proxy protocol header",
+        )),
+        Some(&b) if b == V1_PREFIX[0] => read_v1(stream).await,
+        Some(&b) if b == V2_SIGNATURE[0] => read_v2(stream).await,
+        Some(_) => Err(invalid_data("missing proxy protocol header")),
+    }
+}
+
+async fn read_v1<S>(stream: &mut S) -> io::Result<Option<SocketAddr>>
+where
+    S: AsyncBufRead + Unpin,
+{
+    let mut line = Vec::new();
+    stream.take(V1_MAX_LEN).read_until(b'\n', &mut line).await?;
+    let line = line
+        .strip_prefix(V1_PREFIX)
+        .and_then(|line| line.strip_suffix(b"\r\n"))
+        .and_then(|line| std::str::from_utf8(line).ok())
+        .ok_or_else(|| malformed("invalid v1 header line"))?;
+
+    let fields = line.split(' ').collect::<Vec<_>>();
+    match fields.as_slice() {
+        ["UNKNOWN", ..] => Ok(None),
+        [family @ ("TCP4" | "TCP6"), src, dst, src_port, dst_port] => {
+            let src = src
+                .parse::<IpAddr>()
+                .map_err(|_| malformed("invalid v1 source address"))?;
+            let dst = dst
+                .parse::<IpAddr>()
+                .map_err(|_| malformed("invalid v1 destination address"))?;
+            let is_ipv4 = *family == "TCP4";
+            if src.is_ipv4() != is_ipv4 || dst.is_ipv4() != is_ipv4 {
+                return Err(malformed("v1 address family mismatch"));
+            }
+            let port = |port: &str| {
+                port.parse::<u16>()
+                    .map_err(|_| malformed("invalid v1 port"))
+            };
+            port(dst_port)?;
+            Ok(Some(SocketAddr::new(src, port(src_port)?)))
+        }
+        _ => Err(malformed("invalid v1 header line")),
+    }
+}
+
+async fn read_v2<S>(stream: &mut S) -> io::Result<Option<SocketAddr>>
+where
+    S: AsyncBufRead + Unpin,
+{
+    let mut header = [0; 16];
+    stream.read_exact(&mut header).await?;
+    if &header[..12] != V2_SIGNATURE {
+        return Err(malformed("invalid v2 signature"));
+    }
+    let len = u16::from_be_bytes([header[14], header[15]]) as usize;
+    let mut body = vec![0; len];
+    stream.read_exact(&mut body).await?;
+
+    match header[12] {
+        V2_LOCAL_COMMAND => return Ok(None),
+        V2_PROXY_COMMAND => (),
+        _ => return Err(malformed("unsupported v2 version or command")),
+    }
+    match header[13] {
+        V2_TCP4 if len >= 12 => {
+            let ip = <[u8; 4]>::try_from(&body[0..4]).unwrap();
+            let port = u16::from_be_bytes([body[8], body[9]]);
+            Ok(Some(SocketAddr::new(ip.into(), port)))
+        }
+        V2_TCP6 if len >= 36 => {
+            let ip = <[u8; 16]>::try_from(&body[0..16]).unwrap();
+            let port = u16::from_be_bytes([body[32], body[33]]);
+            Ok(Some(SocketAddr::new(ip.into(), port)))
+        }
+        V2_TCP4 | V2_TCP6 => Err(malformed("truncated v2 address block")),
+        // UNSPEC, UDP and unix sockets announce no TCP client.
+        _ => Ok(None),
+    }
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn malformed(reason: &str) -> io::Error {
+    invalid_data(&format!("malformed proxy protocol header: {reason}"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    /// Parses a v2 header, returning the addresses, the ALPN TLV and the header length.
+    fn parse_v2(buf: &[u8]) -> (SocketAddr, SocketAddr, Option<Vec<u8>>, usize) {
+        assert_eq!(&buf[..12], V2_SIGNATURE);
+        assert_eq!(buf[12], V2_PROXY_COMMAND);
+        let len = u16::from_be_bytes([buf[14], buf[15]]) as usize;
+        let body = &buf[16..16 + len];
+        let (src, dst, mut rest) = match buf[13] {
+            V2_TCP4 => {
+                let ip = |b: &[u8]| IpAddr::from(<[u8; 4]>::try_from(b).unwrap());
+                let port = |b: &[u8]| u16::from_be_bytes([b[0], b[1]]);
+                (
+                    SocketAddr::new(ip(&body[0..4]), port(&body[8..10])),
+                    SocketAddr::new(ip(&body[4..8]), port(&body[10..12])),
+                    &body[12..],
+                )
+            }
+            V2_TCP6 => {
+                let ip = |b: &[u8]| IpAddr::from(<[u8; 16]>::try_from(b).unwrap());
+                let port = |b: &[u8]| u16::from_be_bytes([b[0], b[1]]);
+                (
+                    SocketAddr::new(ip(&body[0..16]), port(&body[32..34])),
+                    SocketAddr::new(ip(&body[16..32]), port(&body[34..36])),
+                    &body[36..],
+                )
+            }
+            family => panic!("unexpected family {family:#x}"),
+        };
+        let mut alpn = None;
+        while !rest.is_empty() {
+            let len = u16::from_be_bytes([rest[1], rest[2]]) as usize;
+            if rest[0] == V2_TLV_ALPN {
+                alpn = Some(rest[3..3 + len].to_vec());
+            }
+            rest = &rest[3 + len..];
+        }
+        (src, dst, alpn, 16 + len)
+    }
+
+    #[test]
+    fn test_v2_round_trip() {
+        let src = SocketAddr::new(Ipv4Addr::new(192, 168, 0, 2).into(), 51234);
+        let dst = SocketAddr::new(Ipv4Addr::new(10, 0, 0, 1).into(), 443);
+        let header = encode_header(ProxyProtocol::V2, src, dst, None);
+        assert_eq!(header.len(), 16 + 12);
+        assert_eq!(parse_v2(&header), (src, dst, None, header.len()));
+
+        let header = encode_header(ProxyProtocol::V2, src, dst, Some(b"h2"));
+        assert_eq!(
+            parse_v2(&header),
+            (src, dst, Some(b"h2".to_vec()), header.len())
+        );
+
+        let src = SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 51234);
+        let header = encode_header(ProxyProtocol::V2, src, dst, Some(b"http/1.1"));
+        let mapped = SocketAddr::new(Ipv4Addr::new(10, 0, 0, 1).to_ipv6_mapped().into(), 443);
+        assert_eq!(
+            parse_v2(&header),
+            (src, mapped, Some(b"http/1.1".to_vec()), header.len())
+        );
+    }
+
+    async fn read_bytes(data: &[u8]) -> (io::Result<Option<SocketAddr>>, Vec<u8>) {
+        let mut stream = tokio::io::BufReader::new(data);
+        let result = read_header(&mut stream).await;
+        let mut rest = Vec::new();
+        stream.read_to_end(&mut rest).await.unwrap();
+        (result, rest)
+    }
+
+    #[tokio::test]
+    async fn test_read_header() {
+        let src = SocketAddr::new(Ipv4Addr::new(192, 168, 0, 2).into(), 51234);
+        let dst = SocketAddr::new(Ipv4Addr::new(10, 0, 0, 1).into(), 443);
+        for version in [ProxyProtocol::V1, ProxyProtocol::V2] {
+            let mut data = encode_header(version, src, dst, Some(b"h2"));
+            data.extend_from_slice(b"hello");
+            let (result, rest) = read_bytes(&data).await;
+            assert_eq!(result.unwrap(), Some(src));
+            assert_eq!(rest, b"hello");
+        }
+
+        let src = SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 51234);
+        let header = encode_header(ProxyProtocol::V2, src, dst, None);
+        assert_eq!(read_bytes(&header).await.0.unwrap(), Some(src));
+
+        let (result, rest) = read_bytes(b"PROXY UNKNOWN\r\nhello").await;
+        assert_eq!(result.unwrap(), None);
+        assert_eq!(rest, b"hello");
+
+        let mut local = encode_header(ProxyProtocol::V2, src, dst, None);
+        local[12] = V2_LOCAL_COMMAND;
+        assert_eq!(read_bytes(&local).await.0.unwrap(), None);
+
+        let (result, _) = read_bytes(b"GET / HTTP/1.1\r\n\r\n").await;
+        let err = result.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(err.to_string(), "missing proxy protocol header");
+
+        for data in [
+            &b"PROXY TCP4 192.168.0.2 10.0.0.1 51234\r\n"[..],
+            b"PROXY TCP4 ::1 10.0.0.1 51234 443\r\n",
+            b"PROXY TCP4 192.168.0.2 10.0.0.1 51234 99999\r\n",
+            b"PROXY TCP4 192.168.0.2 10.0.0.1 51234 443\n",
+            b"\r\n\r\n\0\r\nQUIT!!!!!\0\0",
+        ] {
+            let err = read_bytes(data).await.0.unwrap_err();
+            assert!(
+                err.to_string()
+                    .starts_with("malformed proxy protocol header"),
+                "{err}"
+            );
+        }
+
+        let mut truncated = encode_header(ProxyProtocol::V2, src, dst, None);
+        truncated[15] = 4;
+        truncated.truncate(20);
+        let err = read_bytes(&truncated).await.0.unwrap_err();
+        assert!(err.to_string().contains("truncated"), "{err}");
+
+        assert!(read_bytes(b"").await.0.is_err());
+    }
+
+    #[test]
+    fn test_v1() {
+        let src = SocketAddr::new(Ipv4Addr::new(192, 168, 0, 2).into(), 51234);
+        let dst = SocketAddr::new(Ipv4Addr::new(10, 0, 0, 1).into(), 443);
+        assert_eq!(
+            encode_header(ProxyProtocol::V1, src, dst, Some(b"h2")),
+            b"PROXY TCP4 192.168.0.2 10.0.0.1 51234 443\r\n"
+        );
+    }
+}
@@ -0,0 +1,118 @@
+use std::time::Duration;
+use taxy_api::error::Error;
+use taxy_api::port::{ConnectionRate, RateExceededAction, RetryBudget};
+use tokio::time::Instant;
+
+/// Limits the rate of new connections of a port. Tracks the time at which the
+/// bucket would be full again instead of counting tokens.
+#[derive(Debug)]
+pub struct ConnectionRateLimiter {
+    interval: Duration,
+    burst_tolerance: Duration,
+    exceeded: RateExceededAction,
+    max_delay: Duration,
+    full_at: Option<Instant>,
+}
+
+impl ConnectionRateLimiter {
+    pub fn new(rate: &ConnectionRate) -> Result<Self, Error> {
+        let burst = rate.burst.unwrap_or(rate.per_second);
+        if rate.per_second == 0 || burst == 0 {
+            return Err(Error::InvalidConnectionRate);
+        }
+        let interval = Duration::from_secs(1) / rate.per_second;
+        Ok(Self {
+            interval,
+            burst_tolerance: interval * (burst - 1),
+            exceeded: rate.exceeded,
+            max_delay: rate.max_delay,
+            full_at: None,
+        })
+    }
+
+    /// Takes a token for a new connection. Returns the delay before the connection
+    /// may proceed, or `None` if the connection must be rejected.
+    pub fn acquire(&mut self, now: Instant) -> Option<Duration> {
+        let full_at = self.full_at.map_or(now, |full_at| full_at.max(now));
+        let allowed_at = full_at
+            .checked_sub(self.burst_tolerance)
+            .unwrap_or(now)
+            .max(now);
+        let delay = allowed_at - now;
+        if !delay.is_zero()
+            && (self.exceeded == RateExceededAction::Reject || delay > self.max_delay)
+        {
+            return None;
+        }
+        self.full_at = Some(full_at + self.interval);
+        Some(delay)
+    }
+}
+
+/// Limits the rate of upstream retries of a port.
+#[derive(Debug)]
+pub struct RetryLimiter {
+    budget: RetryBudget,
+    limiter: ConnectionRateLimiter,
+}
+
+impl RetryLimiter {
+    pub fn new(budget: &RetryBudget) -> Result<Self, Error> {
+        let limiter = ConnectionRateLimiter::new(&ConnectionRate {
+            per_second: budget.per_second,
+            burst: budget.burst,
+            exceeded: RateExceededAction::Reject,
+            max_delay: Duration::ZERO,
+        })
+        .map_err(|_| Error::InvalidRetryBudget)?;
+        Ok(Self {
+            budget: *budget,
+            limiter,
+        })
+    }
+
+    pub fn budget(&self) -> &RetryBudget {
+        &self.budget
+    }
+
+    /// Takes a token for a retry. Returns `false` if the budget is exhausted.
+    pub fn acquire(&mut self, now: Instant) -> bool {
+        self.limiter.acquire(now).is_some()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_acquire() {
+        let rate = ConnectionRate {
+            per_second: 10,
+            burst: Some(2),
+            exceeded: RateExceededAction::Reject,
+            max_delay: Duration::from_secs(1),
+        };
+        let mut limiter = ConnectionRateLimiter::new(&rate).unwrap();
+        let now = Instant::now();
+        assert_eq!(limiter.acquire(now), Some(Duration::ZERO));
+        assert_eq!(limiter.acquire(now), Some(Duration::ZERO));
+        assert_eq!(limiter.acquire(now), None);
+        let now = now + Duration::from_millis(100);
+        assert_eq!(limiter.acquire(now), Some(Duration::ZERO));
+        assert_eq!(limiter.acquire(now), None);
+
+        let mut limiter = ConnectionRateLimiter::new(&ConnectionRate {
+            exceeded: RateExceededAction::Delay,
+            max_delay: Duration::from_millis(250),
+            ..rate
+        })
+        .unwrap();
+        let now = Instant::now();
+        assert_eq!(limiter.acquire(now), Some(Duration::ZERO));
+        assert_eq!(limiter.acquire(now), Some(Duration::ZERO));
+        assert_eq!(limiter.acquire(now), Some(Duration::from_millis(100)));
+        assert_eq!(limiter.acquire(now), Some(Duration::from_millis(200)));
+        assert_eq!(limiter.acquire(now), None);
+    }
+}
@@ -0,0 +1,272 @@
+use indexmap::IndexMap;
+use std::{
+    fmt,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::net::UdpSocket;
+use tracing::debug;
+
+const CACHE_SIZE: usize = 1024;
+const CACHE_TTL: Duration = Duration::from_secs(600);
+const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+const RESOLV_CONF: &str = "/etc/resolv.conf";
+
+const DNS_TYPE_PTR: u16 = 12;
+const DNS_CLASS_IN: u16 = 1;
+const DNS_HEADER_LEN: usize = 12;
+const MAX_POINTER_HOPS: usize = 16;
+
+type Cache = IndexMap<IpAddr, (Instant, Option<String>)>;
+
+#[async_trait::async_trait]
+pub trait ReverseResolver: Send + Sync + 'static {
+    async fn resolve(&self, addr: IpAddr) -> Option<String>;
+}
+
+/// Sends PTR queries to the first nameserver in `/etc/resolv.conf`.
+#[derive(Debug, Default)]
+pub struct SystemResolver;
+
+#[async_trait::async_trait]
+impl ReverseResolver for SystemResolver {
+    async fn resolve(&self, addr: IpAddr) -> Option<String> {
+        let conf = tokio::fs::read_to_string(RESOLV_CONF).await.ok()?;
+        let server = conf.lines().find_map(|line| {
+            let mut words = line.split_whitespace();
+            if words.next() != Some("nameserver") {
+                return None;
+            }
+            words.next()?.parse::<IpAddr>().ok()
+        })?;
+        let server = SocketAddr::new(server, 53);
+
+        let id = rand::random::<u16>();
+        let bind: SocketAddr = if server.is_ipv4() {
+            "0.0.0.0:0".parse().unwrap()
+        } else {
+            "[::]:0".parse().unwrap()
+        };
+        let socket = UdpSocket::bind(bind).await.ok()?;
+        socket.connect(server).await.ok()?;
+        socket.send(&ptr_query(id, addr)).await.ok()?;
+
+        let mut buf = [0; 512];
+        let len = tokio::time::timeout(QUERY_TIMEOUT, socket.recv(&mut buf))
+            .await
+            .ok()?
+            .ok()?;
+        parse_ptr_response(id, &buf[..len])
+    }
+}
+
+fn ptr_name(addr: IpAddr) -> String {
+    match addr {
+        IpAddr::V4(addr) => {
+            let [a, b, c, d] = addr.octets();
+            format!("{d}.{c}.{b}.{a}.in-addr.arpa")
+        }
+        IpAddr::V6(addr) => {
+            let mut name = String::new();
+            for byte in addr.octets().iter().rev() {
+                name.push_str(&format!("{:x}.{:x}.", byte & 0xf, byte >> 4));
+            }
+            name + "ip6.arpa"
+        }
+    }
+}
+
+fn ptr_query(id: u16, addr: IpAddr) -> Vec<u8> {
+    let mut query = Vec::new();
+    query.extend_from_slice(&id.to_be_bytes());
+    // Recursion desired, one question.
+    query.extend_from_slice(&[0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+    for label in ptr_name(addr).split('.') {
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.push(0);
+    query.extend_from_slice(&DNS_TYPE_PTR.to_be_bytes());
+    query.extend_from_slice(&DNS_CLASS_IN.to_be_bytes());
+    query
+}
+
+fn parse_ptr_response(id: u16, msg: &[u8]) -> Option<String> {
+    if msg.len() < DNS_HEADER_LEN || msg[..2] != id.to_be_bytes() {
+        return None;
+    }
+    let rcode = msg[3] & 0x0f;
+    if rcode != 0 {
+        return None;
+    }
+    let questions = u16::from_be_bytes([msg[4], msg[5]]);
+    let answers = u16::from_be_bytes([msg[6], msg[7]]);
+
+    let mut pos = DNS_HEADER_LEN;
+    for _ in 0..questions {
+        pos = read_name(msg, pos)?.1 + 4;
+    }
+    for _ in 0..answers {
+        pos = read_name(msg, pos)?.1;
+        let header = msg.get(pos..pos + 10)?;
+        let rtype = u16::from_be_bytes([header[0], header[1]]);
+        let rdlength = u16::from_be_bytes([header[8], header[9]]) as usize;
+        pos += 10;
+        if rtype == DNS_TYPE_PTR {
+            return read_name(msg, pos).map(|(name, _)| name);
+        }
+        pos += rdlength;
+    }
+    None
+}
+
+/// Reads a possibly compressed name, returning it with the position following it.
+fn read_name(msg: &[u8], mut pos: usize) -> Option<(String, usize)> {
+    let mut labels = Vec::new();
+    let mut end = None;
+    let mut hops = 0;
+    loop {
+        let len = *msg.get(pos)? as usize;
+        if len & 0xc0 == 0xc0 {
+            let offset = ((len & 0x3f) << 8) | *msg.get(pos + 1)? as usize;
+            end.get_or_insert(pos + 2);
+            hops += 1;
+            if hops > MAX_POINTER_HOPS {
+                return None;
+            }
+            pos = offset;
+        } else if len == 0 {
+            let name = labels.join(".");
+            return Some((name, end.unwrap_or(pos + 1)));
+        } else {
+            let label = msg.get(pos + 1..pos + 1 + len)?;
+            labels.push(String::from_utf8_lossy(label).into_owned());
+            pos += 1 + len;
+        }
+    }
+}
+
+/// A bounded cache of reverse DNS lookups which never blocks the caller.
+#[derive(Clone)]
+pub struct ReverseDns {
+    resolver: Arc<dyn ReverseResolver>,
+    cache: Arc<Mutex<Cache>>,
+}
+
+impl fmt::Debug for ReverseDns {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ReverseDns").finish()
+    }
+}
+
+impl Default for ReverseDns {
+    fn default() -> Self {
+        Self::with_resolver(SystemResolver)
+    }
+}
+
+impl ReverseDns {
+    pub fn with_resolver<R: ReverseResolver>(resolver: R) -> Self {
+        Self {
+            resolver: Arc::new(resolver),
+            cache: Default::default(),
+        }
+    }
+
+    /// Returns the cached name of the address.
+    ///
+    /// On a miss the lookup runs in the background and `None` is returned,
+    /// so the name becomes available to subsequent connections.
+    pub fn lookup(&self, addr: IpAddr) -> Option<String> {
+        let mut cache = self.cache.lock().unwrap();
+        if let Some((resolved_at, name)) = cache.get(&addr) {
+            if resolved_at.elapsed() < CACHE_TTL {
+                return name.clone();
+            }
+        }
+        if cache.len() >= CACHE_SIZE {
+            cache.shift_remove_index(0);
+        }
+        // Negative entry until the lookup finishes, so that concurrent
+        // connections from the same address don't trigger more lookups.
+        cache.insert(addr, (Instant::now(), None));
+        drop(cache);
+
+        let resolver = self.resolver.clone();
+        let cache = self.cache.clone();
+        tokio::spawn(async move {
+            let name = resolver.resolve(addr).await;
+            debug!(%addr, ?name, "reverse dns lookup");
+            let mut cache = cache.lock().unwrap();
+            if let Some(entry) = cache.get_mut(&addr) {
+                *entry = (Instant::now(), name);
+            }
+        });
+        None
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use std::collections::HashMap;
+
+    pub struct StubResolver(pub HashMap<IpAddr, String>);
+
+    #[async_trait::async_trait]
+    impl ReverseResolver for StubResolver {
+        async fn resolve(&self, addr: IpAddr) -> Option<String> {
+            self.0.get(&addr).cloned()
+        }
+    }
+
+    #[test]
+    fn test_parse_ptr_response() {
+        let addr = IpAddr::from([192, 0, 2, 1]);
+        let mut msg = ptr_query(7, addr);
+        msg[2] = 0x81;
+        msg[3] = 0x80;
+        msg[7] = 1;
+        // Answer pointing back to the question name.
+        msg.extend_from_slice(&[0xc0, 0x0c]);
+        msg.extend_from_slice(&DNS_TYPE_PTR.to_be_bytes());
+        msg.extend_from_slice(&DNS_CLASS_IN.to_be_bytes());
+        msg.extend_from_slice(&300u32.to_be_bytes());
+        let rdata = b"\x06client\x07example\x03com\x00";
+        msg.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+        msg.extend_from_slice(rdata);
+
+        assert_eq!(
+            parse_ptr_response(7, &msg).as_deref(),
+            Some("client.example.com")
+        );
+        assert_eq!(parse_ptr_response(8, &msg), None);
+        assert_eq!(
+            ptr_name("2001:db8::1".parse().unwrap()),
+            "1.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.8.b.d.0.1.0.0.2.ip6.arpa"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_lookup() {
+        let known = IpAddr::from([192, 0, 2, 1]);
+        let unknown = IpAddr::from([192, 0, 2, 2]);
+        let rdns = ReverseDns::with_resolver(StubResolver(HashMap::from([(
+            known,
+            "client.example.com".to_string(),
+        )])));
+
+        assert_eq!(rdns.lookup(known), None);
+        assert_eq!(rdns.lookup(unknown), None);
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while rdns.lookup(known).is_none() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(rdns.lookup(known).as_deref(), Some("client.example.com"));
+        assert_eq!(rdns.lookup(unknown), None);
+    }
+}
@@ -0,0 +1,282 @@
+use super::{
+    client_hello::ClientHelloLimits,
+    tcp::{multiaddr_to_host, upstream_connections, Connection},
+};
+use multiaddr::Multiaddr;
+use std::str::FromStr;
+use taxy_api::{
+    error::Error,
+    port::{PortOptions, UpstreamRewrite, UpstreamServer},
+    subject_name::SubjectName,
+};
+use tracing::warn;
+
+/// Host names standing in for the SNI when validating a template. One of them must
+/// yield a valid upstream address, as `{label}` may be used as a host name or in an IP address.
+const SAMPLE_SERVER_NAMES: [&str; 2] = ["example.internal", "1.internal"];
+
+/// Computes the upstream of a raw TCP connection from its SNI and local port,
+/// taking precedence over the upstream servers of the port.
+#[derive(Debug)]
+pub struct UpstreamRewriter {
+    rules: Vec<Rule>,
+    /// Limits of the ClientHello peeked for the SNI, if any rule needs it.
+    limits: Option<ClientHelloLimits>,
+    opts: PortOptions,
+}
+
+#[derive(Debug)]
+struct Rule {
+    server_name: Option<SubjectName>,
+    port: Option<u16>,
+    upstream: String,
+    tls: bool,
+}
+
+impl UpstreamRewriter {
+    pub fn new(opts: &PortOptions) -> Result<Option<Self>, Error> {
+        if opts.upstream_rewrites.is_empty() {
+            return Ok(None);
+        }
+        let rules = opts
+            .upstream_rewrites
+            .iter()
+            .map(Rule::new)
+            .collect::<Result<Vec<_>, _>>()?;
+        let needs_sni = rules.iter().any(Rule::needs_sni);
+        Ok(Some(Self {
+            rules,
+            limits: needs_sni.then(|| ClientHelloLimits::from(opts)),
+            opts: PortOptions {
+                upstream_servers: vec![],
+                upstream_rewrites: vec![],
+                ..opts.clone()
+            },
+        }))
+    }
+
+    /// Returns the limits of the ClientHello to read before resolving, if any rule matches on the SNI.
+    pub fn client_hello_limits(&self) -> Option<ClientHelloLimits> {
+        self.limits
+    }
+
+    /// Returns `true` if any rule rewrites to a TLS upstream.
+    pub fn uses_tls(&self) -> bool {
+        self.rules.iter().any(|rule| rule.tls)
+    }
+
+    /// Returns the upstream computed by the first rule matching the connection, if any.
+    /// Rules yielding an invalid upstream address are skipped.
+    pub fn resolve(&self, sni: Option<&str>, port: u16) -> Option<Connection> {
+        // A slash would inject protocols into the expanded multiaddr.
+        let sni = sni.filter(|sni| !sni.contains('/'));
+        self.rules
+            .iter()
+            .filter(|rule| rule.matches(sni, port))
+            .find_map(|rule| {
+                let upstream = expand(&rule.upstream, sni, port)?;
+                match self.connection(&upstream) {
+                    Ok(conn) => Some(conn),
+                    Err(err) => {
+                        warn!(upstream, "upstream rewrite skipped: {err}");
+                        None
+                    }
+                }
+            })
+    }
+
+    fn connection(&self, upstream: &str) -> Result<Connection, Error> {
+        let addr = Multiaddr::from_str(upstream).map_err(|_| Error::InvalidUpstreamRewrite {
+            upstream: upstream.to_string(),
+        })?;
+        let server = UpstreamServer::new(addr);
+        let mut conns = upstream_connections(&[server], &self.opts)?;
+        Ok(conns.remove(0))
+    }
+}
+
+impl Rule {
+    fn new(rewrite: &UpstreamRewrite) -> Result<Self, Error> {
+        let server_name = rewrite
+            .server_name
+            .as_deref()
+            .map(SubjectName::from_str)
+            .transpose()?;
+        let sample = SAMPLE_SERVER_NAMES
+            .iter()
+            .filter_map(|sni| expand(&rewrite.upstream, Some(sni), 443))
+            .filter_map(|upstream| Multiaddr::from_str(&upstream).ok())
+            .find_map(|addr| multiaddr_to_host(&addr).ok())
+            .ok_or_else(|| Error::InvalidUpstreamRewrite {
+                upstream: rewrite.upstream.clone(),
+            })?;
+        Ok(Self {
+            server_name,
+            port: rewrite.port,
+            upstream: rewrite.upstream.clone(),
+            tls: sample.tls,
+        })
+    }
+
+    fn needs_sni(&self) -> bool {
+        self.server_name.is_some()
+            || self.upstream.contains("{sni}")
+            || self.upstream.contains("{label}")
+    }
+
+    fn matches(&self, sni: Option<&str>, port: u16) -> bool {
+        self.port.is_none_or(|p| p == port)
+            && self
+                .server_name
+                .as_ref()
+                .is_none_or(|name| sni.is_some_and(|sni| name.test(sni)))
+    }
+}
+
+/// Replaces `{sni}`, `{label}` and `{port}` in the template. Returns `None` if the
+/// template refers to the SNI and there is none, or has an unknown placeholder.
+fn expand(template: &str, sni: Option<&str>, port: u16) -> Option<String> {
+    let mut expanded = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        expanded.push_str(&rest[..start]);
+        let end = start + rest[start..].find('}')?;
+        match &rest[start + 1..end] {
+            "sni" => expanded.push_str(sni?),
+            "label" => expanded.push_str(sni?.split('.').next()?),
+            "port" => expanded.push_str(&port.to_string()),
+            _ => return None,
+        }
+        rest = &rest[end + 1..];
+    }
+    expanded.push_str(rest);
+    Some(expanded)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::keyring::Keyring;
+    use crate::proxy::tcp::{
+        test::{port_entry, proxy_connections, sni_upstream},
+        TcpPortContext,
+    };
+    use multiaddr::Protocol;
+    use std::sync::Arc;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_rustls::rustls::{ClientConfig, RootCertStore, ServerName};
+
+    fn rewriter(rules: &[(Option<&str>, Option<u16>, &str)]) -> Result<UpstreamRewriter, Error> {
+        let opts = PortOptions {
+            upstream_rewrites: rules
+                .iter()
+                .map(|(server_name, port, upstream)| UpstreamRewrite {
+                    server_name: server_name.map(str::to_string),
+                    port: *port,
+                    upstream: upstream.to_string(),
+                })
+                .collect(),
+            ..Default::default()
+        };
+        UpstreamRewriter::new(&opts).map(Option::unwrap)
+    }
+
+    #[test]
+    fn test_expand() {
+        assert_eq!(
+            expand("/ip4/10.0.0.{label}/tcp/{port}", Some("5.internal"), 8080).as_deref(),
+            Some("/ip4/10.0.0.5/tcp/8080")
+        );
+        assert_eq!(
+            expand("/dns/{sni}/tcp/443", Some("a.example.com"), 0).as_deref(),
+            Some("/dns/a.example.com/tcp/443")
+        );
+        assert_eq!(expand("/dns/{sni}/tcp/443", None, 0), None);
+        assert_eq!(expand("/dns/{host}/tcp/443", Some("a.com"), 0), None);
+    }
+
+    #[test]
+    fn test_resolve() {
+        let rewriter = rewriter(&[
+            (Some("*.internal"), None, "/ip4/10.0.0.{label}/tcp/8080"),
+            (None, Some(8443), "/dns/{sni}/tcp/443/tls"),
+            (None, Some(9000), "/ip4/127.0.0.1/tcp/{port}"),
+        ])
+        .unwrap();
+        assert!(rewriter.client_hello_limits().is_some());
+        assert!(rewriter.uses_tls());
+
+        let conn = rewriter.resolve(Some("7.internal"), 8443).unwrap();
+        assert_eq!(conn.hostname(), "10.0.0.7");
+        assert_eq!(conn.port, 8080);
+        assert!(!conn.tls);
+
+        let conn = rewriter.resolve(Some("example.com"), 8443).unwrap();
+        assert_eq!(conn.hostname(), "example.com");
+        assert!(conn.tls);
+
+        let conn = rewriter.resolve(None, 9000).unwrap();
+        assert_eq!(conn.port, 9000);
+
+        // The label is not a number, and the SNI is missing or not a host name, so these
+        // fall back to the upstream servers of the port.
+        assert!(rewriter.resolve(Some("x.internal"), 80).is_none());
+        assert!(rewriter.resolve(None, 8443).is_none());
+        assert!(rewriter.resolve(Some("a/tcp/1"), 8443).is_none());
+    }
+
+    #[test]
+    fn test_invalid_rewrite() {
+        assert!(matches!(
+            rewriter(&[(None, None, "/dns/{host}/tcp/443")]),
+            Err(Error::InvalidUpstreamRewrite { .. })
+        ));
+        assert!(matches!(
+            rewriter(&[(None, None, "/ip4/10.0.0.1")]),
+            Err(Error::InvalidUpstreamRewrite { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_upstream_rewrite() {
+        let rewritten = sni_upstream("rewritten").await;
+        let Some(Protocol::Tcp(rewritten_port)) = rewritten.iter().nth(1) else {
+            unreachable!()
+        };
+        let mut entry = port_entry(&[(sni_upstream("static").await, false)]);
+        entry.port.opts.upstream_rewrites = vec![taxy_api::port::UpstreamRewrite {
+            server_name: Some("*.internal".into()),
+            port: None,
+            upstream: "/ip4/127.0.0.1/tcp/{label}".into(),
+        }];
+        let mut ctx = TcpPortContext::new(&entry).unwrap();
+        ctx.setup(&Keyring::default(), vec![]).await.unwrap();
+
+        let client_config = Arc::new(
+            ClientConfig::builder()
+                .with_safe_defaults()
+                .with_root_certificates(RootCertStore::empty())
+                .with_no_client_auth(),
+        );
+        let rewritten_sni = format!("{rewritten_port}.internal");
+        let cases = [
+            (rewritten_sni.as_str(), format!("rewritten:{rewritten_sni}")),
+            ("example.com", "static:example.com".to_string()),
+        ];
+        let clients = proxy_connections(&mut ctx, cases.len()).await;
+        for (mut client, (sni, expected)) in clients.into_iter().zip(cases) {
+            let mut conn = tokio_rustls::rustls::ClientConnection::new(
+                client_config.clone(),
+                ServerName::try_from(sni).unwrap(),
+            )
+            .unwrap();
+            let mut hello = Vec::new();
+            conn.write_tls(&mut hello).unwrap();
+            client.write_all(&hello).await.unwrap();
+
+            let mut buf = Vec::new();
+            client.read_to_end(&mut buf).await.unwrap();
+            assert_eq!(String::from_utf8(buf).unwrap(), expected);
+        }
+    }
+}
@@ -0,0 +1,168 @@
+use super::connections::ConnectionRegistry;
+use rand::Rng;
+use std::{
+    fmt,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use taxy_api::port::{LoadSignal, OverloadShedding};
+
+const SYSTEM_LOAD_REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Provides the current load, where 1.0 means fully loaded.
+pub trait LoadSource: fmt::Debug + Send + Sync {
+    fn load(&self) -> f64;
+}
+
+/// One-minute load average per CPU, read from `/proc/loadavg`.
+/// Always reports no load on systems without it.
+#[derive(Debug)]
+pub struct SystemLoad {
+    cpus: f64,
+    cached: Mutex<Option<(Instant, f64)>>,
+}
+
+impl Default for SystemLoad {
+    fn default() -> Self {
+        let cpus = std::thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(1);
+        Self {
+            cpus: cpus as f64,
+            cached: Mutex::new(None),
+        }
+    }
+}
+
+impl LoadSource for SystemLoad {
+    fn load(&self) -> f64 {
+        let mut cached = self.cached.lock().unwrap();
+        match *cached {
+            Some((updated_at, load)) if updated_at.elapsed() < SYSTEM_LOAD_REFRESH_INTERVAL => load,
+            _ => {
+                let load = read_load_average().unwrap_or_default() / self.cpus;
+                *cached = Some((Instant::now(), load));
+                load
+            }
+        }
+    }
+}
+
+fn read_load_average() -> Option<f64> {
+    std::fs::read_to_string("/proc/loadavg")
+        .ok()?
+        .split_whitespace()
+        .next()?
+        .parse()
+        .ok()
+}
+
+/// Active connections of a port relative to a maximum.
+#[derive(Debug)]
+pub struct ActiveConnections {
+    connections: ConnectionRegistry,
+    max: usize,
+}
+
+impl LoadSource for ActiveConnections {
+    fn load(&self) -> f64 {
+        self.connections.active_count() as f64 / self.max.max(1) as f64
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LoadShedder {
+    config: OverloadShedding,
+    source: Arc<dyn LoadSource>,
+}
+
+impl LoadShedder {
+    pub fn new(config: &OverloadShedding, connections: &ConnectionRegistry) -> Self {
+        let source: Arc<dyn LoadSource> = match config.signal {
+            LoadSignal::SystemLoad => Arc::new(SystemLoad::default()),
+            LoadSignal::ActiveConnections { max } => Arc::new(ActiveConnections {
+                connections: connections.clone(),
+                max,
+            }),
+        };
+        Self::with_source(config, source)
+    }
+
+    pub fn with_source(config: &OverloadShedding, source: Arc<dyn LoadSource>) -> Self {
+        Self {
+            config: config.clone(),
+            source,
+        }
+    }
+
+    pub fn config(&self) -> &OverloadShedding {
+        &self.config
+    }
+
+    /// Returns the probability of shedding a new connection at the given load.
+    pub fn probability(&self, load: f64) -> f64 {
+        let threshold = self.config.threshold as f64 / 100.0;
+        let ceiling = self.config.ceiling as f64 / 100.0;
+        if load.is_nan() || load < threshold {
+            0.0
+        } else if load >= ceiling {
+            1.0
+        } else {
+            (load - threshold) / (ceiling - threshold)
+        }
+    }
+
+    /// Decides whether to shed a new connection, returning the current load if so.
+    pub fn shed(&self) -> Option<f64> {
+        let load = self.source.load();
+        let probability = self.probability(load);
+        (probability > 0.0 && rand::thread_rng().gen_bool(probability)).then_some(load)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_probability() {
+        let config = OverloadShedding {
+            signal: LoadSignal::SystemLoad,
+            threshold: 80,
+            ceiling: 100,
+        };
+        let shedder = LoadShedder::new(&config, &ConnectionRegistry::default());
+        assert_eq!(shedder.probability(0.5), 0.0);
+        assert_eq!(shedder.probability(0.8), 0.0);
+        assert!((shedder.probability(0.9) - 0.5).abs() < 1e-9);
+        assert_eq!(shedder.probability(1.0), 1.0);
+        assert_eq!(shedder.probability(3.0), 1.0);
+        assert_eq!(shedder.probability(f64::NAN), 0.0);
+
+        let config = OverloadShedding {
+            threshold: 90,
+            ceiling: 90,
+            ..config
+        };
+        let shedder = LoadShedder::new(&config, &ConnectionRegistry::default());
+        assert_eq!(shedder.probability(0.89), 0.0);
+        assert_eq!(shedder.probability(0.9), 1.0);
+    }
+
+    #[test]
+    fn test_active_connections() {
+        let connections = ConnectionRegistry::default();
+        let config = OverloadShedding {
+            signal: LoadSignal::ActiveConnections { max: 2 },
+            threshold: 50,
+            ceiling: 100,
+        };
+        let shedder = LoadShedder::new(&config, &connections);
+        assert_eq!(shedder.shed(), None);
+
+        let addr = "127.0.0.1:8080".parse().unwrap();
+        let _a = connections.register(addr, addr);
+        let _b = connections.register(addr, addr);
+        assert_eq!(shedder.shed(), Some(1.0));
+    }
+}
@@ -0,0 +1,129 @@
+use std::{io, time::Duration};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt};
+
+pub const DEFAULT_SNIFF_TIMEOUT: Duration = Duration::from_secs(10);
+
+const TLS_HANDSHAKE: u8 = 0x16;
+const HTTP_PREFIXES: &[&[u8]] = &[
+    b"GET ",
+    b"HEAD ",
+    b"POST ",
+    b"PUT ",
+    b"DELETE ",
+    b"CONNECT ",
+    b"OPTIONS ",
+    b"TRACE ",
+    b"PATCH ",
+    b"PRI * HTTP/2.0",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DetectedProtocol {
+    Tls,
+    Http,
+    Unknown,
+}
+
+impl DetectedProtocol {
+    /// Guesses the protocol from the first bytes sent by the client.
+    ///
+    /// A partial request line such as `GE` is taken as HTTP, since the
+    /// remaining bytes may not have arrived yet.
+    pub fn detect(buf: &[u8]) -> Self {
+        if buf.first() == Some(&TLS_HANDSHAKE) {
+            return Self::Tls;
+        }
+        let is_http = !buf.is_empty()
+            && HTTP_PREFIXES.iter().any(|prefix| {
+                let len = prefix.len().min(buf.len());
+                buf[..len] == prefix[..len]
+            });
+        if is_http {
+            Self::Http
+        } else {
+            Self::Unknown
+        }
+    }
+}
+
+/// Detects the protocol from the buffered bytes without consuming them.
+///
+/// Fails with [`io::ErrorKind::TimedOut`] if the client sends nothing within `timeout`.
+pub async fn sniff<S>(stream: &mut S, timeout: Duration) -> io::Result<DetectedProtocol>
+where
+    S: AsyncBufRead + Unpin,
+{
+    let buf = tokio::time::timeout(timeout, stream.fill_buf())
+        .await
+        .map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("no data received within {timeout:?}"),
+            )
+        })??;
+    Ok(DetectedProtocol::detect(buf))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Instant;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, BufStream};
+
+    async fn sniff_bytes(data: &[u8]) -> DetectedProtocol {
+        let (mut client, server) = tokio::io::duplex(1024);
+        client.write_all(data).await.unwrap();
+        drop(client);
+
+        let mut stream = BufStream::new(server);
+        let protocol = sniff(&mut stream, DEFAULT_SNIFF_TIMEOUT).await.unwrap();
+
+        let mut read = Vec::new();
+        stream.read_to_end(&mut read).await.unwrap();
+        assert_eq!(read, data);
+        protocol
+    }
+
+    #[tokio::test]
+    async fn test_sniff() {
+        let client_hello = [
+            TLS_HANDSHAKE,
+            0x03,
+            0x01,
+            0x00,
+            0x04,
+            0x01,
+            0x00,
+            0x00,
+            0x00,
+        ];
+        assert_eq!(sniff_bytes(&client_hello).await, DetectedProtocol::Tls);
+        assert_eq!(
+            sniff_bytes(b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n").await,
+            DetectedProtocol::Http
+        );
+        assert_eq!(
+            sniff_bytes(b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n").await,
+            DetectedProtocol::Http
+        );
+        assert_eq!(
+            sniff_bytes(b"SSH-2.0-OpenSSH_9.0\r\n").await,
+            DetectedProtocol::Unknown
+        );
+        assert_eq!(sniff_bytes(b"").await, DetectedProtocol::Unknown);
+    }
+
+    #[tokio::test]
+    async fn test_sniff_timeout() {
+        let (_client, server) = tokio::io::duplex(1024);
+        let mut stream = BufStream::new(server);
+
+        let timeout = Duration::from_millis(200);
+        let start = Instant::now();
+        let err = sniff(&mut stream, timeout).await.unwrap_err();
+        let elapsed = start.elapsed();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert!(elapsed >= timeout);
+        assert!(elapsed < Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,393 @@
+use percent_encoding::percent_decode_str;
+use std::fmt;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+
+/// The backlog of TCP listeners, as used by `TcpListener::bind`.
+pub const DEFAULT_LISTEN_BACKLOG: u32 = 1024;
+
+/// Unix socket peers have no IP address, so they are reported with this placeholder
+/// wherever a socket address is expected, such as in the connection list.
+pub const UNIX_SOCKET_ADDR: SocketAddr =
+    SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0));
+
+/// The address of a listener or an upstream, either a TCP socket or a Unix socket path.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Address {
+    Inet(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl From<SocketAddr> for Address {
+    fn from(addr: SocketAddr) -> Self {
+        Self::Inet(addr)
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Inet(addr) => addr.fmt(f),
+            Self::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// Returns the path of a `/unix/<path>` multiaddr. Multiaddrs split on slashes,
+/// so the slashes of the path must be percent-encoded, as in `/unix/%2Frun%2Fapp.sock`.
+pub fn unix_path(segment: &str) -> PathBuf {
+    PathBuf::from(percent_decode_str(segment).decode_utf8_lossy().into_owned())
+}
+
+/// A connection accepted by a listener.
+pub trait Socket: AsyncRead + AsyncWrite + Unpin + Send + 'static {
+    fn peer_addr(&self) -> io::Result<SocketAddr>;
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+    /// Returns the TCP stream to set TCP options on, if this is one.
+    fn as_tcp(&self) -> Option<&TcpStream>;
+}
+
+impl Socket for TcpStream {
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        TcpStream::peer_addr(self)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        TcpStream::local_addr(self)
+    }
+
+    fn as_tcp(&self) -> Option<&TcpStream> {
+        Some(self)
+    }
+}
+
+#[derive(Debug)]
+pub enum SocketStream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Socket for SocketStream {
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            Self::Tcp(stream) => stream.peer_addr(),
+            #[cfg(unix)]
+            Self::Unix(_) => Ok(UNIX_SOCKET_ADDR),
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            Self::Tcp(stream) => stream.local_addr(),
+            #[cfg(unix)]
+            Self::Unix(_) => Ok(UNIX_SOCKET_ADDR),
+        }
+    }
+
+    fn as_tcp(&self) -> Option<&TcpStream> {
+        match self {
+            Self::Tcp(stream) => Some(stream),
+            #[cfg(unix)]
+            Self::Unix(_) => None,
+        }
+    }
+}
+
+impl AsyncRead for SocketStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            #[cfg(unix)]
+            Self::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for SocketStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            #[cfg(unix)]
+            Self::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_write_vectored(cx, bufs),
+            #[cfg(unix)]
+            Self::Unix(stream) => Pin::new(stream).poll_write_vectored(cx, bufs),
+        }
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        match self {
+            Self::Tcp(stream) => stream.is_write_vectored(),
+            #[cfg(unix)]
+            Self::Unix(stream) => stream.is_write_vectored(),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            #[cfg(unix)]
+            Self::Unix(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            #[cfg(unix)]
+            Self::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+/// Connects to a Unix socket, which is only supported on Unix platforms.
+pub async fn connect_unix(path: &Path) -> io::Result<SocketStream> {
+    #[cfg(unix)]
+    {
+        UnixStream::connect(path).await.map(SocketStream::Unix)
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = path;
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "unix sockets are not supported on this platform",
+        ))
+    }
+}
+
+/// Options of TCP listeners, applied when they are bound.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ListenOptions {
+    /// Maximum number of pending connections, [`DEFAULT_LISTEN_BACKLOG`] if unset.
+    pub backlog: Option<u32>,
+    /// Shares the address with other sockets bound with the same option.
+    pub reuse_port: bool,
+    /// Enables TCP Fast Open, falling back to regular handshakes with a warning if unsupported.
+    pub fast_open: bool,
+}
+
+#[derive(Debug)]
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener, PathBuf),
+}
+
+impl Listener {
+    /// Binds the address. A stale socket file left at a Unix socket path is removed first.
+    /// The options only apply to TCP listeners.
+    pub async fn bind(addr: &Address, opts: ListenOptions) -> io::Result<Self> {
+        match addr {
+            Address::Inet(addr) => bind_tcp(*addr, opts).map(Self::Tcp),
+            #[cfg(unix)]
+            Address::Unix(path) => {
+                use std::os::unix::fs::FileTypeExt;
+                if let Ok(metadata) = std::fs::symlink_metadata(path) {
+                    if metadata.file_type().is_socket() {
+                        std::fs::remove_file(path)?;
+                    }
+                }
+                UnixListener::bind(path).map(|listener| Self::Unix(listener, path.clone()))
+            }
+            #[cfg(not(unix))]
+            Address::Unix(_) => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "unix sockets are not supported on this platform",
+            )),
+        }
+    }
+
+    pub fn addr(&self) -> io::Result<Address> {
+        match self {
+            Self::Tcp(listener) => listener.local_addr().map(Address::Inet),
+            #[cfg(unix)]
+            Self::Unix(_, path) => Ok(Address::Unix(path.clone())),
+        }
+    }
+
+    pub fn poll_accept(&self, cx: &mut Context<'_>) -> Poll<io::Result<SocketStream>> {
+        match self {
+            Self::Tcp(listener) => listener
+                .poll_accept(cx)
+                .map_ok(|(stream, _)| SocketStream::Tcp(stream)),
+            #[cfg(unix)]
+            Self::Unix(listener, _) => listener
+                .poll_accept(cx)
+                .map_ok(|(stream, _)| SocketStream::Unix(stream)),
+        }
+    }
+}
+
+fn bind_tcp(addr: SocketAddr, opts: ListenOptions) -> io::Result<TcpListener> {
+    let backlog = opts.backlog.unwrap_or(DEFAULT_LISTEN_BACKLOG);
+    let sock = if addr.is_ipv4() {
+        TcpSocket::new_v4()
+    } else {
+        TcpSocket::new_v6()
+    }?;
+    // Like `TcpListener::bind`, which allows rebinding while old connections are in TIME_WAIT.
+    #[cfg(not(windows))]
+    sock.set_reuseaddr(true)?;
+    if opts.reuse_port {
+        #[cfg(all(unix, not(target_os = "solaris"), not(target_os = "illumos")))]
+        sock.set_reuseport(true)?;
+        #[cfg(not(all(unix, not(target_os = "solaris"), not(target_os = "illumos"))))]
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "SO_REUSEPORT is not supported on this platform",
+        ));
+    }
+    sock.bind(addr)?;
+    #[cfg(target_os = "linux")]
+    if let Some(max) = std::fs::read_to_string("/proc/sys/net/core/somaxconn")
+        .ok()
+        .and_then(|max| max.trim().parse::<u32>().ok())
+        .filter(|max| backlog > *max)
+    {
+        tracing::warn!(%addr, backlog, max, "listen backlog capped by net.core.somaxconn");
+    }
+    let listener = sock.listen(backlog)?;
+    if opts.fast_open {
+        if let Err(err) = set_fast_open_listen(&listener) {
+            tracing::warn!(%addr, "failed to enable TCP Fast Open: {err}");
+        }
+    }
+    Ok(listener)
+}
+
+/// Sets `TCP_FASTOPEN` on the listener. `TfoListener` takes ownership of the listener it sets
+/// the option on, so it is given a duplicate of the descriptor, which shares the socket.
+#[cfg(target_os = "linux")]
+fn set_fast_open_listen(listener: &TcpListener) -> io::Result<()> {
+    let dup = socket2::SockRef::from(listener).try_clone()?;
+    tokio_tfo::TfoListener::from_std(dup.into()).map(drop)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_fast_open_listen(_listener: &TcpListener) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "TCP Fast Open is not supported on this platform",
+    ))
+}
+
+/// Creates an outbound socket for the address family of `addr`, with TCP Fast Open
+/// if `fast_open` is set and supported.
+pub fn new_tcp_socket(addr: SocketAddr, fast_open: bool) -> io::Result<TcpSocket> {
+    let sock = if addr.is_ipv4() {
+        TcpSocket::new_v4()
+    } else {
+        TcpSocket::new_v6()
+    }?;
+    if fast_open {
+        if let Err(err) = set_fast_open_connect(&sock) {
+            tracing::warn!(%addr, "failed to enable TCP Fast Open: {err}");
+        }
+    }
+    Ok(sock)
+}
+
+/// Sets `TCP_FASTOPEN_CONNECT` on an outbound socket before it is connected, so that
+/// the first bytes written are sent in the SYN.
+#[cfg(target_os = "linux")]
+pub fn set_fast_open_connect(sock: &TcpSocket) -> io::Result<()> {
+    use nix::sys::socket::{setsockopt, sockopt::TcpFastOpenConnect};
+    setsockopt(sock, TcpFastOpenConnect, &true).map_err(io::Error::from)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set_fast_open_connect(_sock: &TcpSocket) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "TCP Fast Open is not supported on this platform",
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_unix_path() {
+        assert_eq!(unix_path("%2Frun%2Fapp.sock"), Path::new("/run/app.sock"));
+        assert_eq!(unix_path("app.sock"), Path::new("app.sock"));
+        assert_eq!(
+            Address::Unix(unix_path("%2Frun%2Fapp.sock")).to_string(),
+            "unix:/run/app.sock"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_listen_backlog() {
+        let opts = ListenOptions {
+            backlog: Some(16),
+            ..Default::default()
+        };
+        let listener = Listener::bind(&"127.0.0.1:0".parse::<SocketAddr>().unwrap().into(), opts)
+            .await
+            .unwrap();
+        let Ok(Address::Inet(addr)) = listener.addr() else {
+            panic!("not a tcp listener");
+        };
+        let _client = TcpStream::connect(addr).await.unwrap();
+        let accepted = std::future::poll_fn(|cx| listener.poll_accept(cx)).await;
+        assert!(accepted.unwrap().as_tcp().is_some());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_fast_open() {
+        use nix::sys::socket::{getsockopt, sockopt::TcpFastOpenConnect};
+
+        let opts = ListenOptions {
+            fast_open: true,
+            ..Default::default()
+        };
+        let listener = bind_tcp("127.0.0.1:0".parse().unwrap(), opts).unwrap();
+        set_fast_open_listen(&listener).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let sock = TcpSocket::new_v4().unwrap();
+        assert!(!getsockopt(&sock, TcpFastOpenConnect).unwrap());
+        set_fast_open_connect(&sock).unwrap();
+        assert!(getsockopt(&sock, TcpFastOpenConnect).unwrap());
+
+        let mut client = sock.connect(addr).await.unwrap();
+        tokio::io::AsyncWriteExt::write_all(&mut client, b"ping")
+            .await
+            .unwrap();
+        let (mut accepted, _) = listener.accept().await.unwrap();
+        let mut buf = [0; 4];
+        tokio::io::AsyncReadExt::read_exact(&mut accepted, &mut buf)
+            .await
+            .unwrap();
+        assert_eq!(&buf, b"ping");
+    }
+}
@@ -1,37 +1,115 @@
-use super::{tls::TlsTermination, PortContextEvent, PortStatus, SocketState};
-use crate::keyring::Keyring;
+use super::{
+    bind::SourceBinding,
+    client_hello::{self, ClientHelloLimits, PrefixedStream},
+    compress::{DeflateStream, ALPN_DEFLATE},
+    conn_limit::{ConnectionLimit, ConnectionLimitPermit},
+    connections::{ConnectionHandle, ConnectionRegistry, Side, DEFAULT_RECENT_CONNECTIONS},
+    deadline::{DeadlineExceeded, DeadlineStream},
+    dns::ResolvedEndpoints,
+    happy_eyeballs::{self, DEFAULT_HAPPY_EYEBALLS_DELAY},
+    health::{HealthChecker, HealthEvents},
+    proxy_protocol,
+    rate_limit::{ConnectionRateLimiter, RetryLimiter},
+    rdns::ReverseDns,
+    rewrite::UpstreamRewriter,
+    shedding::LoadShedder,
+    sniff::{sniff, DetectedProtocol, DEFAULT_SNIFF_TIMEOUT},
+    socket::{self, Address, Socket, SocketStream},
+    tls::{BoundedAcceptor, TlsTermination},
+    tls_close::{self, CloseNotifyStream, DEFAULT_TLS_CLOSE_TIMEOUT},
+    tls_params::TlsParams,
+    trace::TraceParent,
+    upstream_tls::{self, CaBundle},
+    PortContextEvent, PortStatus, SocketState,
+};
+use crate::{keyring::Keyring, log::redact_host};
 use multiaddr::{Multiaddr, Protocol};
+use sha2::{Digest, Sha256};
 use std::{
+    collections::VecDeque,
+    io,
     net::{IpAddr, SocketAddr},
-    sync::Arc,
-    time::SystemTime,
+    path::PathBuf,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicI64, AtomicU32, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex, Weak,
+    },
+    time::{Duration, SystemTime},
 };
 use taxy_api::error::Error;
-use taxy_api::{port::PortEntry, site::SiteEntry};
+use taxy_api::{
+    port::{
+        BufferingMode, ConnectionOutcome, DuplicateUpstreams, HealthCheck, LoadBalanceMode,
+        OutlierDetection, PortEntry, PortOptions, ProxyProtocol, TagAffinity, TcpKeepalive,
+        UpstreamDistribution, UpstreamServer, UpstreamTlsVerification,
+    },
+    site::SiteEntry,
+    subject_name::SubjectName,
+    tls::{TlsMode, TlsState},
+};
 use tokio::{
     io::AsyncWriteExt,
-    net::{self, TcpSocket, TcpStream},
+    net::{self, TcpStream},
 };
 use tokio::{
-    io::{AsyncRead, AsyncWrite, BufStream},
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, BufStream},
     sync::Notify,
+    time::Instant,
 };
 use tokio_rustls::{
-    rustls::{client::ServerName, Certificate, ClientConfig, RootCertStore},
-    TlsAcceptor, TlsConnector,
+    rustls::{
+        client::{ClientSessionMemoryCache, Resumption, ServerName},
+        ClientConfig,
+    },
+    TlsConnector,
 };
 use tracing::{debug, error, info, span, warn, Instrument, Level, Span};
 
+/// Caps the upstreams tried for a single connection, so that a client
+/// cannot trigger a connect storm across a long list of unreachable upstreams.
+pub const MAX_UPSTREAM_ATTEMPTS: usize = 3;
+
+/// Server names of TLS upstreams whose sessions are kept for resumption.
+const UPSTREAM_TLS_SESSION_CACHE_SIZE: usize = 256;
+
 #[derive(Debug)]
 pub struct TcpPortContext {
-    pub listen: SocketAddr,
+    pub listen: Address,
     servers: Vec<Connection>,
     status: PortStatus,
     span: Span,
     tls_termination: Option<TlsTermination>,
+    /// Set at runtime to pass TLS connections through, and kept across config updates.
+    tls_mode: TlsMode,
+    /// Upstreams of plaintext clients on TLS-terminated ports, if enabled.
+    /// Empty if they share the upstreams of the port.
+    plaintext_fallback: Option<Vec<Connection>>,
+    protocol_detection_timeout: Duration,
+    sni_routing: bool,
+    upstream_rewriter: Option<Arc<UpstreamRewriter>>,
+    /// Limits of the ClientHello peeked for SNI routing or upstream rewrites, if either needs it.
+    client_hello: Option<ClientHelloLimits>,
     tls_client_config: Option<Arc<ClientConfig>>,
-    round_robin_counter: usize,
+    /// Sessions of TLS upstreams, shared by their connections to resume them
+    /// without a full handshake, and kept across config updates.
+    tls_resumption: Resumption,
+    upstream_tls_verification: UpstreamTlsVerification,
+    upstream_ca_bundle: Option<CaBundle>,
+    tls_params: TlsParams,
+    stream_opts: StreamOptions,
+    tag_affinity: Vec<TagAffinity>,
+    load_balance: LoadBalanceMode,
+    shedder: Option<LoadShedder>,
+    rate_limiter: Option<ConnectionRateLimiter>,
+    health_check: Option<HealthCheck>,
+    health_checker: Option<HealthChecker>,
+    health_events: Option<HealthEvents>,
+    connection_limit: Option<ConnectionLimit>,
     stop_notifier: Arc<Notify>,
+    draining: bool,
+    connections: ConnectionRegistry,
+    selections: Arc<SelectionWindow>,
 }
 
 impl TcpPortContext {
@@ -42,80 +120,297 @@ impl TcpPortContext {
 
         info!("initializing tcp proxy");
 
-        let listen = multiaddr_to_tcp(&entry.port.listen)?;
+        let listen = multiaddr_to_listen(&entry.port.listen)?;
 
-        let mut servers = Vec::new();
-        for server in &entry.port.opts.upstream_servers {
-            let server = multiaddr_to_host(&server.addr)?;
-            servers.push(server);
-        }
+        let servers = upstream_connections(&entry.port.opts.upstream_servers, &entry.port.opts)?;
+
+        let tls_params = entry
+            .port
+            .opts
+            .tls_parameters
+            .as_ref()
+            .map(TlsParams::new)
+            .transpose()?
+            .unwrap_or_default();
+
+        let upstream_ca_bundle = entry
+            .port
+            .opts
+            .upstream_ca_bundle
+            .as_ref()
+            .map(CaBundle::new)
+            .transpose()?;
 
         let tls_termination = if let Some(tls) = &entry.port.opts.tls_termination {
-            Some(TlsTermination::new(tls, vec![])?)
+            let mut tls = TlsTermination::new(tls, vec![], (&entry.port.opts).into())?;
+            tls.tunnel_compression = entry.port.opts.tunnel_compression;
+            tls.params = tls_params.clone();
+            Some(tls)
         } else if entry.port.listen.iter().any(|p| p == Protocol::Tls) {
             return Err(Error::TlsTerminationConfigMissing);
         } else {
             None
         };
 
+        let plaintext_fallback = match &entry.port.opts.plaintext_fallback {
+            Some(fallback) if tls_termination.is_some() => {
+                warn!("plaintext fallback enabled: clients without tls are proxied unencrypted");
+                Some(upstream_connections(
+                    &fallback.upstream_servers,
+                    &entry.port.opts,
+                )?)
+            }
+            Some(_) => {
+                warn!("plaintext fallback ignored: tls termination is not configured");
+                None
+            }
+            None => None,
+        };
+
+        let sni_routing = match entry.port.opts.sni_routing {
+            true if tls_termination.is_some() => {
+                warn!("sni routing ignored: tls termination is configured");
+                false
+            }
+            sni_routing => sni_routing,
+        };
+
+        let upstream_rewriter = UpstreamRewriter::new(&entry.port.opts)?.map(Arc::new);
+        let rewrite_client_hello = upstream_rewriter
+            .as_ref()
+            .and_then(|rewriter| rewriter.client_hello_limits());
+        let client_hello = if sni_routing {
+            Some(ClientHelloLimits::from(&entry.port.opts))
+        } else if rewrite_client_hello.is_some() && plaintext_fallback.is_some() {
+            warn!("server name rules of upstream rewrites ignored: plaintext fallback is enabled");
+            None
+        } else {
+            rewrite_client_hello
+        };
+
+        let connections = ConnectionRegistry::with_reverse_dns(
+            entry.port.opts.reverse_dns.then(ReverseDns::default),
+        );
+        connections.set_recent_capacity(
+            entry
+                .port
+                .opts
+                .recent_connections
+                .unwrap_or(DEFAULT_RECENT_CONNECTIONS),
+        );
+        connections.set_probe_detection(entry.port.opts.probe_detection);
+        connections.set_retry_limiter(
+            entry
+                .port
+                .opts
+                .retry_budget
+                .as_ref()
+                .map(RetryLimiter::new)
+                .transpose()?,
+        );
+        let shedder = entry
+            .port
+            .opts
+            .overload_shedding
+            .as_ref()
+            .map(|config| LoadShedder::new(config, &connections));
+        let rate_limiter = entry
+            .port
+            .opts
+            .connection_rate
+            .as_ref()
+            .map(ConnectionRateLimiter::new)
+            .transpose()?;
+
         Ok(Self {
             listen,
             servers,
             status: Default::default(),
             span,
             tls_termination,
+            tls_mode: TlsMode::Terminate,
+            plaintext_fallback,
+            protocol_detection_timeout: entry
+                .port
+                .opts
+                .protocol_detection_timeout
+                .unwrap_or(DEFAULT_SNIFF_TIMEOUT),
+            sni_routing,
+            upstream_rewriter,
+            client_hello,
             tls_client_config: None,
-            round_robin_counter: 0,
+            tls_resumption: Resumption::store(Arc::new(ClientSessionMemoryCache::new(
+                UPSTREAM_TLS_SESSION_CACHE_SIZE,
+            ))),
+            upstream_tls_verification: entry.port.opts.upstream_tls_verification,
+            upstream_ca_bundle,
+            tls_params,
+            stream_opts: StreamOptions {
+                first_byte_timeout: entry.port.opts.upstream_first_byte_timeout,
+                connect_timeout: entry.port.opts.upstream_connect_timeout,
+                happy_eyeballs_delay: entry.port.opts.happy_eyeballs_delay,
+                failure_cooldown: entry.port.opts.upstream_failure_cooldown,
+                outlier_detection: entry.port.opts.outlier_detection,
+                idle_timeout: entry.port.opts.idle_timeout,
+                read_deadline: entry.port.opts.read_deadline,
+                write_deadline: entry.port.opts.write_deadline,
+                tls_close_timeout: entry.port.opts.tls_close_timeout,
+                tls_strict_close: entry.port.opts.tls_strict_close,
+                // The stream has already been read from once the PROXY protocol header
+                // or the ClientHello is parsed.
+                buffering: if entry.port.opts.accept_proxy_protocol || client_hello.is_some() {
+                    BufferingMode::Buffered
+                } else {
+                    entry.port.opts.buffering
+                },
+                lifecycle_events: entry.port.opts.lifecycle_events,
+                trace_context: entry.port.opts.trace_context,
+                tls_handshake_retries: entry.port.opts.upstream_tls_handshake_retries,
+                tunnel_compression: entry.port.opts.tunnel_compression,
+                inbound_keepalive: entry.port.opts.inbound_keepalive,
+                outbound_keepalive: entry.port.opts.outbound_keepalive,
+                tcp_nodelay: entry.port.opts.tcp_nodelay,
+                fast_open: entry.port.opts.upstream_fast_open,
+                send_buffer_size: entry.port.opts.send_buffer_size,
+                recv_buffer_size: entry.port.opts.recv_buffer_size,
+            },
+            tag_affinity: entry.port.opts.tag_affinity.clone(),
+            load_balance: entry.port.opts.load_balance,
+            shedder,
+            rate_limiter,
+            health_check: entry.port.opts.health_check.clone(),
+            health_checker: None,
+            health_events: None,
+            connection_limit: entry.port.opts.max_connections.map(ConnectionLimit::new),
             stop_notifier: Arc::new(Notify::new()),
+            draining: false,
+            connections,
+            selections: Default::default(),
         })
     }
 
     pub async fn setup(&mut self, keyring: &Keyring, _sites: Vec<SiteEntry>) -> Result<(), Error> {
-        let use_tls = self.servers.iter().any(|server| server.tls);
+        let use_tls = self
+            .servers
+            .iter()
+            .chain(self.plaintext_fallback.iter().flatten())
+            .any(|server| server.tls)
+            || self
+                .upstream_rewriter
+                .as_ref()
+                .is_some_and(|rewriter| rewriter.uses_tls());
         if self.tls_client_config.is_none() && use_tls {
-            let mut root_certs = RootCertStore::empty();
-            if let Ok(certs) =
-                tokio::task::spawn_blocking(rustls_native_certs::load_native_certs).await
-            {
-                match certs {
-                    Ok(certs) => {
-                        for certs in certs {
-                            if let Err(err) = root_certs.add(&Certificate(certs.0)) {
-                                warn!("failed to add native certs: {err}");
-                            }
-                        }
-                    }
-                    Err(err) => {
-                        warn!("failed to load native certs: {err}");
-                    }
-                }
+            let mut config = upstream_tls::client_config(
+                self.upstream_tls_verification,
+                &self.tls_params,
+                self.upstream_ca_bundle.as_ref(),
+            )
+            .await;
+            if self.stream_opts.tunnel_compression {
+                config.alpn_protocols = vec![ALPN_DEFLATE.to_vec()];
             }
-            let config = ClientConfig::builder()
-                .with_safe_defaults()
-                .with_root_certificates(root_certs)
-                .with_no_client_auth();
+            config.resumption = self.tls_resumption.clone();
             self.tls_client_config = Some(Arc::new(config));
         }
 
         if let Some(tls) = &mut self.tls_termination {
             self.status.state.tls = Some(tls.setup(keyring).await);
         }
+
+        if self.health_checker.is_none() {
+            self.start_health_checker();
+        }
         Ok(())
     }
 
-    pub async fn refresh(&mut self, certs: &Keyring) -> Result<(), Error> {
+    fn start_health_checker(&mut self) {
+        self.health_checker = self.health_check.as_ref().map(|config| {
+            HealthChecker::start(
+                config,
+                self.servers.clone(),
+                self.tls_client_config.clone(),
+                self.health_events.clone(),
+                self.span.clone(),
+            )
+        });
+    }
+
+    /// Reports the upstreams found down or back up by the health checks.
+    pub fn set_health_events(&mut self, events: HealthEvents) {
+        self.health_events = Some(events);
+        if self.health_checker.is_some() {
+            self.start_health_checker();
+        }
+    }
+
+    pub async fn refresh(&mut self, certs: &Keyring, strict: bool) -> Result<(), Error> {
         if let Some(tls) = &mut self.tls_termination {
-            self.status.state.tls = Some(tls.refresh(certs).await);
+            match tls.refresh(certs, strict).await {
+                Ok(state) => self.status.state.tls = Some(state),
+                Err(err) => {
+                    self.status.state.tls = Some(TlsState::Failed);
+                    return Err(err);
+                }
+            }
         }
         Ok(())
     }
 
-    pub fn apply(&mut self, new: Self) {
+    /// Takes the config of `new`, keeping the runtime state of the port and its unchanged upstreams.
+    pub fn apply(&mut self, mut new: Self) {
+        self.connections
+            .set_reverse_dns(new.connections.reverse_dns());
+        self.connections
+            .set_recent_capacity(new.connections.recent_capacity());
+        self.connections
+            .set_probe_detection(new.connections.probe_detection());
+        self.connections
+            .set_retry_limiter(new.connections.take_retry_limiter());
+        inherit_upstream_stats(&mut new.servers, &self.servers);
+        if let (Some(new), Some(old)) = (&mut new.plaintext_fallback, &self.plaintext_fallback) {
+            inherit_upstream_stats(new, old);
+        }
+        // The checker of the new context holds the upstreams without their inherited stats.
+        new.health_checker = None;
+        // Sessions verified under other settings must not be resumed.
+        if new.upstream_tls_verification == self.upstream_tls_verification
+            && new.upstream_ca_bundle.as_ref().map(CaBundle::config)
+                == self.upstream_ca_bundle.as_ref().map(CaBundle::config)
+        {
+            new.tls_resumption = self.tls_resumption.clone();
+            new.tls_client_config = new.tls_client_config.map(|config| {
+                let mut config = (*config).clone();
+                config.resumption = self.tls_resumption.clone();
+                Arc::new(config)
+            });
+        }
+        if new.listen == self.listen {
+            new.status.state.socket = self.status.state.socket;
+            new.status.started_at = self.status.started_at;
+        }
+        new.status.connection_limit_rejections = self.status.connection_limit_rejections;
+        // Resizing keeps the permits held by the existing connections.
+        let connection_limit = match (self.connection_limit.take(), new.connection_limit.take()) {
+            (Some(mut limit), Some(new)) => {
+                limit.resize(new.limit());
+                Some(limit)
+            }
+            (_, new) => new,
+        };
+        let shedder = new
+            .shedder
+            .take()
+            .map(|shedder| LoadShedder::new(shedder.config(), &self.connections));
         *self = Self {
-            round_robin_counter: self.round_robin_counter,
+            shedder,
+            connection_limit,
             stop_notifier: self.stop_notifier.clone(),
+            connections: self.connections.clone(),
+            selections: self.selections.clone(),
+            tls_mode: self.tls_mode,
             ..new
         };
+        self.start_health_checker();
     }
 
     pub fn event(&mut self, event: PortContextEvent) {
@@ -133,153 +428,3782 @@ impl TcpPortContext {
         }
     }
 
-    pub fn status(&self) -> &PortStatus {
-        &self.status
+    pub fn status(&self) -> PortStatus {
+        PortStatus {
+            connections_in_use: self.connection_limit.as_ref().map(ConnectionLimit::in_use),
+            ejected_upstreams: self.stream_opts.outlier_detection.map(|_| {
+                self.servers
+                    .iter()
+                    .filter(|server| server.stats.is_ejected())
+                    .count()
+            }),
+            tls_mode: self.tls_termination.as_ref().map(|_| self.tls_mode),
+            ..self.status
+        }
+    }
+
+    /// Switches between terminating and passing through TLS. Connections in progress
+    /// are kept in the mode they have been accepted in.
+    pub fn set_tls_mode(&mut self, mode: TlsMode) -> Result<(), Error> {
+        if self.tls_termination.is_none() {
+            return Err(Error::TlsModeNotSupported);
+        }
+        if self.tls_mode != mode {
+            self.span.in_scope(|| info!(?mode, "tls mode changed"));
+            self.tls_mode = mode;
+        }
+        Ok(())
+    }
+
+    pub fn connections(&self) -> &ConnectionRegistry {
+        &self.connections
+    }
+
+    pub fn tls_termination(&self) -> Option<&TlsTermination> {
+        self.tls_termination.as_ref()
     }
 
     pub fn reset(&mut self) {
         self.stop_notifier.notify_waiters();
     }
 
-    pub fn start_proxy(&mut self, mut stream: BufStream<TcpStream>) {
-        if self.servers.is_empty() {
+    /// Rejects new connections, and closes the existing ones once `timeout` has elapsed.
+    /// Applying a new config accepts connections again.
+    pub fn drain(&mut self, timeout: Duration) {
+        self.draining = true;
+        stop_after(&mut self.stop_notifier, timeout);
+    }
+
+    pub fn drain_upstream(&self, addr: &Multiaddr) {
+        drain_upstream(&self.servers, addr);
+        if let Some(servers) = &self.plaintext_fallback {
+            drain_upstream(servers, addr);
+        }
+    }
+
+    pub fn upstreams(&self) -> &[Connection] {
+        &self.servers
+    }
+
+    pub fn upstream_distribution(&self) -> Vec<UpstreamDistribution> {
+        self.selections.distribution(&self.servers)
+    }
+
+    pub fn start_proxy<S: Socket>(
+        &mut self,
+        mut stream: BufStream<S>,
+        client_addr: Option<SocketAddr>,
+        permit: Option<ConnectionLimitPermit>,
+    ) {
+        if self.draining {
+            self.span
+                .in_scope(|| warn!("connection rejected: port is draining"));
+            tokio::spawn(async move { stream.get_mut().shutdown().await });
+            return;
+        }
+
+        let limit_permit = match &self.connection_limit {
+            Some(limit) => match limit.try_acquire() {
+                Some(permit) => Some(permit),
+                None => {
+                    self.status.connection_limit_rejections += 1;
+                    self.span.in_scope(|| {
+                        warn!(
+                            limit = limit.limit(),
+                            "connection rejected: max connections reached"
+                        )
+                    });
+                    tokio::spawn(async move { stream.get_mut().shutdown().await });
+                    return;
+                }
+            },
+            None => None,
+        };
+
+        if let Some(load) = self.shedder.as_ref().and_then(|shedder| shedder.shed()) {
+            self.span
+                .in_scope(|| warn!(load, "connection rejected: overloaded"));
+            tokio::spawn(async move { stream.get_mut().shutdown().await });
+            return;
+        }
+
+        let delay = match &mut self.rate_limiter {
+            Some(limiter) => match limiter.acquire(Instant::now()) {
+                Some(delay) => delay,
+                None => {
+                    self.span
+                        .in_scope(|| warn!("connection rejected: rate limit exceeded"));
+                    tokio::spawn(async move { stream.get_mut().shutdown().await });
+                    return;
+                }
+            },
+            None => Duration::ZERO,
+        };
+
+        let client_addr = client_addr.or_else(|| stream.get_ref().peer_addr().ok());
+        let client = client_addr.map(|addr| addr.ip());
+        let tag = client_tag(&self.tag_affinity, client);
+        // With SNI routing, the upstreams are selected once the ClientHello has been read.
+        let routing = self.sni_routing.then(|| SniRouting {
+            servers: self.servers.clone(),
+            tag: tag.map(str::to_string),
+            client,
+            load_balance: self.load_balance,
+            selections: self.selections.clone(),
+        });
+        let candidates = if routing.is_some() {
+            vec![]
+        } else {
+            select_upstreams(&self.servers, tag, client, self.load_balance)
+        };
+        if let Some(selected) = candidates.first() {
+            self.selections.record(selected);
+        }
+        let rewriter = self.upstream_rewriter.clone();
+        if routing.is_none() && rewriter.is_none() && candidates.is_empty() {
+            self.span
+                .in_scope(|| warn!("connection rejected: no upstream servers available"));
             tokio::spawn(async move { stream.get_mut().shutdown().await });
             return;
         }
 
+        let plaintext = self.plaintext_fallback.as_ref().map(|servers| {
+            if servers.is_empty() {
+                candidates.clone()
+            } else {
+                select_upstreams(servers, tag, client, self.load_balance)
+            }
+        });
+
         let span = self.span.clone();
-        let conn = self.servers[self.round_robin_counter % self.servers.len()].clone();
-        let tls_client_config = self
-            .tls_client_config
-            .as_ref()
-            .filter(|_| conn.tls)
-            .cloned();
+        let tls_client_config = self.tls_client_config.clone();
         let tls_acceptor = self
             .tls_termination
             .as_ref()
+            .filter(|_| self.tls_mode == TlsMode::Terminate)
             .and_then(|tls| tls.acceptor.clone());
+        let protocol_detection_timeout = self.protocol_detection_timeout;
+        let client_hello_limits = self.client_hello;
+        let local_port = stream.get_ref().local_addr().map_or(0, |addr| addr.port());
 
         let stop_notifier = self.stop_notifier.clone();
+        let stream_opts = self.stream_opts;
+        let connections = self.connections.clone();
 
         tokio::spawn(
             async move {
-                if let Err(err) =
-                    start(stream, conn, tls_client_config, tls_acceptor, stop_notifier).await
-                {
+                if !delay.is_zero() {
+                    tokio::time::sleep(delay).await;
+                }
+                let peeked = match client_hello_limits {
+                    Some(limits) => {
+                        match client_hello::read_client_hello(&mut stream, &limits).await {
+                            Ok(hello) => hello,
+                            Err(err) => {
+                                warn!(remote = ?client_addr, "connection rejected: {err}");
+                                let _ = stream.get_mut().shutdown().await;
+                                return;
+                            }
+                        }
+                    }
+                    None => vec![],
+                };
+                let sni = client_hello::server_name(&peeked);
+                let rewritten = rewriter
+                    .as_ref()
+                    .and_then(|rewriter| rewriter.resolve(sni.as_deref(), local_port));
+                let candidates = match (rewritten, routing) {
+                    (Some(conn), _) => {
+                        debug!(
+                            sni = sni.map(redact_host),
+                            upstream = conn.hostname(),
+                            "upstream rewritten"
+                        );
+                        vec![conn]
+                    }
+                    (None, Some(routing)) => match routing.route(sni.as_deref()) {
+                        Ok(candidates) => candidates,
+                        Err(err) => {
+                            warn!(remote = ?client_addr, "connection rejected: {err}");
+                            let _ = stream.get_mut().shutdown().await;
+                            return;
+                        }
+                    },
+                    (None, None) => candidates,
+                };
+                let registry = connections.clone();
+                let result = match (plaintext, tls_acceptor) {
+                    (Some(plaintext), Some(acceptor)) => {
+                        // Sniffing fills the buffer, which must not be discarded.
+                        let stream_opts = StreamOptions {
+                            buffering: BufferingMode::Buffered,
+                            ..stream_opts
+                        };
+                        match sniff(&mut stream, protocol_detection_timeout).await {
+                            Ok(DetectedProtocol::Tls) => {
+                                start(
+                                    stream,
+                                    ProxyParams {
+                                        client_addr,
+                                        candidates,
+                                        tls_client_config,
+                                        tls_acceptor: Some(acceptor),
+                                        ..Default::default()
+                                    },
+                                    connections,
+                                    stream_opts,
+                                    stop_notifier,
+                                )
+                                .await
+                            }
+                            Ok(_) => {
+                                warn!(
+                                    remote = ?client_addr,
+                                    "plaintext client proxied without tls termination"
+                                );
+                                start(
+                                    stream,
+                                    ProxyParams {
+                                        client_addr,
+                                        candidates: plaintext,
+                                        tls_client_config,
+                                        ..Default::default()
+                                    },
+                                    connections,
+                                    stream_opts,
+                                    stop_notifier,
+                                )
+                                .await
+                            }
+                            Err(err) => Err(err.into()),
+                        }
+                    }
+                    (_, tls_acceptor) => {
+                        start(
+                            stream,
+                            ProxyParams {
+                                client_addr,
+                                peeked,
+                                candidates,
+                                tls_client_config,
+                                tls_acceptor,
+                            },
+                            connections,
+                            stream_opts,
+                            stop_notifier,
+                        )
+                        .await
+                    }
+                };
+                if let Err(err) = result {
                     error!("{err}");
+                    registry.record_error(&err);
                 }
+                drop(permit);
+                drop(limit_permit);
             }
             .instrument(span),
         );
-        self.round_robin_counter = self.round_robin_counter.wrapping_add(1);
     }
 }
 
-pub async fn start(
-    stream: BufStream<TcpStream>,
-    conn: Connection,
-    tls_client_config: Option<Arc<ClientConfig>>,
-    tls_acceptor: Option<TlsAcceptor>,
-    stop_notifier: Arc<Notify>,
-) -> anyhow::Result<()> {
-    let remote = stream.get_ref().peer_addr()?;
-    let local = stream.get_ref().local_addr()?;
+/// Selects the upstreams of a connection by the SNI of its ClientHello.
+struct SniRouting {
+    servers: Vec<Connection>,
+    tag: Option<String>,
+    client: Option<IpAddr>,
+    load_balance: LoadBalanceMode,
+    selections: Arc<SelectionWindow>,
+}
 
-    let host = match conn.name.clone() {
-        ServerName::DnsName(name) => format!("{}:{}", name.as_ref(), conn.port),
-        ServerName::IpAddress(addr) => format!("{}:{}", addr, conn.port),
-        _ => unreachable!(),
-    };
+impl SniRouting {
+    /// Returns the candidate upstreams for the SNI of the ClientHello, which is read
+    /// without decrypting the connection and replayed to the upstream.
+    ///
+    /// Clients whose SNI matches no upstream, or which send none, are balanced over
+    /// the upstreams without server names, or over all upstreams if there are none.
+    fn route(self, sni: Option<&str>) -> anyhow::Result<Vec<Connection>> {
+        let routed = match sni {
+            Some(sni) => self
+                .servers
+                .iter()
+                .filter(|server| server.server_names.iter().any(|name| name.test(sni)))
+                .cloned()
+                .collect::<Vec<_>>(),
+            None => vec![],
+        };
+        let routed = if routed.is_empty() {
+            self.servers
+                .iter()
+                .filter(|server| server.server_names.is_empty())
+                .cloned()
+                .collect::<Vec<_>>()
+        } else {
+            routed
+        };
+        let servers = if routed.is_empty() {
+            &self.servers
+        } else {
+            &routed
+        };
+        let candidates =
+            select_upstreams(servers, self.tag.as_deref(), self.client, self.load_balance);
+        if candidates.is_empty() {
+            anyhow::bail!("no upstream servers available");
+        }
+        self.selections.record(&candidates[0]);
+        debug!(
+            sni = sni.map(|sni| redact_host(sni.to_string())),
+            upstream = candidates[0].hostname(),
+            "routed by sni"
+        );
+        Ok(candidates)
+    }
+}
+
+/// Options applied to each connection on the raw TCP proxy path.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct StreamOptions {
+    pub first_byte_timeout: Option<Duration>,
+    /// Defaults to [`DEFAULT_CONNECT_TIMEOUT`].
+    pub connect_timeout: Option<Duration>,
+    /// Defaults to [`DEFAULT_HAPPY_EYEBALLS_DELAY`].
+    pub happy_eyeballs_delay: Option<Duration>,
+    /// Excludes an upstream from selection for this period after a failed attempt.
+    pub failure_cooldown: Option<Duration>,
+    pub outlier_detection: Option<OutlierDetection>,
+    pub idle_timeout: Option<Duration>,
+    /// Measured from accepting the connection. Zero means unlimited.
+    pub read_deadline: Option<Duration>,
+    /// Measured from accepting the connection. Zero means unlimited.
+    pub write_deadline: Option<Duration>,
+    /// Defaults to [`DEFAULT_TLS_CLOSE_TIMEOUT`].
+    pub tls_close_timeout: Option<Duration>,
+    /// Fails reads from TLS peers which close the connection without `close_notify`.
+    pub tls_strict_close: bool,
+    pub buffering: BufferingMode,
+    pub lifecycle_events: bool,
+    pub trace_context: bool,
+    pub tls_handshake_retries: u32,
+    pub tunnel_compression: bool,
+    pub inbound_keepalive: Option<TcpKeepalive>,
+    pub outbound_keepalive: Option<TcpKeepalive>,
+    pub tcp_nodelay: bool,
+    /// Enables TCP Fast Open on the sockets connected to upstreams.
+    pub fast_open: bool,
+    pub send_buffer_size: Option<usize>,
+    pub recv_buffer_size: Option<usize>,
+}
 
-    let resolved = net::lookup_host(&host).await?.next().unwrap();
-    debug!(host, %resolved);
+const LIFECYCLE_TARGET: &str = "taxy::lifecycle";
 
-    let sock = if resolved.is_ipv4() {
-        TcpSocket::new_v4()
-    } else {
-        TcpSocket::new_v6()
-    }?;
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Reports the lifecycle stages of a connection, at info level when enabled
+/// for the port and at debug level otherwise.
+struct Lifecycle {
+    id: u64,
+    enabled: bool,
+    started_at: Instant,
+}
+
+impl Lifecycle {
+    fn event(&self, stage: &'static str) {
+        let elapsed_us = self.started_at.elapsed().as_micros() as u64;
+        if self.enabled {
+            info!(target: LIFECYCLE_TARGET, conn_id = self.id, stage, elapsed_us);
+        } else {
+            debug!(conn_id = self.id, stage, elapsed_us);
+        }
+    }
+}
+
+/// The client side of a proxied connection.
+enum ClientStream<S> {
+    Buffered(BufStream<S>),
+    Direct(S),
+}
+
+impl<S: Socket> ClientStream<S> {
+    /// The stream must not have been read from yet in direct mode,
+    /// since unwrapping it discards the buffer.
+    fn new(stream: BufStream<S>, buffering: BufferingMode) -> Self {
+        match buffering {
+            BufferingMode::Buffered => Self::Buffered(stream),
+            BufferingMode::Direct => Self::Direct(stream.into_inner()),
+        }
+    }
+
+    fn into_io(self) -> Box<dyn IoStream> {
+        match self {
+            Self::Buffered(stream) => Box::new(stream),
+            Self::Direct(stream) => Box::new(stream),
+        }
+    }
+}
+
+/// The client and the upstreams of a connection proxied by [`start`].
+#[derive(Default)]
+pub struct ProxyParams {
+    /// Takes the place of the peer address of the stream, such as the client
+    /// address announced by a PROXY protocol header.
+    pub client_addr: Option<SocketAddr>,
+    /// The bytes already consumed from the stream, such as a ClientHello read
+    /// for routing, which are replayed before it.
+    pub peeked: Vec<u8>,
+    pub candidates: Vec<Connection>,
+    /// Only used for TLS upstreams.
+    pub tls_client_config: Option<Arc<ClientConfig>>,
+    pub tls_acceptor: Option<BoundedAcceptor>,
+}
 
-    info!(target: "taxy::access_log", remote = %remote, %local, %resolved);
+/// Proxies the stream to the first of the candidate upstreams which accepts the connection
+/// and completes the TLS handshake.
+pub async fn start<S: Socket>(
+    stream: BufStream<S>,
+    params: ProxyParams,
+    connections: ConnectionRegistry,
+    opts: StreamOptions,
+    stop_notifier: Arc<Notify>,
+) -> anyhow::Result<()> {
+    let ProxyParams {
+        client_addr,
+        peeked,
+        candidates,
+        tls_client_config,
+        tls_acceptor,
+    } = params;
+    let remote = match client_addr {
+        Some(addr) => addr,
+        None => stream.get_ref().peer_addr()?,
+    };
+    let local = stream.get_ref().local_addr()?;
+    let mut active = connections.register(remote, local);
+    let lifecycle = Lifecycle {
+        id: active.id(),
+        enabled: opts.lifecycle_events,
+        started_at: Instant::now(),
+    };
+    lifecycle.event("accepted");
+    if let (Some(keepalive), Some(tcp)) = (&opts.inbound_keepalive, stream.get_ref().as_tcp()) {
+        if let Err(err) = set_keepalive(tcp, keepalive) {
+            warn!(%remote, "failed to set keepalive: {err}");
+        }
+    }
+    if let Some(tcp) = stream.get_ref().as_tcp().filter(|_| opts.tcp_nodelay) {
+        if let Err(err) = tcp.set_nodelay(true) {
+            warn!(%remote, "failed to set TCP_NODELAY: {err}");
+        }
+    }
+    if let Some(tcp) = stream.get_ref().as_tcp() {
+        if let Err(err) = set_buffer_sizes(tcp, &opts) {
+            warn!(%remote, "failed to set socket buffer sizes: {err}");
+        }
+    }
 
-    let out = sock.connect(resolved).await?;
-    debug!(%resolved, "connected");
+    let mut last_err = None;
+    let mut attempts = 0;
+    let (conn, _active, resolved, mut out) = loop {
+        let Some(conn) = candidates.get(attempts) else {
+            return Err(
+                last_err.unwrap_or_else(|| anyhow::anyhow!("no upstream servers available"))
+            );
+        };
+        if attempts > 0 && !active.acquire_retry() {
+            warn!(attempts, "retry budget exhausted, not failing over");
+            return Err(
+                last_err.unwrap_or_else(|| anyhow::anyhow!("no upstream servers available"))
+            );
+        }
+        attempts += 1;
+        lifecycle.event("upstream_selected");
+        let upstream = ActiveUpstream::new(conn.stats.clone());
+        match connect_candidate(conn, tls_client_config.as_ref(), &active, &lifecycle, opts).await {
+            Ok((resolved, out)) => break (conn, upstream, resolved, out),
+            Err(err) => {
+                warn!(
+                    upstream = conn.hostname(),
+                    port = conn.port,
+                    attempts,
+                    "failed to connect to upstream: {err}"
+                );
+                if let Some(cooldown) = opts.failure_cooldown {
+                    conn.stats.start_cooldown(cooldown);
+                }
+                if let Some(config) = &opts.outlier_detection {
+                    record_failure(conn, config);
+                }
+                last_err = Some(err);
+            }
+        }
+    };
+    let hostname = conn.hostname();
+    if attempts > 1 {
+        info!(upstream = hostname, port = conn.port, %resolved, attempts, "connection served by failover upstream");
+    }
+    let stats = conn.stats.clone();
 
-    let mut stream: Box<dyn IoStream> = Box::new(stream);
+    let mut stream = ClientStream::new(stream, opts.buffering).into_io();
+    if !peeked.is_empty() {
+        stream = Box::new(PrefixedStream::new(peeked, stream));
+    }
+    let mut stream: Box<dyn IoStream> = Box::new(active.track(stream, Side::Client));
+    let mut served_cert = None;
+    let mut client_cert = None;
+    let mut sni = None;
+    let mut alpn = None;
     if let Some(acceptor) = tls_acceptor {
-        debug!(%remote, "server: tls handshake");
-        stream = Box::new(acceptor.accept(stream).await?);
+        let accepted;
+        (accepted, served_cert) = acceptor.accept_with_cert(stream).await?;
+        sni = accepted
+            .get_ref()
+            .1
+            .server_name()
+            .map(|sni| sni.to_string());
+        client_cert = acceptor.client_cert(&accepted).map(|cert| cert.subject);
+        alpn = accepted
+            .get_ref()
+            .1
+            .alpn_protocol()
+            .map(|alpn| alpn.to_vec());
+        let accepted = CloseNotifyStream::new(accepted, opts.tls_strict_close);
+        stream = if alpn.as_deref() == Some(ALPN_DEFLATE) {
+            debug!(%remote, "client tunnel compression negotiated");
+            alpn = None;
+            Box::new(DeflateStream::new(accepted))
+        } else {
+            Box::new(accepted)
+        };
+        lifecycle.event("tls_server_done");
+    }
+    let alpn_name = alpn
+        .as_deref()
+        .map(|alpn| String::from_utf8_lossy(alpn).into_owned());
+    if let Some(cert) = &served_cert {
+        active.set_served_cert(cert);
+    }
+    if let Some(subject) = &client_cert {
+        active.set_client_cert(subject);
+    }
+    if let Some(version) = conn.proxy_protocol {
+        let header = proxy_protocol::encode_header(version, remote, local, alpn.as_deref());
+        out.write_all(&header).await?;
     }
 
-    let mut out: Box<dyn IoStream> = Box::new(out);
-    if let Some(config) = tls_client_config {
-        debug!(%resolved, "client: tls handshake");
-        let tls = TlsConnector::from(config);
-        out = Box::new(tls.connect(conn.name, out).await?);
+    if opts.read_deadline.is_some() || opts.write_deadline.is_some() {
+        let deadline = |timeout: Option<Duration>| {
+            timeout
+                .filter(|timeout| !timeout.is_zero())
+                .map(|timeout| lifecycle.started_at + timeout)
+        };
+        stream = Box::new(DeadlineStream::new(
+            stream,
+            deadline(opts.read_deadline),
+            deadline(opts.write_deadline),
+        ));
     }
 
-    tokio::select! {
-        result = tokio::io::copy_bidirectional(&mut stream, &mut out) => {
-            if let Err(err) = result {
-                error!("{err}");
+    let remote_name = active.remote_name().map(redact_host);
+    let host = redact_host(hostname);
+    let sni = sni.map(redact_host);
+    let trace_id = opts
+        .trace_context
+        .then(|| TraceParent::generate().trace_id());
+    info!(target: "taxy::access_log", remote = %remote, remote_name, %local, host, sni, alpn = alpn_name, %resolved, served_cert, client_cert, trace_id);
+
+    let proxy = async {
+        let wait = opts.first_byte_timeout.is_some() || opts.lifecycle_events;
+        if wait && wait_first_byte(&mut stream, &mut out, opts.first_byte_timeout).await? {
+            lifecycle.event("first_byte");
+        }
+        tokio::io::copy_bidirectional(&mut stream, &mut out).await?;
+        anyhow::Ok(())
+    };
+    let idle = async {
+        match opts.idle_timeout {
+            Some(timeout) => wait_idle(&active, timeout).await,
+            None => std::future::pending().await,
+        }
+    };
+
+    let outcome = tokio::select! {
+        result = proxy => {
+            match result {
+                Err(err) if err.is::<FirstByteTimeout>() => {
+                    warn!(%resolved, "{err}");
+                    ConnectionOutcome::FirstByteTimeout
+                }
+                Err(err) => match err
+                    .downcast_ref::<io::Error>()
+                    .and_then(DeadlineExceeded::from_io_error)
+                {
+                    Some(DeadlineExceeded::Read) => {
+                        warn!(%resolved, "{err}");
+                        ConnectionOutcome::ReadDeadline
+                    }
+                    Some(DeadlineExceeded::Write) => {
+                        warn!(%resolved, "{err}");
+                        ConnectionOutcome::WriteDeadline
+                    }
+                    None => {
+                        error!("{err}");
+                        ConnectionOutcome::Error
+                    }
+                },
+                Ok(()) => active.closed_by(),
             }
         },
+        _ = idle => {
+            debug!(%resolved, "idle timeout");
+            ConnectionOutcome::IdleTimeout
+        },
+        _ = active.shutdown_idle() => {
+            debug!(%resolved, "shutdown");
+            ConnectionOutcome::Stopped
+        },
         _ = stop_notifier.notified() => {
             debug!(%resolved, "stop");
+            ConnectionOutcome::Stopped
+        },
+        _ = stats.drain.notified() => {
+            debug!(%resolved, "drain");
+            ConnectionOutcome::Drained
         },
+    };
+    active.set_outcome(outcome);
+    if let Some(config) = &opts.outlier_detection {
+        if active.upstream_reset_early() {
+            record_failure(conn, config);
+        } else {
+            stats.record_success();
+        }
     }
 
-    stream.shutdown().await?;
-    out.shutdown().await?;
+    let close_timeout = opts.tls_close_timeout.unwrap_or(DEFAULT_TLS_CLOSE_TIMEOUT);
+    let (client_shutdown, upstream_shutdown) = tokio::join!(
+        tls_close::shutdown(&mut stream, close_timeout),
+        tls_close::shutdown(&mut out, close_timeout),
+    );
 
-    debug!(%resolved, "eof");
+    lifecycle.event("closed");
+    client_shutdown?;
+    upstream_shutdown?;
     Ok(())
 }
 
-fn multiaddr_to_tcp(addr: &Multiaddr) -> Result<SocketAddr, Error> {
-    let stack = addr.iter().collect::<Vec<_>>();
-    match &stack[..] {
-        [Protocol::Ip4(addr), Protocol::Tcp(port), ..] if *port > 0 => {
-            Ok(SocketAddr::new(std::net::IpAddr::V4(*addr), *port))
+fn record_failure(conn: &Connection, config: &OutlierDetection) {
+    if let Some(ejection) = conn.stats.record_failure(config) {
+        warn!(
+            upstream = conn.hostname(),
+            port = conn.port,
+            "upstream ejected for {ejection:?} after repeated failures"
+        );
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("upstream did not send any data within {0:?}")]
+struct FirstByteTimeout(Duration);
+
+#[derive(Debug, thiserror::Error)]
+#[error("connection to upstream timed out after {0:?}")]
+struct ConnectTimeout(Duration);
+
+/// Waits until the upstream sends its first bytes, forwarding data in both directions meanwhile.
+/// Returns true if the upstream sent any data.
+///
+/// The window restarts whenever the client sends data, so for protocols where the
+/// client speaks first it is measured from the latest request bytes.
+async fn wait_first_byte(
+    client: &mut Box<dyn IoStream>,
+    upstream: &mut Box<dyn IoStream>,
+    window: Option<Duration>,
+) -> anyhow::Result<bool> {
+    let mut buf = vec![0; 8192];
+    let mut upstream_buf = vec![0; 8192];
+    let deadline_after = |now: Instant| window.map(|window| now + window);
+    let mut deadline = deadline_after(Instant::now());
+    loop {
+        tokio::select! {
+            result = upstream.read(&mut upstream_buf) => {
+                let len = result?;
+                client.write_all(&upstream_buf[..len]).await?;
+                client.flush().await?;
+                return Ok(len > 0);
+            }
+            result = client.read(&mut buf) => {
+                let len = result?;
+                if len == 0 {
+                    return Ok(false);
+                }
+                upstream.write_all(&buf[..len]).await?;
+                deadline = deadline_after(Instant::now());
+            }
+            _ = sleep_until_deadline(deadline) => {
+                return Err(FirstByteTimeout(window.unwrap_or_default()).into());
+            }
         }
-        [Protocol::Ip6(addr), Protocol::Tcp(port), ..] if *port > 0 => {
-            Ok(SocketAddr::new(std::net::IpAddr::V6(*addr), *port))
+    }
+}
+
+/// Returns once no bytes have been transferred on the connection for `timeout`,
+/// counting from the time this is called at the earliest.
+async fn wait_idle(active: &ConnectionHandle, timeout: Duration) {
+    let started_at = Instant::now();
+    loop {
+        let idle = active.idle_for().min(started_at.elapsed());
+        if idle >= timeout {
+            return;
         }
-        _ => Err(Error::InvalidListeningAddress { addr: addr.clone() }),
+        tokio::time::sleep(timeout - idle).await;
     }
 }
 
-fn multiaddr_to_host(addr: &Multiaddr) -> Result<Connection, Error> {
-    let stack = addr.iter().collect::<Vec<_>>();
-    let tls = stack.last() == Some(&Protocol::Tls);
-    match stack[..] {
-        [Protocol::Ip4(addr), Protocol::Tcp(port), ..] if port > 0 => Ok(Connection {
-            name: ServerName::IpAddress(IpAddr::V4(addr)),
-            port,
-            tls,
-        }),
-        [Protocol::Ip6(addr), Protocol::Tcp(port), ..] if port > 0 => Ok(Connection {
-            name: ServerName::IpAddress(IpAddr::V6(addr)),
-            port,
-            tls,
-        }),
-        [Protocol::Dns(ref name), Protocol::Tcp(port), ..] if port > 0 => Ok(Connection {
-            name: ServerName::try_from(name.as_ref())
-                .map_err(|_| Error::InvalidServerAddress { addr: addr.clone() })?,
-            port,
-            tls,
-        }),
-        _ => Err(Error::InvalidServerAddress { addr: addr.clone() }),
+/// Connects to the upstream, completing the TLS handshake if it is a TLS upstream.
+async fn connect_candidate(
+    conn: &Connection,
+    tls_client_config: Option<&Arc<ClientConfig>>,
+    active: &ConnectionHandle,
+    lifecycle: &Lifecycle,
+    opts: StreamOptions,
+) -> anyhow::Result<(Address, Box<dyn IoStream>)> {
+    let host = format!("{}:{}", conn.hostname(), conn.port);
+    let addrs = match (&conn.unix, &conn.endpoints) {
+        (Some(path), _) => vec![Address::Unix(path.clone())],
+        (None, Some(endpoints)) => endpoints
+            .select_dual_stack(Instant::now())
+            .await?
+            .into_iter()
+            .map(Address::Inet)
+            .collect(),
+        (None, None) => happy_eyeballs::interleave(net::lookup_host(&host).await?.collect())
+            .into_iter()
+            .map(Address::Inet)
+            .collect(),
+    };
+    debug!(host, ?addrs);
+    lifecycle.event("resolved");
+
+    let connect_timeout = opts.connect_timeout.unwrap_or(DEFAULT_CONNECT_TIMEOUT);
+    let delay = opts
+        .happy_eyeballs_delay
+        .unwrap_or(DEFAULT_HAPPY_EYEBALLS_DELAY);
+    let (resolved, out) = happy_eyeballs::connect(addrs, delay, |addr| async move {
+        connect_upstream(conn, &addr, connect_timeout, opts.fast_open).await
+    })
+    .await?;
+    lifecycle.event("connected");
+    if let (Some(keepalive), Some(tcp)) = (&opts.outbound_keepalive, out.as_tcp()) {
+        if let Err(err) = set_keepalive(tcp, keepalive) {
+            warn!(%resolved, "failed to set keepalive: {err}");
+        }
+    }
+    if let Some(tcp) = out.as_tcp().filter(|_| opts.tcp_nodelay) {
+        if let Err(err) = tcp.set_nodelay(true) {
+            warn!(%resolved, "failed to set TCP_NODELAY: {err}");
+        }
+    }
+    if let Some(tcp) = out.as_tcp() {
+        if let Err(err) = set_buffer_sizes(tcp, &opts) {
+            warn!(%resolved, "failed to set socket buffer sizes: {err}");
+        }
+    }
+
+    let mut out: Box<dyn IoStream> = Box::new(active.track(out, Side::Upstream));
+    if let Some(config) = tls_client_config.filter(|_| conn.tls) {
+        let tls = TlsConnector::from(conn.tls_client_config(config));
+        let mut retries = 0;
+        loop {
+            match tls.connect(conn.tls_server_name(), out).await {
+                Ok(stream) => {
+                    let compressed = stream.get_ref().1.alpn_protocol() == Some(ALPN_DEFLATE);
+                    let stream = CloseNotifyStream::new(stream, opts.tls_strict_close);
+                    out = if compressed {
+                        debug!(%resolved, "upstream tunnel compression negotiated");
+                        Box::new(DeflateStream::new(stream))
+                    } else {
+                        Box::new(stream)
+                    };
+                    break;
+                }
+                Err(err)
+                    if retries < opts.tls_handshake_retries
+                        && is_transient_handshake_error(&err)
+                        && active.acquire_retry() =>
+                {
+                    retries += 1;
+                    warn!(%resolved, retries, "upstream tls handshake failed, retrying: {err}");
+                    let stream =
+                        connect_upstream(conn, &resolved, connect_timeout, opts.fast_open).await?;
+                    out = Box::new(active.track(stream, Side::Upstream));
+                }
+                Err(err) => return Err(err.into()),
+            }
+        }
+        lifecycle.event("tls_client_done");
     }
+    Ok((resolved, out))
 }
 
-trait IoStream: AsyncRead + AsyncWrite + Unpin + Send {}
+/// Connects to the upstream. Connections which time out are counted
+/// separately from the ones which fail.
+async fn connect_upstream(
+    conn: &Connection,
+    resolved: &Address,
+    timeout: Duration,
+    fast_open: bool,
+) -> anyhow::Result<SocketStream> {
+    let connect = async {
+        let resolved = match resolved {
+            Address::Inet(resolved) => *resolved,
+            Address::Unix(path) => return socket::connect_unix(path).await,
+        };
+        let stream = if let Some(source) = &conn.source {
+            source.connect(resolved, fast_open).await
+        } else {
+            socket::new_tcp_socket(resolved, fast_open)?
+                .connect(resolved)
+                .await
+        };
+        stream.map(SocketStream::Tcp)
+    };
+    let out = match tokio::time::timeout(timeout, connect).await {
+        Ok(out) => out.map_err(anyhow::Error::from),
+        Err(_) => Err(ConnectTimeout(timeout).into()),
+    };
+    match &out {
+        Ok(_) => {
+            conn.stats.connections.fetch_add(1, Ordering::Relaxed);
+            if let (Some(endpoints), Address::Inet(resolved)) = (&conn.endpoints, resolved) {
+                endpoints.mark_healthy(*resolved);
+            }
+        }
+        Err(err) => {
+            let counter = if err.is::<ConnectTimeout>() {
+                &conn.stats.connect_timeouts
+            } else {
+                &conn.stats.connect_failures
+            };
+            counter.fetch_add(1, Ordering::Relaxed);
+            if let (Some(endpoints), Address::Inet(resolved)) = (&conn.endpoints, resolved) {
+                endpoints.mark_unhealthy(*resolved, Instant::now());
+            }
+        }
+    }
+    out
+}
 
-impl<S> IoStream for S where S: AsyncRead + AsyncWrite + Unpin + Send {}
+fn set_keepalive(stream: &TcpStream, config: &TcpKeepalive) -> io::Result<()> {
+    let keepalive = socket2::TcpKeepalive::new()
+        .with_time(config.time)
+        .with_interval(config.interval);
+    #[cfg(not(windows))]
+    let keepalive = keepalive.with_retries(config.retries);
+    socket2::SockRef::from(stream).set_tcp_keepalive(&keepalive)
+}
 
-#[derive(Debug, Clone)]
-pub struct Connection {
-    pub name: ServerName,
-    pub port: u16,
-    pub tls: bool,
+/// Requests the configured buffer sizes, warning if the OS caps them below the requested size.
+fn set_buffer_sizes(stream: &TcpStream, opts: &StreamOptions) -> io::Result<()> {
+    let sock = socket2::SockRef::from(stream);
+    if let Some(size) = opts.send_buffer_size {
+        sock.set_send_buffer_size(size)?;
+        let actual = sock.send_buffer_size()?;
+        if actual < size {
+            warn!(
+                requested = size,
+                actual, "send buffer size capped by the OS"
+            );
+        }
+    }
+    if let Some(size) = opts.recv_buffer_size {
+        sock.set_recv_buffer_size(size)?;
+        let actual = sock.recv_buffer_size()?;
+        if actual < size {
+            warn!(
+                requested = size,
+                actual, "receive buffer size capped by the OS"
+            );
+        }
+    }
+    Ok(())
+}
+
+/// TLS protocol errors, such as an invalid certificate, are reported as `InvalidData`
+/// and would fail again. Only a connection lost during the handshake is worth retrying.
+fn is_transient_handshake_error(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::UnexpectedEof
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::BrokenPipe
+            | io::ErrorKind::TimedOut
+    )
+}
+
+async fn sleep_until_deadline(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
+
+pub(super) fn upstream_connections(
+    servers: &[UpstreamServer],
+    opts: &PortOptions,
+) -> Result<Vec<Connection>, Error> {
+    let mut conns: Vec<Connection> = Vec::new();
+    for server in servers {
+        let mut conn = multiaddr_to_host(&server.addr)?;
+        conn.source = SourceBinding::new(server, &opts.source_addrs)?.map(Arc::new);
+        conn.disabled = server.disabled;
+        conn.tags = server.tags.clone();
+        conn.weight = server.weight;
+        conn.disable_sni = server.disable_sni;
+        conn.insecure_skip_verify = server.insecure_skip_verify;
+        conn.server_name_override = server
+            .server_name_override
+            .as_deref()
+            .map(parse_server_name)
+            .transpose()?;
+        conn.proxy_protocol = server.proxy_protocol;
+        conn.server_names = server
+            .server_names
+            .iter()
+            .map(|name| SubjectName::from_str(name))
+            .collect::<Result<_, _>>()?;
+        if let Some(existing) = conns.iter_mut().find(|c| c.is_same_upstream(&conn)) {
+            match opts.duplicate_upstreams {
+                DuplicateUpstreams::Keep => {}
+                DuplicateUpstreams::Error => {
+                    return Err(Error::DuplicateUpstream {
+                        addr: server.addr.clone(),
+                    })
+                }
+                DuplicateUpstreams::Merge => {
+                    existing.weight = existing.weight.saturating_add(conn.weight);
+                    continue;
+                }
+            }
+        }
+        if let (ServerName::DnsName(name), None) = (&conn.name, &conn.unix) {
+            let config = opts.dns_resolution.clone().unwrap_or_default();
+            conn.endpoints = Some(ResolvedEndpoints::new(name.as_ref(), conn.port, &config));
+        }
+        conns.push(conn);
+    }
+    Ok(conns)
+}
+
+pub(super) fn multiaddr_to_tcp(addr: &Multiaddr) -> Result<SocketAddr, Error> {
+    let stack = addr.iter().collect::<Vec<_>>();
+    let socket = match &stack[..] {
+        [Protocol::Ip4(addr), Protocol::Tcp(port), ..] if *port > 0 => {
+            SocketAddr::new(std::net::IpAddr::V4(*addr), *port)
+        }
+        [Protocol::Ip6(addr), Protocol::Tcp(port), ..] if *port > 0 => {
+            SocketAddr::new(std::net::IpAddr::V6(*addr), *port)
+        }
+        _ => return Err(Error::InvalidListeningAddress { addr: addr.clone() }),
+    };
+    match &stack[2..] {
+        [] | [Protocol::Tls] | [Protocol::Http] | [Protocol::Tls, Protocol::Http] => Ok(socket),
+        [Protocol::Https] => Ok(socket),
+        trailing => Err(unsupported_protocol(addr, trailing)),
+    }
+}
+
+/// Parses the address of a raw TCP port, which may also listen on a Unix socket
+/// at `/unix/<path>`, optionally followed by `/tls`.
+pub(super) fn multiaddr_to_listen(addr: &Multiaddr) -> Result<Address, Error> {
+    let stack = addr.iter().collect::<Vec<_>>();
+    match &stack[..] {
+        [Protocol::Unix(path)] | [Protocol::Unix(path), Protocol::Tls] => {
+            Ok(Address::Unix(socket::unix_path(path)))
+        }
+        [Protocol::Unix(_), trailing @ ..] => Err(unsupported_protocol(addr, trailing)),
+        _ => multiaddr_to_tcp(addr).map(Address::Inet),
+    }
+}
+
+/// Returns whether the protocols following the TCP port of an upstream
+/// address request TLS. WebSocket upstreams are proxied as plain streams.
+fn trailing_protocols_tls(addr: &Multiaddr, trailing: &[Protocol]) -> Result<bool, Error> {
+    match trailing {
+        [] | [Protocol::Http] | [Protocol::Ws(_)] => Ok(false),
+        [Protocol::Tls] | [Protocol::Tls, Protocol::Http] | [Protocol::Tls, Protocol::Ws(_)] => {
+            Ok(true)
+        }
+        [Protocol::Https] | [Protocol::Wss(_)] => Ok(true),
+        trailing => Err(unsupported_protocol(addr, trailing)),
+    }
+}
+
+fn unsupported_protocol(addr: &Multiaddr, trailing: &[Protocol]) -> Error {
+    let known = |protocol: &Protocol| {
+        matches!(
+            protocol,
+            Protocol::Tls | Protocol::Http | Protocol::Https | Protocol::Ws(_) | Protocol::Wss(_)
+        )
+    };
+    // Report the unknown protocol, or the whole sequence if only the order is wrong.
+    let protocol = match trailing.iter().find(|protocol| !known(protocol)) {
+        Some(protocol) => protocol.to_string(),
+        None => trailing
+            .iter()
+            .map(|protocol| protocol.to_string())
+            .collect(),
+    };
+    Error::UnsupportedProtocol {
+        addr: addr.clone(),
+        protocol,
+    }
+}
+
+pub(super) fn multiaddr_to_host(addr: &Multiaddr) -> Result<Connection, Error> {
+    let stack = addr.iter().collect::<Vec<_>>();
+    let (name, port, unix, trailing) = match stack[..] {
+        // The TLS handshake with a Unix socket upstream expects a certificate for localhost.
+        [Protocol::Unix(ref path), ..] => (
+            ServerName::try_from("localhost").unwrap(),
+            0,
+            Some(socket::unix_path(path)),
+            &stack[1..],
+        ),
+        [Protocol::Ip4(addr), Protocol::Tcp(port), ..] if port > 0 => (
+            ServerName::IpAddress(IpAddr::V4(addr)),
+            port,
+            None,
+            &stack[2..],
+        ),
+        [Protocol::Ip6(addr), Protocol::Tcp(port), ..] if port > 0 => (
+            ServerName::IpAddress(IpAddr::V6(addr)),
+            port,
+            None,
+            &stack[2..],
+        ),
+        [Protocol::Dns(ref name), Protocol::Tcp(port), ..] if port > 0 => (
+            ServerName::try_from(name.as_ref())
+                .map_err(|_| Error::InvalidServerAddress { addr: addr.clone() })?,
+            port,
+            None,
+            &stack[2..],
+        ),
+        _ => return Err(Error::InvalidServerAddress { addr: addr.clone() }),
+    };
+    Ok(Connection {
+        name,
+        port,
+        unix,
+        tls: trailing_protocols_tls(addr, trailing)?,
+        source: None,
+        disabled: false,
+        tags: vec![],
+        weight: 1,
+        disable_sni: false,
+        insecure_skip_verify: false,
+        server_name_override: None,
+        proxy_protocol: None,
+        server_names: vec![],
+        stats: Default::default(),
+        endpoints: None,
+    })
+}
+
+pub(super) fn parse_server_name(name: &str) -> Result<ServerName, Error> {
+    ServerName::try_from(name).map_err(|_| Error::InvalidSubjectName {
+        name: name.to_string(),
+    })
+}
+
+trait IoStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<S> IoStream for S where S: AsyncRead + AsyncWrite + Unpin + Send {}
+
+#[derive(Debug, Clone)]
+pub struct Connection {
+    pub name: ServerName,
+    pub port: u16,
+    /// The socket path of upstreams at `/unix/<path>`, which is connected to
+    /// in place of the name and port.
+    pub unix: Option<PathBuf>,
+    pub tls: bool,
+    pub source: Option<Arc<SourceBinding>>,
+    pub disabled: bool,
+    pub tags: Vec<String>,
+    pub weight: u32,
+    pub disable_sni: bool,
+    pub insecure_skip_verify: bool,
+    /// The name of TLS upstreams sent in the SNI and verified, in place of `name`.
+    pub server_name_override: Option<ServerName>,
+    pub proxy_protocol: Option<ProxyProtocol>,
+    /// Host patterns matched against the SNI of clients on ports with SNI routing.
+    pub server_names: Vec<SubjectName>,
+    pub stats: Arc<UpstreamStats>,
+    pub endpoints: Option<ResolvedEndpoints>,
+}
+
+impl Connection {
+    fn is_same_upstream(&self, other: &Self) -> bool {
+        self.name == other.name && self.port == other.port && self.unix == other.unix
+    }
+
+    /// Returns the client config for the TLS handshake with this upstream.
+    pub fn tls_client_config(&self, config: &Arc<ClientConfig>) -> Arc<ClientConfig> {
+        if !self.disable_sni && !self.insecure_skip_verify {
+            return config.clone();
+        }
+        let mut config = ClientConfig::clone(config);
+        if self.disable_sni {
+            config.enable_sni = false;
+        }
+        if self.insecure_skip_verify {
+            config
+                .dangerous()
+                .set_certificate_verifier(Arc::new(upstream_tls::InsecureVerifier));
+            // Verified upstreams of the same name must not resume these sessions.
+            config.resumption = Resumption::disabled();
+        }
+        Arc::new(config)
+    }
+
+    /// Returns the name sent in the SNI and verified against the certificate of TLS upstreams.
+    pub fn tls_server_name(&self) -> ServerName {
+        self.server_name_override
+            .clone()
+            .unwrap_or_else(|| self.name.clone())
+    }
+
+    pub fn hostname(&self) -> String {
+        if let Some(path) = &self.unix {
+            return path.display().to_string();
+        }
+        match &self.name {
+            ServerName::DnsName(name) => name.as_ref().to_string(),
+            ServerName::IpAddress(addr) => addr.to_string(),
+            _ => unreachable!(),
+        }
+    }
+}
+
+/// Runtime state of an upstream, kept across config updates as long as the upstream is unchanged.
+#[derive(Debug, Default)]
+pub struct UpstreamStats {
+    pub drain: Notify,
+    pub connections: AtomicU64,
+    pub connect_failures: AtomicU64,
+    pub connect_timeouts: AtomicU64,
+    /// Current weight of the smooth weighted round-robin schedule.
+    pub current_weight: AtomicI64,
+    /// Connections currently proxied to the upstream.
+    pub active: AtomicUsize,
+    /// Consecutive failed health checks.
+    pub health_failures: AtomicU32,
+    /// Set by the health checker once the upstream is down.
+    pub unhealthy: AtomicBool,
+    /// Set after a failed attempt on ports with `upstream_failure_cooldown`.
+    pub cooldown_until: Mutex<Option<Instant>>,
+    /// Failures counted on ports with `outlier_detection`.
+    pub outliers: Mutex<OutlierState>,
+}
+
+#[derive(Debug, Default)]
+pub struct OutlierState {
+    failures: u32,
+    last_failure: Option<Instant>,
+    /// Ejections in a row, each one following a failed attempt after the previous one.
+    ejections: u32,
+    ejected_until: Option<Instant>,
+}
+
+impl UpstreamStats {
+    /// Excludes the upstream from selection until `cooldown` has elapsed.
+    pub fn start_cooldown(&self, cooldown: Duration) {
+        *self.cooldown_until.lock().unwrap() = Some(Instant::now() + cooldown);
+    }
+
+    /// Counts a failed connection, and ejects the upstream once the failures in a row reach
+    /// the threshold, or right away if the upstream was being tried again after an ejection.
+    /// Returns the ejection time if the upstream has been ejected.
+    pub fn record_failure(&self, config: &OutlierDetection) -> Option<Duration> {
+        let now = Instant::now();
+        let mut state = self.outliers.lock().unwrap();
+        if state.ejected_until.is_some_and(|until| now < until) {
+            return None;
+        }
+        if state
+            .last_failure
+            .is_none_or(|last| now.duration_since(last) > config.interval)
+        {
+            state.failures = 0;
+        }
+        state.failures += 1;
+        state.last_failure = Some(now);
+        if state.ejected_until.is_none() && state.failures < config.consecutive_failures {
+            return None;
+        }
+        let ejection = config
+            .base_ejection_time
+            .saturating_mul(2u32.saturating_pow(state.ejections))
+            .min(config.max_ejection_time);
+        state.failures = 0;
+        state.ejections = state.ejections.saturating_add(1);
+        state.ejected_until = Some(now + ejection);
+        Some(ejection)
+    }
+
+    /// Resets the failures in a row, and brings the upstream back if it was being tried
+    /// again after an ejection.
+    pub fn record_success(&self) {
+        let mut state = self.outliers.lock().unwrap();
+        state.failures = 0;
+        if state
+            .ejected_until
+            .is_some_and(|until| until <= Instant::now())
+        {
+            state.ejections = 0;
+            state.ejected_until = None;
+        }
+    }
+
+    pub fn is_ejected(&self) -> bool {
+        self.outliers
+            .lock()
+            .unwrap()
+            .ejected_until
+            .is_some_and(|until| Instant::now() < until)
+    }
+
+    /// Returns `false` if the upstream is unhealthy, ejected, or cooling down after a failure.
+    pub fn is_available(&self) -> bool {
+        !self.unhealthy.load(Ordering::Relaxed)
+            && !self.is_ejected()
+            && self
+                .cooldown_until
+                .lock()
+                .unwrap()
+                .is_none_or(|until| until <= Instant::now())
+    }
+}
+
+/// Number of recent upstream picks kept per port to report their distribution.
+const SELECTION_WINDOW_SIZE: usize = 1000;
+
+/// The upstreams picked for the recent connections of a port, identified by their stats,
+/// which are kept across config updates as long as the upstream is unchanged.
+#[derive(Debug, Default)]
+pub struct SelectionWindow {
+    picks: Mutex<VecDeque<Weak<UpstreamStats>>>,
+}
+
+impl SelectionWindow {
+    pub fn record(&self, upstream: &Connection) {
+        let mut picks = self.picks.lock().unwrap();
+        if picks.len() >= SELECTION_WINDOW_SIZE {
+            picks.pop_front();
+        }
+        picks.push_back(Arc::downgrade(&upstream.stats));
+    }
+
+    /// Returns the share of the recent picks of each upstream along with its share of the
+    /// total weight. Picks of removed upstreams count towards the total until they roll out.
+    pub fn distribution(&self, servers: &[Connection]) -> Vec<UpstreamDistribution> {
+        let picks = self.picks.lock().unwrap();
+        let weight = |server: &Connection| if server.disabled { 0 } else { server.weight };
+        let total_weight = servers
+            .iter()
+            .map(|server| weight(server) as u64)
+            .sum::<u64>();
+        servers
+            .iter()
+            .map(|server| {
+                let selections = picks
+                    .iter()
+                    .filter(|pick| std::ptr::eq(pick.as_ptr(), Arc::as_ptr(&server.stats)))
+                    .count();
+                UpstreamDistribution {
+                    addr: format!("{}:{}", server.hostname(), server.port),
+                    weight: server.weight,
+                    expected: if total_weight == 0 {
+                        0.0
+                    } else {
+                        weight(server) as f64 / total_weight as f64
+                    },
+                    observed: if picks.is_empty() {
+                        0.0
+                    } else {
+                        selections as f64 / picks.len() as f64
+                    },
+                    selections,
+                }
+            })
+            .collect()
+    }
+}
+
+/// Counts a connection as active on its upstream until dropped,
+/// so that every exit path of a connection releases it.
+struct ActiveUpstream(Arc<UpstreamStats>);
+
+impl ActiveUpstream {
+    fn new(stats: Arc<UpstreamStats>) -> Self {
+        stats.active.fetch_add(1, Ordering::Relaxed);
+        Self(stats)
+    }
+}
+
+impl Drop for ActiveUpstream {
+    fn drop(&mut self) {
+        self.0.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Picks the next enabled upstream by smooth weighted round-robin, as nginx does,
+/// which spreads the picks of heavier upstreams evenly over the schedule.
+/// Upstreams with the given tag are preferred if any of them is enabled.
+/// Unavailable upstreams, unhealthy or cooling down after a failure, are skipped
+/// unless all of the enabled ones are unavailable.
+///
+/// In [`LoadBalanceMode::LeastConnections`] mode, only the upstreams with the fewest
+/// active connections per weight take part in the schedule.
+pub(super) fn select_upstream(
+    servers: &[Connection],
+    tag: Option<&str>,
+    mode: LoadBalanceMode,
+) -> Option<Connection> {
+    let enabled = servers
+        .iter()
+        .filter(|server| !server.disabled && server.weight > 0)
+        .collect::<Vec<_>>();
+    let available = enabled
+        .iter()
+        .filter(|server| server.stats.is_available())
+        .copied()
+        .collect::<Vec<_>>();
+    let enabled = if available.is_empty() {
+        enabled
+    } else {
+        available
+    };
+    let preferred = enabled
+        .iter()
+        .filter(|server| tag.is_some_and(|tag| server.tags.iter().any(|t| t == tag)))
+        .copied()
+        .collect::<Vec<_>>();
+    let candidates = if preferred.is_empty() {
+        enabled
+    } else {
+        preferred
+    };
+    let candidates = match mode {
+        // Falls back to round-robin when the client address is unknown.
+        LoadBalanceMode::RoundRobin | LoadBalanceMode::IpHash => candidates,
+        LoadBalanceMode::LeastConnections => {
+            // Compares active / weight by cross-multiplying.
+            let load = |server: &Connection| {
+                (
+                    server.stats.active.load(Ordering::Relaxed) as u64,
+                    server.weight as u64,
+                )
+            };
+            let (active, weight) = candidates
+                .iter()
+                .map(|server| load(server))
+                .min_by(|(a, a_weight), (b, b_weight)| (a * b_weight).cmp(&(b * a_weight)))?;
+            candidates
+                .into_iter()
+                .filter(|server| {
+                    let (a, a_weight) = load(server);
+                    a * weight == active * a_weight
+                })
+                .collect()
+        }
+    };
+    let total = candidates
+        .iter()
+        .map(|server| server.weight as i64)
+        .sum::<i64>();
+    let mut selected: Option<(&Connection, i64)> = None;
+    for server in candidates {
+        let weight = server.weight as i64;
+        let current = server
+            .stats
+            .current_weight
+            .fetch_add(weight, Ordering::Relaxed)
+            + weight;
+        if selected.is_none_or(|(_, max)| current > max) {
+            selected = Some((server, current));
+        }
+    }
+    let (server, _) = selected?;
+    server
+        .stats
+        .current_weight
+        .fetch_sub(total, Ordering::Relaxed);
+    Some(server.clone())
+}
+
+/// Picks the upstream owning the hash of the client address among the enabled upstreams,
+/// each of them owning a share of the hash space proportional to its weight.
+/// Upstreams with the given tag are preferred if any of them is enabled.
+/// An unavailable pick moves on to the next available upstream in the list.
+fn select_by_ip_hash(
+    servers: &[Connection],
+    tag: Option<&str>,
+    client: IpAddr,
+) -> Option<Connection> {
+    let enabled = servers
+        .iter()
+        .filter(|server| !server.disabled && server.weight > 0)
+        .collect::<Vec<_>>();
+    let preferred = enabled
+        .iter()
+        .filter(|server| tag.is_some_and(|tag| server.tags.iter().any(|t| t == tag)))
+        .copied()
+        .collect::<Vec<_>>();
+    let candidates = if preferred.is_empty() {
+        enabled
+    } else {
+        preferred
+    };
+    let total = candidates
+        .iter()
+        .map(|server| server.weight as u64)
+        .sum::<u64>();
+    if total == 0 {
+        return None;
+    }
+    let mut slot = client_hash(client) % total;
+    let index = candidates.iter().position(|server| {
+        let weight = server.weight as u64;
+        if slot < weight {
+            true
+        } else {
+            slot -= weight;
+            false
+        }
+    })?;
+    let server = (0..candidates.len())
+        .map(|offset| candidates[(index + offset) % candidates.len()])
+        .find(|server| server.stats.is_available())
+        .unwrap_or(candidates[index]);
+    Some(server.clone())
+}
+
+/// Hashes the client address without a random seed, so that it is stable across restarts.
+fn client_hash(addr: IpAddr) -> u64 {
+    let digest = match addr {
+        IpAddr::V4(addr) => Sha256::digest(addr.octets()),
+        IpAddr::V6(addr) => Sha256::digest(addr.octets()),
+    };
+    u64::from_be_bytes(digest[..8].try_into().unwrap())
+}
+
+/// Returns the upstream picked by [`select_upstream`], or by the client address in
+/// [`LoadBalanceMode::IpHash`] mode, followed by the other enabled upstreams to fail over to,
+/// available ones first, up to [`MAX_UPSTREAM_ATTEMPTS`] in total.
+pub(super) fn select_upstreams(
+    servers: &[Connection],
+    tag: Option<&str>,
+    client: Option<IpAddr>,
+    mode: LoadBalanceMode,
+) -> Vec<Connection> {
+    let selected = match (mode, client) {
+        (LoadBalanceMode::IpHash, Some(client)) => select_by_ip_hash(servers, tag, client),
+        _ => select_upstream(servers, tag, mode),
+    };
+    let Some(selected) = selected else {
+        return vec![];
+    };
+    // Starts after the selected upstream so that failovers are spread over the list.
+    let start = servers
+        .iter()
+        .position(|server| server.is_same_upstream(&selected))
+        .map_or(0, |index| index + 1);
+    let mut fallbacks = servers[start..]
+        .iter()
+        .chain(&servers[..start])
+        .filter(|server| {
+            !server.disabled && server.weight > 0 && !server.is_same_upstream(&selected)
+        })
+        .collect::<Vec<_>>();
+    fallbacks.sort_by_key(|server| !server.stats.is_available());
+    std::iter::once(selected)
+        .chain(fallbacks.into_iter().cloned())
+        .take(MAX_UPSTREAM_ATTEMPTS)
+        .collect()
+}
+
+/// Returns the tag of the first network containing the client address.
+pub(super) fn client_tag(affinity: &[TagAffinity], client: Option<IpAddr>) -> Option<&str> {
+    let addr = client?;
+    affinity
+        .iter()
+        .find(|rule| rule.network.contains(addr))
+        .map(|rule| rule.tag.as_str())
+}
+
+/// Keeps the stats of the existing upstreams, so that counters survive config updates
+/// and connections started before an update can still be drained.
+pub(super) fn inherit_upstream_stats(servers: &mut [Connection], old: &[Connection]) {
+    for server in servers {
+        if let Some(old) = old.iter().find(|old| old.is_same_upstream(server)) {
+            server.stats = old.stats.clone();
+            if let (Some(endpoints), Some(old)) = (&mut server.endpoints, &old.endpoints) {
+                endpoints.inherit(old);
+            }
+        }
+    }
+}
+
+/// Stops the connections started so far once `timeout` has elapsed. Connections started
+/// later are stopped by the fresh notifier left in place.
+pub(super) fn stop_after(stop_notifier: &mut Arc<Notify>, timeout: Duration) {
+    let notifier = std::mem::replace(stop_notifier, Arc::new(Notify::new()));
+    tokio::spawn(async move {
+        tokio::time::sleep(timeout).await;
+        notifier.notify_waiters();
+    });
+}
+
+/// Closes the existing connections to the upstream.
+pub(super) fn drain_upstream(servers: &[Connection], addr: &Multiaddr) {
+    let Ok(target) = multiaddr_to_host(addr) else {
+        return;
+    };
+    for server in servers
+        .iter()
+        .filter(|server| server.is_same_upstream(&target))
+    {
+        server.stats.drain.notify_waiters();
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::keyring::KeyringItem;
+    use crate::proxy::dns::test::StubResolver;
+    use crate::proxy::shedding::LoadSource;
+    use crate::testing;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use taxy_api::port::{
+        ConnectionRate, DnsResolution, LoadSignal, OverloadShedding, Port, PortOptions,
+        RateExceededAction, RetryBudget, UpstreamServer,
+    };
+    use tokio::net::TcpListener;
+    use tokio_rustls::rustls::{Certificate, RootCertStore};
+
+    /// Returns a plaintext upstream at the loopback port, with `name` for its SNI and logs.
+    fn upstream_connection(name: ServerName, port: u16) -> Connection {
+        Connection {
+            name,
+            ..multiaddr_to_host(&format!("/ip4/127.0.0.1/tcp/{port}").parse().unwrap()).unwrap()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_served_cert_snapshot() {
+        let cert = testing::self_signed("localhost");
+        let keyring = Keyring::new([KeyringItem::ServerCert(cert.clone())]);
+        let config = taxy_api::tls::TlsTermination {
+            server_names: vec!["localhost".into()],
+            ..Default::default()
+        };
+        let mut tls = TlsTermination::new(&config, vec![], Default::default()).unwrap();
+        tls.setup(&keyring).await;
+
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream_port = upstream.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let _stream = upstream.accept().await.unwrap();
+            std::future::pending::<()>().await
+        });
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_addr = listener.local_addr().unwrap();
+        let connections = ConnectionRegistry::default();
+        let registry = connections.clone();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let conn = upstream_connection(
+                ServerName::IpAddress(IpAddr::from([127, 0, 0, 1])),
+                upstream_port,
+            );
+            start(
+                BufStream::new(stream),
+                ProxyParams {
+                    candidates: vec![conn],
+                    tls_acceptor: tls.acceptor.clone(),
+                    ..Default::default()
+                },
+                registry,
+                Default::default(),
+                Arc::new(Notify::new()),
+            )
+            .await
+        });
+
+        let client_config = testing::client_config(&cert);
+        let stream = TcpStream::connect(proxy_addr).await.unwrap();
+        let _stream = TlsConnector::from(Arc::new(client_config))
+            .connect(ServerName::try_from("localhost").unwrap(), stream)
+            .await
+            .unwrap();
+
+        let snapshot = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let snapshot = connections.snapshot();
+                if snapshot.iter().any(|conn| conn.served_cert.is_some()) {
+                    break snapshot;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(snapshot.len(), 1);
+        assert_eq!(snapshot[0].served_cert.as_deref(), Some(cert.id()));
+    }
+
+    /// Accepts connections which are greeted with `tag`.
+    async fn tagged_upstream(tag: &'static [u8]) -> Multiaddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    stream.write_all(tag).await?;
+                    std::future::pending::<io::Result<()>>().await
+                });
+            }
+        });
+        format!("/ip4/127.0.0.1/tcp/{port}").parse().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_plaintext_fallback() {
+        let cert = testing::self_signed("localhost");
+        let keyring = Keyring::new([KeyringItem::ServerCert(cert.clone())]);
+
+        let tls_upstream = tagged_upstream(b"tls").await;
+        let plaintext_upstream = tagged_upstream(b"plaintext").await;
+        let mut entry = port_entry(&[(tls_upstream, false)]);
+        entry.port.opts.tls_termination = Some(taxy_api::tls::TlsTermination {
+            server_names: vec!["localhost".into()],
+            ..Default::default()
+        });
+        entry.port.opts.plaintext_fallback = Some(taxy_api::port::PlaintextFallback {
+            upstream_servers: port_entry(&[(plaintext_upstream, false)])
+                .port
+                .opts
+                .upstream_servers,
+        });
+        let mut ctx = TcpPortContext::new(&entry).unwrap();
+        ctx.setup(&keyring, vec![]).await.unwrap();
+
+        let mut clients = proxy_connections(&mut ctx, 2).await.into_iter();
+
+        let client_config = testing::client_config(&cert);
+        let mut tls_client = TlsConnector::from(Arc::new(client_config))
+            .connect(
+                ServerName::try_from("localhost").unwrap(),
+                clients.next().unwrap(),
+            )
+            .await
+            .unwrap();
+        let mut buf = [0; 3];
+        tls_client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"tls");
+
+        let mut plaintext_client = clients.next().unwrap();
+        plaintext_client.write_all(b"hello").await.unwrap();
+        let mut buf = [0; 9];
+        plaintext_client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"plaintext");
+
+        // Without the fallback, plaintext clients fail the TLS handshake.
+        entry.port.opts.plaintext_fallback = None;
+        let mut ctx = TcpPortContext::new(&entry).unwrap();
+        ctx.setup(&keyring, vec![]).await.unwrap();
+        let mut plaintext_client = proxy_connections(&mut ctx, 1).await.remove(0);
+        plaintext_client.write_all(b"hello").await.unwrap();
+        let mut buf = Vec::new();
+        let _ = tokio::time::timeout(
+            Duration::from_secs(5),
+            plaintext_client.read_to_end(&mut buf),
+        )
+        .await
+        .unwrap();
+        assert!(!buf.starts_with(b"tls"));
+    }
+
+    #[tokio::test]
+    async fn test_tls_mode() {
+        let cert = testing::self_signed("localhost");
+        let keyring = Keyring::new([KeyringItem::ServerCert(cert.clone())]);
+
+        let upstream = tagged_upstream(b"hello").await;
+        let mut entry = port_entry(&[(upstream, false)]);
+        assert!(matches!(
+            TcpPortContext::new(&entry)
+                .unwrap()
+                .set_tls_mode(TlsMode::Passthrough),
+            Err(Error::TlsModeNotSupported)
+        ));
+
+        entry.port.opts.tls_termination = Some(taxy_api::tls::TlsTermination {
+            server_names: vec!["localhost".into()],
+            ..Default::default()
+        });
+        let mut ctx = TcpPortContext::new(&entry).unwrap();
+        ctx.setup(&keyring, vec![]).await.unwrap();
+        assert_eq!(ctx.status().tls_mode, Some(TlsMode::Terminate));
+
+        let client_config = testing::client_config(&cert);
+        let client = proxy_connections(&mut ctx, 1).await.remove(0);
+        let mut tls_client = TlsConnector::from(Arc::new(client_config))
+            .connect(ServerName::try_from("localhost").unwrap(), client)
+            .await
+            .unwrap();
+        let mut buf = [0; 5];
+        tls_client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+
+        // The next connection is proxied without terminating TLS.
+        ctx.set_tls_mode(TlsMode::Passthrough).unwrap();
+        assert_eq!(ctx.status().tls_mode, Some(TlsMode::Passthrough));
+        let mut client = proxy_connections(&mut ctx, 1).await.remove(0);
+        let mut buf = [0; 5];
+        tokio::time::timeout(Duration::from_secs(2), client.read_exact(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&buf, b"hello");
+
+        // The mode survives config updates.
+        let mut new = TcpPortContext::new(&entry).unwrap();
+        new.setup(&keyring, vec![]).await.unwrap();
+        ctx.apply(new);
+        assert_eq!(ctx.status().tls_mode, Some(TlsMode::Passthrough));
+    }
+
+    #[tokio::test]
+    async fn test_tls_close_notify() {
+        let cert = testing::self_signed("localhost");
+        let keyring = Keyring::new([KeyringItem::ServerCert(cert.clone())]);
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await?;
+            stream.write_all(b"hello").await?;
+            stream.shutdown().await
+        });
+        let mut entry =
+            port_entry(&[(format!("/ip4/127.0.0.1/tcp/{port}").parse().unwrap(), false)]);
+        entry.port.opts.tls_termination = Some(taxy_api::tls::TlsTermination {
+            server_names: vec!["localhost".into()],
+            ..Default::default()
+        });
+        let mut ctx = TcpPortContext::new(&entry).unwrap();
+        ctx.setup(&keyring, vec![]).await.unwrap();
+        let client = proxy_connections(&mut ctx, 1).await.remove(0);
+
+        let client_config = testing::client_config(&cert);
+        let mut tls_client = TlsConnector::from(Arc::new(client_config))
+            .connect(ServerName::try_from("localhost").unwrap(), client)
+            .await
+            .unwrap();
+
+        // rustls fails the read with `UnexpectedEof` if the connection is closed
+        // without close_notify.
+        let mut buf = Vec::new();
+        tokio::time::timeout(Duration::from_secs(5), tls_client.read_to_end(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(buf, b"hello");
+    }
+
+    /// Replies to a ClientHello with `tag` followed by its SNI, then closes the connection.
+    pub async fn sni_upstream(tag: &'static str) -> Multiaddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let hello = client_hello::read_client_hello(&mut stream, &Default::default())
+                        .await
+                        .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))?;
+                    let sni = client_hello::server_name(&hello).unwrap_or_default();
+                    stream.write_all(format!("{tag}:{sni}").as_bytes()).await?;
+                    stream.shutdown().await
+                });
+            }
+        });
+        format!("/ip4/127.0.0.1/tcp/{port}").parse().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_sni_routing() {
+        let mut entry = port_entry(&[
+            (sni_upstream("a").await, false),
+            (sni_upstream("b").await, false),
+            (sni_upstream("default").await, false),
+        ]);
+        entry.port.opts.upstream_servers[0].server_names = vec!["a.example.com".into()];
+        entry.port.opts.upstream_servers[1].server_names = vec!["*.b.example.com".into()];
+        entry.port.opts.sni_routing = true;
+        let mut ctx = TcpPortContext::new(&entry).unwrap();
+        ctx.setup(&Keyring::default(), vec![]).await.unwrap();
+
+        let client_config = Arc::new(
+            ClientConfig::builder()
+                .with_safe_defaults()
+                .with_root_certificates(RootCertStore::empty())
+                .with_no_client_auth(),
+        );
+        let cases = [
+            ("a.example.com", "a:a.example.com"),
+            ("x.b.example.com", "b:x.b.example.com"),
+            ("c.example.com", "default:c.example.com"),
+        ];
+        let clients = proxy_connections(&mut ctx, cases.len()).await;
+        for (mut client, (sni, expected)) in clients.into_iter().zip(cases) {
+            let mut conn = tokio_rustls::rustls::ClientConnection::new(
+                client_config.clone(),
+                ServerName::try_from(sni).unwrap(),
+            )
+            .unwrap();
+            let mut hello = Vec::new();
+            conn.write_tls(&mut hello).unwrap();
+            client.write_all(&hello).await.unwrap();
+
+            let mut buf = Vec::new();
+            client.read_to_end(&mut buf).await.unwrap();
+            assert_eq!(String::from_utf8(buf).unwrap(), expected);
+        }
+
+        // Clients not speaking TLS are closed.
+        let mut client = proxy_connections(&mut ctx, 1).await.remove(0);
+        client.write_all(b"hello world!").await.unwrap();
+        let mut buf = Vec::new();
+        let _ = client.read_to_end(&mut buf).await;
+        assert!(buf.is_empty());
+    }
+
+    /// Proxies a connection to a TLS upstream which drops the first `drops`
+    /// connections, returning the echoed data and the number of upstream connections.
+    async fn upstream_tls_handshake(name: &str, drops: usize, retries: u32) -> (Vec<u8>, usize) {
+        let cert = testing::self_signed("localhost");
+        let config = taxy_api::tls::TlsTermination {
+            server_names: vec!["localhost".into()],
+            ..Default::default()
+        };
+        let mut tls = TlsTermination::new(&config, vec![], Default::default()).unwrap();
+        tls.setup(&Keyring::new([KeyringItem::ServerCert(cert.clone())]))
+            .await;
+        let acceptor = tls.acceptor.unwrap();
+
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        let accepted = Arc::new(AtomicUsize::new(0));
+        let counter = accepted.clone();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = upstream.accept().await.unwrap();
+                if counter.fetch_add(1, Ordering::SeqCst) < drops {
+                    drop(stream);
+                    continue;
+                }
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    let mut stream = acceptor.accept(stream).await?;
+                    let mut buf = [0; 4];
+                    stream.read_exact(&mut buf).await?;
+                    stream.write_all(&buf).await?;
+                    stream.shutdown().await?;
+                    anyhow::Ok(())
+                });
+            }
+        });
+
+        let client_config = testing::client_config(&cert);
+
+        let resolver = StubResolver(Arc::new(std::sync::Mutex::new(vec![upstream_addr])));
+        let conn = Connection {
+            tls: true,
+            endpoints: Some(ResolvedEndpoints::with_resolver(
+                name,
+                upstream_addr.port(),
+                &DnsResolution {
+                    refresh_interval: Duration::from_secs(30),
+                    unhealthy_cooldown: Duration::from_secs(30),
+                },
+                resolver,
+            )),
+            ..upstream_connection(ServerName::try_from(name).unwrap(), upstream_addr.port())
+        };
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            start(
+                BufStream::new(stream),
+                ProxyParams {
+                    candidates: vec![conn],
+                    tls_client_config: Some(Arc::new(client_config)),
+                    ..Default::default()
+                },
+                Default::default(),
+                StreamOptions {
+                    tls_handshake_retries: retries,
+                    ..Default::default()
+                },
+                Arc::new(Notify::new()),
+            )
+            .await
+        });
+
+        let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+        client.write_all(b"ping").await.unwrap();
+        let mut echoed = Vec::new();
+        tokio::time::timeout(Duration::from_secs(5), client.read_to_end(&mut echoed))
+            .await
+            .unwrap()
+            .ok();
+        (echoed, accepted.load(Ordering::SeqCst))
+    }
+
+    #[tokio::test]
+    async fn test_upstream_tls_handshake_retries() {
+        assert_eq!(
+            upstream_tls_handshake("localhost", 1, 2).await,
+            (b"ping".to_vec(), 2)
+        );
+        assert_eq!(upstream_tls_handshake("localhost", 1, 0).await, (vec![], 1));
+        // Certificate errors are not retried.
+        assert_eq!(
+            upstream_tls_handshake("example.com", 0, 2).await,
+            (vec![], 1)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_upstream_tls_resumption() {
+        use tokio_rustls::rustls::{PrivateKey, ServerConfig};
+        use tokio_rustls::TlsAcceptor;
+
+        let cert = testing::self_signed("localhost");
+        let chain = rustls_pemfile::certs(&mut cert.raw_chain.as_slice()).unwrap();
+        let key = rustls_pemfile::pkcs8_private_keys(&mut cert.raw_key.as_slice())
+            .unwrap()
+            .remove(0);
+        let server_config = Arc::new(
+            ServerConfig::builder()
+                .with_safe_defaults()
+                .with_no_client_auth()
+                .with_single_cert(
+                    chain.into_iter().map(Certificate).collect(),
+                    PrivateKey(key),
+                )
+                .unwrap(),
+        );
+
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = upstream.local_addr().unwrap().port();
+        let full_handshakes = Arc::new(AtomicUsize::new(0));
+        let counter = full_handshakes.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = upstream.accept().await {
+                let acceptor = TlsAcceptor::from(server_config.clone());
+                let counter = counter.clone();
+                tokio::spawn(async move {
+                    let mut stream = acceptor.accept(stream).await?;
+                    if stream.get_ref().1.received_resumption_data().is_none() {
+                        counter.fetch_add(1, Ordering::SeqCst);
+                    }
+                    let mut buf = [0; 4];
+                    stream.read_exact(&mut buf).await?;
+                    stream.write_all(&buf).await?;
+                    stream.shutdown().await
+                });
+            }
+        });
+
+        let mut entry = port_entry(&[(
+            format!("/dns/localhost/tcp/{port}/tls").parse().unwrap(),
+            false,
+        )]);
+        entry.port.opts.upstream_tls_verification = UpstreamTlsVerification::NameMatch;
+        let mut ctx = TcpPortContext::new(&entry).unwrap();
+        ctx.setup(&Keyring::default(), vec![]).await.unwrap();
+
+        for i in 0..4 {
+            // The sessions survive config updates.
+            if i == 2 {
+                let mut new = TcpPortContext::new(&entry).unwrap();
+                new.setup(&Keyring::default(), vec![]).await.unwrap();
+                ctx.apply(new);
+            }
+            let mut client = proxy_connections(&mut ctx, 1).await.remove(0);
+            client.write_all(b"ping").await.unwrap();
+            let mut buf = [0; 4];
+            tokio::time::timeout(Duration::from_secs(5), client.read_exact(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(&buf, b"ping");
+        }
+        assert_eq!(full_handshakes.load(Ordering::SeqCst), 1);
+    }
+
+    /// Proxies a connection to a TLS upstream, returning the SNI received by the upstream.
+    /// Its certificate covers `localhost` and `backend.internal`, and is trusted if `trusted` is set.
+    async fn upstream_sni(
+        configure: impl FnOnce(&mut Connection),
+        trusted: bool,
+    ) -> Option<String> {
+        use tokio_rustls::rustls::{PrivateKey, ServerConfig};
+        use tokio_rustls::TlsAcceptor;
+
+        let cert = testing::self_signed_with_sans(&["localhost", "backend.internal"]);
+        let chain = rustls_pemfile::certs(&mut cert.raw_chain.as_slice()).unwrap();
+        let key = rustls_pemfile::pkcs8_private_keys(&mut cert.raw_key.as_slice())
+            .unwrap()
+            .remove(0);
+        let server_config = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(
+                chain.iter().cloned().map(Certificate).collect(),
+                PrivateKey(key),
+            )
+            .unwrap();
+
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        let sni = tokio::spawn(async move {
+            let (stream, _) = upstream.accept().await.unwrap();
+            let mut stream = TlsAcceptor::from(Arc::new(server_config))
+                .accept(stream)
+                .await
+                .unwrap();
+            let sni = stream.get_ref().1.server_name().map(|sni| sni.to_string());
+            let mut buf = [0; 4];
+            stream.read_exact(&mut buf).await.unwrap();
+            stream.write_all(&buf).await.unwrap();
+            stream.shutdown().await.unwrap();
+            sni
+        });
+
+        let client_config = if trusted {
+            testing::client_config(&cert)
+        } else {
+            ClientConfig::builder()
+                .with_safe_defaults()
+                .with_root_certificates(RootCertStore::empty())
+                .with_no_client_auth()
+        };
+
+        let resolver = StubResolver(Arc::new(std::sync::Mutex::new(vec![upstream_addr])));
+        let mut conn = Connection {
+            tls: true,
+            endpoints: Some(ResolvedEndpoints::with_resolver(
+                "localhost",
+                upstream_addr.port(),
+                &DnsResolution {
+                    refresh_interval: Duration::from_secs(30),
+                    unhealthy_cooldown: Duration::from_secs(30),
+                },
+                resolver,
+            )),
+            ..multiaddr_to_host(
+                &format!("/dns/localhost/tcp/{}", upstream_addr.port())
+                    .parse()
+                    .unwrap(),
+            )
+            .unwrap()
+        };
+        configure(&mut conn);
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            start(
+                BufStream::new(stream),
+                ProxyParams {
+                    candidates: vec![conn],
+                    tls_client_config: Some(Arc::new(client_config)),
+                    ..Default::default()
+                },
+                Default::default(),
+                Default::default(),
+                Arc::new(Notify::new()),
+            )
+            .await
+        });
+
+        let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+        client.write_all(b"ping").await.unwrap();
+        let mut echoed = Vec::new();
+        tokio::time::timeout(Duration::from_secs(5), client.read_to_end(&mut echoed))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(echoed, b"ping");
+        sni.await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_disable_sni() {
+        assert_eq!(
+            upstream_sni(|_| {}, true).await.as_deref(),
+            Some("localhost")
+        );
+        assert_eq!(
+            upstream_sni(|conn| conn.disable_sni = true, true).await,
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_server_name_override() {
+        let sni = upstream_sni(
+            |conn| conn.server_name_override = Some(parse_server_name("backend.internal").unwrap()),
+            true,
+        )
+        .await;
+        assert_eq!(sni.as_deref(), Some("backend.internal"));
+        assert!(matches!(
+            parse_server_name("backend internal"),
+            Err(Error::InvalidSubjectName { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_insecure_skip_verify() {
+        let sni = upstream_sni(|conn| conn.insecure_skip_verify = true, false).await;
+        assert_eq!(sni.as_deref(), Some("localhost"));
+
+        let sni = upstream_sni(
+            |conn| {
+                conn.insecure_skip_verify = true;
+                conn.server_name_override = Some(parse_server_name("other.internal").unwrap());
+            },
+            false,
+        )
+        .await;
+        assert_eq!(sni.as_deref(), Some("other.internal"));
+    }
+
+    /// Tunnels data from an edge proxy to a TLS-terminating peer proxy through a relay,
+    /// returning the data echoed behind the peer and the number of bytes relayed to the peer.
+    async fn tls_tunnel(edge_compression: bool, peer_compression: bool) -> (Vec<u8>, u64) {
+        let cert = testing::self_signed("localhost");
+        let config = taxy_api::tls::TlsTermination {
+            server_names: vec!["localhost".into()],
+            ..Default::default()
+        };
+        let mut tls = TlsTermination::new(&config, vec![], Default::default()).unwrap();
+        tls.tunnel_compression = peer_compression;
+        tls.setup(&Keyring::new([KeyringItem::ServerCert(cert.clone())]))
+            .await;
+
+        let echo = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let echo_addr = format!("/ip4/127.0.0.1/tcp/{}", echo.local_addr().unwrap().port());
+        tokio::spawn(async move {
+            let (mut stream, _) = echo.accept().await.unwrap();
+            let (mut reader, mut writer) = stream.split();
+            tokio::io::copy(&mut reader, &mut writer).await
+        });
+
+        let peer = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let peer_addr = peer.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = peer.accept().await.unwrap();
+            start(
+                BufStream::new(stream),
+                ProxyParams {
+                    candidates: vec![multiaddr_to_host(&echo_addr.parse().unwrap()).unwrap()],
+                    tls_acceptor: tls.acceptor,
+                    ..Default::default()
+                },
+                Default::default(),
+                Default::default(),
+                Arc::new(Notify::new()),
+            )
+            .await
+        });
+
+        let relay = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let relay_addr = relay.local_addr().unwrap();
+        let relayed = tokio::spawn(async move {
+            let (mut stream, _) = relay.accept().await.unwrap();
+            let mut upstream = TcpStream::connect(peer_addr).await.unwrap();
+            let (sent, _) = tokio::io::copy_bidirectional(&mut stream, &mut upstream)
+                .await
+                .unwrap();
+            sent
+        });
+
+        let mut client_config = testing::client_config(&cert);
+        if edge_compression {
+            client_config.alpn_protocols = vec![ALPN_DEFLATE.to_vec()];
+        }
+        let resolver = StubResolver(Arc::new(std::sync::Mutex::new(vec![relay_addr])));
+        let conn = Connection {
+            name: ServerName::try_from("localhost").unwrap(),
+            tls: true,
+            endpoints: Some(ResolvedEndpoints::with_resolver(
+                "localhost",
+                relay_addr.port(),
+                &DnsResolution {
+                    refresh_interval: Duration::from_secs(30),
+                    unhealthy_cooldown: Duration::from_secs(30),
+                },
+                resolver,
+            )),
+            ..multiaddr_to_host(
+                &format!("/dns/localhost/tcp/{}", relay_addr.port())
+                    .parse()
+                    .unwrap(),
+            )
+            .unwrap()
+        };
+
+        let edge = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let edge_addr = edge.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = edge.accept().await.unwrap();
+            start(
+                BufStream::new(stream),
+                ProxyParams {
+                    candidates: vec![conn],
+                    tls_client_config: Some(Arc::new(client_config)),
+                    ..Default::default()
+                },
+                Default::default(),
+                StreamOptions {
+                    tunnel_compression: edge_compression,
+                    ..Default::default()
+                },
+                Arc::new(Notify::new()),
+            )
+            .await
+        });
+
+        let mut client = TcpStream::connect(edge_addr).await.unwrap();
+        client.write_all(&b"taxy".repeat(16 * 1024)).await.unwrap();
+        client.shutdown().await.unwrap();
+        let mut echoed = Vec::new();
+        tokio::time::timeout(Duration::from_secs(5), client.read_to_end(&mut echoed))
+            .await
+            .unwrap()
+            .unwrap();
+        let relayed = tokio::time::timeout(Duration::from_secs(5), relayed)
+            .await
+            .unwrap()
+            .unwrap();
+        (echoed, relayed)
+    }
+
+    #[tokio::test]
+    async fn test_tunnel_compression() {
+        let data = b"taxy".repeat(16 * 1024);
+        let (echoed, compressed) = tls_tunnel(true, true).await;
+        assert_eq!(echoed, data);
+        assert!(compressed < 4096);
+
+        // Mismatched support falls back to plaintext.
+        for (edge, peer) in [(true, false), (false, true)] {
+            let (echoed, relayed) = tls_tunnel(edge, peer).await;
+            assert_eq!(echoed, data);
+            assert!(relayed > data.len() as u64);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_upstream_first_byte_timeout() {
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream_port = upstream.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let _stream = upstream.accept().await.unwrap();
+            std::future::pending::<()>().await
+        });
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let conn = upstream_connection(
+                ServerName::IpAddress(IpAddr::from([127, 0, 0, 1])),
+                upstream_port,
+            );
+            start(
+                BufStream::new(stream),
+                ProxyParams {
+                    candidates: vec![conn],
+                    ..Default::default()
+                },
+                Default::default(),
+                StreamOptions {
+                    first_byte_timeout: Some(Duration::from_millis(200)),
+                    ..Default::default()
+                },
+                Arc::new(Notify::new()),
+            )
+            .await
+        });
+
+        let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+        client.write_all(b"hello").await.unwrap();
+        let mut buf = [0; 1];
+        let read = tokio::time::timeout(Duration::from_secs(2), client.read(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(read, 0);
+    }
+
+    async fn counting_upstream() -> (Multiaddr, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = format!(
+            "/ip4/127.0.0.1/tcp/{}",
+            listener.local_addr().unwrap().port()
+        );
+        let count = Arc::new(AtomicUsize::new(0));
+        let counter = count.clone();
+        tokio::spawn(async move {
+            let mut streams = Vec::new();
+            while let Ok((stream, _)) = listener.accept().await {
+                counter.fetch_add(1, Ordering::SeqCst);
+                streams.push(stream);
+            }
+        });
+        (addr.parse().unwrap(), count)
+    }
+
+    pub fn port_entry(upstreams: &[(Multiaddr, bool)]) -> PortEntry {
+        PortEntry {
+            id: "test".into(),
+            port: Port {
+                listen: "/ip4/127.0.0.1/tcp/8080".parse().unwrap(),
+                opts: PortOptions {
+                    upstream_servers: upstreams
+                        .iter()
+                        .map(|(addr, disabled)| UpstreamServer {
+                            disabled: *disabled,
+                            ..UpstreamServer::new(addr.clone())
+                        })
+                        .collect(),
+                    ..Default::default()
+                },
+            },
+        }
+    }
+
+    pub async fn proxy_connections(ctx: &mut TcpPortContext, count: usize) -> Vec<TcpStream> {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut clients = Vec::new();
+        for _ in 0..count {
+            clients.push(TcpStream::connect(addr).await.unwrap());
+            let (stream, _) = listener.accept().await.unwrap();
+            ctx.start_proxy(BufStream::new(stream), None, None);
+        }
+        clients
+    }
+
+    async fn wait_for_total(counts: &[&Arc<AtomicUsize>], total: usize) {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while counts
+                .iter()
+                .map(|c| c.load(Ordering::SeqCst))
+                .sum::<usize>()
+                < total
+            {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+    }
+
+    #[test]
+    fn test_duplicate_upstreams() {
+        let a: Multiaddr = "/ip4/127.0.0.1/tcp/8080".parse().unwrap();
+        let b: Multiaddr = "/ip4/127.0.0.1/tcp/8081".parse().unwrap();
+        let mut entry = port_entry(&[(a.clone(), false), (b, false), (a.clone(), false)]);
+        entry.port.opts.upstream_servers[2].weight = 2;
+        let weights = |entry: &PortEntry| {
+            TcpPortContext::new(entry).map(|ctx| {
+                ctx.upstreams()
+                    .iter()
+                    .map(|server| (server.port, server.weight))
+                    .collect::<Vec<_>>()
+            })
+        };
+
+        assert_eq!(weights(&entry).unwrap(), [(8080, 1), (8081, 1), (8080, 2)]);
+
+        entry.port.opts.duplicate_upstreams = DuplicateUpstreams::Merge;
+        assert_eq!(weights(&entry).unwrap(), [(8080, 3), (8081, 1)]);
+
+        entry.port.opts.duplicate_upstreams = DuplicateUpstreams::Error;
+        assert!(matches!(
+            weights(&entry),
+            Err(Error::DuplicateUpstream { addr }) if addr == a
+        ));
+    }
+
+    #[test]
+    fn test_default_dns_resolution() {
+        let entry = port_entry(&[
+            ("/dns/localhost/tcp/8080".parse().unwrap(), false),
+            ("/ip4/127.0.0.1/tcp/8080".parse().unwrap(), false),
+        ]);
+        assert_eq!(entry.port.opts.dns_resolution, None);
+        let ctx = TcpPortContext::new(&entry).unwrap();
+        // Names are resolved once per refresh interval rather than for each connection.
+        assert!(ctx.upstreams()[0].endpoints.is_some());
+        assert!(ctx.upstreams()[1].endpoints.is_none());
+    }
+
+    #[test]
+    fn test_trailing_protocols() {
+        let host = |addr: &str| multiaddr_to_host(&addr.parse().unwrap());
+        assert!(!host("/dns/example.com/tcp/8080").unwrap().tls);
+        assert!(host("/dns/example.com/tcp/8443/tls").unwrap().tls);
+        assert!(host("/dns/example.com/tcp/8443/tls/http").unwrap().tls);
+        assert!(host("/dns/example.com/tcp/8443/https").unwrap().tls);
+        assert!(!host("/dns/example.com/tcp/8080/ws").unwrap().tls);
+        assert!(host("/dns/example.com/tcp/8443/wss").unwrap().tls);
+        assert!(matches!(
+            host("/dns/example.com/tcp/8080/http/tls"),
+            Err(Error::UnsupportedProtocol { protocol, .. }) if protocol == "/http/tls"
+        ));
+        assert!(matches!(
+            host("/dns/example.com/tcp/8080/tls/p2p-circuit"),
+            Err(Error::UnsupportedProtocol { protocol, .. }) if protocol == "/p2p-circuit"
+        ));
+        assert!(matches!(
+            host("/dns/example.com/udp/53"),
+            Err(Error::InvalidServerAddress { .. })
+        ));
+
+        let listen = |addr: &str| multiaddr_to_tcp(&addr.parse().unwrap());
+        assert!(listen("/ip4/127.0.0.1/tcp/8080").is_ok());
+        assert!(listen("/ip4/127.0.0.1/tcp/8443/tls/http").is_ok());
+        assert!(matches!(
+            listen("/ip4/127.0.0.1/tcp/8080/ws"),
+            Err(Error::UnsupportedProtocol { protocol, .. }) if protocol == "/ws"
+        ));
+    }
+
+    #[test]
+    fn test_weighted_selection() {
+        let servers = [5, 1, 0]
+            .into_iter()
+            .enumerate()
+            .map(|(i, weight)| Connection {
+                weight,
+                ..multiaddr_to_host(&format!("/ip4/127.0.0.1/tcp/{}", 8080 + i).parse().unwrap())
+                    .unwrap()
+            })
+            .collect::<Vec<_>>();
+        let picks = (0..60)
+            .map(|_| {
+                select_upstream(&servers, None, LoadBalanceMode::RoundRobin)
+                    .unwrap()
+                    .port
+                    - 8080
+            })
+            .collect::<Vec<_>>();
+        let count = |index| picks.iter().filter(|&&pick| pick == index).count();
+        assert_eq!((count(0), count(1), count(2)), (50, 10, 0));
+        // The lighter upstream is interleaved rather than picked in a burst.
+        assert_eq!(picks[..6], [0, 0, 0, 1, 0, 0]);
+    }
+
+    #[test]
+    fn test_least_connections_selection() {
+        let servers = [1, 1, 2]
+            .into_iter()
+            .enumerate()
+            .map(|(i, weight)| Connection {
+                weight,
+                ..multiaddr_to_host(&format!("/ip4/127.0.0.1/tcp/{}", 8080 + i).parse().unwrap())
+                    .unwrap()
+            })
+            .collect::<Vec<_>>();
+        let pick = || {
+            select_upstream(&servers, None, LoadBalanceMode::LeastConnections)
+                .unwrap()
+                .port
+                - 8080
+        };
+        let set_active = |active: [usize; 3]| {
+            for (server, active) in servers.iter().zip(active) {
+                server.stats.active.store(active, Ordering::SeqCst);
+            }
+        };
+
+        set_active([3, 1, 4]);
+        assert_eq!(pick(), 1);
+        set_active([3, 3, 4]);
+        assert_eq!(pick(), 2);
+
+        // Ties are broken in round-robin order.
+        set_active([2, 2, 5]);
+        let picks = [pick(), pick(), pick(), pick()];
+        assert_eq!(picks.iter().filter(|&&pick| pick == 0).count(), 2);
+        assert_eq!(picks.iter().filter(|&&pick| pick == 1).count(), 2);
+    }
+
+    #[test]
+    fn test_unhealthy_selection() {
+        let servers = (0..2)
+            .map(|i| {
+                multiaddr_to_host(&format!("/ip4/127.0.0.1/tcp/{}", 8080 + i).parse().unwrap())
+                    .unwrap()
+            })
+            .collect::<Vec<_>>();
+        let pick = || {
+            select_upstream(&servers, None, LoadBalanceMode::RoundRobin)
+                .unwrap()
+                .port
+                - 8080
+        };
+
+        servers[0].stats.unhealthy.store(true, Ordering::SeqCst);
+        assert_eq!([pick(), pick(), pick()], [1, 1, 1]);
+
+        // All upstreams are down: they are tried anyway.
+        servers[1].stats.unhealthy.store(true, Ordering::SeqCst);
+        let picks = [pick(), pick()];
+        assert!(picks.contains(&0) && picks.contains(&1));
+    }
+
+    #[test]
+    fn test_failover_selection() {
+        let mut servers = (0..5)
+            .map(|i| {
+                multiaddr_to_host(&format!("/ip4/127.0.0.1/tcp/{}", 8080 + i).parse().unwrap())
+                    .unwrap()
+            })
+            .collect::<Vec<_>>();
+        servers[2].disabled = true;
+        servers[3].stats.unhealthy.store(true, Ordering::SeqCst);
+        let ports = |candidates: Vec<Connection>| {
+            candidates
+                .iter()
+                .map(|server| server.port - 8080)
+                .collect::<Vec<_>>()
+        };
+
+        let candidates = select_upstreams(&servers, None, None, LoadBalanceMode::RoundRobin);
+        assert_eq!(ports(candidates), [0, 1, 4]);
+        let candidates = select_upstreams(&servers, None, None, LoadBalanceMode::RoundRobin);
+        assert_eq!(ports(candidates), [1, 4, 0]);
+
+        servers.truncate(1);
+        let candidates = select_upstreams(&servers, None, None, LoadBalanceMode::RoundRobin);
+        assert_eq!(ports(candidates), [0]);
+    }
+
+    #[test]
+    fn test_ip_hash_selection() {
+        let servers = (0..3)
+            .map(|i| {
+                multiaddr_to_host(&format!("/ip4/127.0.0.1/tcp/{}", 8080 + i).parse().unwrap())
+                    .unwrap()
+            })
+            .collect::<Vec<_>>();
+        let pick = |client: &str| {
+            select_upstreams(
+                &servers,
+                None,
+                Some(client.parse().unwrap()),
+                LoadBalanceMode::IpHash,
+            )[0]
+            .port
+                - 8080
+        };
+
+        let clients = (1..=16).map(|i| format!("10.0.0.{i}")).collect::<Vec<_>>();
+        let picks = clients
+            .iter()
+            .map(|client| pick(client))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            clients
+                .iter()
+                .map(|client| pick(client))
+                .collect::<Vec<_>>(),
+            picks
+        );
+        assert!((0..3).all(|index| picks.contains(&index)));
+
+        // The hash is not seeded.
+        let client = "192.0.2.1".parse().unwrap();
+        assert_eq!(pick("192.0.2.1") as u64, client_hash(client) % 3);
+
+        let index = pick("192.0.2.1");
+        servers[index as usize]
+            .stats
+            .unhealthy
+            .store(true, Ordering::SeqCst);
+        assert_eq!(pick("192.0.2.1"), (index + 1) % 3);
+        servers[index as usize]
+            .stats
+            .unhealthy
+            .store(false, Ordering::SeqCst);
+        assert_eq!(pick("192.0.2.1"), index);
+    }
+
+    #[tokio::test]
+    async fn test_failover() {
+        let dead_port = {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            listener.local_addr().unwrap().port()
+        };
+        let echo = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let echo_port = echo.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut stream, _) = echo.accept().await.unwrap();
+            let (mut reader, mut writer) = stream.split();
+            tokio::io::copy(&mut reader, &mut writer).await
+        });
+
+        let candidates = [dead_port, echo_port]
+            .into_iter()
+            .map(|port| {
+                multiaddr_to_host(&format!("/ip4/127.0.0.1/tcp/{port}").parse().unwrap()).unwrap()
+            })
+            .collect::<Vec<_>>();
+        let dead_stats = candidates[0].stats.clone();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            start(
+                BufStream::new(stream),
+                ProxyParams {
+                    candidates,
+                    ..Default::default()
+                },
+                Default::default(),
+                Default::default(),
+                Arc::new(Notify::new()),
+            )
+            .await
+        });
+
+        let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+        client.write_all(b"hello").await.unwrap();
+        let mut buf = [0; 5];
+        tokio::time::timeout(Duration::from_secs(2), client.read_exact(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&buf, b"hello");
+        assert_eq!(dead_stats.connect_failures.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_retry_budget() {
+        let mut dead = vec![];
+        for _ in 0..3 {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let port = listener.local_addr().unwrap().port();
+            dead.push((format!("/ip4/127.0.0.1/tcp/{port}").parse().unwrap(), false));
+        }
+        let mut entry = port_entry(&dead);
+        entry.port.opts.retry_budget = Some(RetryBudget {
+            per_second: 5,
+            burst: Some(5),
+        });
+        let mut ctx = TcpPortContext::new(&entry).unwrap();
+        ctx.setup(&Keyring::default(), vec![]).await.unwrap();
+
+        let started_at = Instant::now();
+        let clients = proxy_connections(&mut ctx, 20).await;
+        for mut client in clients {
+            let mut buf = vec![];
+            let _ = tokio::time::timeout(Duration::from_secs(5), client.read_to_end(&mut buf))
+                .await
+                .unwrap();
+        }
+        let attempts = ctx
+            .upstreams()
+            .iter()
+            .map(|conn| conn.stats.connect_failures.load(Ordering::Relaxed))
+            .sum::<u64>();
+        let retries = attempts - 20;
+        let refilled = (started_at.elapsed().as_secs_f64() * 5.0).ceil() as u64;
+        assert!(retries >= 5, "{retries} retries");
+        assert!(retries <= 5 + refilled, "{retries} retries");
+    }
+
+    #[tokio::test]
+    async fn test_failure_cooldown() {
+        let dead_port = {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            listener.local_addr().unwrap().port()
+        };
+        let echo = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let echo_port = echo.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut stream, _) = echo.accept().await.unwrap();
+            let (mut reader, mut writer) = stream.split();
+            tokio::io::copy(&mut reader, &mut writer).await
+        });
+
+        let servers = [dead_port, echo_port]
+            .into_iter()
+            .map(|port| {
+                multiaddr_to_host(&format!("/ip4/127.0.0.1/tcp/{port}").parse().unwrap()).unwrap()
+            })
+            .collect::<Vec<_>>();
+        let candidates = servers.clone();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            start(
+                BufStream::new(stream),
+                ProxyParams {
+                    candidates,
+                    ..Default::default()
+                },
+                Default::default(),
+                StreamOptions {
+                    failure_cooldown: Some(Duration::from_millis(500)),
+                    ..Default::default()
+                },
+                Arc::new(Notify::new()),
+            )
+            .await
+        });
+
+        let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+        client.write_all(b"hello").await.unwrap();
+        let mut buf = [0; 5];
+        tokio::time::timeout(Duration::from_secs(2), client.read_exact(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&buf, b"hello");
+        assert!(!servers[0].stats.is_available());
+
+        let pick = || select_upstreams(&servers, None, None, LoadBalanceMode::RoundRobin)[0].port;
+        assert_eq!([pick(), pick(), pick()], [echo_port; 3]);
+
+        tokio::time::sleep(Duration::from_millis(600)).await;
+        assert!(servers[0].stats.is_available());
+        let picks = [pick(), pick()];
+        assert!(picks.contains(&dead_port) && picks.contains(&echo_port));
+    }
+
+    #[tokio::test]
+    async fn test_outlier_detection() {
+        let config = OutlierDetection {
+            consecutive_failures: 2,
+            interval: Duration::from_secs(10),
+            base_ejection_time: Duration::from_millis(100),
+            max_ejection_time: Duration::from_millis(150),
+        };
+        let stats = UpstreamStats::default();
+        assert_eq!(stats.record_failure(&config), None);
+        stats.record_success();
+        assert_eq!(stats.record_failure(&config), None);
+        assert_eq!(
+            stats.record_failure(&config),
+            Some(Duration::from_millis(100))
+        );
+        assert!(stats.is_ejected());
+        assert!(!stats.is_available());
+
+        // Connections which were in flight do not extend the ejection.
+        assert_eq!(stats.record_failure(&config), None);
+
+        // The upstream is tried again and fails, so it is ejected for longer.
+        tokio::time::sleep(Duration::from_millis(120)).await;
+        assert!(stats.is_available());
+        assert_eq!(
+            stats.record_failure(&config),
+            Some(Duration::from_millis(150))
+        );
+
+        // A success brings the upstream back with the threshold and ejection time reset.
+        tokio::time::sleep(Duration::from_millis(170)).await;
+        stats.record_success();
+        assert!(stats.is_available());
+        assert_eq!(stats.record_failure(&config), None);
+        assert_eq!(
+            stats.record_failure(&config),
+            Some(Duration::from_millis(100))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_outlier_detection_reset() {
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream_port = upstream.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (stream, _) = upstream.accept().await.unwrap();
+            stream.set_linger(Some(Duration::ZERO)).unwrap();
+        });
+
+        let servers = vec![multiaddr_to_host(
+            &format!("/ip4/127.0.0.1/tcp/{upstream_port}")
+                .parse()
+                .unwrap(),
+        )
+        .unwrap()];
+        let candidates = servers.clone();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_addr = listener.local_addr().unwrap();
+        let proxy = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            start(
+                BufStream::new(stream),
+                ProxyParams {
+                    candidates,
+                    ..Default::default()
+                },
+                Default::default(),
+                StreamOptions {
+                    outlier_detection: Some(OutlierDetection {
+                        consecutive_failures: 1,
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                },
+                Arc::new(Notify::new()),
+            )
+            .await
+        });
+
+        let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+        let mut buf = vec![];
+        let _ = tokio::time::timeout(Duration::from_secs(2), client.read_to_end(&mut buf))
+            .await
+            .unwrap();
+        assert!(buf.is_empty());
+        let _ = proxy.await.unwrap();
+        assert!(servers[0].stats.is_ejected());
+    }
+
+    #[tokio::test]
+    async fn test_connect_timeout() {
+        let echo = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let echo_port = echo.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut stream, _) = echo.accept().await.unwrap();
+            let (mut reader, mut writer) = stream.split();
+            tokio::io::copy(&mut reader, &mut writer).await
+        });
+
+        // A listener whose accept queue is full, so that further connections are black-holed.
+        let blackhole = tokio::net::TcpSocket::new_v4().unwrap();
+        blackhole.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let blackhole = blackhole.listen(0).unwrap();
+        let blackhole_addr = blackhole.local_addr().unwrap();
+        let _queued = TcpStream::connect(blackhole_addr).await.unwrap();
+
+        let candidates = [
+            format!("/ip4/127.0.0.1/tcp/{}", blackhole_addr.port()),
+            format!("/ip4/127.0.0.1/tcp/{echo_port}"),
+        ]
+        .into_iter()
+        .map(|addr| multiaddr_to_host(&addr.parse().unwrap()).unwrap())
+        .collect::<Vec<_>>();
+        let dead_stats = candidates[0].stats.clone();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            start(
+                BufStream::new(stream),
+                ProxyParams {
+                    candidates,
+                    ..Default::default()
+                },
+                Default::default(),
+                StreamOptions {
+                    connect_timeout: Some(Duration::from_millis(200)),
+                    ..Default::default()
+                },
+                Arc::new(Notify::new()),
+            )
+            .await
+        });
+
+        let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+        client.write_all(b"hello").await.unwrap();
+        let mut buf = [0; 5];
+        tokio::time::timeout(Duration::from_secs(2), client.read_exact(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&buf, b"hello");
+        assert_eq!(dead_stats.connect_timeouts.load(Ordering::SeqCst), 1);
+        assert_eq!(dead_stats.connect_failures.load(Ordering::SeqCst), 0);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_keepalive() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let outbound = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (inbound, _) = listener.accept().await.unwrap();
+
+        let inbound_config = TcpKeepalive {
+            time: Duration::from_secs(30),
+            interval: Duration::from_secs(5),
+            retries: 2,
+        };
+        let outbound_config = TcpKeepalive {
+            time: Duration::from_secs(600),
+            interval: Duration::from_secs(60),
+            retries: 9,
+        };
+        set_keepalive(&inbound, &inbound_config).unwrap();
+        set_keepalive(&outbound, &outbound_config).unwrap();
+
+        for (stream, config) in [(&inbound, inbound_config), (&outbound, outbound_config)] {
+            let sock = socket2::SockRef::from(stream);
+            assert!(sock.keepalive().unwrap());
+            assert_eq!(sock.keepalive_time().unwrap(), config.time);
+            assert_eq!(sock.keepalive_interval().unwrap(), config.interval);
+            assert_eq!(sock.keepalive_retries().unwrap(), config.retries);
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_buffer_sizes() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let opts = StreamOptions {
+            send_buffer_size: Some(64 * 1024),
+            recv_buffer_size: Some(32 * 1024),
+            ..Default::default()
+        };
+        set_buffer_sizes(&stream, &opts).unwrap();
+
+        // Linux doubles the requested sizes to account for bookkeeping overhead.
+        let sock = socket2::SockRef::from(&stream);
+        assert!(sock.send_buffer_size().unwrap() >= 64 * 1024);
+        assert!(sock.recv_buffer_size().unwrap() >= 32 * 1024);
+    }
+
+    #[tokio::test]
+    async fn test_least_connections_release() {
+        let (live, live_count) = counting_upstream().await;
+        let dead = {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            format!(
+                "/ip4/127.0.0.1/tcp/{}",
+                listener.local_addr().unwrap().port()
+            )
+            .parse::<Multiaddr>()
+            .unwrap()
+        };
+        let mut entry = port_entry(&[(live, false), (dead, false)]);
+        entry.port.opts.load_balance = LoadBalanceMode::LeastConnections;
+        let mut ctx = TcpPortContext::new(&entry).unwrap();
+
+        let live_stats = ctx.servers[0].stats.clone();
+        let dead_stats = ctx.servers[1].stats.clone();
+
+        // Connections to the dead upstream fail over to the live one and release their slot.
+        let _clients = proxy_connections(&mut ctx, 4).await;
+        tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                if live_count.load(Ordering::SeqCst) == 4
+                    && dead_stats.active.load(Ordering::SeqCst) == 0
+                {
+                    break;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert!(dead_stats.connect_failures.load(Ordering::SeqCst) > 0);
+        assert_eq!(
+            live_stats.active.load(Ordering::SeqCst),
+            live_count.load(Ordering::SeqCst)
+        );
+
+        // Stopped connections release their slot as well.
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while live_stats.active.load(Ordering::SeqCst) > 0 {
+                ctx.reset();
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_upstream_distribution() {
+        let (a, a_count) = counting_upstream().await;
+        let (b, b_count) = counting_upstream().await;
+        let (c, _) = counting_upstream().await;
+
+        let mut entry = port_entry(&[(a, false), (b, false), (c, true)]);
+        entry.port.opts.upstream_servers[0].weight = 3;
+        let mut ctx = TcpPortContext::new(&entry).unwrap();
+        let _clients = proxy_connections(&mut ctx, 8).await;
+        wait_for_total(&[&a_count, &b_count], 8).await;
+
+        let distribution = ctx.upstream_distribution();
+        assert_eq!(
+            distribution
+                .iter()
+                .map(|upstream| (upstream.selections, upstream.expected, upstream.observed))
+                .collect::<Vec<_>>(),
+            vec![(6, 0.75, 0.75), (2, 0.25, 0.25), (0, 0.0, 0.0)]
+        );
+
+        // The picks survive config updates which keep the upstream.
+        entry.port.opts.upstream_servers.truncate(1);
+        ctx.apply(TcpPortContext::new(&entry).unwrap());
+        let distribution = ctx.upstream_distribution();
+        assert_eq!(distribution.len(), 1);
+        assert_eq!(distribution[0].selections, 6);
+        assert_eq!(distribution[0].expected, 1.0);
+        assert_eq!(distribution[0].observed, 0.75);
+    }
+
+    #[tokio::test]
+    async fn test_disabled_upstream() {
+        let (a, a_count) = counting_upstream().await;
+        let (b, b_count) = counting_upstream().await;
+
+        let entry = port_entry(&[(a.clone(), true), (b.clone(), false)]);
+        let mut ctx = TcpPortContext::new(&entry).unwrap();
+        let _clients = proxy_connections(&mut ctx, 4).await;
+        wait_for_total(&[&a_count, &b_count], 4).await;
+        assert_eq!(a_count.load(Ordering::SeqCst), 0);
+        assert_eq!(b_count.load(Ordering::SeqCst), 4);
+
+        let entry = port_entry(&[(a, false), (b, false)]);
+        ctx.apply(TcpPortContext::new(&entry).unwrap());
+        let _clients = proxy_connections(&mut ctx, 4).await;
+        wait_for_total(&[&a_count, &b_count], 8).await;
+        assert_eq!(a_count.load(Ordering::SeqCst), 2);
+        assert_eq!(b_count.load(Ordering::SeqCst), 6);
+    }
+
+    #[tokio::test]
+    async fn test_apply_keeps_upstream_stats() {
+        let (live, live_count) = counting_upstream().await;
+        let dead = {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            format!(
+                "/ip4/127.0.0.1/tcp/{}",
+                listener.local_addr().unwrap().port()
+            )
+            .parse::<Multiaddr>()
+            .unwrap()
+        };
+
+        let mut ctx =
+            TcpPortContext::new(&port_entry(&[(live.clone(), false), (dead.clone(), false)]))
+                .unwrap();
+        ctx.event(PortContextEvent::SocketStateUpadted(SocketState::Listening));
+        let started_at = ctx.status().started_at;
+        let _clients = proxy_connections(&mut ctx, 4).await;
+        wait_for_total(&[&live_count], 2).await;
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while ctx.servers[1].stats.connect_failures.load(Ordering::SeqCst) < 2 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+
+        let (added, _) = counting_upstream().await;
+        let mut entry = port_entry(&[(live, false), (dead, false), (added, false)]);
+        entry.port.opts.tls_termination = Some(taxy_api::tls::TlsTermination {
+            server_names: vec!["localhost".into()],
+            self_signed_fallback: true,
+            ..Default::default()
+        });
+        ctx.apply(TcpPortContext::new(&entry).unwrap());
+
+        assert!(ctx.tls_termination.is_some());
+        assert_eq!(ctx.status().state.socket, SocketState::Listening);
+        assert_eq!(ctx.status().started_at, started_at);
+        let stats = |index: usize| {
+            let stats = &ctx.servers[index].stats;
+            (
+                stats.connections.load(Ordering::SeqCst),
+                stats.connect_failures.load(Ordering::SeqCst),
+            )
+        };
+        // The connections which failed on the dead upstream were served by the live one.
+        assert_eq!(stats(0), (4, 0));
+        assert_eq!(stats(1), (0, 2));
+        assert_eq!(stats(2), (0, 0));
+    }
+
+    #[tokio::test]
+    async fn test_tag_affinity() {
+        let (eu, eu_count) = counting_upstream().await;
+        let (us, us_count) = counting_upstream().await;
+
+        let tagged_entry = |us_disabled| {
+            let mut entry = port_entry(&[(eu.clone(), false), (us.clone(), us_disabled)]);
+            let servers = &mut entry.port.opts.upstream_servers;
+            servers[0].tags = vec!["eu".into()];
+            servers[1].tags = vec!["us".into()];
+            entry.port.opts.tag_affinity = vec![TagAffinity {
+                network: "127.0.0.0/8".parse().unwrap(),
+                tag: "us".into(),
+            }];
+            entry
+        };
+
+        let mut ctx = TcpPortContext::new(&tagged_entry(false)).unwrap();
+        let _clients = proxy_connections(&mut ctx, 4).await;
+        wait_for_total(&[&eu_count, &us_count], 4).await;
+        assert_eq!(eu_count.load(Ordering::SeqCst), 0);
+        assert_eq!(us_count.load(Ordering::SeqCst), 4);
+
+        ctx.apply(TcpPortContext::new(&tagged_entry(true)).unwrap());
+        let _clients = proxy_connections(&mut ctx, 4).await;
+        wait_for_total(&[&eu_count, &us_count], 8).await;
+        assert_eq!(eu_count.load(Ordering::SeqCst), 4);
+        assert_eq!(us_count.load(Ordering::SeqCst), 4);
+    }
+
+    #[tokio::test]
+    async fn test_recent_connections() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream = format!(
+            "/ip4/127.0.0.1/tcp/{}",
+            listener.local_addr().unwrap().port()
+        )
+        .parse()
+        .unwrap();
+        let count = Arc::new(AtomicUsize::new(0));
+        let counter = count.clone();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                counter.fetch_add(1, Ordering::SeqCst);
+                tokio::spawn(async move {
+                    let mut buf = Vec::new();
+                    let _ = stream.read_to_end(&mut buf).await;
+                });
+            }
+        });
+
+        let mut ctx = TcpPortContext::new(&port_entry(&[(upstream, false)])).unwrap();
+        let wait_for_recent = |ctx: &TcpPortContext, len: usize| {
+            let connections = ctx.connections().clone();
+            tokio::time::timeout(Duration::from_secs(5), async move {
+                while connections.recent().len() < len {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+                connections.recent()
+            })
+        };
+
+        let mut clients = proxy_connections(&mut ctx, 1).await;
+        wait_for_total(&[&count], 1).await;
+        clients[0].write_all(b"hello").await.unwrap();
+        drop(clients);
+        let recent = wait_for_recent(&ctx, 1).await.unwrap();
+        assert_eq!(recent[0].outcome, ConnectionOutcome::ClientClosed);
+        assert_eq!(recent[0].bytes_received, 5);
+        assert_eq!(recent[0].bytes_sent, 0);
+        assert!(ctx.connections().snapshot().is_empty());
+
+        let _clients = proxy_connections(&mut ctx, 1).await;
+        wait_for_total(&[&count], 2).await;
+        ctx.reset();
+        let recent = wait_for_recent(&ctx, 2).await.unwrap();
+        assert_eq!(recent[0].outcome, ConnectionOutcome::Stopped);
+        assert_eq!(recent[1].outcome, ConnectionOutcome::ClientClosed);
+    }
+
+    #[tokio::test]
+    async fn test_drain() {
+        let (upstream, count) = counting_upstream().await;
+        let entry = port_entry(&[(upstream, false)]);
+        let mut ctx = TcpPortContext::new(&entry).unwrap();
+
+        let _active = proxy_connections(&mut ctx, 1).await;
+        wait_for_total(&[&count], 1).await;
+        ctx.drain(Duration::from_millis(300));
+
+        // New connections are rejected while the existing one keeps running.
+        let mut rejected = proxy_connections(&mut ctx, 1).await;
+        let mut buf = [0; 1];
+        let read = tokio::time::timeout(Duration::from_secs(2), rejected[0].read(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(read, 0);
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+        assert_eq!(ctx.connections().snapshot().len(), 1);
+
+        let connections = ctx.connections().clone();
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while connections.recent().is_empty() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(connections.recent()[0].outcome, ConnectionOutcome::Stopped);
+
+        // Applying a config accepts connections again.
+        ctx.apply(TcpPortContext::new(&entry).unwrap());
+        let _clients = proxy_connections(&mut ctx, 1).await;
+        wait_for_total(&[&count], 2).await;
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_unix_sockets() {
+        use crate::proxy::socket::{Listener, UNIX_SOCKET_ADDR};
+        use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+        use tokio::net::{UnixListener, UnixStream};
+
+        let dir = std::env::temp_dir().join(format!("taxy-unix-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let upstream_path = dir.join("upstream.sock");
+        let listen_path = dir.join("listen.sock");
+        let unix_addr = |path: &std::path::Path| -> Multiaddr {
+            let path = utf8_percent_encode(path.to_str().unwrap(), NON_ALPHANUMERIC);
+            format!("/unix/{path}").parse().unwrap()
+        };
+
+        let upstream = UnixListener::bind(&upstream_path).unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = upstream.accept().await.unwrap();
+            let (mut reader, mut writer) = stream.split();
+            tokio::io::copy(&mut reader, &mut writer).await
+        });
+
+        let mut entry = port_entry(&[(unix_addr(&upstream_path), false)]);
+        entry.port.listen = unix_addr(&listen_path);
+        let mut ctx = TcpPortContext::new(&entry).unwrap();
+        assert_eq!(ctx.listen, Address::Unix(listen_path.clone()));
+        assert_eq!(
+            ctx.upstreams()[0].hostname(),
+            upstream_path.display().to_string()
+        );
+
+        // The socket file left by a previous listener is replaced.
+        drop(
+            Listener::bind(&ctx.listen, Default::default())
+                .await
+                .unwrap(),
+        );
+        let listener = Listener::bind(&ctx.listen, Default::default())
+            .await
+            .unwrap();
+
+        let mut client = UnixStream::connect(&listen_path).await.unwrap();
+        let stream = std::future::poll_fn(|cx| listener.poll_accept(cx))
+            .await
+            .unwrap();
+        ctx.start_proxy(BufStream::new(stream), None, None);
+
+        client.write_all(b"hello").await.unwrap();
+        let mut buf = [0; 5];
+        tokio::time::timeout(Duration::from_secs(2), client.read_exact(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&buf, b"hello");
+        assert_eq!(
+            ctx.connections().snapshot()[0].remote,
+            UNIX_SOCKET_ADDR.to_string()
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_idle_timeout() {
+        let (upstream, count) = counting_upstream().await;
+        let mut entry = port_entry(&[(upstream, false)]);
+        entry.port.opts.idle_timeout = Some(Duration::from_millis(300));
+        let mut ctx = TcpPortContext::new(&entry).unwrap();
+
+        let mut clients = proxy_connections(&mut ctx, 1).await;
+        wait_for_total(&[&count], 1).await;
+
+        // Activity resets the timer.
+        let start = Instant::now();
+        for _ in 0..5 {
+            clients[0].write_all(b"ping").await.unwrap();
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        assert!(ctx.connections().recent().is_empty());
+
+        let mut buf = [0; 1];
+        let read = tokio::time::timeout(Duration::from_secs(2), clients[0].read(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(read, 0);
+        // The last write was 400ms in.
+        assert!(start.elapsed() >= Duration::from_millis(700));
+
+        let connections = ctx.connections().clone();
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while connections.recent().is_empty() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(
+            connections.recent()[0].outcome,
+            ConnectionOutcome::IdleTimeout
+        );
+    }
+
+    async fn recent_outcome(connections: &ConnectionRegistry) -> ConnectionOutcome {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                if let Some(conn) = connections.recent().first() {
+                    break conn.outcome;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_read_deadline() {
+        let (upstream, count) = counting_upstream().await;
+        let mut entry = port_entry(&[(upstream, false)]);
+        entry.port.opts.read_deadline = Some(Duration::from_millis(300));
+        entry.port.opts.write_deadline = Some(Duration::ZERO);
+        let mut ctx = TcpPortContext::new(&entry).unwrap();
+
+        let start = Instant::now();
+        let mut clients = proxy_connections(&mut ctx, 1).await;
+        wait_for_total(&[&count], 1).await;
+
+        // Activity does not extend the deadline.
+        while clients[0].write_all(b"ping").await.is_ok()
+            && start.elapsed() < Duration::from_secs(2)
+        {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        assert_eq!(
+            recent_outcome(ctx.connections()).await,
+            ConnectionOutcome::ReadDeadline
+        );
+        assert!(start.elapsed() >= Duration::from_millis(300));
+    }
+
+    #[tokio::test]
+    async fn test_write_deadline() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream = format!(
+            "/ip4/127.0.0.1/tcp/{}",
+            listener.local_addr().unwrap().port()
+        );
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            while stream.write_all(b"tick").await.is_ok() {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        });
+
+        let mut entry = port_entry(&[(upstream.parse().unwrap(), false)]);
+        entry.port.opts.write_deadline = Some(Duration::from_millis(300));
+        let mut ctx = TcpPortContext::new(&entry).unwrap();
+
+        let start = Instant::now();
+        let mut clients = proxy_connections(&mut ctx, 1).await;
+        let mut buf = [0; 64];
+        while tokio::time::timeout(Duration::from_secs(2), clients[0].read(&mut buf))
+            .await
+            .unwrap()
+            .is_ok_and(|read| read > 0)
+        {}
+        assert_eq!(
+            recent_outcome(ctx.connections()).await,
+            ConnectionOutcome::WriteDeadline
+        );
+        assert!(start.elapsed() >= Duration::from_millis(300));
+        assert!(start.elapsed() < Duration::from_secs(2));
+    }
+
+    #[tokio::test]
+    async fn test_max_connections() {
+        let (upstream, count) = counting_upstream().await;
+        let mut entry = port_entry(&[(upstream, false)]);
+        entry.port.opts.max_connections = Some(1);
+        let mut ctx = TcpPortContext::new(&entry).unwrap();
+
+        let _active = proxy_connections(&mut ctx, 1).await;
+        wait_for_total(&[&count], 1).await;
+        assert_eq!(ctx.status().connections_in_use, Some(1));
+
+        let mut rejected = proxy_connections(&mut ctx, 1).await;
+        let mut buf = [0; 1];
+        let read = tokio::time::timeout(Duration::from_secs(2), rejected[0].read(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(read, 0);
+        assert_eq!(ctx.status().connection_limit_rejections, 1);
+
+        // Raising the limit keeps the existing connection counted.
+        entry.port.opts.max_connections = Some(2);
+        ctx.apply(TcpPortContext::new(&entry).unwrap());
+        assert_eq!(ctx.status().connections_in_use, Some(1));
+        assert_eq!(ctx.status().connection_limit_rejections, 1);
+
+        let _clients = proxy_connections(&mut ctx, 2).await;
+        wait_for_total(&[&count], 2).await;
+        assert_eq!(ctx.status().connections_in_use, Some(2));
+        assert_eq!(ctx.status().connection_limit_rejections, 2);
+    }
+
+    #[tokio::test]
+    async fn test_connection_rate() {
+        let (upstream, count) = counting_upstream().await;
+        let mut entry = port_entry(&[(upstream, false)]);
+        entry.port.opts.connection_rate = Some(ConnectionRate {
+            per_second: 10,
+            burst: Some(5),
+            exceeded: RateExceededAction::Reject,
+            max_delay: Duration::from_secs(1),
+        });
+        let mut ctx = TcpPortContext::new(&entry).unwrap();
+
+        let _clients = proxy_connections(&mut ctx, 20).await;
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        let proxied = count.load(Ordering::SeqCst);
+        assert!((5..=7).contains(&proxied), "proxied {proxied} of 20");
+
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        let mut clients = Vec::new();
+        for _ in 0..5 {
+            clients.extend(proxy_connections(&mut ctx, 1).await);
+            tokio::time::sleep(Duration::from_millis(150)).await;
+        }
+        wait_for_total(&[&count], proxied + 5).await;
+    }
+
+    #[derive(Debug, Default)]
+    struct InjectedLoad(AtomicU64);
+
+    impl LoadSource for InjectedLoad {
+        fn load(&self) -> f64 {
+            f64::from_bits(self.0.load(Ordering::SeqCst))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_overload_shedding() {
+        let (upstream, count) = counting_upstream().await;
+        let mut ctx = TcpPortContext::new(&port_entry(&[(upstream, false)])).unwrap();
+
+        let load = Arc::new(InjectedLoad::default());
+        let config = OverloadShedding {
+            signal: LoadSignal::SystemLoad,
+            threshold: 80,
+            ceiling: 100,
+        };
+        ctx.shedder = Some(LoadShedder::with_source(&config, load.clone()));
+
+        load.0.store(0.9f64.to_bits(), Ordering::SeqCst);
+        let _clients = proxy_connections(&mut ctx, 100).await;
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        let proxied = count.load(Ordering::SeqCst);
+        assert!((20..=80).contains(&proxied), "proxied {proxied} of 100");
+
+        load.0.store(0.5f64.to_bits(), Ordering::SeqCst);
+        let _clients = proxy_connections(&mut ctx, 10).await;
+        wait_for_total(&[&count], proxied + 10).await;
+    }
+
+    #[tokio::test]
+    async fn test_direct_buffering() {
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream_port = upstream.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut stream, _) = upstream.accept().await.unwrap();
+            let (mut reader, mut writer) = stream.split();
+            tokio::io::copy(&mut reader, &mut writer).await
+        });
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_addr = listener.local_addr().unwrap();
+        let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+
+        let _other = TcpStream::connect(proxy_addr).await.unwrap();
+        let (other, _) = listener.accept().await.unwrap();
+        assert!(matches!(
+            ClientStream::new(BufStream::new(other), BufferingMode::Direct),
+            ClientStream::Direct(_)
+        ));
+
+        tokio::spawn(async move {
+            let conn = upstream_connection(
+                ServerName::IpAddress(IpAddr::from([127, 0, 0, 1])),
+                upstream_port,
+            );
+            start(
+                BufStream::new(stream),
+                ProxyParams {
+                    candidates: vec![conn],
+                    ..Default::default()
+                },
+                Default::default(),
+                StreamOptions {
+                    buffering: BufferingMode::Direct,
+                    ..Default::default()
+                },
+                Arc::new(Notify::new()),
+            )
+            .await
+        });
+
+        let data = vec![0x5a; 256 * 1024];
+        let (mut reader, mut writer) = client.split();
+        let write = async {
+            writer.write_all(&data).await.unwrap();
+            writer.shutdown().await.unwrap();
+        };
+        let mut echoed = Vec::new();
+        let read = reader.read_to_end(&mut echoed);
+        let (_, read) =
+            tokio::time::timeout(Duration::from_secs(5), async { tokio::join!(write, read) })
+                .await
+                .unwrap();
+        read.unwrap();
+        assert_eq!(echoed, data);
+    }
+
+    #[derive(Clone, Default)]
+    struct StageRecorder(Arc<std::sync::Mutex<Vec<String>>>);
+
+    struct StageVisitor(Option<String>);
+
+    impl tracing::field::Visit for StageVisitor {
+        fn record_str(&mut self, field: &tracing::field::Field, value: &str) {
+            if field.name() == "stage" {
+                self.0 = Some(value.to_string());
+            }
+        }
+
+        fn record_debug(&mut self, _: &tracing::field::Field, _: &dyn std::fmt::Debug) {}
+    }
+
+    impl<S: tracing::Subscriber> tracing_subscriber::Layer<S> for StageRecorder {
+        fn on_event(
+            &self,
+            event: &tracing::Event<'_>,
+            _: tracing_subscriber::layer::Context<'_, S>,
+        ) {
+            if event.metadata().target() == LIFECYCLE_TARGET {
+                let mut visitor = StageVisitor(None);
+                event.record(&mut visitor);
+                self.0.lock().unwrap().extend(visitor.0);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_lifecycle_events() {
+        use tracing_subscriber::layer::SubscriberExt;
+
+        let recorder = StageRecorder::default();
+        let _guard =
+            tracing::subscriber::set_default(tracing_subscriber::registry().with(recorder.clone()));
+
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream_port = upstream.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut stream, _) = upstream.accept().await.unwrap();
+            let (mut reader, mut writer) = stream.split();
+            tokio::io::copy(&mut reader, &mut writer).await
+        });
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let conn = upstream_connection(
+                ServerName::IpAddress(IpAddr::from([127, 0, 0, 1])),
+                upstream_port,
+            );
+            start(
+                BufStream::new(stream),
+                ProxyParams {
+                    candidates: vec![conn],
+                    ..Default::default()
+                },
+                Default::default(),
+                StreamOptions {
+                    lifecycle_events: true,
+                    ..Default::default()
+                },
+                Arc::new(Notify::new()),
+            )
+            .await
+        });
+
+        let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+        client.write_all(b"ping").await.unwrap();
+        let mut buf = [0; 4];
+        client.read_exact(&mut buf).await.unwrap();
+        client.shutdown().await.unwrap();
+        let mut rest = Vec::new();
+        client.read_to_end(&mut rest).await.unwrap();
+
+        let stages = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let stages = recorder.0.lock().unwrap().clone();
+                if stages.last().map(String::as_str) == Some("closed") {
+                    break stages;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(
+            stages,
+            [
+                "accepted",
+                "upstream_selected",
+                "resolved",
+                "connected",
+                "first_byte",
+                "closed"
+            ]
+        );
+    }
+
+    #[derive(Clone, Default)]
+    struct LogBuffer(Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl std::io::Write for LogBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_access_log_redaction() {
+        use tracing_subscriber::{fmt, layer::SubscriberExt};
+
+        let text = LogBuffer::default();
+        let json = LogBuffer::default();
+        let subscriber = tracing_subscriber::registry()
+            .with(fmt::layer().with_ansi(false).with_writer({
+                let text = text.clone();
+                move || text.clone()
+            }))
+            .with(fmt::layer().json().with_writer({
+                let json = json.clone();
+                move || json.clone()
+            }));
+        let _guard = tracing::subscriber::set_default(subscriber);
+        crate::log::set_redacted_hosts(&["LOCAL*".into()]);
+
+        for (name, bind) in [
+            (ServerName::try_from("localhost").unwrap(), "localhost:0"),
+            (
+                ServerName::IpAddress(IpAddr::from([127, 0, 0, 1])),
+                "127.0.0.1:0",
+            ),
+        ] {
+            let upstream = TcpListener::bind(bind).await.unwrap();
+            let upstream_port = upstream.local_addr().unwrap().port();
+            tokio::spawn(async move {
+                let (mut stream, _) = upstream.accept().await.unwrap();
+                let (mut reader, mut writer) = stream.split();
+                tokio::io::copy(&mut reader, &mut writer).await
+            });
+
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let proxy_addr = listener.local_addr().unwrap();
+            let proxy = tokio::spawn(async move {
+                let (stream, _) = listener.accept().await.unwrap();
+                let conn = upstream_connection(name, upstream_port);
+                start(
+                    BufStream::new(stream),
+                    ProxyParams {
+                        candidates: vec![conn],
+                        ..Default::default()
+                    },
+                    Default::default(),
+                    Default::default(),
+                    Arc::new(Notify::new()),
+                )
+                .await
+            });
+
+            let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+            client.write_all(b"ping").await.unwrap();
+            let mut buf = [0; 4];
+            client.read_exact(&mut buf).await.unwrap();
+            client.shutdown().await.unwrap();
+            let mut rest = Vec::new();
+            client.read_to_end(&mut rest).await.unwrap();
+            let _ = proxy.await;
+        }
+        crate::log::set_redacted_hosts(&[]);
+
+        let text = String::from_utf8(text.0.lock().unwrap().clone()).unwrap();
+        let json = String::from_utf8(json.0.lock().unwrap().clone()).unwrap();
+        let access_logs = |logs: &str| {
+            logs.lines()
+                .filter(|line| line.contains("taxy::access_log"))
+                .map(str::to_string)
+                .collect::<Vec<_>>()
+        };
+
+        let text = access_logs(&text);
+        assert_eq!(text.len(), 2);
+        assert!(text[0].contains("host=\"[REDACTED]\""));
+        assert!(!text[0].contains("localhost"));
+        assert!(text[1].contains("host=\"127.0.0.1\""));
+
+        let json = access_logs(&json);
+        assert_eq!(json.len(), 2);
+        assert!(json[0].contains(r#""host":"[REDACTED]""#));
+        assert!(!json[0].contains("localhost"));
+        assert!(json[1].contains(r#""host":"127.0.0.1""#));
+    }
 }
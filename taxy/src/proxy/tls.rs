@@ -1,22 +1,60 @@
+use super::client_hello::{read_client_hello, ClientHelloLimits, PrefixedStream};
+use super::compress::ALPN_DEFLATE;
+use super::tls_params::TlsParams;
 use crate::keyring::certs::Cert;
 use crate::keyring::Keyring;
 use dashmap::DashMap;
+use indexmap::IndexMap;
+use pkcs8::der::pem;
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
 use std::fmt;
+use std::net::IpAddr;
 use std::str::FromStr;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::{Arc, Mutex};
+use taxy_api::cert::SelfSignedCertRequest;
 use taxy_api::error::Error;
 use taxy_api::subject_name::SubjectName;
-use taxy_api::tls::TlsState;
-use tokio_rustls::rustls::server::{ClientHello, ResolvesServerCert};
+use taxy_api::tls::{CertSelection, ClientAuth, ClientAuthMode, TlsState};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::rustls::server::Acceptor;
+use tokio_rustls::rustls::server::{
+    AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient, ClientHello,
+    ResolvesServerCert, StoresServerSessions, WantsServerCert,
+};
 use tokio_rustls::rustls::sign::CertifiedKey;
-use tokio_rustls::rustls::ServerConfig;
-use tokio_rustls::TlsAcceptor;
-use tracing::error;
+use tokio_rustls::rustls::{Certificate, ConfigBuilder, RootCertStore, ServerConfig};
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::{LazyConfigAcceptor, TlsAcceptor};
+use tracing::{error, info, warn};
+use x509_parser::extensions::GeneralName;
+use x509_parser::parse_x509_certificate;
+use x509_parser::time::ASN1Time;
+
+const MAX_GENERATED_CERTS: usize = 64;
+const MAX_SELECTED_CERTS: usize = 1024;
+const DEFAULT_SESSION_CACHE_SIZE: usize = 256;
+
+tokio::task_local! {
+    /// The id of the certificate picked by the resolver for the connection being accepted.
+    static SERVED_CERT: RefCell<Option<String>>;
+}
 
 pub struct TlsTermination {
     pub server_names: Vec<SubjectName>,
-    pub acceptor: Option<TlsAcceptor>,
+    pub cert_selection: CertSelection,
+    pub self_signed_fallback: bool,
+    pub client_auth: Option<ClientAuth>,
+    pub sni_client_auth_modes: Vec<(Vec<SubjectName>, ClientAuthMode)>,
+    pub serve_expired_acme_certs: bool,
+    pub acceptor: Option<BoundedAcceptor>,
     pub alpn_protocols: Vec<Vec<u8>>,
+    pub client_hello_limits: ClientHelloLimits,
+    /// Negotiates [`ALPN_DEFLATE`] with the clients which offer it.
+    pub tunnel_compression: bool,
+    pub params: TlsParams,
+    pub session_cache_size: usize,
 }
 
 impl fmt::Debug for TlsTermination {
@@ -28,43 +66,364 @@ impl fmt::Debug for TlsTermination {
 }
 
 impl TlsTermination {
+    /// Creates a TLS termination offering `alpn_protocols` unless `config` sets its own.
     pub fn new(
         config: &taxy_api::tls::TlsTermination,
         alpn_protocols: Vec<Vec<u8>>,
+        client_hello_limits: ClientHelloLimits,
     ) -> Result<Self, Error> {
+        if config.ech {
+            return Err(Error::EchNotSupported);
+        }
+        if config.cert_compression {
+            return Err(Error::CertCompressionNotSupported);
+        }
         let mut server_names = Vec::new();
         for name in &config.server_names {
             let name = SubjectName::from_str(name)?;
             server_names.push(name);
         }
+        let mut sni_client_auth_modes = Vec::new();
+        for sni_mode in config.client_auth.iter().flat_map(|auth| &auth.sni_modes) {
+            let names = sni_mode
+                .server_names
+                .iter()
+                .map(|name| SubjectName::from_str(name))
+                .collect::<Result<Vec<_>, _>>()?;
+            sni_client_auth_modes.push((names, sni_mode.mode));
+        }
+        let alpn_protocols = if config.alpn.is_empty() {
+            alpn_protocols
+        } else {
+            config
+                .alpn
+                .iter()
+                .map(|protocol| {
+                    if protocol.is_empty() || protocol.len() > u8::MAX as usize {
+                        return Err(Error::InvalidAlpnProtocol {
+                            protocol: protocol.clone(),
+                        });
+                    }
+                    Ok(protocol.as_bytes().to_vec())
+                })
+                .collect::<Result<_, _>>()?
+        };
         Ok(Self {
             server_names,
+            cert_selection: config.cert_selection.clone(),
+            self_signed_fallback: config.self_signed_fallback,
+            client_auth: config.client_auth.clone(),
+            sni_client_auth_modes,
+            serve_expired_acme_certs: config.serve_expired_acme_certs,
             acceptor: None,
             alpn_protocols,
+            client_hello_limits,
+            tunnel_compression: false,
+            params: TlsParams::default(),
+            session_cache_size: config
+                .session_cache_size
+                .unwrap_or(DEFAULT_SESSION_CACHE_SIZE),
         })
     }
 
+    /// Returns true if the certificate is pinned, or if it or the ACME entry which
+    /// issued it covers one of the server names, so that it may be served by this port.
+    pub fn references_cert(&self, cert: &Cert, acme_identifiers: &[SubjectName]) -> bool {
+        matches!(&self.cert_selection, CertSelection::Pinned(id) if id == cert.id())
+            || self.server_names.iter().any(|name| {
+                cert.has_subject_name(name) || acme_identifiers.iter().any(|id| id.overlaps(name))
+            })
+    }
+
     pub async fn setup(&mut self, keyring: &Keyring) -> TlsState {
-        let resolver: Arc<dyn ResolvesServerCert> = Arc::new(ServerCertResolver::new(
+        let resolver = Arc::new(ServerCertResolver::new(
             keyring.certs(),
             self.server_names.clone(),
             true,
+            self.cert_selection.clone(),
+            self.self_signed_fallback,
+            self.serve_expired_acme_certs,
         ));
+        let state = if resolver.serves_expired_cert() {
+            warn!("no valid certificate found, serving an expired acme certificate");
+            TlsState::Degraded
+        } else {
+            TlsState::Active
+        };
 
-        let mut server_config = ServerConfig::builder()
-            .with_safe_defaults()
-            .with_no_client_auth()
-            .with_cert_resolver(resolver);
-        server_config.alpn_protocols = self.alpn_protocols.clone();
+        let roots = self
+            .client_auth
+            .as_ref()
+            .map(|client_auth| client_roots(client_auth, keyring))
+            .unwrap_or_else(RootCertStore::empty);
+        let mode = self
+            .client_auth
+            .as_ref()
+            .map_or(ClientAuthMode::None, |client_auth| client_auth.mode);
+        let configs = self.server_configs(mode, &roots, &resolver);
+        let sni_configs = self
+            .sni_client_auth_modes
+            .iter()
+            .map(|(names, mode)| (names.clone(), self.server_configs(*mode, &roots, &resolver)))
+            .collect();
+        self.acceptor = Some(BoundedAcceptor {
+            inner: TlsAcceptor::from(configs.config.clone()),
+            configs,
+            sni_configs,
+            resolver,
+            limits: self.client_hello_limits,
+        });
+
+        state
+    }
+
+    fn server_configs(
+        &self,
+        mode: ClientAuthMode,
+        roots: &RootCertStore,
+        resolver: &Arc<ServerCertResolver>,
+    ) -> AcceptorConfigs {
+        let mut config =
+            server_config_builder(&self.params, mode, roots).with_cert_resolver(resolver.clone());
+        config.alpn_protocols = self.alpn_protocols.clone();
+        config.session_storage = Arc::new(SessionCache::new(self.session_cache_size));
+        // Certificate compression (RFC 8879) needs the `cert_compressors` of rustls 0.23,
+        // so `cert_compression` is rejected by `new` until rustls is upgraded.
+
+        let deflate_config = self.tunnel_compression.then(|| {
+            let mut config = config.clone();
+            config.alpn_protocols = vec![ALPN_DEFLATE.to_vec()];
+            Arc::new(config)
+        });
+        AcceptorConfigs {
+            config: Arc::new(config),
+            deflate_config,
+        }
+    }
+
+    /// Sets up the termination with the refreshed keyring. If the certificate for the
+    /// server names fails to load, the previous one keeps being served unless `strict` is set.
+    pub async fn refresh(&mut self, keyring: &Keyring, strict: bool) -> Result<TlsState, Error> {
+        let previous = self.acceptor.take();
+        let state = self.setup(keyring).await;
+        let resolver = self
+            .acceptor
+            .as_ref()
+            .map(|acceptor| acceptor.resolver.clone());
+        let Some(Err(err)) = resolver.map(|resolver| resolver.load_default_cert()) else {
+            return Ok(state);
+        };
+        match previous.filter(|previous| previous.resolver.resolve_name(None).is_some()) {
+            Some(previous) if !strict => {
+                warn!("failed to load the refreshed certificate, keeping the previous one: {err}");
+                self.acceptor = Some(previous);
+                Ok(TlsState::Stale)
+            }
+            _ => Err(err),
+        }
+    }
+}
+
+/// Returns the root certificates which may issue client certificates.
+fn client_roots(client_auth: &ClientAuth, keyring: &Keyring) -> RootCertStore {
+    let mut roots = RootCertStore::empty();
+    for cert in keyring
+        .certs()
+        .into_iter()
+        .filter(|cert| client_auth.trusted_certs.iter().any(|id| id == cert.id()))
+    {
+        let chain = rustls_pemfile::certs(&mut cert.raw_chain.as_slice()).unwrap_or_default();
+        if let Some(root) = chain.last() {
+            if let Err(err) = roots.add(&Certificate(root.clone())) {
+                warn!(id = cert.id(), "failed to add client root cert: {err}");
+            }
+        }
+    }
+    if roots.is_empty() {
+        warn!("no trusted certs for client auth, client certificates will be rejected");
+    }
+    roots
+}
+
+fn server_config_builder(
+    params: &TlsParams,
+    mode: ClientAuthMode,
+    roots: &RootCertStore,
+) -> ConfigBuilder<ServerConfig, WantsServerCert> {
+    let builder = params.server_builder();
+    match mode {
+        ClientAuthMode::Optional => builder.with_client_cert_verifier(
+            AllowAnyAnonymousOrAuthenticatedClient::new(roots.clone()).boxed(),
+        ),
+        ClientAuthMode::Required => builder
+            .with_client_cert_verifier(AllowAnyAuthenticatedClient::new(roots.clone()).boxed()),
+        ClientAuthMode::None => builder.with_no_client_auth(),
+    }
+}
+
+/// An in-memory session store which evicts the oldest sessions once it holds `capacity`.
+///
+/// Unlike `ServerSessionMemoryCache`, whose capacity may be rounded up, the bound is exact.
+struct SessionCache {
+    capacity: usize,
+    sessions: Mutex<IndexMap<Vec<u8>, Vec<u8>>>,
+}
+
+impl SessionCache {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            sessions: Mutex::new(IndexMap::new()),
+        }
+    }
+}
+
+impl StoresServerSessions for SessionCache {
+    fn put(&self, key: Vec<u8>, value: Vec<u8>) -> bool {
+        if self.capacity == 0 {
+            return false;
+        }
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.insert(key, value);
+        while sessions.len() > self.capacity {
+            sessions.shift_remove_index(0);
+        }
+        true
+    }
+
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.sessions.lock().unwrap().get(key).cloned()
+    }
+
+    fn take(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.sessions.lock().unwrap().shift_remove(key)
+    }
+
+    fn can_cache(&self) -> bool {
+        self.capacity > 0
+    }
+}
+
+/// Returns the earliest `not_before` of the certs which are not valid yet.
+fn next_activation(certs: &[Arc<Cert>]) -> i64 {
+    let now = ASN1Time::now().timestamp();
+    certs
+        .iter()
+        .map(|cert| cert.not_before.timestamp())
+        .filter(|not_before| *not_before > now)
+        .min()
+        .unwrap_or(i64::MAX)
+}
+
+/// Server configs which differ only by the offered ALPN protocols.
+#[derive(Clone)]
+struct AcceptorConfigs {
+    config: Arc<ServerConfig>,
+    /// Configured for clients offering [`ALPN_DEFLATE`]. rustls rejects clients
+    /// which offer none of the configured protocols, so it is not offered to the others.
+    deflate_config: Option<Arc<ServerConfig>>,
+}
 
-        let server_config = Arc::new(server_config);
-        self.acceptor = Some(TlsAcceptor::from(server_config));
+/// A TLS acceptor which reads the ClientHello within the configured bounds
+/// before handing the connection to rustls.
+#[derive(Clone)]
+pub struct BoundedAcceptor {
+    inner: TlsAcceptor,
+    configs: AcceptorConfigs,
+    /// Configs with the client auth mode overridden for the server names,
+    /// chosen once the SNI has been read from the ClientHello.
+    sni_configs: Vec<(Vec<SubjectName>, AcceptorConfigs)>,
+    resolver: Arc<ServerCertResolver>,
+    limits: ClientHelloLimits,
+}
+
+impl BoundedAcceptor {
+    pub async fn accept<S>(&self, mut stream: S) -> anyhow::Result<TlsStream<PrefixedStream<S>>>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let hello = read_client_hello(&mut stream, &self.limits).await?;
+        let stream = PrefixedStream::new(hello, stream);
+        if self.configs.deflate_config.is_none() && self.sni_configs.is_empty() {
+            return Ok(self.inner.accept(stream).await?);
+        }
+        let start = LazyConfigAcceptor::new(Acceptor::default(), stream).await?;
+        let hello = start.client_hello();
+        let configs = hello
+            .server_name()
+            .and_then(|sni| {
+                self.sni_configs
+                    .iter()
+                    .find(|(names, _)| names.iter().any(|name| name.test(sni)))
+            })
+            .map_or(&self.configs, |(_, configs)| configs);
+        let offers_deflate = hello
+            .alpn()
+            .is_some_and(|mut protocols| protocols.any(|p| p == ALPN_DEFLATE));
+        let config = match &configs.deflate_config {
+            Some(deflate_config) if offers_deflate => deflate_config.clone(),
+            _ => configs.config.clone(),
+        };
+        Ok(start.into_stream(config).await?)
+    }
+
+    /// Accepts the connection, and returns the id of the certificate the resolver
+    /// picked for it. No certificate is picked for resumed sessions.
+    pub async fn accept_with_cert<S>(
+        &self,
+        stream: S,
+    ) -> anyhow::Result<(TlsStream<PrefixedStream<S>>, Option<String>)>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        SERVED_CERT
+            .scope(RefCell::new(None), async {
+                let stream = self.accept(stream).await?;
+                Ok((stream, SERVED_CERT.with(RefCell::take)))
+            })
+            .await
+    }
 
-        TlsState::Active
+    /// Returns the verified client certificate, if the client presented one.
+    pub fn client_cert<S>(&self, stream: &TlsStream<S>) -> Option<ClientCert> {
+        let cert = stream.get_ref().1.peer_certificates()?.first()?;
+        ClientCert::new(&cert.0)
     }
+}
+
+/// Details of a verified client certificate.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientCert {
+    pub subject: String,
+    pub san: Vec<String>,
+    pub fingerprint: String,
+    pub pem: String,
+}
 
-    pub async fn refresh(&mut self, certs: &Keyring) -> TlsState {
-        self.setup(certs).await
+impl ClientCert {
+    fn new(der: &[u8]) -> Option<Self> {
+        let (_, x509) = parse_x509_certificate(der).ok()?;
+        let san = x509
+            .subject_alternative_name()
+            .into_iter()
+            .flatten()
+            .flat_map(|name| &name.value.general_names)
+            .filter_map(|name| match name {
+                GeneralName::DNSName(name) => Some(name.to_string()),
+                GeneralName::IPAddress(addr) => <[u8; 4]>::try_from(*addr)
+                    .map(IpAddr::from)
+                    .or_else(|_| <[u8; 16]>::try_from(*addr).map(IpAddr::from))
+                    .ok()
+                    .map(|addr| addr.to_string()),
+                _ => None,
+            })
+            .collect();
+        Some(Self {
+            subject: x509.subject().to_string(),
+            san,
+            fingerprint: hex::encode(Sha256::digest(der)),
+            pem: pem::encode_string("CERTIFICATE", pem::LineEnding::LF, der).ok()?,
+        })
     }
 }
 
@@ -72,24 +431,74 @@ pub struct ServerCertResolver {
     certs: Vec<Arc<Cert>>,
     default_names: Vec<SubjectName>,
     sni: bool,
+    selection: CertSelection,
+    self_signed_fallback: bool,
+    serve_expired_acme_certs: bool,
+    generated: Mutex<IndexMap<String, Arc<Cert>>>,
+    /// Maps SNI names to the selected keyring certs. The resolver is rebuilt
+    /// whenever the keyring changes, which invalidates this cache.
+    selected: DashMap<String, Arc<Cert>>,
+    /// Timestamp at which the next not-yet-valid cert becomes valid, which invalidates
+    /// `selected` so that the cert is picked up without waiting for a keyring change.
+    next_activation: AtomicI64,
     cache: DashMap<String, Arc<CertifiedKey>>,
 }
 
 impl ServerCertResolver {
-    pub fn new(certs: Vec<Arc<Cert>>, default_names: Vec<SubjectName>, sni: bool) -> Self {
+    pub fn new(
+        certs: Vec<Arc<Cert>>,
+        default_names: Vec<SubjectName>,
+        sni: bool,
+        selection: CertSelection,
+        self_signed_fallback: bool,
+        serve_expired_acme_certs: bool,
+    ) -> Self {
+        let next_activation = AtomicI64::new(next_activation(&certs));
         Self {
             certs,
             default_names,
             sni,
+            selection,
+            self_signed_fallback,
+            serve_expired_acme_certs,
+            generated: Mutex::new(IndexMap::new()),
+            selected: DashMap::new(),
+            next_activation,
             cache: DashMap::new(),
         }
     }
-}
 
-impl ResolvesServerCert for ServerCertResolver {
-    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
-        let sni = client_hello
-            .server_name()
+    fn select(&self, sni: Option<&str>) -> Option<Arc<Cert>> {
+        self.select_cached(sni).or_else(|| {
+            let generated = self.generated.lock().unwrap();
+            generated.get(sni?).cloned()
+        })
+    }
+
+    fn select_cached(&self, sni: Option<&str>) -> Option<Arc<Cert>> {
+        if ASN1Time::now().timestamp() >= self.next_activation.load(Ordering::Relaxed) {
+            self.next_activation
+                .store(next_activation(&self.certs), Ordering::Relaxed);
+            self.selected.clear();
+        }
+
+        let key = sni.unwrap_or_default().to_ascii_lowercase();
+        if let Some(cert) = self.selected.get(&key) {
+            if cert.is_valid() {
+                return Some(cert.clone());
+            }
+        }
+
+        let cert = self.select_keyring_cert(sni).cloned()?;
+        if self.selected.len() >= MAX_SELECTED_CERTS {
+            self.selected.clear();
+        }
+        self.selected.insert(key, cert.clone());
+        Some(cert)
+    }
+
+    fn select_keyring_cert(&self, sni: Option<&str>) -> Option<&Arc<Cert>> {
+        let sni = sni
             .filter(|_| self.sni)
             .map(|sni| SubjectName::DnsName(sni.into()))
             .into_iter()
@@ -101,10 +510,99 @@ impl ResolvesServerCert for ServerCertResolver {
             &sni
         };
 
+        self.select_valid_cert(names)
+            .or_else(|| self.select_expired_acme_cert(names))
+    }
+
+    fn select_valid_cert(&self, names: &[SubjectName]) -> Option<&Arc<Cert>> {
+        let mut candidates = self
+            .certs
+            .iter()
+            .filter(|cert| cert.is_valid() && names.iter().all(|name| cert.has_subject_name(name)))
+            .peekable();
+
+        // Certs are kept sorted by their `Ord`, which already implements `NewestNotBefore`.
+        let first = candidates.peek().cloned();
+        match &self.selection {
+            CertSelection::NewestNotBefore => first,
+            CertSelection::LongestValidity => {
+                candidates.min_by(|a, b| b.not_after.partial_cmp(&a.not_after).unwrap())
+            }
+            CertSelection::Pinned(id) => candidates.find(|cert| cert.id() == id).or(first),
+        }
+    }
+
+    /// Returns the ACME certificate which expired last, if serving expired certificates is enabled.
+    fn select_expired_acme_cert(&self, names: &[SubjectName]) -> Option<&Arc<Cert>> {
+        if !self.serve_expired_acme_certs {
+            return None;
+        }
+        let now = ASN1Time::now();
         let cert = self
             .certs
             .iter()
-            .find(|cert| cert.is_valid() && names.iter().all(|name| cert.has_subject_name(name)))?;
+            .filter(|cert| {
+                cert.acme_id().is_some()
+                    && cert.not_before <= now
+                    && names.iter().all(|name| cert.has_subject_name(name))
+            })
+            .max_by(|a, b| a.not_after.partial_cmp(&b.not_after).unwrap())?;
+        warn!(
+            id = cert.id(),
+            not_after = %cert.not_after,
+            "serving expired acme certificate"
+        );
+        Some(cert)
+    }
+
+    /// Loads the key of the certificate for the default names, if there is one.
+    fn load_default_cert(&self) -> Result<(), Error> {
+        match self.select_keyring_cert(None) {
+            Some(cert) => cert.certified().map(|_| ()),
+            None => Ok(()),
+        }
+    }
+
+    /// Returns true if the certificate for the default names is an expired one.
+    fn serves_expired_cert(&self) -> bool {
+        self.select_keyring_cert(None)
+            .is_some_and(|cert| !cert.is_valid())
+    }
+
+    /// Returns a cached self-signed certificate for the name, generating it on first use.
+    fn generate(&self, sni: &str) -> Option<Arc<Cert>> {
+        let mut generated = self.generated.lock().unwrap();
+        if let Some(cert) = generated.get(sni) {
+            return Some(cert.clone());
+        }
+
+        let req = SelfSignedCertRequest {
+            san: vec![SubjectName::from_str(sni).ok()?],
+        };
+        let cert = match Cert::new_self_signed(&req) {
+            Ok(cert) => Arc::new(cert),
+            Err(err) => {
+                error!(sni, "failed to generate self-signed certificate: {}", err);
+                return None;
+            }
+        };
+        info!(sni, id = cert.id(), "generated self-signed certificate");
+
+        if generated.len() >= MAX_GENERATED_CERTS {
+            if let Some((_, evicted)) = generated.shift_remove_index(0) {
+                self.cache.remove(evicted.id());
+            }
+        }
+        generated.insert(sni.to_string(), cert.clone());
+        Some(cert)
+    }
+
+    fn resolve_name(&self, sni: Option<&str>) -> Option<Arc<CertifiedKey>> {
+        let cert = self.select(sni).or_else(|| {
+            sni.filter(|_| self.self_signed_fallback)
+                .and_then(|sni| self.generate(sni))
+        })?;
+        let _ = SERVED_CERT.try_with(|served| *served.borrow_mut() = Some(cert.id().to_string()));
 
         if let Some(cert) = self.cache.get(cert.id()) {
             Some(cert.clone())
@@ -121,3 +619,585 @@ impl ResolvesServerCert for ServerCertResolver {
         }
     }
 }
+
+impl ResolvesServerCert for ServerCertResolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        self.resolve_name(client_hello.server_name())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::keyring::KeyringItem;
+    use crate::testing;
+    use taxy_api::cert::CertMetadata;
+    use taxy_api::tls::SniClientAuthMode;
+    use tokio_rustls::rustls::client::{ServerCertVerified, ServerCertVerifier, ServerName};
+    use tokio_rustls::rustls::{ClientConfig, PrivateKey};
+    use tokio_rustls::TlsConnector;
+    use x509_parser::time::ASN1Time;
+
+    const DAY: i64 = 60 * 60 * 24;
+
+    fn cert(not_before: i64, not_after: i64) -> Arc<Cert> {
+        let mut cert = Cert::new_self_signed(&SelfSignedCertRequest {
+            san: vec![SubjectName::from_str("example.com").unwrap()],
+        })
+        .unwrap();
+        let now = ASN1Time::now().timestamp();
+        cert.not_before = ASN1Time::from_timestamp(now + not_before).unwrap();
+        cert.not_after = ASN1Time::from_timestamp(now + not_after).unwrap();
+        Arc::new(cert)
+    }
+
+    #[test]
+    fn test_cert_selection() {
+        let long = cert(-10 * DAY, 300 * DAY);
+        let newest = cert(-DAY, 30 * DAY);
+        let mut certs = vec![long.clone(), newest.clone()];
+        certs.sort();
+
+        let select = |selection| {
+            let resolver =
+                ServerCertResolver::new(certs.clone(), vec![], true, selection, false, false);
+            resolver
+                .select(Some("example.com"))
+                .unwrap()
+                .id()
+                .to_string()
+        };
+
+        assert_eq!(select(CertSelection::NewestNotBefore), newest.id());
+        assert_eq!(select(CertSelection::LongestValidity), long.id());
+        assert_eq!(
+            select(CertSelection::Pinned(long.id().to_string())),
+            long.id()
+        );
+        assert_eq!(select(CertSelection::Pinned("unknown".into())), newest.id());
+    }
+
+    #[test]
+    fn test_not_yet_valid_cert() {
+        let current = cert(-DAY, 30 * DAY);
+        let next = cert(2, 60 * DAY);
+
+        let resolver = ServerCertResolver::new(
+            vec![next.clone()],
+            vec![],
+            true,
+            Default::default(),
+            false,
+            true,
+        );
+        assert!(resolver.select(Some("example.com")).is_none());
+
+        let mut certs = vec![current.clone(), next.clone()];
+        certs.sort();
+        let resolver =
+            ServerCertResolver::new(certs, vec![], true, Default::default(), false, false);
+        let select = || {
+            resolver
+                .select(Some("example.com"))
+                .unwrap()
+                .id()
+                .to_string()
+        };
+        assert_eq!(select(), current.id());
+
+        std::thread::sleep(std::time::Duration::from_millis(2100));
+        assert_eq!(select(), next.id());
+    }
+
+    #[tokio::test]
+    async fn test_sni_cert_selection() {
+        let a = testing::self_signed("a.example.com");
+        let b = testing::self_signed("b.example.com");
+        let config = taxy_api::tls::TlsTermination {
+            server_names: vec!["a.example.com".into()],
+            ..Default::default()
+        };
+        let mut tls = TlsTermination::new(&config, vec![], Default::default()).unwrap();
+        tls.setup(&Keyring::new([
+            KeyringItem::ServerCert(a.clone()),
+            KeyringItem::ServerCert(b.clone()),
+        ]))
+        .await;
+        let select = |tls: &TlsTermination, sni| {
+            let resolver = &tls.acceptor.as_ref().unwrap().resolver;
+            resolver.select(sni).map(|cert| cert.id().to_string())
+        };
+        assert_eq!(select(&tls, Some("a.example.com")).as_deref(), Some(a.id()));
+        assert_eq!(select(&tls, Some("b.example.com")).as_deref(), Some(b.id()));
+        // Clients without SNI get the cert of the server names.
+        assert_eq!(select(&tls, None).as_deref(), Some(a.id()));
+        assert_eq!(select(&tls, Some("c.example.com")), None);
+
+        let c = testing::self_signed("c.example.com");
+        tls.refresh(
+            &Keyring::new([
+                KeyringItem::ServerCert(a.clone()),
+                KeyringItem::ServerCert(b.clone()),
+                KeyringItem::ServerCert(c.clone()),
+            ]),
+            false,
+        )
+        .await
+        .unwrap();
+        assert_eq!(select(&tls, Some("c.example.com")).as_deref(), Some(c.id()));
+    }
+
+    #[tokio::test]
+    async fn test_serve_expired_acme_certs() {
+        let mut expired = cert(-90 * DAY, -DAY);
+        Arc::make_mut(&mut expired).metadata = Some(CertMetadata {
+            acme_id: "example".into(),
+            created_at: std::time::SystemTime::now(),
+            is_trusted: true,
+            ocsp_response_path: None,
+        });
+        let keyring = Keyring::new([KeyringItem::ServerCert(expired.clone())]);
+
+        for serve_expired_acme_certs in [true, false] {
+            let config = taxy_api::tls::TlsTermination {
+                server_names: vec!["example.com".into()],
+                serve_expired_acme_certs,
+                ..Default::default()
+            };
+            let mut tls = TlsTermination::new(&config, vec![], Default::default()).unwrap();
+            let state = tls.setup(&keyring).await;
+            let resolver = tls.acceptor.as_ref().unwrap().resolver.clone();
+            let served = resolver.resolve_name(Some("example.com"));
+            if serve_expired_acme_certs {
+                assert_eq!(state, TlsState::Degraded);
+                assert!(served.is_some());
+                assert_eq!(
+                    resolver.select(Some("example.com")).unwrap().id(),
+                    expired.id()
+                );
+            } else {
+                assert_eq!(state, TlsState::Active);
+                assert!(served.is_none());
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_selection_cache() {
+        let old = cert(-10 * DAY, 30 * DAY);
+        let config = taxy_api::tls::TlsTermination {
+            server_names: vec!["example.com".into()],
+            ..Default::default()
+        };
+        let mut tls = TlsTermination::new(&config, vec![], Default::default()).unwrap();
+        tls.setup(&Keyring::new([KeyringItem::ServerCert(old.clone())]))
+            .await;
+
+        let resolver = tls.acceptor.as_ref().unwrap().resolver.clone();
+        assert_eq!(resolver.select(Some("example.com")).unwrap().id(), old.id());
+        assert_eq!(resolver.selected.len(), 1);
+
+        // Repeated lookups are served from the cache.
+        let other = cert(-DAY, 30 * DAY);
+        resolver
+            .selected
+            .insert("example.com".into(), other.clone());
+        assert_eq!(
+            resolver.select(Some("EXAMPLE.com")).unwrap().id(),
+            other.id()
+        );
+
+        // A renewal swaps the cert in the keyring, which rebuilds the resolver.
+        let renewed = cert(-DAY, 90 * DAY);
+        tls.refresh(
+            &Keyring::new([
+                KeyringItem::ServerCert(old.clone()),
+                KeyringItem::ServerCert(renewed.clone()),
+            ]),
+            false,
+        )
+        .await
+        .unwrap();
+        let resolver = tls.acceptor.as_ref().unwrap().resolver.clone();
+        assert!(resolver.selected.is_empty());
+        assert_eq!(
+            resolver.select(Some("example.com")).unwrap().id(),
+            renewed.id()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_refresh_failure() {
+        let old = cert(-10 * DAY, 30 * DAY);
+        let mut broken = cert(-DAY, 90 * DAY);
+        Arc::make_mut(&mut broken).key =
+            pkcs8::SecretDocument::try_from([0x30, 0x00].as_slice()).unwrap();
+        let config = taxy_api::tls::TlsTermination {
+            server_names: vec!["example.com".into()],
+            ..Default::default()
+        };
+        let served = |tls: &TlsTermination| {
+            let resolver = &tls.acceptor.as_ref().unwrap().resolver;
+            resolver
+                .resolve_name(Some("example.com"))
+                .map(|key| key.cert.clone())
+        };
+
+        for strict in [false, true] {
+            let mut tls = TlsTermination::new(&config, vec![], Default::default()).unwrap();
+            tls.setup(&Keyring::new([KeyringItem::ServerCert(old.clone())]))
+                .await;
+            let before = served(&tls);
+            assert!(before.is_some());
+
+            let result = tls
+                .refresh(
+                    &Keyring::new([
+                        KeyringItem::ServerCert(old.clone()),
+                        KeyringItem::ServerCert(broken.clone()),
+                    ]),
+                    strict,
+                )
+                .await;
+            if strict {
+                assert!(result.is_err());
+                assert!(served(&tls).is_none());
+            } else {
+                assert_eq!(result.unwrap(), TlsState::Stale);
+                assert_eq!(served(&tls), before);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_trusted_cert_selection() {
+        let older = cert(-10 * DAY, 30 * DAY);
+        let newer = cert(-DAY, 30 * DAY);
+        let set_trusted = |cert: &Arc<Cert>, is_trusted| {
+            let mut toggled = cert.with_trusted(is_trusted).unwrap();
+            toggled.not_before = cert.not_before;
+            toggled.not_after = cert.not_after;
+            Arc::new(toggled)
+        };
+        let config = taxy_api::tls::TlsTermination {
+            server_names: vec!["example.com".into()],
+            ..Default::default()
+        };
+        let mut tls = TlsTermination::new(&config, vec![], Default::default()).unwrap();
+        async fn select(tls: &mut TlsTermination, certs: [Arc<Cert>; 2]) -> String {
+            tls.refresh(&Keyring::new(certs.map(KeyringItem::ServerCert)), false)
+                .await
+                .unwrap();
+            let resolver = tls.acceptor.as_ref().unwrap().resolver.clone();
+            let cert = resolver.select(Some("example.com")).unwrap();
+            cert.id().to_string()
+        }
+
+        let selected = select(&mut tls, [older.clone(), newer.clone()]).await;
+        assert_eq!(selected, newer.id());
+
+        let trusted = set_trusted(&older, true);
+        let selected = select(&mut tls, [trusted.clone(), newer.clone()]).await;
+        assert_eq!(selected, older.id());
+
+        let untrusted = set_trusted(&trusted, false);
+        let selected = select(&mut tls, [untrusted, newer.clone()]).await;
+        assert_eq!(selected, newer.id());
+    }
+
+    #[test]
+    fn test_self_signed_fallback() {
+        let resolver =
+            ServerCertResolver::new(vec![], vec![], true, Default::default(), true, false);
+        let first = resolver.resolve_name(Some("dev.example.com")).unwrap();
+        let cert = resolver.select(Some("dev.example.com")).unwrap();
+        assert!(cert.has_subject_name(&SubjectName::from_str("dev.example.com").unwrap()));
+
+        let second = resolver.resolve_name(Some("dev.example.com")).unwrap();
+        assert!(Arc::ptr_eq(&first, &second));
+        assert_eq!(resolver.generated.lock().unwrap().len(), 1);
+
+        let resolver =
+            ServerCertResolver::new(vec![], vec![], true, Default::default(), false, false);
+        assert!(resolver.resolve_name(Some("dev.example.com")).is_none());
+    }
+
+    async fn handshake(mode: ClientAuthMode, with_cert: bool) -> anyhow::Result<Option<String>> {
+        handshake_sni(mode, vec![], "localhost", with_cert).await
+    }
+
+    async fn handshake_sni(
+        mode: ClientAuthMode,
+        sni_modes: Vec<SniClientAuthMode>,
+        sni: &'static str,
+        with_cert: bool,
+    ) -> anyhow::Result<Option<String>> {
+        handshake_trust(mode, sni_modes, sni, with_cert, true).await
+    }
+
+    /// Performs the handshake with a client certificate issued by a trusted root
+    /// only if `trusted` is true.
+    async fn handshake_trust(
+        mode: ClientAuthMode,
+        sni_modes: Vec<SniClientAuthMode>,
+        sni: &'static str,
+        with_cert: bool,
+        trusted: bool,
+    ) -> anyhow::Result<Option<String>> {
+        let server_cert = testing::self_signed_with_sans(&[
+            "localhost",
+            "admin.example.com",
+            "public.example.com",
+        ]);
+        let client_cert = testing::self_signed("client.example.com");
+        let keyring = Keyring::new([
+            KeyringItem::ServerCert(server_cert.clone()),
+            KeyringItem::ServerCert(client_cert.clone()),
+        ]);
+        let config = taxy_api::tls::TlsTermination {
+            server_names: vec!["localhost".into()],
+            client_auth: Some(ClientAuth {
+                mode,
+                trusted_certs: vec![if trusted {
+                    client_cert.id().to_string()
+                } else {
+                    server_cert.id().to_string()
+                }],
+                forward_headers: Default::default(),
+                sni_modes,
+            }),
+            ..Default::default()
+        };
+        let mut tls = TlsTermination::new(&config, vec![], Default::default()).unwrap();
+        tls.setup(&keyring).await;
+        let acceptor = tls.acceptor.unwrap();
+
+        let builder = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(testing::root_certs(&server_cert));
+        let client_config = if with_cert {
+            let chain = rustls_pemfile::certs(&mut client_cert.raw_chain.as_slice())
+                .unwrap()
+                .into_iter()
+                .map(Certificate)
+                .collect();
+            let key = rustls_pemfile::pkcs8_private_keys(&mut client_cert.raw_key.as_slice())
+                .unwrap()
+                .remove(0);
+            builder.with_single_cert(chain, PrivateKey(key)).unwrap()
+        } else {
+            builder.with_no_client_auth()
+        };
+
+        let (client, server) = tokio::io::duplex(16 * 1024);
+        // Keep the client stream alive in the join handle until the server has finished.
+        let _client = tokio::spawn(async move {
+            TlsConnector::from(Arc::new(client_config))
+                .connect(ServerName::try_from(sni).unwrap(), client)
+                .await
+        });
+        let accepted = acceptor.accept(server).await?;
+        Ok(acceptor.client_cert(&accepted).map(|cert| cert.subject))
+    }
+
+    #[tokio::test]
+    async fn test_optional_client_auth() {
+        let subject = handshake(ClientAuthMode::Optional, true).await.unwrap();
+        assert_eq!(subject.as_deref(), Some("CN=client.example.com"));
+
+        let subject = handshake(ClientAuthMode::Optional, false).await.unwrap();
+        assert_eq!(subject, None);
+    }
+
+    #[tokio::test]
+    async fn test_required_client_auth() {
+        let subject = handshake(ClientAuthMode::Required, true).await.unwrap();
+        assert_eq!(subject.as_deref(), Some("CN=client.example.com"));
+
+        assert!(handshake(ClientAuthMode::Required, false).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_untrusted_client_cert() {
+        for mode in [ClientAuthMode::Required, ClientAuthMode::Optional] {
+            let result = handshake_trust(mode, vec![], "localhost", true, false).await;
+            assert!(result.is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sni_client_auth_modes() {
+        let sni_modes = || {
+            vec![
+                SniClientAuthMode {
+                    server_names: vec!["admin.example.com".into()],
+                    mode: ClientAuthMode::Required,
+                },
+                SniClientAuthMode {
+                    server_names: vec!["public.example.com".into()],
+                    mode: ClientAuthMode::None,
+                },
+            ]
+        };
+        let handshake =
+            |sni, with_cert| handshake_sni(ClientAuthMode::Optional, sni_modes(), sni, with_cert);
+
+        assert!(handshake("admin.example.com", false).await.is_err());
+        let subject = handshake("admin.example.com", true).await.unwrap();
+        assert_eq!(subject.as_deref(), Some("CN=client.example.com"));
+
+        // No certificate is requested, so the client doesn't present one.
+        assert_eq!(handshake("public.example.com", false).await.unwrap(), None);
+        assert_eq!(handshake("public.example.com", true).await.unwrap(), None);
+
+        // Other names use the default mode.
+        assert_eq!(handshake("localhost", false).await.unwrap(), None);
+        let subject = handshake("localhost", true).await.unwrap();
+        assert_eq!(subject.as_deref(), Some("CN=client.example.com"));
+    }
+
+    /// Accepts any server certificate, counting the full handshakes which present one.
+    struct CountingVerifier(std::sync::atomic::AtomicUsize);
+
+    impl ServerCertVerifier for CountingVerifier {
+        fn verify_server_cert(
+            &self,
+            _end_entity: &Certificate,
+            _intermediates: &[Certificate],
+            _server_name: &ServerName,
+            _scts: &mut dyn Iterator<Item = &[u8]>,
+            _ocsp_response: &[u8],
+            _now: std::time::SystemTime,
+        ) -> Result<ServerCertVerified, tokio_rustls::rustls::Error> {
+            self.0.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            Ok(ServerCertVerified::assertion())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_session_cache_size() {
+        let names = ["a.example.com", "b.example.com", "c.example.com"];
+        let server_cert = testing::self_signed_with_sans(&names);
+        let config = taxy_api::tls::TlsTermination {
+            server_names: names.into_iter().map(Into::into).collect(),
+            session_cache_size: Some(2),
+            ..Default::default()
+        };
+        let mut tls = TlsTermination::new(&config, vec![], Default::default()).unwrap();
+        tls.setup(&Keyring::new([KeyringItem::ServerCert(server_cert)]))
+            .await;
+        let acceptor = tls.acceptor.unwrap();
+
+        // TLS 1.2 stores a single session per handshake.
+        let verifier = Arc::new(CountingVerifier(Default::default()));
+        let client_config = Arc::new(
+            ClientConfig::builder()
+                .with_safe_default_cipher_suites()
+                .with_safe_default_kx_groups()
+                .with_protocol_versions(&[&tokio_rustls::rustls::version::TLS12])
+                .unwrap()
+                .with_custom_certificate_verifier(verifier.clone())
+                .with_no_client_auth(),
+        );
+        let full_handshake = |sni: &'static str| {
+            let acceptor = acceptor.clone();
+            let client_config = client_config.clone();
+            let verifier = verifier.clone();
+            async move {
+                let before = verifier.0.load(std::sync::atomic::Ordering::SeqCst);
+                let (client, server) = tokio::io::duplex(16 * 1024);
+                let (client, server) = tokio::join!(
+                    TlsConnector::from(client_config)
+                        .connect(ServerName::try_from(sni).unwrap(), client),
+                    acceptor.accept(server)
+                );
+                client.unwrap();
+                server.unwrap();
+                verifier.0.load(std::sync::atomic::Ordering::SeqCst) > before
+            }
+        };
+
+        for name in names {
+            assert!(full_handshake(name).await);
+        }
+        // The session of the first name has been evicted.
+        assert!(!full_handshake("c.example.com").await);
+        assert!(!full_handshake("b.example.com").await);
+        assert!(full_handshake("a.example.com").await);
+    }
+
+    #[tokio::test]
+    async fn test_alpn() {
+        let server_cert = testing::self_signed("localhost");
+        let mut config = taxy_api::tls::TlsTermination {
+            server_names: vec!["localhost".into()],
+            alpn: vec!["h2".into(), "acme-proto".into()],
+            ..Default::default()
+        };
+        let mut tls =
+            TlsTermination::new(&config, vec![b"http/1.1".to_vec()], Default::default()).unwrap();
+        tls.setup(&Keyring::new([KeyringItem::ServerCert(server_cert)]))
+            .await;
+        let acceptor = tls.acceptor.unwrap();
+
+        let negotiate = |protocols: &[&str]| {
+            let acceptor = acceptor.clone();
+            let mut client_config = ClientConfig::builder()
+                .with_safe_defaults()
+                .with_custom_certificate_verifier(Arc::new(CountingVerifier(Default::default())))
+                .with_no_client_auth();
+            client_config.alpn_protocols = protocols
+                .iter()
+                .map(|protocol| protocol.as_bytes().to_vec())
+                .collect();
+            async move {
+                let (client, server) = tokio::io::duplex(16 * 1024);
+                let (_, server) = tokio::join!(
+                    TlsConnector::from(Arc::new(client_config))
+                        .connect(ServerName::try_from("localhost").unwrap(), client),
+                    acceptor.accept(server)
+                );
+                Ok::<_, anyhow::Error>(server?.get_ref().1.alpn_protocol().map(|p| p.to_vec()))
+            }
+        };
+
+        assert_eq!(
+            negotiate(&["acme-proto", "http/1.1"]).await.unwrap(),
+            Some(b"acme-proto".to_vec())
+        );
+        assert_eq!(negotiate(&[]).await.unwrap(), None);
+        assert!(negotiate(&["http/1.1"]).await.is_err());
+
+        config.alpn = vec!["".into()];
+        assert!(matches!(
+            TlsTermination::new(&config, vec![], Default::default()),
+            Err(Error::InvalidAlpnProtocol { .. })
+        ));
+    }
+
+    #[test]
+    fn test_ech_not_supported() {
+        let config = taxy_api::tls::TlsTermination {
+            server_names: vec!["localhost".into()],
+            ech: true,
+            ..Default::default()
+        };
+        assert!(matches!(
+            TlsTermination::new(&config, vec![], Default::default()),
+            Err(Error::EchNotSupported)
+        ));
+    }
+
+    #[test]
+    fn test_cert_compression_not_supported() {
+        let config = taxy_api::tls::TlsTermination {
+            server_names: vec!["localhost".into()],
+            cert_compression: true,
+            ..Default::default()
+        };
+        assert!(matches!(
+            TlsTermination::new(&config, vec![], Default::default()),
+            Err(Error::CertCompressionNotSupported)
+        ));
+    }
+}
@@ -0,0 +1,123 @@
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+pub const DEFAULT_TLS_CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A TLS stream which reads a peer closing the connection without `close_notify` as EOF,
+/// unless strict. rustls reports such a close as `UnexpectedEof` since it cannot be told
+/// apart from a truncation attack, but many TLS implementations never send `close_notify`.
+///
+/// Shutting down the stream sends `close_notify` to the peer either way.
+#[derive(Debug)]
+pub struct CloseNotifyStream<S> {
+    inner: S,
+    strict: bool,
+}
+
+impl<S> CloseNotifyStream<S> {
+    pub fn new(inner: S, strict: bool) -> Self {
+        Self { inner, strict }
+    }
+}
+
+impl<S> AsyncRead for CloseNotifyStream<S>
+where
+    S: AsyncRead + Unpin,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let strict = self.strict;
+        match Pin::new(&mut self.inner).poll_read(cx, buf) {
+            Poll::Ready(Err(err)) if !strict && err.kind() == io::ErrorKind::UnexpectedEof => {
+                Poll::Ready(Ok(()))
+            }
+            poll => poll,
+        }
+    }
+}
+
+impl<S> AsyncWrite for CloseNotifyStream<S>
+where
+    S: AsyncWrite + Unpin,
+{
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Shuts down the stream, which sends `close_notify` first on TLS streams.
+/// Gives up after `timeout`, such as when the peer stops reading.
+pub async fn shutdown<S>(stream: &mut S, timeout: Duration) -> io::Result<()>
+where
+    S: AsyncWrite + Unpin + ?Sized,
+{
+    match tokio::time::timeout(timeout, stream.shutdown()).await {
+        Ok(result) => result,
+        Err(_) => Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("connection was not shut down within {timeout:?}"),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+
+    /// Closes the connection without `close_notify`, as rustls reports it.
+    struct Truncated;
+
+    impl AsyncRead for Truncated {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            _buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_truncation() {
+        let mut buf = Vec::new();
+        let mut lenient = CloseNotifyStream::new(Truncated, false);
+        assert_eq!(lenient.read_to_end(&mut buf).await.unwrap(), 0);
+
+        let mut strict = CloseNotifyStream::new(Truncated, true);
+        let err = strict.read_to_end(&mut buf).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_timeout() {
+        // The peer never reads, so flushing before the shutdown is stuck behind a full pipe.
+        let (stream, _peer) = tokio::io::duplex(4);
+        let mut stream = tokio::io::BufWriter::new(stream);
+        stream.write_all(b"more than fits").await.unwrap();
+        let err = shutdown(&mut stream, Duration::from_millis(50))
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    }
+}
@@ -0,0 +1,158 @@
+use taxy_api::error::Error;
+use taxy_api::tls::{TlsParameters, TlsVersion};
+use tokio_rustls::rustls::{
+    version, ClientConfig, ConfigBuilder, ConfigSide, ServerConfig, SupportedCipherSuite,
+    SupportedKxGroup, SupportedProtocolVersion, WantsCipherSuites, WantsVerifier,
+    ALL_CIPHER_SUITES, ALL_KX_GROUPS, DEFAULT_CIPHER_SUITES, DEFAULT_VERSIONS,
+};
+
+/// TLS versions, cipher suites and key exchange groups validated against those
+/// compiled into rustls.
+#[derive(Debug, Clone)]
+pub struct TlsParams {
+    cipher_suites: Vec<SupportedCipherSuite>,
+    kx_groups: Vec<&'static SupportedKxGroup>,
+    versions: Vec<&'static SupportedProtocolVersion>,
+}
+
+impl Default for TlsParams {
+    fn default() -> Self {
+        Self {
+            cipher_suites: DEFAULT_CIPHER_SUITES.to_vec(),
+            kx_groups: ALL_KX_GROUPS.to_vec(),
+            versions: DEFAULT_VERSIONS.to_vec(),
+        }
+    }
+}
+
+impl TlsParams {
+    pub fn new(params: &TlsParameters) -> Result<Self, Error> {
+        let mut tls_params = Self::default();
+        if !params.versions.is_empty() {
+            tls_params.versions = params
+                .versions
+                .iter()
+                .map(|version| match version {
+                    TlsVersion::Tls12 => &version::TLS12,
+                    TlsVersion::Tls13 => &version::TLS13,
+                })
+                .collect();
+        }
+        if !params.cipher_suites.is_empty() {
+            tls_params.cipher_suites = params
+                .cipher_suites
+                .iter()
+                .map(|name| {
+                    ALL_CIPHER_SUITES
+                        .iter()
+                        .find(|suite| {
+                            suite
+                                .suite()
+                                .as_str()
+                                .is_some_and(|suite| suite.eq_ignore_ascii_case(name))
+                        })
+                        .copied()
+                        .ok_or_else(|| Error::UnsupportedCipherSuite { name: name.clone() })
+                })
+                .collect::<Result<_, _>>()?;
+        }
+        if !params.kx_groups.is_empty() {
+            tls_params.kx_groups = params
+                .kx_groups
+                .iter()
+                .map(|name| {
+                    ALL_KX_GROUPS
+                        .iter()
+                        .find(|group| {
+                            group
+                                .name
+                                .as_str()
+                                .is_some_and(|group| group.eq_ignore_ascii_case(name))
+                        })
+                        .copied()
+                        .ok_or_else(|| Error::UnsupportedKxGroup { name: name.clone() })
+                })
+                .collect::<Result<_, _>>()?;
+        }
+
+        // rustls rejects the combination if none of the cipher suites belong to the versions.
+        tls_params
+            .apply(ServerConfig::builder())
+            .map_err(|err| Error::InvalidTlsParameters {
+                reason: err.to_string(),
+            })?;
+        Ok(tls_params)
+    }
+
+    fn apply<S: ConfigSide>(
+        &self,
+        builder: ConfigBuilder<S, WantsCipherSuites>,
+    ) -> Result<ConfigBuilder<S, WantsVerifier>, tokio_rustls::rustls::Error> {
+        builder
+            .with_cipher_suites(&self.cipher_suites)
+            .with_kx_groups(&self.kx_groups)
+            .with_protocol_versions(&self.versions)
+    }
+
+    pub fn server_builder(&self) -> ConfigBuilder<ServerConfig, WantsVerifier> {
+        // The parameters have been validated in `new`.
+        self.apply(ServerConfig::builder())
+            .expect("invalid TLS parameters")
+    }
+
+    pub fn client_builder(&self) -> ConfigBuilder<ClientConfig, WantsVerifier> {
+        self.apply(ClientConfig::builder())
+            .expect("invalid TLS parameters")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn params(
+        versions: &[TlsVersion],
+        cipher_suites: &[&str],
+        kx_groups: &[&str],
+    ) -> TlsParameters {
+        TlsParameters {
+            versions: versions.to_vec(),
+            cipher_suites: cipher_suites.iter().map(|s| s.to_string()).collect(),
+            kx_groups: kx_groups.iter().map(|s| s.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn test_tls_params() {
+        let default = TlsParams::new(&TlsParameters::default()).unwrap();
+        assert_eq!(default.cipher_suites, DEFAULT_CIPHER_SUITES);
+        assert_eq!(default.versions.len(), DEFAULT_VERSIONS.len());
+
+        let tls13 = TlsParams::new(&params(
+            &[TlsVersion::Tls13],
+            &["tls13_aes_256_gcm_sha384"],
+            &["X25519", "secp384r1"],
+        ))
+        .unwrap();
+        assert_eq!(tls13.versions, [&version::TLS13]);
+        assert_eq!(tls13.cipher_suites.len(), 1);
+        assert_eq!(tls13.kx_groups.len(), 2);
+
+        assert!(matches!(
+            TlsParams::new(&params(&[], &["TLS_RSA_WITH_RC4_128_MD5"], &[])),
+            Err(Error::UnsupportedCipherSuite { .. })
+        ));
+        assert!(matches!(
+            TlsParams::new(&params(&[], &[], &["ffdhe2048"])),
+            Err(Error::UnsupportedKxGroup { .. })
+        ));
+        assert!(matches!(
+            TlsParams::new(&params(
+                &[TlsVersion::Tls13],
+                &["TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256"],
+                &[]
+            )),
+            Err(Error::InvalidTlsParameters { .. })
+        ));
+    }
+}
@@ -0,0 +1,137 @@
+use rand::RngCore;
+use std::fmt;
+
+pub const TRACEPARENT: &str = "traceparent";
+pub const TRACESTATE: &str = "tracestate";
+
+const SAMPLED: u8 = 0x01;
+
+/// A W3C trace context `traceparent`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TraceParent {
+    trace_id: [u8; 16],
+    parent_id: [u8; 8],
+    flags: u8,
+}
+
+impl TraceParent {
+    /// Starts a new sampled trace.
+    pub fn generate() -> Self {
+        let mut rng = rand::thread_rng();
+        let mut trace_id = [0; 16];
+        while trace_id == [0; 16] {
+            rng.fill_bytes(&mut trace_id);
+        }
+        Self {
+            trace_id,
+            parent_id: new_parent_id(),
+            flags: SAMPLED,
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        let mut parts = value.trim().split('-');
+        let version = decode::<1>(parts.next()?)?;
+        let trace_id = decode::<16>(parts.next()?)?;
+        let parent_id = decode::<8>(parts.next()?)?;
+        let flags = decode::<1>(parts.next()?)?;
+        let valid_version = match version[0] {
+            0x00 => parts.next().is_none(),
+            0xff => false,
+            _ => true,
+        };
+        if !valid_version || trace_id == [0; 16] || parent_id == [0; 8] {
+            return None;
+        }
+        Some(Self {
+            trace_id,
+            parent_id,
+            flags: flags[0],
+        })
+    }
+
+    /// Continues the trace with a new parent id for the next hop.
+    pub fn child(&self) -> Self {
+        Self {
+            parent_id: new_parent_id(),
+            ..*self
+        }
+    }
+
+    pub fn trace_id(&self) -> String {
+        hex::encode(self.trace_id)
+    }
+}
+
+impl fmt::Display for TraceParent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "00-{}-{}-{:02x}",
+            hex::encode(self.trace_id),
+            hex::encode(self.parent_id),
+            self.flags
+        )
+    }
+}
+
+fn new_parent_id() -> [u8; 8] {
+    let mut rng = rand::thread_rng();
+    let mut parent_id = [0; 8];
+    while parent_id == [0; 8] {
+        rng.fill_bytes(&mut parent_id);
+    }
+    parent_id
+}
+
+/// Decodes exactly `N` bytes of lowercase hex.
+fn decode<const N: usize>(s: &str) -> Option<[u8; N]> {
+    if s.len() != N * 2 || s.bytes().any(|b| b.is_ascii_uppercase()) {
+        return None;
+    }
+    let mut buf = [0; N];
+    hex::decode_to_slice(s, &mut buf).ok()?;
+    Some(buf)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_traceparent() {
+        let value = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+        let parent = TraceParent::parse(value).unwrap();
+        assert_eq!(parent.to_string(), value);
+        assert_eq!(parent.trace_id(), "4bf92f3577b34da6a3ce929d0e0e4736");
+
+        let child = parent.child();
+        assert_eq!(child.trace_id(), parent.trace_id());
+        assert_ne!(child.to_string(), value);
+        assert_eq!(TraceParent::parse(&child.to_string()), Some(child));
+
+        let generated = TraceParent::generate();
+        assert_eq!(TraceParent::parse(&generated.to_string()), Some(generated));
+
+        assert!(
+            TraceParent::parse("00-00000000000000000000000000000000-00f067aa0ba902b7-01").is_none()
+        );
+        assert!(
+            TraceParent::parse("00-4bf92f3577b34da6a3ce929d0e0e4736-0000000000000000-01").is_none()
+        );
+        assert!(
+            TraceParent::parse("00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01").is_none()
+        );
+        assert!(
+            TraceParent::parse("ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01").is_none()
+        );
+        assert!(
+            TraceParent::parse("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-00")
+                .is_none()
+        );
+        assert!(
+            TraceParent::parse("01-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-00")
+                .is_some()
+        );
+    }
+}
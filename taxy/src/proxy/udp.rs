@@ -0,0 +1,481 @@
+use super::{
+    connections::{ConnectionHandle, ConnectionRegistry, DEFAULT_RECENT_CONNECTIONS},
+    tcp::stop_after,
+    PortContextEvent,
+};
+use multiaddr::{Multiaddr, Protocol};
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use taxy_api::error::Error;
+use taxy_api::port::{ConnectionOutcome, PortEntry, PortStatus, SocketState};
+use tokio::net::UdpSocket;
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+use tracing::{debug, info, span, warn, Instrument, Level, Span};
+
+pub const DEFAULT_UDP_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+const MAX_DATAGRAM_SIZE: usize = 65535;
+
+/// Maps client addresses to their flows.
+type Flows = Arc<Mutex<HashMap<SocketAddr, Flow>>>;
+
+#[derive(Debug)]
+struct Flow {
+    id: u64,
+    upstream: Arc<UdpSocket>,
+    last_active: Instant,
+}
+
+#[derive(Debug, Clone)]
+struct UdpUpstream {
+    host: String,
+    port: u16,
+    disabled: bool,
+}
+
+/// Aborts the relay task once dropped.
+#[derive(Debug)]
+struct RelayTask(JoinHandle<()>);
+
+impl Drop for RelayTask {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// Relays datagrams between clients and upstreams. Each client address gets a flow,
+/// a socket connected to an upstream picked by round-robin, which is closed once
+/// no datagrams have been relayed in either direction for the idle timeout.
+#[derive(Debug)]
+pub struct UdpPortContext {
+    pub listen: SocketAddr,
+    servers: Arc<[UdpUpstream]>,
+    idle_timeout: Duration,
+    status: PortStatus,
+    span: Span,
+    socket: Option<Arc<UdpSocket>>,
+    relay: Option<RelayTask>,
+    flows: Flows,
+    stop_notifier: Arc<Notify>,
+    draining: Arc<AtomicBool>,
+    connections: ConnectionRegistry,
+}
+
+impl UdpPortContext {
+    pub fn new(entry: &PortEntry) -> Result<Self, Error> {
+        let span = span!(Level::INFO, "proxy", resource_id = entry.id, listen = ?entry.port.listen);
+        let enter = span.clone();
+        let _enter = enter.enter();
+
+        info!("initializing udp proxy");
+
+        let listen = multiaddr_to_udp(&entry.port.listen)?;
+        if entry.port.opts.tls_termination.is_some() {
+            warn!("tls termination is not supported on udp ports, ignoring");
+        }
+        let servers = entry
+            .port
+            .opts
+            .upstream_servers
+            .iter()
+            .map(|server| {
+                let mut upstream = multiaddr_to_udp_upstream(&server.addr)?;
+                upstream.disabled = server.disabled;
+                Ok(upstream)
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+
+        let connections = ConnectionRegistry::default();
+        connections.set_recent_capacity(
+            entry
+                .port
+                .opts
+                .recent_connections
+                .unwrap_or(DEFAULT_RECENT_CONNECTIONS),
+        );
+
+        Ok(Self {
+            listen,
+            servers: servers.into(),
+            idle_timeout: entry
+                .port
+                .opts
+                .idle_timeout
+                .filter(|timeout| !timeout.is_zero())
+                .unwrap_or(DEFAULT_UDP_IDLE_TIMEOUT),
+            status: Default::default(),
+            span,
+            socket: None,
+            relay: None,
+            flows: Default::default(),
+            stop_notifier: Arc::new(Notify::new()),
+            draining: Default::default(),
+            connections,
+        })
+    }
+
+    /// Keeps the socket and the flows of the current context, which keep relaying
+    /// to their upstreams until they become idle.
+    pub fn apply(&mut self, new: Self) {
+        self.connections
+            .set_recent_capacity(new.connections.recent_capacity());
+        *self = Self {
+            socket: self.socket.take(),
+            flows: self.flows.clone(),
+            stop_notifier: self.stop_notifier.clone(),
+            connections: self.connections.clone(),
+            status: self.status,
+            ..new
+        };
+        self.start_relay();
+    }
+
+    /// Sets the socket bound by the listener pool, restarting the relay if it has changed.
+    pub fn set_socket(&mut self, socket: Option<Arc<UdpSocket>>) {
+        let unchanged = match (&self.socket, &socket) {
+            (Some(current), Some(socket)) => Arc::ptr_eq(current, socket),
+            (None, None) => true,
+            _ => false,
+        };
+        if unchanged && (self.relay.is_some() || socket.is_none()) {
+            return;
+        }
+        self.socket = socket;
+        self.start_relay();
+    }
+
+    fn start_relay(&mut self) {
+        self.relay = self.socket.clone().map(|socket| {
+            let relay = Relay {
+                socket,
+                servers: self.servers.clone(),
+                idle_timeout: self.idle_timeout,
+                flows: self.flows.clone(),
+                stop_notifier: self.stop_notifier.clone(),
+                draining: self.draining.clone(),
+                connections: self.connections.clone(),
+                next: 0,
+            };
+            RelayTask(tokio::spawn(relay.run().instrument(self.span.clone())))
+        });
+    }
+
+    pub fn event(&mut self, event: PortContextEvent) {
+        match event {
+            PortContextEvent::SocketStateUpadted(state) => {
+                if self.status.state.socket != state {
+                    self.status.started_at = if state == SocketState::Listening {
+                        Some(SystemTime::now())
+                    } else {
+                        None
+                    };
+                }
+                self.status.state.socket = state;
+            }
+        }
+    }
+
+    pub fn status(&self) -> &PortStatus {
+        &self.status
+    }
+
+    pub fn connections(&self) -> &ConnectionRegistry {
+        &self.connections
+    }
+
+    pub fn reset(&mut self) {
+        self.stop_notifier.notify_waiters();
+    }
+
+    /// Rejects new flows, and closes the existing ones once `timeout` has elapsed.
+    /// Applying a new config accepts flows again.
+    pub fn drain(&mut self, timeout: Duration) {
+        self.draining.store(true, Ordering::Relaxed);
+        stop_after(&mut self.stop_notifier, timeout);
+    }
+}
+
+struct Relay {
+    socket: Arc<UdpSocket>,
+    servers: Arc<[UdpUpstream]>,
+    idle_timeout: Duration,
+    flows: Flows,
+    stop_notifier: Arc<Notify>,
+    draining: Arc<AtomicBool>,
+    connections: ConnectionRegistry,
+    next: usize,
+}
+
+impl Relay {
+    async fn run(mut self) {
+        let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+        loop {
+            let (len, client) = match self.socket.recv_from(&mut buf).await {
+                Ok(received) => received,
+                Err(err) => {
+                    debug!(%err, "failed to receive datagram");
+                    continue;
+                }
+            };
+            let Some(upstream) = self.flow(client).await else {
+                continue;
+            };
+            if let Err(err) = upstream.send(&buf[..len]).await {
+                debug!(remote = %client, %err, "failed to relay datagram");
+            }
+        }
+    }
+
+    /// Returns the upstream socket of the client, starting a new flow if it has none.
+    async fn flow(&mut self, client: SocketAddr) -> Option<Arc<UdpSocket>> {
+        let existing = {
+            let mut flows = self.flows.lock().unwrap();
+            flows.get_mut(&client).map(|flow| {
+                flow.last_active = Instant::now();
+                flow.upstream.clone()
+            })
+        };
+        if existing.is_some() {
+            return existing;
+        }
+        if self.draining.load(Ordering::Relaxed) {
+            warn!(remote = %client, "flow rejected: port is draining");
+            return None;
+        }
+        match self.connect(client).await {
+            Ok(upstream) => Some(upstream),
+            Err(err) => {
+                warn!(remote = %client, "failed to start flow: {err}");
+                None
+            }
+        }
+    }
+
+    async fn connect(&mut self, client: SocketAddr) -> anyhow::Result<Arc<UdpSocket>> {
+        let enabled = self
+            .servers
+            .iter()
+            .filter(|server| !server.disabled)
+            .collect::<Vec<_>>();
+        if enabled.is_empty() {
+            anyhow::bail!("no upstream servers available");
+        }
+        let server = enabled[self.next % enabled.len()];
+        self.next = self.next.wrapping_add(1);
+
+        let resolved = tokio::net::lookup_host((server.host.as_str(), server.port))
+            .await?
+            .next()
+            .ok_or_else(|| anyhow::anyhow!("failed to resolve {}", server.host))?;
+        let bind: SocketAddr = if resolved.is_ipv4() {
+            (Ipv4Addr::UNSPECIFIED, 0).into()
+        } else {
+            (Ipv6Addr::UNSPECIFIED, 0).into()
+        };
+        let upstream = UdpSocket::bind(bind).await?;
+        upstream.connect(resolved).await?;
+        let upstream = Arc::new(upstream);
+
+        let local = self.socket.local_addr()?;
+        let handle = self.connections.register(client, local);
+        info!(target: "taxy::access_log", remote = %client, %local, %resolved);
+
+        self.flows.lock().unwrap().insert(
+            client,
+            Flow {
+                id: handle.id(),
+                upstream: upstream.clone(),
+                last_active: Instant::now(),
+            },
+        );
+        tokio::spawn(
+            relay_upstream(
+                self.socket.clone(),
+                upstream.clone(),
+                client,
+                self.idle_timeout,
+                self.flows.clone(),
+                self.stop_notifier.clone(),
+                handle,
+            )
+            .in_current_span(),
+        );
+        Ok(upstream)
+    }
+}
+
+/// Relays the datagrams of the upstream back to the client until the flow is idle or stopped.
+async fn relay_upstream(
+    socket: Arc<UdpSocket>,
+    upstream: Arc<UdpSocket>,
+    client: SocketAddr,
+    idle_timeout: Duration,
+    flows: Flows,
+    stop_notifier: Arc<Notify>,
+    mut handle: ConnectionHandle,
+) {
+    let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+    let outcome = loop {
+        tokio::select! {
+            result = tokio::time::timeout(idle_timeout, upstream.recv(&mut buf)) => match result {
+                Ok(Ok(len)) => {
+                    if let Some(flow) = flows.lock().unwrap().get_mut(&client) {
+                        flow.last_active = Instant::now();
+                    }
+                    if let Err(err) = socket.send_to(&buf[..len], client).await {
+                        debug!(remote = %client, %err, "failed to relay datagram");
+                    }
+                }
+                Ok(Err(err)) => {
+                    debug!(remote = %client, %err, "upstream flow failed");
+                    break ConnectionOutcome::Error;
+                }
+                Err(_) => {
+                    // The client may have been active in the meantime.
+                    let idle = flows
+                        .lock()
+                        .unwrap()
+                        .get(&client)
+                        .is_none_or(|flow| flow.last_active.elapsed() >= idle_timeout);
+                    if idle {
+                        break ConnectionOutcome::IdleTimeout;
+                    }
+                }
+            },
+            _ = stop_notifier.notified() => break ConnectionOutcome::Stopped,
+        }
+    };
+
+    let mut flows = flows.lock().unwrap();
+    if flows
+        .get(&client)
+        .is_some_and(|flow| flow.id == handle.id())
+    {
+        flows.remove(&client);
+    }
+    handle.set_outcome(outcome);
+}
+
+pub(super) fn multiaddr_to_udp(addr: &Multiaddr) -> Result<SocketAddr, Error> {
+    let stack = addr.iter().collect::<Vec<_>>();
+    match stack[..] {
+        [Protocol::Ip4(ip), Protocol::Udp(port)] if port > 0 => Ok((ip, port).into()),
+        [Protocol::Ip6(ip), Protocol::Udp(port)] if port > 0 => Ok((ip, port).into()),
+        _ => Err(Error::InvalidListeningAddress { addr: addr.clone() }),
+    }
+}
+
+fn multiaddr_to_udp_upstream(addr: &Multiaddr) -> Result<UdpUpstream, Error> {
+    let stack = addr.iter().collect::<Vec<_>>();
+    let (host, port) = match stack[..] {
+        [Protocol::Ip4(ip), Protocol::Udp(port)] if port > 0 => (ip.to_string(), port),
+        [Protocol::Ip6(ip), Protocol::Udp(port)] if port > 0 => (ip.to_string(), port),
+        [Protocol::Dns(ref name), Protocol::Udp(port)] if port > 0 => (name.to_string(), port),
+        _ => return Err(Error::InvalidServerAddress { addr: addr.clone() }),
+    };
+    Ok(UdpUpstream {
+        host,
+        port,
+        disabled: false,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use taxy_api::port::{Port, PortOptions, UpstreamServer};
+
+    async fn tagged_echo(tag: &'static [u8]) -> u16 {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let port = socket.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let mut buf = [0; 1024];
+            while let Ok((len, peer)) = socket.recv_from(&mut buf).await {
+                let reply = [tag, &buf[..len]].concat();
+                let _ = socket.send_to(&reply, peer).await;
+            }
+        });
+        port
+    }
+
+    async fn request(client: &UdpSocket, data: &[u8]) -> Vec<u8> {
+        client.send(data).await.unwrap();
+        let mut buf = [0; 1024];
+        let len = tokio::time::timeout(Duration::from_secs(5), client.recv(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        buf[..len].to_vec()
+    }
+
+    #[tokio::test]
+    async fn test_udp_flows() {
+        let upstreams = [tagged_echo(b"a:").await, tagged_echo(b"b:").await];
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let listen = socket.local_addr().unwrap();
+        let entry = PortEntry {
+            id: "udp".into(),
+            port: Port {
+                listen: format!("/ip4/127.0.0.1/udp/{}", listen.port())
+                    .parse()
+                    .unwrap(),
+                opts: PortOptions {
+                    upstream_servers: upstreams
+                        .iter()
+                        .map(|port| {
+                            UpstreamServer::new(
+                                format!("/ip4/127.0.0.1/udp/{port}").parse().unwrap(),
+                            )
+                        })
+                        .collect(),
+                    idle_timeout: Some(Duration::from_millis(300)),
+                    ..Default::default()
+                },
+            },
+        };
+        let mut ctx = UdpPortContext::new(&entry).unwrap();
+        assert_eq!(ctx.listen, listen);
+        ctx.set_socket(Some(socket));
+
+        let first = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        first.connect(listen).await.unwrap();
+        let second = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        second.connect(listen).await.unwrap();
+
+        // Each client keeps the upstream picked for its flow.
+        assert_eq!(request(&first, b"ping").await, b"a:ping");
+        assert_eq!(request(&second, b"ping").await, b"b:ping");
+        assert_eq!(request(&first, b"pong").await, b"a:pong");
+        assert_eq!(ctx.connections().active_count(), 2);
+
+        tokio::time::sleep(Duration::from_millis(700)).await;
+        assert_eq!(ctx.connections().active_count(), 0);
+        let recent = ctx.connections().recent();
+        assert_eq!(recent.len(), 2);
+        assert!(recent
+            .iter()
+            .all(|conn| conn.outcome == ConnectionOutcome::IdleTimeout));
+
+        // A new flow is started once the previous one has been closed.
+        assert_eq!(request(&first, b"ping").await, b"a:ping");
+        assert_eq!(ctx.connections().total_count(), 3);
+    }
+
+    #[test]
+    fn test_multiaddr_to_udp() {
+        let addr = "/ip4/127.0.0.1/udp/53".parse().unwrap();
+        assert_eq!(
+            multiaddr_to_udp(&addr).unwrap(),
+            "127.0.0.1:53".parse().unwrap()
+        );
+        assert!(multiaddr_to_udp(&"/ip4/127.0.0.1/tcp/53".parse().unwrap()).is_err());
+
+        let upstream = multiaddr_to_udp_upstream(&"/dns/example.com/udp/53".parse().unwrap());
+        assert_eq!(upstream.unwrap().host, "example.com");
+        assert!(multiaddr_to_udp_upstream(&"/ip4/127.0.0.1/udp/0".parse().unwrap()).is_err());
+    }
+}
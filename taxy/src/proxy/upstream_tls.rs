@@ -0,0 +1,352 @@
+use super::tls_params::TlsParams;
+use crate::keyring::certs::{san_matches, subject_alt_names};
+use rustls_pemfile::Item;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use taxy_api::port::{UpstreamCaBundle, UpstreamTlsVerification};
+use taxy_api::subject_name::SubjectName;
+use tokio_rustls::rustls::client::{ServerCertVerified, ServerCertVerifier, ServerName};
+use tokio_rustls::rustls::{Certificate, CertificateError, ClientConfig, Error, RootCertStore};
+use tracing::warn;
+use x509_parser::parse_x509_certificate;
+
+/// Returns a client config without client auth which verifies upstream certificates as configured.
+pub async fn client_config(
+    verification: UpstreamTlsVerification,
+    params: &TlsParams,
+    ca_bundle: Option<&CaBundle>,
+) -> ClientConfig {
+    let builder = params.client_builder();
+    match verification {
+        UpstreamTlsVerification::Full => builder
+            .with_root_certificates(root_certs(ca_bundle).await)
+            .with_no_client_auth(),
+        UpstreamTlsVerification::NameMatch => {
+            if ca_bundle.is_some() {
+                warn!("upstream CA bundle is ignored by the name_match verification");
+            }
+            builder
+                .with_custom_certificate_verifier(Arc::new(NameMatchVerifier))
+                .with_no_client_auth()
+        }
+    }
+}
+
+/// Returns the native root certificates, unless the bundle excludes them, and the ones of the bundle.
+async fn root_certs(ca_bundle: Option<&CaBundle>) -> RootCertStore {
+    let mut roots = match ca_bundle {
+        Some(bundle) if bundle.config.exclude_native_roots => RootCertStore::empty(),
+        _ => native_root_certs().await,
+    };
+    if let Some(bundle) = ca_bundle {
+        roots.roots.extend(bundle.roots.roots.iter().cloned());
+    }
+    roots
+}
+
+async fn native_root_certs() -> RootCertStore {
+    let mut root_certs = RootCertStore::empty();
+    if let Ok(certs) = tokio::task::spawn_blocking(rustls_native_certs::load_native_certs).await {
+        match certs {
+            Ok(certs) => {
+                for certs in certs {
+                    if let Err(err) = root_certs.add(&Certificate(certs.0)) {
+                        warn!("failed to add native certs: {err}");
+                    }
+                }
+            }
+            Err(err) => {
+                warn!("failed to load native certs: {err}");
+            }
+        }
+    }
+    root_certs
+}
+
+/// The root certificates of an `upstream_ca_bundle`, parsed when the port is loaded.
+#[derive(Debug, Clone)]
+pub struct CaBundle {
+    config: UpstreamCaBundle,
+    roots: RootCertStore,
+}
+
+impl CaBundle {
+    pub fn new(config: &UpstreamCaBundle) -> Result<Self, taxy_api::error::Error> {
+        let invalid = |reason: String| taxy_api::error::Error::InvalidCaBundle { reason };
+        if config.path.is_none() && config.pem.is_none() {
+            return Err(invalid("either path or pem is required".into()));
+        }
+        let mut roots = RootCertStore::empty();
+        if let Some(path) = &config.path {
+            let pem = std::fs::read(path)
+                .map_err(|err| invalid(format!("failed to read {}: {err}", path.display())))?;
+            add_pem_certs(&mut roots, &pem)
+                .map_err(|reason| invalid(format!("{}: {reason}", path.display())))?;
+        }
+        if let Some(pem) = &config.pem {
+            add_pem_certs(&mut roots, pem.as_bytes())
+                .map_err(|reason| invalid(format!("inline pem: {reason}")))?;
+        }
+        Ok(Self {
+            config: config.clone(),
+            roots,
+        })
+    }
+
+    pub fn config(&self) -> &UpstreamCaBundle {
+        &self.config
+    }
+}
+
+/// Adds every certificate of the PEM data, failing on anything else
+/// rather than skipping it.
+fn add_pem_certs(roots: &mut RootCertStore, pem: &[u8]) -> Result<(), String> {
+    let sections = pem
+        .split(|&b| b == b'\n')
+        .filter(|line| line.starts_with(b"-----BEGIN "))
+        .count();
+    if sections == 0 {
+        return Err("no certificates found".into());
+    }
+    let items =
+        rustls_pemfile::read_all(&mut &pem[..]).map_err(|err| format!("malformed PEM: {err}"))?;
+    // rustls_pemfile skips the sections of unknown types.
+    if items.len() != sections {
+        return Err(format!(
+            "{} PEM sections of an unsupported type, only CERTIFICATE sections are supported",
+            sections - items.len()
+        ));
+    }
+    for (i, item) in items.into_iter().enumerate() {
+        match item {
+            Item::X509Certificate(der) => roots
+                .add(&Certificate(der))
+                .map_err(|err| format!("certificate #{} is invalid: {err}", i + 1))?,
+            _ => return Err(format!("PEM section #{} is not a certificate", i + 1)),
+        }
+    }
+    Ok(())
+}
+
+/// Accepts a leaf certificate within its validity period whose SAN covers the server name,
+/// without verifying the chain. The handshake signatures are still verified against the leaf.
+pub struct NameMatchVerifier;
+
+impl ServerCertVerifier for NameMatchVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, Error> {
+        let (_, x509) = parse_x509_certificate(&end_entity.0)
+            .map_err(|_| Error::InvalidCertificate(CertificateError::BadEncoding))?;
+
+        let now = now
+            .duration_since(UNIX_EPOCH)
+            .map_err(|_| Error::FailedToGetCurrentTime)?
+            .as_secs() as i64;
+        let validity = x509.validity();
+        if now < validity.not_before.timestamp() {
+            return Err(Error::InvalidCertificate(CertificateError::NotValidYet));
+        }
+        if now > validity.not_after.timestamp() {
+            return Err(Error::InvalidCertificate(CertificateError::Expired));
+        }
+
+        let name = match server_name {
+            ServerName::DnsName(name) => SubjectName::DnsName(name.as_ref().into()),
+            ServerName::IpAddress(addr) => SubjectName::IPAddress(*addr),
+            _ => return Err(Error::InvalidCertificate(CertificateError::NotValidForName)),
+        };
+        if san_matches(&subject_alt_names(&x509), &name) {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(Error::InvalidCertificate(CertificateError::NotValidForName))
+        }
+    }
+}
+
+/// Accepts any certificate, for upstreams with `insecure_skip_verify`.
+/// The handshake signatures are still verified against the presented certificate.
+pub struct InsecureVerifier;
+
+impl ServerCertVerifier for InsecureVerifier {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, Error> {
+        warn!(
+            ?server_name,
+            "insecure: upstream certificate accepted without verification"
+        );
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::keyring::certs::Cert;
+    use crate::testing::self_signed;
+    use tokio_rustls::rustls::{PrivateKey, ServerConfig};
+    use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+    /// Returns the PEM of the CA which issued the self-signed certificate.
+    fn ca_pem(cert: &Cert) -> String {
+        let chain = String::from_utf8(cert.raw_chain.clone()).unwrap();
+        let start = chain.rfind("-----BEGIN CERTIFICATE-----").unwrap();
+        chain[start..].to_string()
+    }
+
+    async fn handshake(
+        cert: &Cert,
+        verification: UpstreamTlsVerification,
+        ca_bundle: Option<&CaBundle>,
+    ) -> std::io::Result<()> {
+        let chain = rustls_pemfile::certs(&mut cert.raw_chain.as_slice())
+            .unwrap()
+            .into_iter()
+            .map(Certificate)
+            .collect();
+        let key = rustls_pemfile::pkcs8_private_keys(&mut cert.raw_key.as_slice())
+            .unwrap()
+            .remove(0);
+        let server_config = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(chain, PrivateKey(key))
+            .unwrap();
+
+        let (client, server) = tokio::io::duplex(16 * 1024);
+        tokio::spawn(async move {
+            TlsAcceptor::from(Arc::new(server_config))
+                .accept(server)
+                .await
+        });
+
+        let client_config = client_config(verification, &TlsParams::default(), ca_bundle).await;
+        TlsConnector::from(Arc::new(client_config))
+            .connect(
+                ServerName::try_from("upstream.example.com").unwrap(),
+                client,
+            )
+            .await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_name_match_verification() {
+        let name_match = UpstreamTlsVerification::NameMatch;
+        for san in ["upstream.example.com", "*.example.com"] {
+            handshake(&self_signed(san), name_match, None)
+                .await
+                .unwrap();
+        }
+        assert!(
+            handshake(&self_signed("other.example.com"), name_match, None)
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_ca_bundle() {
+        let cert = self_signed("upstream.example.com");
+        let full = UpstreamTlsVerification::Full;
+        assert!(handshake(&cert, full, None).await.is_err());
+
+        let bundle = CaBundle::new(&UpstreamCaBundle {
+            path: None,
+            pem: Some(ca_pem(&cert)),
+            exclude_native_roots: true,
+        })
+        .unwrap();
+        handshake(&cert, full, Some(&bundle)).await.unwrap();
+
+        let path = std::env::temp_dir().join(cuid2::cuid());
+        std::fs::write(&path, ca_pem(&cert)).unwrap();
+        let bundle = CaBundle::new(&UpstreamCaBundle {
+            path: Some(path.clone()),
+            pem: None,
+            exclude_native_roots: false,
+        })
+        .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        handshake(&cert, full, Some(&bundle)).await.unwrap();
+
+        // The bundle is a trust anchor, not an allowlist: the name must still match.
+        let other = self_signed("other.example.com");
+        let bundle = CaBundle::new(&UpstreamCaBundle {
+            path: None,
+            pem: Some(ca_pem(&other)),
+            exclude_native_roots: true,
+        })
+        .unwrap();
+        assert!(handshake(&other, full, Some(&bundle)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_native_roots() {
+        let cert = self_signed("upstream.example.com");
+        let native = native_root_certs().await.len();
+        for exclude_native_roots in [false, true] {
+            let bundle = CaBundle::new(&UpstreamCaBundle {
+                path: None,
+                pem: Some(ca_pem(&cert)),
+                exclude_native_roots,
+            })
+            .unwrap();
+            let expected = if exclude_native_roots { 1 } else { native + 1 };
+            assert_eq!(root_certs(Some(&bundle)).await.len(), expected);
+            handshake(&cert, UpstreamTlsVerification::Full, Some(&bundle))
+                .await
+                .unwrap();
+        }
+        assert_eq!(root_certs(None).await.len(), native);
+    }
+
+    #[test]
+    fn test_invalid_ca_bundle() {
+        let reason =
+            |path: Option<&str>, pem: Option<&str>| match CaBundle::new(&UpstreamCaBundle {
+                path: path.map(Into::into),
+                pem: pem.map(str::to_string),
+                exclude_native_roots: false,
+            }) {
+                Err(taxy_api::error::Error::InvalidCaBundle { reason }) => reason,
+                other => panic!("unexpected result: {other:?}"),
+            };
+        let cert = self_signed("upstream.example.com");
+        let ca = ca_pem(&cert);
+        let key = String::from_utf8(cert.raw_key.clone()).unwrap();
+
+        assert_eq!(reason(None, None), "either path or pem is required");
+        assert_eq!(
+            reason(None, Some("garbage")),
+            "inline pem: no certificates found"
+        );
+        assert_eq!(
+            reason(None, Some(&format!("{ca}{key}"))),
+            "inline pem: PEM section #2 is not a certificate"
+        );
+        assert!(reason(None, Some(&ca[..ca.len() - 20])).starts_with("inline pem: malformed PEM: "));
+        assert!(reason(
+            None,
+            Some(&format!(
+                "{ca}-----BEGIN FOO-----\nAAAA\n-----END FOO-----\n"
+            ))
+        )
+        .starts_with("inline pem: 1 PEM sections of an unsupported type"));
+        assert!(reason(Some("/nonexistent/ca.pem"), None)
+            .starts_with("failed to read /nonexistent/ca.pem: "));
+    }
+}
@@ -0,0 +1,135 @@
+use crate::proxy::conn_limit::{ConnectionLimit, ConnectionLimitPermit};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+#[derive(Debug, Default)]
+pub struct ConnectionGroups {
+    groups: HashMap<String, Arc<ConnectionGroup>>,
+}
+
+impl ConnectionGroups {
+    pub fn new(limits: &HashMap<String, usize>) -> Self {
+        let mut groups = Self::default();
+        groups.update(limits);
+        groups
+    }
+
+    /// Replaces the groups with the given limits.
+    ///
+    /// Existing groups are resized in place so that their active
+    /// connections are still accounted for.
+    pub fn update(&mut self, limits: &HashMap<String, usize>) {
+        let mut groups = HashMap::new();
+        for (name, limit) in limits {
+            let group = match self.groups.remove(name) {
+                Some(group) => {
+                    group.limit.lock().unwrap().resize(*limit);
+                    group
+                }
+                None => Arc::new(ConnectionGroup::new(name, *limit)),
+            };
+            groups.insert(name.clone(), group);
+        }
+        self.groups = groups;
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Arc<ConnectionGroup>> {
+        self.groups.get(name)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Arc<ConnectionGroup>> {
+        self.groups.values()
+    }
+}
+
+#[derive(Debug)]
+pub struct ConnectionGroup {
+    pub name: String,
+    limit: Mutex<ConnectionLimit>,
+    /// Connections rejected because the limit was reached.
+    rejections: AtomicU64,
+}
+
+impl ConnectionGroup {
+    fn new(name: &str, limit: usize) -> Self {
+        Self {
+            name: name.to_string(),
+            limit: Mutex::new(ConnectionLimit::new(limit)),
+            rejections: AtomicU64::new(0),
+        }
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit.lock().unwrap().limit()
+    }
+
+    pub fn rejections(&self) -> u64 {
+        self.rejections.load(Ordering::Relaxed)
+    }
+
+    /// Reserves a connection slot which is released when the permit is dropped.
+    pub fn try_acquire(&self) -> Option<ConnectionLimitPermit> {
+        let permit = self.limit.lock().unwrap().try_acquire();
+        if permit.is_none() {
+            self.rejections.fetch_add(1, Ordering::Relaxed);
+        }
+        permit
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_shared_limit() {
+        let limits = HashMap::from([("backend".to_string(), 2)]);
+        let groups = ConnectionGroups::new(&limits);
+
+        let port_a = groups.get("backend").unwrap().clone();
+        let port_b = groups.get("backend").unwrap().clone();
+
+        let first = port_a.try_acquire().unwrap();
+        let _second = port_b.try_acquire().unwrap();
+        assert!(port_a.try_acquire().is_none());
+        assert!(port_b.try_acquire().is_none());
+
+        drop(first);
+        assert!(port_b.try_acquire().is_some());
+    }
+
+    #[test]
+    fn test_update_keeps_unchanged_groups() {
+        let mut groups =
+            ConnectionGroups::new(&HashMap::from([("a".to_string(), 1), ("b".to_string(), 1)]));
+        let _permit = groups.get("a").unwrap().try_acquire().unwrap();
+
+        groups.update(&HashMap::from([("a".to_string(), 1), ("c".to_string(), 1)]));
+        assert!(groups.get("a").unwrap().try_acquire().is_none());
+        assert!(groups.get("b").is_none());
+        assert!(groups.get("c").is_some());
+    }
+
+    #[test]
+    fn test_update_resizes_limit() {
+        let mut groups = ConnectionGroups::new(&HashMap::from([("a".to_string(), 2)]));
+        let group = groups.get("a").unwrap().clone();
+        let first = group.try_acquire().unwrap();
+        let _second = group.try_acquire().unwrap();
+
+        groups.update(&HashMap::from([("a".to_string(), 3)]));
+        let _third = group.try_acquire().unwrap();
+        assert!(group.try_acquire().is_none());
+
+        groups.update(&HashMap::from([("a".to_string(), 1)]));
+        drop(first);
+        assert!(group.try_acquire().is_none());
+        assert_eq!(group.limit(), 1);
+        assert_eq!(group.rejections(), 2);
+    }
+}
@@ -1,21 +1,32 @@
-use crate::proxy::{PortContext, PortContextEvent, PortContextKind};
+use crate::proxy::{
+    socket::{Address, ListenOptions, Listener, SocketStream},
+    udp::UdpPortContext,
+    PortContext, PortContextEvent, PortContextKind,
+};
 use futures::{Stream, StreamExt};
 use once_cell::sync::Lazy;
 use std::collections::{HashMap, HashSet};
 use std::io;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::pin::Pin;
+use std::sync::Arc;
 use std::task::{Context, Poll};
+use std::time::Duration;
 use taxy_api::port::SocketState;
-use tokio::net::{TcpListener, TcpStream};
-use tracing::{error, info, span, Instrument, Level};
+use tokio::net::UdpSocket;
+use tokio::time::Instant;
+use tracing::{error, info, span, Instrument, Level, Span};
+
+pub const HTTP_CHALLENGE_PORT: u16 = 80;
 
 static RESERVED_ADDR: Lazy<SocketAddr> =
-    Lazy::new(|| SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 80));
+    Lazy::new(|| SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), HTTP_CHALLENGE_PORT));
 
 #[derive(Debug)]
 pub struct TcpListenerPool {
     listeners: Vec<TcpListenerStream>,
+    udp_sockets: HashMap<SocketAddr, Arc<UdpSocket>>,
+    idle_unbound: HashSet<Address>,
     http_challenges: bool,
 }
 
@@ -23,10 +34,17 @@ impl TcpListenerPool {
     pub fn new() -> Self {
         Self {
             listeners: Vec::new(),
+            udp_sockets: HashMap::new(),
+            idle_unbound: HashSet::new(),
             http_challenges: false,
         }
     }
 
+    /// Allows a listener unbound for being idle to be bound again on the next update.
+    pub fn rebind(&mut self, addr: &Address) -> bool {
+        self.idle_unbound.remove(addr)
+    }
+
     pub fn set_http_challenges(&mut self, enabled: bool) {
         self.http_challenges = enabled;
     }
@@ -35,11 +53,19 @@ impl TcpListenerPool {
         !self.listeners.is_empty()
     }
 
+    /// Stops accepting connections on all ports.
+    pub fn close(&mut self) {
+        self.listeners.clear();
+        self.udp_sockets.clear();
+    }
+
     pub async fn update(&mut self, ports: &mut [PortContext]) {
         let mut reserved_ports = Vec::new();
         if self.http_challenges {
             let port_used = ports.iter().any(|ctx| match ctx.kind() {
-                PortContextKind::Tcp(state) => state.listen.port() == RESERVED_ADDR.port(),
+                PortContextKind::Tcp(state) => {
+                    matches!(state.listen, Address::Inet(addr) if addr.port() == RESERVED_ADDR.port())
+                }
                 PortContextKind::Http(state) => state.listen.port() == RESERVED_ADDR.port(),
                 _ => false,
             });
@@ -52,24 +78,30 @@ impl TcpListenerPool {
             .iter()
             .chain(&reserved_ports)
             .filter_map(|ctx| match ctx.kind() {
-                PortContextKind::Tcp(state) => Some(state.listen),
-                PortContextKind::Http(state) => Some(state.listen),
+                PortContextKind::Tcp(state) => Some(state.listen.clone()),
+                PortContextKind::Http(state) => Some(state.listen.into()),
                 _ => None,
             })
             .collect::<HashSet<_>>();
 
-        let mut listeners: HashMap<_, _> = self
+        let mut listeners: HashMap<_, Vec<_>> = HashMap::new();
+        for (addr, listener) in self
             .listeners
             .drain(..)
-            .filter_map(|listener| {
-                listener
-                    .inner
-                    .local_addr()
-                    .ok()
-                    .map(|addr| (addr, listener))
-            })
+            .filter_map(|listener| listener.inner.addr().ok().map(|addr| (addr, listener)))
             .filter(|(addr, _)| used_addrs.contains(addr))
-            .collect();
+        {
+            listeners.entry(addr).or_default().push(listener);
+        }
+
+        let udp_addrs = ports
+            .iter()
+            .filter_map(|ctx| match ctx.kind() {
+                PortContextKind::Udp(state) => Some(state.listen),
+                _ => None,
+            })
+            .collect::<HashSet<_>>();
+        self.udp_sockets.retain(|addr, _| udp_addrs.contains(addr));
 
         for (index, ctx) in ports
             .iter_mut()
@@ -77,72 +109,352 @@ impl TcpListenerPool {
             .enumerate()
         {
             let span = span!(Level::INFO, "port", resource_id = ctx.entry.id);
+            if let PortContextKind::Udp(state) = ctx.kind_mut() {
+                let state = self.bind_udp(state, &span).await;
+                ctx.event(PortContextEvent::SocketStateUpadted(state));
+                continue;
+            }
             let bind = match ctx.kind() {
-                PortContextKind::Tcp(state) => state.listen,
-                PortContextKind::Http(state) => state.listen,
-                _ => *RESERVED_ADDR,
+                PortContextKind::Tcp(state) => state.listen.clone(),
+                PortContextKind::Http(state) => state.listen.into(),
+                _ => (*RESERVED_ADDR).into(),
+            };
+            let idle_timeout = ctx.entry.port.opts.idle_unbind;
+            let listen_opts = ListenOptions {
+                backlog: ctx.entry.port.opts.listen_backlog,
+                reuse_port: false,
+                fast_open: ctx.entry.port.opts.listen_fast_open,
             };
-            let (listener, state) = if let Some(listener) = listeners.remove(&bind) {
-                (Some(listener), SocketState::Listening)
+            let sockets = match &bind {
+                Address::Inet(_) => ctx.entry.port.opts.listen_sockets.unwrap_or(1).max(1),
+                Address::Unix(_) => 1,
+            };
+            if idle_timeout.is_some() && self.idle_unbound.contains(&bind) {
+                ctx.event(PortContextEvent::SocketStateUpadted(
+                    SocketState::IdleUnbound,
+                ));
+                continue;
+            }
+            self.idle_unbound.remove(&bind);
+
+            // The sockets are bound anew if their number changes, which requires
+            // closing the old ones first as they may not have `SO_REUSEPORT` set.
+            let bound = listeners
+                .remove(&bind)
+                .filter(|bound| bound.len() == sockets);
+            let (bound, state) = if let Some(bound) = bound {
+                (bound, SocketState::Listening)
             } else {
                 span.in_scope(|| {
-                    info!(%bind, "listening on tcp port");
+                    info!(%bind, sockets, "listening on tcp port");
                 });
-                match TcpListener::bind(bind).instrument(span.clone()).await {
-                    Ok(sock) => (
-                        Some(TcpListenerStream {
-                            index: 0,
-                            inner: sock,
-                        }),
-                        SocketState::Listening,
-                    ),
+                match bind_listeners(&bind, listen_opts, sockets)
+                    .instrument(span.clone())
+                    .await
+                {
+                    Ok(bound) => (bound, SocketState::Listening),
                     Err(err) => {
                         let _enter = span.enter();
                         error!(%bind, %err, "failed to listen on tcp port");
-                        let error = match err.kind() {
-                            io::ErrorKind::AddrInUse => SocketState::PortAlreadyInUse,
-                            io::ErrorKind::PermissionDenied => SocketState::PermissionDenied,
-                            io::ErrorKind::AddrNotAvailable => SocketState::AddressNotAvailable,
-                            _ => SocketState::Error,
-                        };
-                        (None, error)
+                        (Vec::new(), socket_error_state(&err))
                     }
                 }
             };
-            if let Some(mut sock) = listener {
+            for mut sock in bound {
                 sock.index = index;
+                sock.idle_timeout = idle_timeout;
                 self.listeners.push(sock);
             }
             ctx.event(PortContextEvent::SocketStateUpadted(state));
         }
+        self.idle_unbound.retain(|addr| used_addrs.contains(addr));
     }
 
-    pub async fn select(&mut self) -> Option<(usize, TcpStream)> {
-        let streams = &mut self.listeners;
-        match futures::stream::select_all(streams).next().await {
+    /// Binds the socket of a UDP port, or hands over the one already bound to its address.
+    async fn bind_udp(&mut self, ctx: &mut UdpPortContext, span: &Span) -> SocketState {
+        let bind = ctx.listen;
+        let socket = if let Some(socket) = self.udp_sockets.get(&bind) {
+            socket.clone()
+        } else {
+            span.in_scope(|| {
+                info!(%bind, "listening on udp port");
+            });
+            match UdpSocket::bind(bind).instrument(span.clone()).await {
+                Ok(socket) => {
+                    let socket = Arc::new(socket);
+                    self.udp_sockets.insert(bind, socket.clone());
+                    socket
+                }
+                Err(err) => {
+                    let _enter = span.enter();
+                    error!(%bind, %err, "failed to listen on udp port");
+                    ctx.set_socket(None);
+                    return socket_error_state(&err);
+                }
+            }
+        };
+        ctx.set_socket(Some(socket));
+        SocketState::Listening
+    }
+
+    /// Waits for a connection on any socket, returning `None` when a port reaches its idle timeout.
+    pub async fn select(&mut self) -> Option<(usize, SocketStream)> {
+        let deadline = self.idle_deadlines().into_values().min();
+        let mut streams = futures::stream::select_all(&mut self.listeners);
+        let accept = streams.next();
+        let result = match deadline {
+            Some(deadline) => tokio::time::timeout_at(deadline, accept).await.ok()?,
+            None => accept.await,
+        };
+        match result {
             Some((index, Ok(sock))) => Some((index, sock)),
             _ => None,
         }
     }
+
+    /// Unbinds the listeners which have reached their idle timeout.
+    /// Returns `true` if any listener has been unbound.
+    pub fn unbind_idle(&mut self, ports: &mut [PortContext]) -> bool {
+        let now = Instant::now();
+        let idle_ports = self
+            .idle_deadlines()
+            .into_iter()
+            .filter(|(_, deadline)| *deadline <= now)
+            .map(|(index, _)| index)
+            .collect::<HashSet<_>>();
+        let (idle, active): (Vec<_>, Vec<_>) = self
+            .listeners
+            .drain(..)
+            .partition(|listener| idle_ports.contains(&listener.index));
+        self.listeners = active;
+
+        let mut unbound = HashSet::new();
+        for listener in &idle {
+            let Ok(addr) = listener.inner.addr() else {
+                continue;
+            };
+            if !unbound.insert(listener.index) {
+                continue;
+            }
+            self.idle_unbound.insert(addr.clone());
+            if let Some(ctx) = ports.get_mut(listener.index) {
+                let span = span!(Level::INFO, "port", resource_id = ctx.entry.id);
+                span.in_scope(|| {
+                    info!(%addr, "unbinding idle tcp port");
+                });
+                ctx.event(PortContextEvent::SocketStateUpadted(
+                    SocketState::IdleUnbound,
+                ));
+            }
+        }
+        !idle.is_empty()
+    }
+
+    /// Returns the idle deadline of each port, reached once none of its sockets has
+    /// accepted a connection for the idle timeout.
+    fn idle_deadlines(&self) -> HashMap<usize, Instant> {
+        let mut deadlines = HashMap::new();
+        for listener in &self.listeners {
+            if let Some(deadline) = listener.idle_deadline() {
+                deadlines
+                    .entry(listener.index)
+                    .and_modify(|latest: &mut Instant| *latest = (*latest).max(deadline))
+                    .or_insert(deadline);
+            }
+        }
+        deadlines
+    }
+}
+
+/// Binds the sockets of a port, with `SO_REUSEPORT` if there are more than one.
+async fn bind_listeners(
+    bind: &Address,
+    opts: ListenOptions,
+    sockets: usize,
+) -> io::Result<Vec<TcpListenerStream>> {
+    let opts = ListenOptions {
+        reuse_port: sockets > 1,
+        ..opts
+    };
+    let mut bound = Vec::with_capacity(sockets);
+    for _ in 0..sockets {
+        bound.push(TcpListenerStream {
+            index: 0,
+            inner: Listener::bind(bind, opts).await?,
+            last_accept: Instant::now(),
+            idle_timeout: None,
+        });
+    }
+    Ok(bound)
 }
 
 #[derive(Debug)]
 struct TcpListenerStream {
     index: usize,
-    inner: TcpListener,
+    inner: Listener,
+    last_accept: Instant,
+    idle_timeout: Option<Duration>,
+}
+
+impl TcpListenerStream {
+    fn idle_deadline(&self) -> Option<Instant> {
+        self.idle_timeout.map(|timeout| self.last_accept + timeout)
+    }
 }
 
 impl Stream for TcpListenerStream {
-    type Item = (usize, io::Result<TcpStream>);
+    type Item = (usize, io::Result<SocketStream>);
 
     fn poll_next(
-        self: Pin<&mut Self>,
+        mut self: Pin<&mut Self>,
         cx: &mut Context<'_>,
-    ) -> Poll<Option<(usize, io::Result<TcpStream>)>> {
+    ) -> Poll<Option<(usize, io::Result<SocketStream>)>> {
         match self.inner.poll_accept(cx) {
-            Poll::Ready(Ok((stream, _))) => Poll::Ready(Some((self.index, Ok(stream)))),
+            Poll::Ready(Ok(stream)) => {
+                self.last_accept = Instant::now();
+                Poll::Ready(Some((self.index, Ok(stream))))
+            }
             Poll::Ready(Err(err)) => Poll::Ready(Some((self.index, Err(err)))),
             Poll::Pending => Poll::Pending,
         }
     }
 }
+
+fn socket_error_state(err: &io::Error) -> SocketState {
+    match err.kind() {
+        io::ErrorKind::AddrInUse => SocketState::PortAlreadyInUse,
+        io::ErrorKind::PermissionDenied => SocketState::PermissionDenied,
+        io::ErrorKind::AddrNotAvailable => SocketState::AddressNotAvailable,
+        _ => SocketState::Error,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use taxy_api::port::{Port, PortEntry, PortOptions};
+    use tokio::net::TcpStream;
+
+    #[tokio::test]
+    async fn test_idle_unbind() {
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let entry = PortEntry {
+            id: "test".into(),
+            port: Port {
+                listen: format!("/ip4/127.0.0.1/tcp/{}", addr.port())
+                    .parse()
+                    .unwrap(),
+                opts: PortOptions {
+                    idle_unbind: Some(Duration::from_millis(200)),
+                    ..Default::default()
+                },
+            },
+        };
+        let mut ports = vec![PortContext::new(entry).unwrap()];
+        let mut pool = TcpListenerPool::new();
+        pool.update(&mut ports).await;
+        assert_eq!(ports[0].status().state.socket, SocketState::Listening);
+
+        let started_at = Instant::now();
+        assert!(pool.select().await.is_none());
+        assert!(started_at.elapsed() >= Duration::from_millis(200));
+        assert!(pool.unbind_idle(&mut ports));
+        assert!(!pool.has_active_listeners());
+        assert_eq!(ports[0].status().state.socket, SocketState::IdleUnbound);
+        assert!(TcpStream::connect(addr).await.is_err());
+
+        pool.update(&mut ports).await;
+        assert_eq!(ports[0].status().state.socket, SocketState::IdleUnbound);
+
+        assert!(pool.rebind(&addr.into()));
+        pool.update(&mut ports).await;
+        assert_eq!(ports[0].status().state.socket, SocketState::Listening);
+        assert!(TcpStream::connect(addr).await.is_ok());
+    }
+
+    fn reuse_port_entry(port: u16, sockets: usize) -> PortEntry {
+        PortEntry {
+            id: "test".into(),
+            port: Port {
+                listen: format!("/ip4/127.0.0.1/tcp/{port}").parse().unwrap(),
+                opts: PortOptions {
+                    listen_sockets: Some(sockets),
+                    ..Default::default()
+                },
+            },
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_reuse_port() {
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let mut ports = vec![PortContext::new(reuse_port_entry(addr.port(), 4)).unwrap()];
+        let mut pool = TcpListenerPool::new();
+        pool.update(&mut ports).await;
+        assert_eq!(ports[0].status().state.socket, SocketState::Listening);
+        assert_eq!(pool.listeners.len(), 4);
+
+        let mut clients = Vec::new();
+        for _ in 0..16 {
+            clients.push(TcpStream::connect(addr).await.unwrap());
+        }
+        for _ in 0..16 {
+            assert_eq!(pool.select().await.unwrap().0, 0);
+        }
+
+        // Changing the number of sockets rebinds the port.
+        ports[0].apply(PortContext::new(reuse_port_entry(addr.port(), 2)).unwrap());
+        pool.update(&mut ports).await;
+        assert_eq!(ports[0].status().state.socket, SocketState::Listening);
+        assert_eq!(pool.listeners.len(), 2);
+        assert!(TcpStream::connect(addr).await.is_ok());
+    }
+
+    /// Compares the accept rate of a port with one socket and with `SO_REUSEPORT` sockets.
+    /// Run with `cargo test --release -- --ignored --nocapture bench_accept_rate`.
+    #[cfg(unix)]
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn bench_accept_rate() {
+        const CONNECTIONS: usize = 20_000;
+        const CLIENTS: usize = 16;
+        for sockets in [1, 4] {
+            let addr = std::net::TcpListener::bind("127.0.0.1:0")
+                .unwrap()
+                .local_addr()
+                .unwrap();
+            let mut ports = vec![PortContext::new(reuse_port_entry(addr.port(), sockets)).unwrap()];
+            let mut pool = TcpListenerPool::new();
+            pool.update(&mut ports).await;
+
+            let started_at = Instant::now();
+            let clients = (0..CLIENTS)
+                .map(|_| {
+                    tokio::spawn(async move {
+                        for _ in 0..CONNECTIONS / CLIENTS {
+                            drop(TcpStream::connect(addr).await.unwrap());
+                        }
+                    })
+                })
+                .collect::<Vec<_>>();
+            for _ in 0..CONNECTIONS {
+                pool.select().await.unwrap();
+            }
+            let elapsed = started_at.elapsed();
+            for client in clients {
+                client.await.unwrap();
+            }
+            println!(
+                "{sockets} socket(s): {:.0} accepts/sec",
+                CONNECTIONS as f64 / elapsed.as_secs_f64()
+            );
+        }
+    }
+}
@@ -7,9 +7,11 @@ use tokio::sync::broadcast::error::RecvError;
 use tokio::sync::{broadcast, mpsc};
 use tracing::{info, warn};
 
+mod groups;
 mod listener;
 pub mod rpc;
 mod sites;
+mod source;
 mod state;
 mod table;
 
@@ -21,7 +23,7 @@ pub async fn start_server(
     event: broadcast::Sender<ServerEvent>,
 ) -> anyhow::Result<()> {
     let mut event_recv = event.subscribe();
-    let mut server = ServerState::new(config, command_send, callback, event).await;
+    let mut server = ServerState::new(config, command_send, callback, event).await?;
 
     let mut background_task_interval =
         tokio::time::interval(server.config().background_task_interval);
@@ -64,5 +66,6 @@ pub async fn start_server(
         }
     }
 
+    server.shutdown().await;
     Ok(())
 }
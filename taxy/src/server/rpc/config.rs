@@ -26,3 +26,14 @@ impl RpcMethod for SetConfig {
         state.set_config(self.config).await
     }
 }
+
+pub struct ReloadConfig;
+
+#[async_trait::async_trait]
+impl RpcMethod for ReloadConfig {
+    type Output = ();
+
+    async fn call(self, state: &mut ServerState) -> Result<Self::Output, Error> {
+        state.reload_config().await
+    }
+}
@@ -0,0 +1,15 @@
+use super::RpcMethod;
+use crate::server::state::ServerState;
+use taxy_api::error::Error;
+use taxy_api::metrics::MetricFamily;
+
+pub struct GetMetrics;
+
+#[async_trait::async_trait]
+impl RpcMethod for GetMetrics {
+    type Output = Vec<MetricFamily>;
+
+    async fn call(self, state: &mut ServerState) -> Result<Self::Output, Error> {
+        Ok(state.get_metrics())
+    }
+}
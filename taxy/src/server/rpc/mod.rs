@@ -4,6 +4,7 @@ use taxy_api::error::Error;
 
 pub mod acme;
 pub mod config;
+pub mod metrics;
 pub mod ports;
 pub mod server_certs;
 pub mod sites;
@@ -1,7 +1,12 @@
 use super::RpcMethod;
 use crate::server::state::ServerState;
+use std::time::Duration;
 use taxy_api::error::Error;
-use taxy_api::port::{PortEntry, PortStatus};
+use taxy_api::port::{
+    ClosedConnectionInfo, ConnectionInfo, PortEntry, PortStats, PortStatus, UpstreamDistribution,
+    UpstreamState,
+};
+use taxy_api::tls::TlsMode;
 
 pub struct GetPortList;
 
@@ -27,6 +32,43 @@ impl RpcMethod for GetPortStatus {
     }
 }
 
+pub struct GetPortStats;
+
+#[async_trait::async_trait]
+impl RpcMethod for GetPortStats {
+    type Output = Vec<PortStats>;
+
+    async fn call(self, state: &mut ServerState) -> Result<Self::Output, Error> {
+        Ok(state.get_port_stats())
+    }
+}
+
+pub struct GetPortConnections {
+    pub id: String,
+}
+
+#[async_trait::async_trait]
+impl RpcMethod for GetPortConnections {
+    type Output = Vec<ConnectionInfo>;
+
+    async fn call(self, state: &mut ServerState) -> Result<Self::Output, Error> {
+        state.get_port_connections(&self.id)
+    }
+}
+
+pub struct GetRecentPortConnections {
+    pub id: String,
+}
+
+#[async_trait::async_trait]
+impl RpcMethod for GetRecentPortConnections {
+    type Output = Vec<ClosedConnectionInfo>;
+
+    async fn call(self, state: &mut ServerState) -> Result<Self::Output, Error> {
+        state.get_recent_port_connections(&self.id)
+    }
+}
+
 pub struct DeletePort {
     pub id: String,
 }
@@ -78,3 +120,71 @@ impl RpcMethod for ResetPort {
         state.reset_port(&self.id)
     }
 }
+
+pub struct DrainPort {
+    pub id: String,
+    pub timeout: Duration,
+}
+
+#[async_trait::async_trait]
+impl RpcMethod for DrainPort {
+    type Output = ();
+
+    async fn call(self, state: &mut ServerState) -> Result<Self::Output, Error> {
+        state.drain_port(&self.id, self.timeout)
+    }
+}
+
+pub struct RebindPort {
+    pub id: String,
+}
+
+#[async_trait::async_trait]
+impl RpcMethod for RebindPort {
+    type Output = ();
+
+    async fn call(self, state: &mut ServerState) -> Result<Self::Output, Error> {
+        state.rebind_port(&self.id).await
+    }
+}
+
+pub struct SetUpstreamState {
+    pub id: String,
+    pub state: UpstreamState,
+}
+
+#[async_trait::async_trait]
+impl RpcMethod for SetUpstreamState {
+    type Output = ();
+
+    async fn call(self, state: &mut ServerState) -> Result<Self::Output, Error> {
+        state.set_upstream_state(&self.id, self.state).await
+    }
+}
+
+pub struct SetTlsMode {
+    pub id: String,
+    pub mode: TlsMode,
+}
+
+#[async_trait::async_trait]
+impl RpcMethod for SetTlsMode {
+    type Output = ();
+
+    async fn call(self, state: &mut ServerState) -> Result<Self::Output, Error> {
+        state.set_tls_mode(&self.id, self.mode)
+    }
+}
+
+pub struct GetUpstreamDistribution {
+    pub id: String,
+}
+
+#[async_trait::async_trait]
+impl RpcMethod for GetUpstreamDistribution {
+    type Output = Vec<UpstreamDistribution>;
+
+    async fn call(self, state: &mut ServerState) -> Result<Self::Output, Error> {
+        state.get_upstream_distribution(&self.id)
+    }
+}
@@ -1,15 +1,20 @@
 use super::RpcMethod;
 use crate::{keyring::certs::Cert, server::state::ServerState};
-use taxy_api::{cert::CertInfo, error::Error};
+use taxy_api::{
+    cert::{CertInfo, CertListQuery, CertValidation, SelfSignedCertRequest},
+    error::Error,
+};
 
-pub struct GetServerCertList;
+pub struct GetServerCertList {
+    pub query: CertListQuery,
+}
 
 #[async_trait::async_trait]
 impl RpcMethod for GetServerCertList {
     type Output = Vec<CertInfo>;
 
     async fn call(self, state: &mut ServerState) -> Result<Self::Output, Error> {
-        Ok(state.get_server_cert_list())
+        Ok(state.list_server_certs(&self.query))
     }
 }
 
@@ -26,6 +31,36 @@ impl RpcMethod for AddServerCert {
     }
 }
 
+pub struct SelfSignServerCert {
+    pub request: SelfSignedCertRequest,
+}
+
+#[async_trait::async_trait]
+impl RpcMethod for SelfSignServerCert {
+    type Output = ();
+
+    async fn call(self, state: &mut ServerState) -> Result<Self::Output, Error> {
+        let cert = Cert::new_self_signed_with_policy(&self.request, &state.config().key_policy)?;
+        state.add_server_cert(cert).await
+    }
+}
+
+pub struct ValidateServerCert {
+    pub cert: Cert,
+}
+
+#[async_trait::async_trait]
+impl RpcMethod for ValidateServerCert {
+    type Output = CertValidation;
+
+    async fn call(self, state: &mut ServerState) -> Result<Self::Output, Error> {
+        Ok(CertValidation {
+            info: self.cert.info(),
+            warnings: self.cert.validate(&state.config().key_policy),
+        })
+    }
+}
+
 pub struct DeleteServerCert {
     pub id: String,
 }
@@ -38,3 +73,30 @@ impl RpcMethod for DeleteServerCert {
         state.delete_keyring_item(&self.id).await
     }
 }
+
+pub struct SetServerCertTrust {
+    pub id: String,
+    pub is_trusted: bool,
+}
+
+#[async_trait::async_trait]
+impl RpcMethod for SetServerCertTrust {
+    type Output = ();
+
+    async fn call(self, state: &mut ServerState) -> Result<Self::Output, Error> {
+        state.set_server_cert_trust(&self.id, self.is_trusted).await
+    }
+}
+
+pub struct GetServerCertPorts {
+    pub id: String,
+}
+
+#[async_trait::async_trait]
+impl RpcMethod for GetServerCertPorts {
+    type Output = Vec<String>;
+
+    async fn call(self, state: &mut ServerState) -> Result<Self::Output, Error> {
+        state.get_server_cert_ports(&self.id)
+    }
+}
@@ -1,23 +1,28 @@
 use indexmap::IndexMap;
+use taxy_api::app::Source;
 use taxy_api::error::Error;
 use taxy_api::site::SiteEntry;
 
 #[derive(Debug, Default)]
 pub struct SiteTable {
-    sites: IndexMap<String, SiteEntry>,
+    sites: IndexMap<String, (SiteEntry, Source)>,
 }
 
 impl SiteTable {
-    pub fn new(sites: Vec<SiteEntry>) -> Self {
+    pub fn with_sources(sites: Vec<(SiteEntry, Source)>) -> Self {
         Self {
             sites: sites
                 .into_iter()
-                .map(|site| (site.id.clone(), site))
+                .map(|(site, source)| (site.id.clone(), (site, source)))
                 .collect(),
         }
     }
 
     pub fn entries(&self) -> Vec<SiteEntry> {
+        self.sites.values().map(|(site, _)| site.clone()).collect()
+    }
+
+    pub fn entries_with_sources(&self) -> Vec<(SiteEntry, Source)> {
         self.sites.values().cloned().collect()
     }
 
@@ -25,7 +30,7 @@ impl SiteTable {
         if self.sites.contains_key(&entry.id) {
             Err(Error::IdAlreadyExists { id: entry.id })
         } else {
-            self.sites.insert(entry.id.clone(), entry);
+            self.sites.insert(entry.id.clone(), (entry, Source::Api));
             Ok(())
         }
     }
@@ -34,7 +39,7 @@ impl SiteTable {
         if !self.sites.contains_key(&entry.id) {
             Err(Error::IdNotFound { id: entry.id })
         } else {
-            self.sites.insert(entry.id.clone(), entry);
+            self.sites.insert(entry.id.clone(), (entry, Source::Api));
             Ok(())
         }
     }
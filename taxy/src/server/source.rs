@@ -0,0 +1,96 @@
+use taxy_api::app::Source;
+
+/// Merges the entries loaded from a configuration file into the current ones.
+///
+/// The file takes precedence over API changes made to the same resource, but
+/// resources created through the API and missing from the file are kept.
+pub fn merge_file_entries<T, F>(current: Vec<(T, Source)>, file: Vec<T>, id: F) -> Vec<(T, Source)>
+where
+    F: Fn(&T) -> &str,
+{
+    let mut merged = file
+        .into_iter()
+        .map(|entry| (entry, Source::File))
+        .collect::<Vec<_>>();
+    for (entry, source) in current {
+        if source == Source::Api && !merged.iter().any(|(e, _)| id(e) == id(&entry)) {
+            merged.push((entry, source));
+        }
+    }
+    merged
+}
+
+/// Overlays the entries saved through the API onto the ones loaded from a
+/// configuration file.
+///
+/// API changes take precedence until the next file reload.
+pub fn merge_api_entries<T, F>(file: Vec<T>, api: Vec<T>, id: F) -> Vec<(T, Source)>
+where
+    F: Fn(&T) -> &str,
+{
+    let mut merged = file
+        .into_iter()
+        .map(|entry| (entry, Source::File))
+        .collect::<Vec<_>>();
+    for entry in api {
+        match merged.iter_mut().find(|(e, _)| id(e) == id(&entry)) {
+            Some(current) => *current = (entry, Source::Api),
+            None => merged.push((entry, Source::Api)),
+        }
+    }
+    merged
+}
+
+/// Splits entries into the file-defined and API-defined ones.
+pub fn split_entries<T>(entries: Vec<(T, Source)>) -> (Vec<T>, Vec<T>) {
+    let (file, api): (Vec<_>, Vec<_>) = entries
+        .into_iter()
+        .partition(|(_, source)| *source == Source::File);
+    (
+        file.into_iter().map(|(entry, _)| entry).collect(),
+        api.into_iter().map(|(entry, _)| entry).collect(),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn id<'a>(entry: &'a (&'static str, u32)) -> &'a str {
+        entry.0
+    }
+
+    #[test]
+    fn test_file_overrides_api() {
+        let current = vec![(("a", 1), Source::File), (("b", 2), Source::Api)];
+        let file = vec![("a", 10), ("b", 20)];
+        assert_eq!(
+            merge_file_entries(current, file, id),
+            vec![(("a", 10), Source::File), (("b", 20), Source::File)]
+        );
+    }
+
+    #[test]
+    fn test_api_entries_preserved() {
+        let current = vec![(("a", 1), Source::File), (("b", 2), Source::Api)];
+        let file = vec![("c", 3)];
+        assert_eq!(
+            merge_file_entries(current, file, id),
+            vec![(("c", 3), Source::File), (("b", 2), Source::Api)]
+        );
+    }
+
+    #[test]
+    fn test_api_overrides_file() {
+        let file = vec![("a", 1), ("b", 2)];
+        let api = vec![("b", 20), ("c", 3)];
+        assert_eq!(
+            merge_api_entries(file, api, id),
+            vec![
+                (("a", 1), Source::File),
+                (("b", 20), Source::Api),
+                (("c", 3), Source::Api)
+            ]
+        );
+    }
+}
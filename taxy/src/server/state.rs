@@ -1,38 +1,71 @@
+use super::groups::ConnectionGroups;
 use super::sites::SiteTable;
-use super::{listener::TcpListenerPool, rpc::RpcCallback, table::ProxyTable};
+use super::source::{merge_api_entries, merge_file_entries, split_entries};
+use super::{
+    listener::{TcpListenerPool, HTTP_CHALLENGE_PORT},
+    rpc::RpcCallback,
+    table::ProxyTable,
+};
 use crate::keyring::certs::Cert;
 use crate::{
     command::ServerCommand,
     config::storage::ConfigStorage,
-    keyring::{acme::AcmeEntry, Keyring, KeyringItem},
-    proxy::{PortContext, PortContextKind},
+    keyring::{
+        acme::{
+            check_identifier_limit, check_overlapping_identifiers, AcmeEntry, SelfCheckOptions,
+        },
+        hooks::RenewalHookRunner,
+        ocsp, Keyring, KeyringItem,
+    },
+    log::set_redacted_hosts,
+    proxy::{
+        connections::ConnectionRegistry,
+        proxy_protocol,
+        socket::{Socket, SocketStream},
+        PortContext, PortContextKind,
+    },
+    webhook::WebhookDispatcher,
 };
 use hyper::server::conn::Http;
 use hyper::{service::service_fn, Body};
+use instant_acme::Identifier;
 use std::convert::Infallible;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr};
 use std::{
-    collections::HashMap,
-    sync::Arc,
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::{atomic::Ordering, Arc},
     time::{Duration, SystemTime},
 };
 use taxy_api::acme::AcmeInfo;
 use taxy_api::app::{AppConfig, Source};
-use taxy_api::cert::{CertInfo, KeyringInfo};
+use taxy_api::cert::{CertInfo, CertListQuery, KeyringInfo};
 use taxy_api::error::Error;
 use taxy_api::event::ServerEvent;
+use taxy_api::metrics::{MetricFamily, MetricKind, MetricSample};
 use taxy_api::port::PortEntry;
-use taxy_api::port::PortStatus;
+use taxy_api::port::{
+    ClosedConnectionInfo, ConnectionInfo, PortStats, PortStatus, SocketState, UpstreamDistribution,
+    UpstreamInfo, UpstreamState,
+};
 use taxy_api::site::SiteEntry;
-use tokio::{io::AsyncBufReadExt, task::JoinHandle};
+use taxy_api::tls::{TlsMode, TlsState};
+use taxy_api::webhook::WebhookEvent;
 use tokio::{
     io::BufStream,
-    net::TcpStream,
     sync::{broadcast, mpsc},
 };
-use tracing::{error, info, span, Instrument, Level};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt},
+    task::JoinHandle,
+};
+use tracing::{error, info, span, warn, Instrument, Level};
 use warp::http::Response;
 use x509_parser::time::ASN1Time;
 
+const CERT_EXPIRY_WARNING: Duration = Duration::from_secs(60 * 60 * 24 * 7);
+const SHUTDOWN_CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
+
 pub struct ServerState {
     config: AppConfig,
     storage: ConfigStorage,
@@ -40,10 +73,18 @@ pub struct ServerState {
     sites: SiteTable,
     pool: TcpListenerPool,
     certs: Keyring,
+    failed_certs: usize,
     http_challenges: HashMap<String, String>,
+    hook_errors: HashMap<String, String>,
+    groups: ConnectionGroups,
+    webhook: WebhookDispatcher,
     command_sender: mpsc::Sender<ServerCommand>,
     br_sender: broadcast::Sender<ServerEvent>,
     callback_sender: mpsc::Sender<RpcCallback>,
+    /// Tasks resetting the counters of the ports with `stats_reset_interval`.
+    stats_resets: HashMap<String, (Duration, JoinHandle<()>)>,
+    /// Certs already reported as near expiry.
+    expiring_certs: HashSet<String>,
 }
 
 impl ServerState {
@@ -52,34 +93,60 @@ impl ServerState {
         command_sender: mpsc::Sender<ServerCommand>,
         callback_sender: mpsc::Sender<RpcCallback>,
         br_sender: broadcast::Sender<ServerEvent>,
-    ) -> Self {
+    ) -> anyhow::Result<Self> {
         let config = storage.load_app_config().await;
+        set_redacted_hosts(&config.redacted_hosts);
         let _ = br_sender.send(ServerEvent::AppConfigUpdated {
-            config: config.clone(),
+            config: Box::new(config.clone()),
             source: Source::File,
         });
 
-        let certs = storage.load_keychain().await;
+        let (certs, failed_certs) = storage.load_keychain().await;
+        if !failed_certs.is_empty() {
+            error!(
+                count = failed_certs.len(),
+                "some keyring certs failed to load: {failed_certs:?}"
+            );
+            if config.keyring_fail_fast {
+                anyhow::bail!("{} keyring cert(s) failed to load", failed_certs.len());
+            }
+        }
+
         let table = ProxyTable::new();
-        let ports = storage.load_entries().await;
-        let sites = storage.load_sites().await;
+        let ports = merge_api_entries(
+            storage.load_entries().await,
+            storage.load_api_entries().await,
+            |entry| &entry.id,
+        );
+        let sites = merge_api_entries(
+            storage.load_sites().await,
+            storage.load_api_sites().await,
+            |entry| &entry.id,
+        );
 
         let mut this = Self {
+            groups: ConnectionGroups::new(&config.connection_limit_groups),
             config,
             storage,
             table,
-            sites: SiteTable::new(sites),
+            sites: SiteTable::with_sources(sites),
             pool: TcpListenerPool::new(),
             certs,
+            failed_certs: failed_certs.len(),
             http_challenges: HashMap::new(),
+            hook_errors: HashMap::new(),
+            webhook: WebhookDispatcher::new(),
             command_sender,
             br_sender,
             callback_sender,
+            stats_resets: HashMap::new(),
+            expiring_certs: HashSet::new(),
         };
 
-        for entry in ports {
+        for (entry, source) in ports {
             match PortContext::new(entry) {
-                Ok(ctx) => {
+                Ok(mut ctx) => {
+                    ctx.source = source;
                     this.update_port_ctx(ctx).await;
                 }
                 Err(err) => {
@@ -100,12 +167,18 @@ impl ServerState {
 
         this.update_port_statuses().await;
         this.start_http_challenges().await;
-        this
+        Ok(this)
     }
 
     pub async fn handle_command(&mut self, cmd: ServerCommand) {
         match cmd {
             ServerCommand::AddKeyringItem { item } => {
+                let item = match item {
+                    KeyringItem::ServerCert(cert) => {
+                        KeyringItem::ServerCert(self.apply_trust_rules(cert))
+                    }
+                    item => item,
+                };
                 match &item {
                     KeyringItem::Acme(entry) => {
                         self.storage.save_acme(entry).await;
@@ -122,12 +195,76 @@ impl ServerState {
                     items: self.get_server_cert_list(),
                 });
                 self.start_http_challenges().await;
+                self.start_ocsp_refresh();
+            }
+            ServerCommand::SetOcspResponse { cert_id, response } => {
+                if let Some(cert) = self.certs.find_server_cert(&cert_id) {
+                    let cert = Cert {
+                        ocsp: Some(response),
+                        ..Cert::clone(cert)
+                    };
+                    self.certs.add(KeyringItem::ServerCert(Arc::new(cert)));
+                    self.refresh_ports().await;
+                }
             }
             ServerCommand::StopHttpChallenges => {
                 self.pool.set_http_challenges(false);
                 self.http_challenges.clear();
                 self.pool.update(self.table.contexts_mut()).await;
             }
+            ServerCommand::SetRenewalHookError { acme_id, error } => {
+                if let Some(error) = error {
+                    self.hook_errors.insert(acme_id, error);
+                } else {
+                    self.hook_errors.remove(&acme_id);
+                }
+                let _ = self.br_sender.send(ServerEvent::AcmeUpdated {
+                    items: self.get_acme_list(),
+                });
+            }
+            ServerCommand::ResetPortStats { id } => {
+                let registry = self
+                    .table
+                    .contexts()
+                    .iter()
+                    .find(|ctx| ctx.entry.id == id)
+                    .and_then(|ctx| ctx.connection_registry());
+                if let Some(registry) = registry {
+                    let stats = registry.reset_stats();
+                    info!(id, ?stats, "port stats reset");
+                    let _ = self
+                        .br_sender
+                        .send(ServerEvent::PortStatsReset { id, stats });
+                }
+            }
+            ServerCommand::SetUpstreamHealth {
+                port_id,
+                addr,
+                healthy,
+            } => {
+                self.notify(if healthy {
+                    WebhookEvent::UpstreamUp { port_id, addr }
+                } else {
+                    WebhookEvent::UpstreamDown { port_id, addr }
+                });
+            }
+            ServerCommand::ProxyConnection {
+                id,
+                mut stream,
+                client_addr,
+            } => {
+                let index = self
+                    .table
+                    .contexts()
+                    .iter()
+                    .position(|ctx| ctx.entry.id == id);
+                match index {
+                    Some(index) => self.proxy_connection(index, stream, client_addr).await,
+                    None => {
+                        tokio::spawn(async move { stream.get_mut().shutdown().await });
+                    }
+                }
+            }
             ServerCommand::CallMethod { id, mut arg } => {
                 let result = arg.call(self).await;
                 let _ = self.callback_sender.send(RpcCallback { id, result }).await;
@@ -145,16 +282,26 @@ impl ServerState {
                     self.storage.save_app_config(&app_config).await;
                 }
             }
-            ServerEvent::PortTableUpdated { entries } => {
-                self.storage.save_entries(&entries).await;
+            ServerEvent::PortTableUpdated { .. } => {
+                let (file, api) = split_entries(
+                    self.table
+                        .contexts()
+                        .iter()
+                        .map(|ctx| (ctx.entry.clone(), ctx.source))
+                        .collect(),
+                );
+                self.storage.save_entries(&file, &api).await;
             }
             ServerEvent::ServerCertsUpdated { .. } => {
                 for ctx in self.table.contexts_mut() {
-                    let _ = ctx.refresh(&self.certs).await;
+                    let _ = ctx
+                        .refresh(&self.certs, self.config.strict_cert_refresh)
+                        .await;
                 }
             }
             ServerEvent::SitesUpdated { items } => {
-                self.storage.save_sites(&items).await;
+                let (file, api) = split_entries(self.sites.entries_with_sources());
+                self.storage.save_sites(&file, &api).await;
                 for ctx in self.table.contexts_mut() {
                     let sites = items
                         .iter()
@@ -177,13 +324,110 @@ impl ServerState {
         self.pool.has_active_listeners()
     }
 
-    pub async fn select(&mut self) -> Option<(usize, TcpStream)> {
-        self.pool.select().await
+    /// Stops accepting connections and waits for the existing ones to close. Connections are
+    /// closed once idle for the grace period, and the remaining ones are force-closed at the deadline.
+    pub async fn shutdown(&mut self) {
+        let grace = self.config.shutdown_grace_period;
+        let deadline = self.config.shutdown_deadline;
+        self.pool.close();
+        for (_, task) in self.stats_resets.values() {
+            task.abort();
+        }
+
+        let registries = self
+            .table
+            .contexts()
+            .iter()
+            .filter_map(|ctx| ctx.connection_registry().cloned())
+            .collect::<Vec<_>>();
+        for registry in &registries {
+            registry.shutdown(grace);
+        }
+        info!(
+            count = active_connections(&registries),
+            ?grace,
+            ?deadline,
+            "shutting down"
+        );
+
+        if tokio::time::timeout(deadline, wait_closed(&registries))
+            .await
+            .is_err()
+        {
+            warn!(
+                count = active_connections(&registries),
+                "force-closing connections at the shutdown deadline"
+            );
+            for ctx in self.table.contexts_mut() {
+                ctx.reset();
+            }
+            let _ = tokio::time::timeout(SHUTDOWN_CLOSE_TIMEOUT, wait_closed(&registries)).await;
+        }
+    }
+
+    pub async fn select(&mut self) -> Option<(usize, SocketStream)> {
+        let sock = self.pool.select().await;
+        if sock.is_none() && self.pool.unbind_idle(self.table.contexts_mut()) {
+            for (entry, ctx) in self.table.entries().iter().zip(self.table.contexts()) {
+                let _ = self.br_sender.send(ServerEvent::PortStatusUpdated {
+                    id: entry.id.clone(),
+                    status: ctx.status(),
+                });
+            }
+        }
+        sock
     }
 
-    pub async fn handle_connection(&mut self, index: usize, stream: TcpStream) {
+    pub async fn handle_connection(&mut self, index: usize, stream: SocketStream) {
         let mut stream = BufStream::new(stream);
 
+        if let Some(ctx) = self.table.contexts().get(index) {
+            if ctx.entry.port.opts.accept_proxy_protocol {
+                let id = ctx.entry.id.clone();
+                let command = self.command_sender.clone();
+                tokio::spawn(async move {
+                    let header = tokio::time::timeout(
+                        proxy_protocol::HEADER_TIMEOUT,
+                        proxy_protocol::read_header(&mut stream),
+                    )
+                    .await
+                    .unwrap_or_else(|_| {
+                        Err(io::Error::new(
+                            io::ErrorKind::TimedOut,
+                            "no proxy protocol header received",
+                        ))
+                    });
+                    match header {
+                        Ok(client_addr) => {
+                            let _ = command
+                                .send(ServerCommand::ProxyConnection {
+                                    id,
+                                    stream,
+                                    client_addr,
+                                })
+                                .await;
+                        }
+                        Err(err) => {
+                            let span = span!(Level::INFO, "port", resource_id = id);
+                            let peer = stream.get_ref().peer_addr().ok();
+                            span.in_scope(|| warn!(?peer, "connection rejected: {err}"));
+                            let _ = stream.get_mut().shutdown().await;
+                        }
+                    }
+                });
+                return;
+            }
+        }
+
+        self.proxy_connection(index, stream, None).await;
+    }
+
+    async fn proxy_connection(
+        &mut self,
+        index: usize,
+        mut stream: BufStream<SocketStream>,
+        client_addr: Option<SocketAddr>,
+    ) {
         if !self.http_challenges.is_empty() {
             if let Some(body) = self.handle_http_challenge(&mut stream).await {
                 tokio::task::spawn(async move {
@@ -205,36 +449,126 @@ impl ServerState {
         }
 
         if index < self.table.contexts().len() {
+            let entry = &self.table.contexts()[index].entry;
+            let group = entry
+                .port
+                .opts
+                .connection_limit_group
+                .as_ref()
+                .and_then(|group| self.groups.get(group));
+            let permit = match group {
+                Some(group) => match group.try_acquire() {
+                    Some(permit) => Some(permit),
+                    None => {
+                        let span = span!(Level::INFO, "port", resource_id = entry.id);
+                        span.in_scope(|| {
+                            warn!(
+                                group = group.name,
+                                limit = group.limit(),
+                                "connection rejected: group limit reached"
+                            );
+                        });
+                        return;
+                    }
+                },
+                None => None,
+            };
+
             let state = &mut self.table.contexts_mut()[index];
             match state.kind_mut() {
                 PortContextKind::Tcp(tcp) => {
-                    tcp.start_proxy(stream);
+                    tcp.start_proxy(stream, client_addr, permit);
                 }
                 PortContextKind::Http(http) => {
-                    http.start_proxy(stream);
+                    http.start_proxy(stream, client_addr, permit);
                 }
-                PortContextKind::Reserved => (),
+                PortContextKind::Udp(_) | PortContextKind::Reserved => (),
             }
         }
     }
 
     async fn update_port_statuses(&mut self) {
+        let prev_states = self
+            .table
+            .contexts()
+            .iter()
+            .map(|ctx| (ctx.entry.id.clone(), ctx.status().state.socket))
+            .collect::<HashMap<_, _>>();
         self.pool.update(self.table.contexts_mut()).await;
+        for ctx in self.table.contexts() {
+            let state = ctx.status().state.socket;
+            let failed = !matches!(
+                state,
+                SocketState::Listening | SocketState::IdleUnbound | SocketState::Unknown
+            );
+            if failed && prev_states.get(&ctx.entry.id) != Some(&state) {
+                self.notify(WebhookEvent::PortBindFailed {
+                    id: ctx.entry.id.clone(),
+                    addr: ctx.entry.port.listen.to_string(),
+                    state,
+                });
+            }
+        }
+        self.update_stats_resets();
         let _ = self.br_sender.send(ServerEvent::PortTableUpdated {
             entries: self.table.entries().to_vec(),
         });
         for (entry, ctx) in self.table.entries().iter().zip(self.table.contexts()) {
             let _ = self.br_sender.send(ServerEvent::PortStatusUpdated {
                 id: entry.id.clone(),
-                status: *ctx.status(),
+                status: ctx.status(),
             });
         }
     }
 
     async fn update_sites(&mut self) {
-        let _ = self
-            .br_sender
-            .send(ServerEvent::SitesUpdated { items: vec![] });
+        let _ = self.br_sender.send(ServerEvent::SitesUpdated {
+            items: self.get_site_list(),
+        });
+    }
+
+    /// Starts or stops the tasks resetting the port stats to match the port configs.
+    fn update_stats_resets(&mut self) {
+        let intervals = self
+            .table
+            .entries()
+            .into_iter()
+            .filter_map(|entry| {
+                let interval = entry.port.opts.stats_reset_interval?;
+                (!interval.is_zero()).then_some((entry.id, interval))
+            })
+            .collect::<HashMap<_, _>>();
+        self.stats_resets.retain(|id, (interval, task)| {
+            let keep = intervals.get(id) == Some(interval);
+            if !keep {
+                task.abort();
+            }
+            keep
+        });
+        for (id, interval) in intervals {
+            if self.stats_resets.contains_key(&id) {
+                continue;
+            }
+            let command = self.command_sender.clone();
+            let task_id = id.clone();
+            let task = tokio::spawn(async move {
+                let mut boundary = next_stats_reset(SystemTime::now(), interval);
+                loop {
+                    let delay = boundary
+                        .duration_since(SystemTime::now())
+                        .unwrap_or_default();
+                    tokio::time::sleep(delay).await;
+                    let cmd = ServerCommand::ResetPortStats {
+                        id: task_id.clone(),
+                    };
+                    if command.send(cmd).await.is_err() {
+                        break;
+                    }
+                    boundary += interval;
+                }
+            });
+            self.stats_resets.insert(id, (interval, task));
+        }
     }
 
     async fn update_port_ctx(&mut self, mut ctx: PortContext) {
@@ -244,6 +578,7 @@ impl ServerState {
             .into_iter()
             .filter(|entry: &SiteEntry| entry.site.ports.contains(&ctx.entry.id))
             .collect();
+        ctx.set_command_sender(self.command_sender.clone());
         let span = span!(Level::INFO, "port", resource_id = ctx.entry.id);
         if let Err(err) = ctx.setup(&self.certs, sites).instrument(span.clone()).await {
             span.in_scope(|| {
@@ -253,7 +588,10 @@ impl ServerState {
         self.table.set_port(ctx);
     }
 
-    async fn handle_http_challenge(&mut self, stream: &mut BufStream<TcpStream>) -> Option<String> {
+    async fn handle_http_challenge(
+        &mut self,
+        stream: &mut BufStream<SocketStream>,
+    ) -> Option<String> {
         const HTTP_CHALLENGE_HEADER: &[u8] = b"GET /.well-known/acme-challenge/";
         if let Ok(buf) = stream.fill_buf().await {
             if buf.starts_with(HTTP_CHALLENGE_HEADER) {
@@ -271,15 +609,89 @@ impl ServerState {
 
     pub async fn run_background_tasks(&mut self) {
         let _ = self.start_http_challenges().await.await;
+        self.start_ocsp_refresh();
+        self.refresh_ports().await;
+        self.remove_expired_certs();
+        self.notify_expiring_certs();
+    }
+
+    async fn refresh_ports(&mut self) {
         for ctx in self.table.contexts_mut() {
             let span = span!(Level::INFO, "port", resource_id = ctx.entry.id);
-            if let Err(err) = ctx.refresh(&self.certs).instrument(span.clone()).await {
+            if let Err(err) = ctx
+                .refresh(&self.certs, self.config.strict_cert_refresh)
+                .instrument(span.clone())
+                .await
+            {
                 span.in_scope(|| {
                     error!(?err, "failed to refresh port");
                 });
             }
         }
-        self.remove_expired_certs();
+    }
+
+    /// Loads the OCSP responses of the certificates which have none or whose response is
+    /// past half of its validity, and reports them to the server to be stapled.
+    fn start_ocsp_refresh(&self) {
+        let now = SystemTime::now();
+        let certs = self
+            .certs
+            .certs()
+            .into_iter()
+            .filter(|cert| cert.is_valid())
+            .filter(|cert| {
+                cert.ocsp
+                    .as_ref()
+                    .is_none_or(|ocsp| ocsp.needs_refresh(now))
+            })
+            .collect::<Vec<_>>();
+        if certs.is_empty() {
+            return;
+        }
+        let command = self.command_sender.clone();
+        tokio::spawn(async move {
+            for cert in certs {
+                let span = span!(Level::INFO, "ocsp", resource_id = cert.id());
+                match ocsp::load(&cert).instrument(span.clone()).await {
+                    Ok(Some(response)) => {
+                        let _ = command
+                            .send(ServerCommand::SetOcspResponse {
+                                cert_id: cert.id().to_string(),
+                                response: Arc::new(response),
+                            })
+                            .await;
+                    }
+                    Ok(None) => (),
+                    Err(err) => span.in_scope(|| {
+                        warn!("failed to load ocsp response: {err}");
+                    }),
+                }
+            }
+        });
+    }
+
+    fn notify_expiring_certs(&mut self) {
+        let deadline = ASN1Time::from(time::OffsetDateTime::now_utc() + CERT_EXPIRY_WARNING);
+        let expiring = self
+            .certs
+            .certs()
+            .into_iter()
+            .filter(|cert| cert.not_after < deadline)
+            .collect::<Vec<_>>();
+        self.expiring_certs
+            .retain(|id| expiring.iter().any(|cert| cert.id() == id));
+        for cert in expiring {
+            if self.expiring_certs.insert(cert.id().to_string()) {
+                self.notify(WebhookEvent::CertNearExpiry {
+                    cert_id: cert.id().to_string(),
+                    not_after: cert.not_after.timestamp(),
+                });
+            }
+        }
+    }
+
+    fn notify(&self, event: WebhookEvent) {
+        self.webhook.send(self.config.webhook_url.as_ref(), event);
     }
 
     fn remove_expired_certs(&mut self) {
@@ -360,23 +772,89 @@ impl ServerState {
         self.pool.update(self.table.contexts_mut()).await;
 
         let command = self.command_sender.clone();
+        let self_check_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), HTTP_CHALLENGE_PORT);
+        let self_check_opts = SelfCheckOptions::from(&self.config);
+        let webhook = self.webhook.clone();
+        let webhook_url = self.config.webhook_url.clone();
+        let hooks = RenewalHookRunner::new(
+            self.config.renewal_hooks.clone(),
+            self.command_sender.clone(),
+        );
         tokio::task::spawn(async move {
             for mut req in requests {
                 let span = span!(Level::INFO, "acme", resource_id = req.id);
+                let identifiers = req
+                    .identifiers
+                    .iter()
+                    .map(|Identifier::Dns(name)| name.clone())
+                    .collect::<Vec<_>>();
+                if !hooks
+                    .pre_renewal(&req.id, &identifiers)
+                    .instrument(span.clone())
+                    .await
+                {
+                    webhook.send(
+                        webhook_url.as_ref(),
+                        WebhookEvent::CertRenewalFailed {
+                            acme_id: req.id.clone(),
+                            reason: "pre-renewal hook failed".into(),
+                        },
+                    );
+                    continue;
+                }
+                if let Err(err) = req
+                    .self_check(self_check_addr, self_check_opts)
+                    .instrument(span.clone())
+                    .await
+                {
+                    span.in_scope(|| {
+                        error!(%err, "http-01 self-check failed");
+                    });
+                    webhook.send(
+                        webhook_url.as_ref(),
+                        WebhookEvent::CertRenewalFailed {
+                            acme_id: req.id.clone(),
+                            reason: err.to_string(),
+                        },
+                    );
+                    continue;
+                }
                 match req.start_challenge().instrument(span.clone()).await {
                     Ok(cert) => {
                         span.in_scope(|| {
                             info!(id = cert.id(), "acme request completed");
                         });
+                        webhook.send(
+                            webhook_url.as_ref(),
+                            WebhookEvent::CertRenewed {
+                                acme_id: req.id.clone(),
+                                cert_id: cert.id().to_string(),
+                            },
+                        );
+                        let cert = Arc::new(cert);
                         let _ = command
                             .send(ServerCommand::AddKeyringItem {
-                                item: KeyringItem::ServerCert(Arc::new(cert)),
+                                item: KeyringItem::ServerCert(cert.clone()),
                             })
                             .await;
+                        let hooks = hooks.clone();
+                        let acme_id = req.id.clone();
+                        tokio::spawn(
+                            async move { hooks.post_renewal(&acme_id, &cert).await }
+                                .instrument(span.clone()),
+                        );
                     }
                     Err(err) => {
-                        let _enter = span.enter();
-                        error!(?err, "failed to start challenge");
+                        span.in_scope(|| {
+                            error!(?err, "failed to start challenge");
+                        });
+                        webhook.send(
+                            webhook_url.as_ref(),
+                            WebhookEvent::CertRenewalFailed {
+                                acme_id: req.id.clone(),
+                                reason: err.to_string(),
+                            },
+                        );
                     }
                 }
             }
@@ -389,14 +867,80 @@ impl ServerState {
     }
 
     pub async fn set_config(&mut self, config: AppConfig) -> Result<(), Error> {
+        config.validate()?;
+        self.groups.update(&config.connection_limit_groups);
+        set_redacted_hosts(&config.redacted_hosts);
         self.config = config.clone();
         let _ = self.br_sender.send(ServerEvent::AppConfigUpdated {
-            config,
+            config: Box::new(config),
             source: Source::Api,
         });
         Ok(())
     }
 
+    pub async fn reload_config(&mut self) -> Result<(), Error> {
+        let config = self.storage.load_app_config().await;
+        self.groups.update(&config.connection_limit_groups);
+        set_redacted_hosts(&config.redacted_hosts);
+        self.config = config.clone();
+        let _ = self.br_sender.send(ServerEvent::AppConfigUpdated {
+            config: Box::new(config),
+            source: Source::File,
+        });
+
+        let current = self
+            .table
+            .contexts()
+            .iter()
+            .map(|ctx| (ctx.entry.clone(), ctx.source))
+            .collect();
+        let ports = merge_file_entries(current, self.storage.load_entries().await, |entry| {
+            &entry.id
+        });
+        for entry in self.table.entries() {
+            if !ports.iter().any(|(port, _)| port.id == entry.id) {
+                self.table.delete_port(&entry.id);
+            }
+        }
+        for (entry, source) in ports {
+            let unchanged = self
+                .table
+                .contexts_mut()
+                .iter_mut()
+                .find(|ctx| ctx.entry == entry);
+            if let Some(ctx) = unchanged {
+                ctx.source = source;
+                let span = span!(Level::INFO, "port", resource_id = ctx.entry.id);
+                if let Err(err) = ctx
+                    .refresh(&self.certs, self.config.strict_cert_refresh)
+                    .instrument(span.clone())
+                    .await
+                {
+                    span.in_scope(|| {
+                        error!(?err, "failed to refresh port");
+                    });
+                }
+                continue;
+            }
+            match PortContext::new(entry) {
+                Ok(mut ctx) => {
+                    ctx.source = source;
+                    self.update_port_ctx(ctx).await;
+                }
+                Err(err) => {
+                    error!(?err, "failed to create proxy state");
+                }
+            }
+        }
+        self.update_port_statuses().await;
+
+        let current = self.sites.entries_with_sources();
+        let sites = merge_file_entries(current, self.storage.load_sites().await, |entry| &entry.id);
+        self.sites = SiteTable::with_sources(sites);
+        self.update_sites().await;
+        Ok(())
+    }
+
     pub fn get_port_list(&self) -> Vec<PortEntry> {
         self.table.entries()
     }
@@ -406,7 +950,232 @@ impl ServerState {
             .contexts()
             .iter()
             .find(|ctx| ctx.entry.id == id)
-            .map(|ctx| *ctx.status())
+            .map(|ctx| ctx.status())
+            .ok_or_else(|| Error::IdNotFound { id: id.to_string() })
+    }
+
+    /// Returns a snapshot of the status and counters of every port.
+    pub fn get_port_stats(&self) -> Vec<PortStats> {
+        self.table
+            .contexts()
+            .iter()
+            .map(|ctx| PortStats {
+                id: ctx.entry.id.clone(),
+                status: ctx.status(),
+                connections: ctx
+                    .connection_registry()
+                    .map(|registry| registry.stats())
+                    .unwrap_or_default(),
+                upstreams: ctx
+                    .upstreams()
+                    .iter()
+                    .map(|upstream| UpstreamInfo {
+                        addr: format!("{}:{}", upstream.hostname(), upstream.port),
+                        healthy: !upstream.stats.unhealthy.load(Ordering::Relaxed),
+                        active: upstream.stats.active.load(Ordering::Relaxed),
+                        connections: upstream.stats.connections.load(Ordering::Relaxed),
+                        connect_failures: upstream.stats.connect_failures.load(Ordering::Relaxed),
+                        connect_timeouts: upstream.stats.connect_timeouts.load(Ordering::Relaxed),
+                        ejected: upstream.stats.is_ejected(),
+                    })
+                    .collect(),
+            })
+            .collect()
+    }
+
+    pub fn get_metrics(&self) -> Vec<MetricFamily> {
+        let mut up = Vec::new();
+        let mut active = Vec::new();
+        let mut total = Vec::new();
+        let mut probes = Vec::new();
+        let mut tls_degraded = Vec::new();
+        let mut upstream_connections = Vec::new();
+        let mut upstream_failures = Vec::new();
+        let mut upstream_timeouts = Vec::new();
+        for ctx in self.table.contexts() {
+            for upstream in ctx.upstreams() {
+                let labels = BTreeMap::from([
+                    ("port".to_string(), ctx.entry.id.clone()),
+                    (
+                        "upstream".to_string(),
+                        format!("{}:{}", upstream.hostname(), upstream.port),
+                    ),
+                ]);
+                upstream_connections.push(MetricSample {
+                    labels: labels.clone(),
+                    value: upstream.stats.connections.load(Ordering::Relaxed) as f64,
+                });
+                upstream_failures.push(MetricSample {
+                    labels: labels.clone(),
+                    value: upstream.stats.connect_failures.load(Ordering::Relaxed) as f64,
+                });
+                upstream_timeouts.push(MetricSample {
+                    labels,
+                    value: upstream.stats.connect_timeouts.load(Ordering::Relaxed) as f64,
+                });
+            }
+            let Some(registry) = ctx.connection_registry() else {
+                continue;
+            };
+            let labels = BTreeMap::from([("port".to_string(), ctx.entry.id.clone())]);
+            let listening = ctx.status().state.socket == SocketState::Listening;
+            up.push(MetricSample {
+                labels: labels.clone(),
+                value: if listening { 1.0 } else { 0.0 },
+            });
+            if let Some(tls) = ctx.status().state.tls {
+                tls_degraded.push(MetricSample {
+                    labels: labels.clone(),
+                    value: if tls == TlsState::Active { 0.0 } else { 1.0 },
+                });
+            }
+            active.push(MetricSample {
+                labels: labels.clone(),
+                value: registry.active_count() as f64,
+            });
+            total.push(MetricSample {
+                labels: labels.clone(),
+                value: registry.total_count() as f64,
+            });
+            probes.push(MetricSample {
+                labels,
+                value: registry.probe_count() as f64,
+            });
+        }
+        let group_rejections = self
+            .groups
+            .iter()
+            .map(|group| MetricSample {
+                labels: BTreeMap::from([("group".to_string(), group.name.clone())]),
+                value: group.rejections() as f64,
+            })
+            .collect();
+        vec![
+            MetricFamily {
+                name: "taxy_port_active_connections".into(),
+                help: "Number of connections currently being proxied.".into(),
+                kind: MetricKind::Gauge,
+                samples: active,
+            },
+            MetricFamily {
+                name: "taxy_port_connections".into(),
+                help: "Total number of accepted connections.".into(),
+                kind: MetricKind::Counter,
+                samples: total,
+            },
+            MetricFamily {
+                name: "taxy_port_probe_connections".into(),
+                help: "Total number of closed connections classified as probes.".into(),
+                kind: MetricKind::Counter,
+                samples: probes,
+            },
+            MetricFamily {
+                name: "taxy_port_up".into(),
+                help: "Whether the port is listening.".into(),
+                kind: MetricKind::Gauge,
+                samples: up,
+            },
+            MetricFamily {
+                name: "taxy_upstream_connections".into(),
+                help: "Total number of connections established to the upstream.".into(),
+                kind: MetricKind::Counter,
+                samples: upstream_connections,
+            },
+            MetricFamily {
+                name: "taxy_upstream_connect_failures".into(),
+                help: "Total number of failed connection attempts to the upstream, excluding timeouts.".into(),
+                kind: MetricKind::Counter,
+                samples: upstream_failures,
+            },
+            MetricFamily {
+                name: "taxy_upstream_connect_timeouts".into(),
+                help: "Total number of connection attempts to the upstream which timed out.".into(),
+                kind: MetricKind::Counter,
+                samples: upstream_timeouts,
+            },
+            MetricFamily {
+                name: "taxy_port_tls_degraded".into(),
+                help: "Whether the port is serving an expired or stale certificate, or failed to load one.".into(),
+                kind: MetricKind::Gauge,
+                samples: tls_degraded,
+            },
+            MetricFamily {
+                name: "taxy_connection_group_rejections".into(),
+                help: "Total number of connections rejected because the connection limit group was full.".into(),
+                kind: MetricKind::Counter,
+                samples: group_rejections,
+            },
+            MetricFamily {
+                name: "taxy_keyring_failed_certs".into(),
+                help: "Number of keyring certs which failed to load at startup.".into(),
+                kind: MetricKind::Gauge,
+                samples: vec![MetricSample {
+                    labels: BTreeMap::new(),
+                    value: self.failed_certs as f64,
+                }],
+            },
+        ]
+    }
+
+    pub fn get_port_connections(&self, id: &str) -> Result<Vec<ConnectionInfo>, Error> {
+        self.table
+            .contexts()
+            .iter()
+            .find(|ctx| ctx.entry.id == id)
+            .map(|ctx| ctx.connections())
+            .ok_or_else(|| Error::IdNotFound { id: id.to_string() })
+    }
+
+    /// Returns the ids of the ports whose TLS termination may serve the certificate.
+    pub fn get_server_cert_ports(&self, id: &str) -> Result<Vec<String>, Error> {
+        let cert = self
+            .certs
+            .find_server_cert(id)
+            .ok_or_else(|| Error::IdNotFound { id: id.to_string() })?;
+        let acme_identifiers = cert
+            .acme_id()
+            .and_then(|acme_id| {
+                self.certs
+                    .acme_entries()
+                    .into_iter()
+                    .find(|entry| entry.id == acme_id)
+            })
+            .map(|entry| entry.acme.identifiers.clone())
+            .unwrap_or_default();
+        Ok(self
+            .table
+            .contexts()
+            .iter()
+            .filter(|ctx| {
+                ctx.tls_termination()
+                    .is_some_and(|tls| tls.references_cert(cert, &acme_identifiers))
+            })
+            .map(|ctx| ctx.entry.id.clone())
+            .collect())
+    }
+
+    pub fn get_recent_port_connections(
+        &self,
+        id: &str,
+    ) -> Result<Vec<ClosedConnectionInfo>, Error> {
+        self.table
+            .contexts()
+            .iter()
+            .find(|ctx| ctx.entry.id == id)
+            .map(|ctx| {
+                ctx.connection_registry()
+                    .map(|registry| registry.recent())
+                    .unwrap_or_default()
+            })
+            .ok_or_else(|| Error::IdNotFound { id: id.to_string() })
+    }
+
+    pub fn get_upstream_distribution(&self, id: &str) -> Result<Vec<UpstreamDistribution>, Error> {
+        self.table
+            .contexts()
+            .iter()
+            .find(|ctx| ctx.entry.id == id)
+            .map(|ctx| ctx.upstream_distribution())
             .ok_or_else(|| Error::IdNotFound { id: id.to_string() })
     }
 
@@ -414,7 +1183,9 @@ impl ServerState {
         if self.get_port_status(&entry.id).is_ok() {
             Err(Error::IdAlreadyExists { id: entry.id })
         } else {
-            self.update_port_ctx(PortContext::new(entry)?).await;
+            let mut ctx = PortContext::new(entry)?;
+            ctx.source = Source::Api;
+            self.update_port_ctx(ctx).await;
             self.update_port_statuses().await;
             Ok(())
         }
@@ -422,7 +1193,9 @@ impl ServerState {
 
     pub async fn update_port(&mut self, entry: PortEntry) -> Result<(), Error> {
         if self.get_port_status(&entry.id).is_ok() {
-            self.update_port_ctx(PortContext::new(entry)?).await;
+            let mut ctx = PortContext::new(entry)?;
+            ctx.source = Source::Api;
+            self.update_port_ctx(ctx).await;
             self.update_port_statuses().await;
             Ok(())
         } else {
@@ -439,6 +1212,53 @@ impl ServerState {
         }
     }
 
+    pub async fn set_upstream_state(
+        &mut self,
+        id: &str,
+        state: UpstreamState,
+    ) -> Result<(), Error> {
+        let mut entry = self
+            .table
+            .contexts()
+            .iter()
+            .find(|ctx| ctx.entry.id == id)
+            .map(|ctx| ctx.entry.clone())
+            .ok_or_else(|| Error::IdNotFound { id: id.to_string() })?;
+        let server = entry
+            .port
+            .opts
+            .upstream_servers
+            .iter_mut()
+            .find(|server| server.addr == state.addr)
+            .ok_or_else(|| Error::UpstreamNotFound {
+                addr: state.addr.clone(),
+            })?;
+        server.disabled = state.disabled;
+        self.update_port(entry).await?;
+        if state.disabled && state.drain {
+            self.table.drain_upstream(id, &state.addr);
+        }
+        Ok(())
+    }
+
+    pub async fn rebind_port(&mut self, id: &str) -> Result<(), Error> {
+        let ctx = self
+            .table
+            .contexts()
+            .iter()
+            .find(|ctx| ctx.entry.id == id)
+            .ok_or_else(|| Error::IdNotFound { id: id.to_string() })?;
+        let listen = match ctx.kind() {
+            PortContextKind::Tcp(state) => state.listen.clone(),
+            PortContextKind::Http(state) => state.listen.into(),
+            PortContextKind::Udp(_) | PortContextKind::Reserved => return Ok(()),
+        };
+        if self.pool.rebind(&listen) {
+            self.update_port_statuses().await;
+        }
+        Ok(())
+    }
+
     pub fn reset_port(&mut self, id: &str) -> Result<(), Error> {
         if self.table.reset_port(id) {
             Ok(())
@@ -447,12 +1267,27 @@ impl ServerState {
         }
     }
 
+    pub fn drain_port(&mut self, id: &str, timeout: Duration) -> Result<(), Error> {
+        if self.table.drain_port(id, timeout) {
+            Ok(())
+        } else {
+            Err(Error::IdNotFound { id: id.to_string() })
+        }
+    }
+
+    pub fn set_tls_mode(&mut self, id: &str, mode: TlsMode) -> Result<(), Error> {
+        self.table.set_tls_mode(id, mode)
+    }
+
     pub fn get_acme_list(&self) -> Vec<AcmeInfo> {
         self.certs
             .list()
             .into_iter()
             .filter_map(|item| match item {
-                KeyringInfo::Acme(acme) => Some(acme),
+                KeyringInfo::Acme(acme) => Some(AcmeInfo {
+                    hook_error: self.hook_errors.get(&acme.id).cloned(),
+                    ..acme
+                }),
                 _ => None,
             })
             .collect()
@@ -462,6 +1297,13 @@ impl ServerState {
         if self.certs.iter().any(|item| item.id() == entry.id) {
             Err(Error::IdAlreadyExists { id: entry.id })
         } else {
+            check_identifier_limit(&entry.acme, self.config.max_acme_identifiers)?;
+            let entries = self.certs.acme_entries();
+            check_overlapping_identifiers(
+                &entry.acme,
+                entries.iter().map(|acme| (acme.id(), &acme.acme)),
+                self.config.reject_overlapping_acme,
+            )?;
             let _ = self
                 .command_sender
                 .send(ServerCommand::AddKeyringItem {
@@ -507,12 +1349,17 @@ impl ServerState {
             .collect()
     }
 
+    pub fn list_server_certs(&self, query: &CertListQuery) -> Vec<CertInfo> {
+        self.certs.list_server_certs(query)
+    }
+
     pub async fn add_server_cert(&mut self, cert: Cert) -> Result<(), Error> {
         if self.certs.iter().any(|item| item.id() == cert.id()) {
             Err(Error::IdAlreadyExists {
                 id: cert.id().into(),
             })
         } else {
+            cert.check_key_policy(&self.config.key_policy)?;
             let _ = self
                 .command_sender
                 .send(ServerCommand::AddKeyringItem {
@@ -523,6 +1370,59 @@ impl ServerState {
         }
     }
 
+    pub async fn set_server_cert_trust(&mut self, id: &str, is_trusted: bool) -> Result<(), Error> {
+        let cert = self
+            .certs
+            .find_server_cert(id)
+            .ok_or_else(|| Error::IdNotFound { id: id.to_string() })?;
+        if cert.is_trusted() == is_trusted {
+            return Ok(());
+        }
+        let cert = cert.with_trusted(is_trusted)?;
+        self.storage.save_cert(&cert).await;
+        self.certs.add(KeyringItem::ServerCert(Arc::new(cert)));
+        let _ = self.br_sender.send(ServerEvent::ServerCertsUpdated {
+            items: self.get_server_cert_list(),
+        });
+        Ok(())
+    }
+
+    /// Marks the certificate as trusted if it matches any of the trust rules.
+    fn apply_trust_rules(&self, cert: Arc<Cert>) -> Arc<Cert> {
+        if cert.is_trusted() {
+            return cert;
+        }
+        let acme_provider = cert.acme_id().and_then(|acme_id| {
+            self.certs
+                .acme_entries()
+                .into_iter()
+                .find(|entry| entry.id == acme_id)
+                .map(|entry| entry.acme.provider.as_str())
+        });
+        let matched = self
+            .config
+            .cert_trust_rules
+            .iter()
+            .any(|rule| cert.matches_trust_rule(rule, acme_provider));
+        if !matched {
+            return cert;
+        }
+        match cert.with_trusted(true) {
+            Ok(trusted) => {
+                info!(id = cert.id(), "marked certificate as trusted");
+                Arc::new(trusted)
+            }
+            Err(err) => {
+                error!(
+                    id = cert.id(),
+                    ?err,
+                    "failed to mark certificate as trusted"
+                );
+                cert
+            }
+        }
+    }
+
     pub fn get_site_list(&self) -> Vec<SiteEntry> {
         self.sites.entries()
     }
@@ -545,3 +1445,624 @@ impl ServerState {
         Ok(())
     }
 }
+
+fn active_connections(registries: &[ConnectionRegistry]) -> usize {
+    registries
+        .iter()
+        .map(ConnectionRegistry::active_count)
+        .sum()
+}
+
+async fn wait_closed(registries: &[ConnectionRegistry]) {
+    while active_connections(registries) > 0 {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+}
+
+/// Returns the first multiple of `interval` since the UNIX epoch after `now`.
+fn next_stats_reset(now: SystemTime, interval: Duration) -> SystemTime {
+    let elapsed = now
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    let interval_nanos = interval.as_nanos().max(1);
+    let boundary = (elapsed / interval_nanos + 1) * interval_nanos;
+    SystemTime::UNIX_EPOCH + Duration::from_nanos(boundary as u64)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Instant;
+    use taxy_api::cert::SelfSignedCertRequest;
+    use taxy_api::port::ConnectionOutcome;
+    use taxy_api::port::{HealthCheck, Port, PortOptions, UpstreamServer};
+    use taxy_api::tls::{CertSelection, TlsTermination};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    async fn echo_server() -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let (mut reader, mut writer) = stream.split();
+                    tokio::io::copy(&mut reader, &mut writer).await
+                });
+            }
+        });
+        port
+    }
+
+    async fn free_port() -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        listener.local_addr().unwrap().port()
+    }
+
+    fn tcp_port(id: &str, listen: u16, upstream: u16) -> PortEntry {
+        PortEntry {
+            id: id.into(),
+            port: Port {
+                listen: format!("/ip4/127.0.0.1/tcp/{listen}").parse().unwrap(),
+                opts: PortOptions {
+                    upstream_servers: vec![UpstreamServer::new(
+                        format!("/ip4/127.0.0.1/tcp/{upstream}").parse().unwrap(),
+                    )],
+                    ..Default::default()
+                },
+            },
+        }
+    }
+
+    fn tls_port(
+        id: &str,
+        listen: u16,
+        server_name: &str,
+        cert_selection: CertSelection,
+    ) -> PortEntry {
+        let mut entry = tcp_port(id, listen, 1);
+        entry.port.opts.tls_termination = Some(TlsTermination {
+            server_names: vec![server_name.into()],
+            cert_selection,
+            ..Default::default()
+        });
+        entry
+    }
+
+    #[tokio::test]
+    async fn test_server_cert_ports() {
+        let dir = std::env::temp_dir().join(cuid2::cuid());
+        let cert = Cert::new_self_signed(&SelfSignedCertRequest {
+            san: vec!["*.example.com".parse().unwrap()],
+        })
+        .unwrap();
+        let pinned = CertSelection::Pinned(cert.id().to_string());
+        ConfigStorage::new(&dir)
+            .save_entries(
+                &[
+                    tls_port("a", free_port().await, "a.example.com", Default::default()),
+                    tls_port("b", free_port().await, "b.example.com", Default::default()),
+                    tls_port(
+                        "other",
+                        free_port().await,
+                        "example.org",
+                        Default::default(),
+                    ),
+                    tls_port("pinned", free_port().await, "example.org", pinned),
+                    tcp_port("plain", free_port().await, 1),
+                ],
+                &[],
+            )
+            .await;
+
+        let (command_sender, _command_recv) = mpsc::channel(1);
+        let (callback_sender, _callback_recv) = mpsc::channel(1);
+        let (br_sender, _br_recv) = broadcast::channel(64);
+        let mut state = ServerState::new(
+            ConfigStorage::new(&dir),
+            command_sender,
+            callback_sender,
+            br_sender,
+        )
+        .await
+        .unwrap();
+        assert!(matches!(
+            state.get_server_cert_ports(cert.id()),
+            Err(Error::IdNotFound { .. })
+        ));
+
+        let id = cert.id().to_string();
+        state
+            .handle_command(ServerCommand::AddKeyringItem {
+                item: KeyringItem::ServerCert(Arc::new(cert)),
+            })
+            .await;
+        let mut ports = state.get_server_cert_ports(&id).unwrap();
+        ports.sort();
+        assert_eq!(ports, ["a", "b", "pinned"]);
+    }
+
+    #[tokio::test]
+    async fn test_invalid_config() {
+        let dir = std::env::temp_dir().join(cuid2::cuid());
+        let (command_sender, _command_recv) = mpsc::channel(1);
+        let (callback_sender, _callback_recv) = mpsc::channel(1);
+        let (br_sender, _br_recv) = broadcast::channel(64);
+        let mut state = ServerState::new(
+            ConfigStorage::new(&dir),
+            command_sender,
+            callback_sender,
+            br_sender,
+        )
+        .await
+        .unwrap();
+
+        let config = AppConfig {
+            acme_self_check_max_body_size: 0,
+            ..Default::default()
+        };
+        assert!(matches!(
+            state.set_config(config).await,
+            Err(Error::InvalidAcmeSelfCheckMaxBodySize)
+        ));
+        assert_eq!(state.config().acme_self_check_max_body_size, 4096);
+    }
+
+    #[tokio::test]
+    async fn test_reload_unchanged_port() {
+        let dir = std::env::temp_dir().join(cuid2::cuid());
+        let storage = ConfigStorage::new(&dir);
+        let upstream = echo_server().await;
+        let (stable, changed) = (free_port().await, free_port().await);
+        storage
+            .save_entries(
+                &[
+                    tcp_port("stable", stable, upstream),
+                    tcp_port("changed", changed, upstream),
+                ],
+                &[],
+            )
+            .await;
+
+        let (command_sender, _command_recv) = mpsc::channel(1);
+        let (callback_sender, _callback_recv) = mpsc::channel(1);
+        let (br_sender, _br_recv) = broadcast::channel(64);
+        let mut state = ServerState::new(
+            ConfigStorage::new(&dir),
+            command_sender,
+            callback_sender,
+            br_sender,
+        )
+        .await
+        .unwrap();
+        let started_at = state.get_port_status("stable").unwrap().started_at;
+
+        let mut client = TcpStream::connect(("127.0.0.1", stable)).await.unwrap();
+        let (index, stream) = state.select().await.unwrap();
+        state.handle_connection(index, stream).await;
+        client.write_all(b"ping").await.unwrap();
+        let mut buf = [0; 4];
+        client.read_exact(&mut buf).await.unwrap();
+
+        let other = echo_server().await;
+        storage
+            .save_entries(
+                &[
+                    tcp_port("stable", stable, upstream),
+                    tcp_port("changed", changed, other),
+                ],
+                &[],
+            )
+            .await;
+        state.reload_config().await.unwrap();
+        assert_eq!(
+            state.get_port_status("stable").unwrap().started_at,
+            started_at
+        );
+
+        client.write_all(b"pong").await.unwrap();
+        tokio::time::timeout(Duration::from_secs(5), client.read_exact(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&buf, b"pong");
+    }
+
+    #[tokio::test]
+    async fn test_reload_after_api_edit() {
+        let dir = std::env::temp_dir().join(cuid2::cuid());
+        let storage = ConfigStorage::new(&dir);
+        let upstream = echo_server().await;
+        let (a, b) = (free_port().await, free_port().await);
+        let file_entry = tcp_port("a", a, upstream);
+        storage
+            .save_entries(std::slice::from_ref(&file_entry), &[])
+            .await;
+
+        let new_state = || async {
+            let (command_sender, _command_recv) = mpsc::channel(1);
+            let (callback_sender, _callback_recv) = mpsc::channel(1);
+            let (br_sender, _br_recv) = broadcast::channel(64);
+            ServerState::new(
+                ConfigStorage::new(&dir),
+                command_sender,
+                callback_sender,
+                br_sender,
+            )
+            .await
+            .unwrap()
+        };
+        let mut state = new_state().await;
+        let api_entry = tcp_port("a", a, echo_server().await);
+        state.update_port(api_entry.clone()).await.unwrap();
+        state.add_port(tcp_port("b", b, upstream)).await.unwrap();
+        state
+            .handle_event(ServerEvent::PortTableUpdated {
+                entries: state.get_port_list(),
+            })
+            .await;
+        assert_eq!(
+            storage.load_entries().await,
+            std::slice::from_ref(&file_entry)
+        );
+        drop(state);
+
+        // API changes survive a restart...
+        let mut state = new_state().await;
+        assert_eq!(
+            state.get_port_list(),
+            [api_entry, tcp_port("b", b, upstream)]
+        );
+        assert_eq!(state.get_port_status("a").unwrap().source, Source::Api);
+
+        // ...until the file is reloaded, which keeps the API-only ports.
+        state.reload_config().await.unwrap();
+        assert_eq!(
+            state.get_port_list(),
+            [file_entry.clone(), tcp_port("b", b, upstream)]
+        );
+        assert_eq!(state.get_port_status("a").unwrap().source, Source::File);
+        assert_eq!(state.get_port_status("b").unwrap().source, Source::Api);
+        state
+            .handle_event(ServerEvent::PortTableUpdated {
+                entries: state.get_port_list(),
+            })
+            .await;
+        assert_eq!(storage.load_entries().await, [file_entry]);
+        assert_eq!(
+            storage.load_api_entries().await,
+            [tcp_port("b", b, upstream)]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_port_stats() {
+        let dir = std::env::temp_dir().join(cuid2::cuid());
+        let upstream = echo_server().await;
+        let listen = free_port().await;
+        ConfigStorage::new(&dir)
+            .save_entries(&[tcp_port("echo", listen, upstream)], &[])
+            .await;
+
+        let (command_sender, _command_recv) = mpsc::channel(1);
+        let (callback_sender, _callback_recv) = mpsc::channel(1);
+        let (br_sender, _br_recv) = broadcast::channel(64);
+        let mut state = ServerState::new(
+            ConfigStorage::new(&dir),
+            command_sender,
+            callback_sender,
+            br_sender,
+        )
+        .await
+        .unwrap();
+        let stats = state.get_port_stats();
+        assert_eq!(stats.len(), 1);
+        assert_eq!(stats[0].connections, Default::default());
+
+        let mut client = TcpStream::connect(("127.0.0.1", listen)).await.unwrap();
+        let (index, stream) = state.select().await.unwrap();
+        state.handle_connection(index, stream).await;
+        client.write_all(b"ping").await.unwrap();
+        let mut buf = [0; 4];
+        client.read_exact(&mut buf).await.unwrap();
+
+        let stats = state.get_port_stats();
+        assert_eq!(stats[0].id, "echo");
+        assert_eq!(stats[0].status.state.socket, SocketState::Listening);
+        let connections = &stats[0].connections;
+        assert_eq!(connections.active, 1);
+        assert_eq!(connections.peak, 1);
+        assert_eq!(connections.total, 1);
+        assert_eq!(connections.bytes_received, 4);
+        assert_eq!(connections.bytes_sent, 4);
+        assert_eq!(stats[0].upstreams.len(), 1);
+        assert!(stats[0].upstreams[0].healthy);
+        assert_eq!(stats[0].upstreams[0].active, 1);
+        assert_eq!(stats[0].upstreams[0].connections, 1);
+    }
+
+    #[test]
+    fn test_next_stats_reset() {
+        let day = Duration::from_secs(60 * 60 * 24);
+        let epoch = SystemTime::UNIX_EPOCH;
+        assert_eq!(
+            next_stats_reset(epoch + day * 3 + Duration::from_secs(5), day),
+            epoch + day * 4
+        );
+        assert_eq!(next_stats_reset(epoch + day * 3, day), epoch + day * 4);
+    }
+
+    #[tokio::test]
+    async fn test_reset_port_stats() {
+        let dir = std::env::temp_dir().join(cuid2::cuid());
+        let upstream = echo_server().await;
+        let listen = free_port().await;
+        let mut entry = tcp_port("echo", listen, upstream);
+        entry.port.opts.stats_reset_interval = Some(Duration::from_secs(1));
+        ConfigStorage::new(&dir).save_entries(&[entry], &[]).await;
+
+        let (command_sender, mut command_recv) = mpsc::channel(1);
+        let (callback_sender, _callback_recv) = mpsc::channel(1);
+        let (br_sender, mut br_recv) = broadcast::channel(64);
+        let mut state = ServerState::new(
+            ConfigStorage::new(&dir),
+            command_sender,
+            callback_sender,
+            br_sender,
+        )
+        .await
+        .unwrap();
+        assert!(state.stats_resets.contains_key("echo"));
+
+        let mut client = TcpStream::connect(("127.0.0.1", listen)).await.unwrap();
+        let (index, stream) = state.select().await.unwrap();
+        state.handle_connection(index, stream).await;
+        client.write_all(b"ping").await.unwrap();
+        let mut buf = [0; 4];
+        client.read_exact(&mut buf).await.unwrap();
+        drop(client);
+        let registry = state.table.contexts()[0].connection_registry().unwrap();
+        tokio::time::timeout(
+            Duration::from_secs(5),
+            wait_closed(std::slice::from_ref(registry)),
+        )
+        .await
+        .unwrap();
+
+        // Resets only take effect once the command is handled.
+        let cmd = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                match command_recv.recv().await.unwrap() {
+                    cmd @ ServerCommand::ResetPortStats { .. } => break cmd,
+                    _ => continue,
+                }
+            }
+        })
+        .await
+        .unwrap();
+        state.handle_command(cmd).await;
+
+        let stats = loop {
+            if let ServerEvent::PortStatsReset { id, stats } = br_recv.recv().await.unwrap() {
+                assert_eq!(id, "echo");
+                break stats;
+            }
+        };
+        assert_eq!(stats.total, 1);
+        assert_eq!(stats.bytes_received, 4);
+        assert_eq!(stats.bytes_sent, 4);
+
+        let stats = &state.get_port_stats()[0].connections;
+        assert_eq!(stats.total, 0);
+        assert_eq!(stats.bytes_received, 0);
+        assert_eq!(stats.bytes_sent, 0);
+        assert_eq!(stats.peak, 1);
+    }
+
+    #[tokio::test]
+    async fn test_shutdown() {
+        let dir = std::env::temp_dir().join(cuid2::cuid());
+        let storage = ConfigStorage::new(&dir);
+        storage
+            .save_app_config(&AppConfig {
+                shutdown_grace_period: Duration::from_millis(300),
+                shutdown_deadline: Duration::from_millis(1500),
+                ..Default::default()
+            })
+            .await;
+        let (listen, upstream) = (free_port().await, echo_server().await);
+        storage
+            .save_entries(&[tcp_port("test", listen, upstream)], &[])
+            .await;
+
+        let (command_sender, _command_recv) = mpsc::channel(1);
+        let (callback_sender, _callback_recv) = mpsc::channel(1);
+        let (br_sender, _br_recv) = broadcast::channel(64);
+        let mut state = ServerState::new(storage, command_sender, callback_sender, br_sender)
+            .await
+            .unwrap();
+
+        let mut clients = Vec::new();
+        for _ in 0..3 {
+            let mut client = TcpStream::connect(("127.0.0.1", listen)).await.unwrap();
+            let (index, stream) = state.select().await.unwrap();
+            state.handle_connection(index, stream).await;
+            client.write_all(b"ping").await.unwrap();
+            let mut buf = [0; 4];
+            client.read_exact(&mut buf).await.unwrap();
+            clients.push(client);
+        }
+        let remotes = clients
+            .iter()
+            .map(|client| client.local_addr().unwrap().to_string())
+            .collect::<Vec<_>>();
+
+        // Keeps the connection busy for `busy`, then closes it or waits until it is closed.
+        let keep_busy = |mut client: TcpStream, busy: Duration, close: bool| async move {
+            let start = Instant::now();
+            let mut buf = [0; 4];
+            while start.elapsed() < busy {
+                client.write_all(b"ping").await?;
+                client.read_exact(&mut buf).await?;
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+            if !close {
+                client.read_exact(&mut buf).await?;
+            }
+            std::io::Result::Ok(())
+        };
+        let mut clients = clients.into_iter();
+        let mut idle = clients.next().unwrap();
+        let in_grace = tokio::spawn(keep_busy(
+            clients.next().unwrap(),
+            Duration::from_millis(1000),
+            true,
+        ));
+        let over_deadline = tokio::spawn(keep_busy(
+            clients.next().unwrap(),
+            Duration::from_secs(4),
+            false,
+        ));
+        tokio::time::sleep(Duration::from_millis(400)).await;
+
+        let start = Instant::now();
+        let closed_idle = tokio::spawn(async move {
+            let mut buf = [0; 1];
+            let read = idle.read(&mut buf).await.unwrap();
+            (read, start.elapsed())
+        });
+        state.shutdown().await;
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(1500));
+        assert!(elapsed < Duration::from_secs(3));
+
+        let (read, closed_after) = closed_idle.await.unwrap();
+        assert_eq!(read, 0);
+        assert!(closed_after < Duration::from_millis(200));
+        in_grace.await.unwrap().unwrap();
+        assert!(over_deadline.await.unwrap().is_err());
+
+        let recent = state.get_recent_port_connections("test").unwrap();
+        let outcome = |remote: &str| {
+            recent
+                .iter()
+                .find(|conn| conn.connection.remote == remote)
+                .unwrap()
+                .outcome
+        };
+        assert_eq!(outcome(&remotes[0]), ConnectionOutcome::Stopped);
+        assert_eq!(outcome(&remotes[1]), ConnectionOutcome::ClientClosed);
+        assert_eq!(outcome(&remotes[2]), ConnectionOutcome::Stopped);
+    }
+
+    async fn proxy_client(state: &mut ServerState, listen: u16, data: &[u8]) -> TcpStream {
+        let mut client = TcpStream::connect(("127.0.0.1", listen)).await.unwrap();
+        client.write_all(data).await.unwrap();
+        let (index, stream) = state.select().await.unwrap();
+        tokio::time::timeout(
+            Duration::from_secs(1),
+            state.handle_connection(index, stream),
+        )
+        .await
+        .unwrap();
+        client
+    }
+
+    #[tokio::test]
+    async fn test_upstream_health_commands() {
+        let dir = std::env::temp_dir().join(cuid2::cuid());
+        let upstream = free_port().await;
+        let mut entry = tcp_port("test", free_port().await, upstream);
+        entry.port.opts.health_check = Some(HealthCheck {
+            interval: Duration::from_millis(50),
+            timeout: Duration::from_secs(1),
+            unhealthy_threshold: 1,
+            tls_handshake: false,
+        });
+        ConfigStorage::new(&dir).save_entries(&[entry], &[]).await;
+
+        let (command_sender, mut command_recv) = mpsc::channel(1);
+        let (callback_sender, _callback_recv) = mpsc::channel(1);
+        let (br_sender, _br_recv) = broadcast::channel(64);
+        let _state = ServerState::new(
+            ConfigStorage::new(&dir),
+            command_sender,
+            callback_sender,
+            br_sender,
+        )
+        .await
+        .unwrap();
+
+        let mut listener = None;
+        for healthy in [false, true] {
+            if healthy {
+                listener = Some(TcpListener::bind(("127.0.0.1", upstream)).await.unwrap());
+            }
+            let cmd = tokio::time::timeout(Duration::from_secs(5), command_recv.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            let ServerCommand::SetUpstreamHealth {
+                port_id,
+                addr,
+                healthy: reported,
+            } = cmd
+            else {
+                panic!("unexpected command: {cmd:?}");
+            };
+            assert_eq!(port_id, "test");
+            assert_eq!(addr, format!("127.0.0.1:{upstream}"));
+            assert_eq!(reported, healthy);
+        }
+        drop(listener);
+    }
+
+    #[tokio::test]
+    async fn test_accept_proxy_protocol() {
+        let dir = std::env::temp_dir().join(cuid2::cuid());
+        let storage = ConfigStorage::new(&dir);
+        let (listen, upstream) = (free_port().await, echo_server().await);
+        let mut entry = tcp_port("test", listen, upstream);
+        entry.port.opts.accept_proxy_protocol = true;
+        storage.save_entries(&[entry], &[]).await;
+
+        let (command_sender, mut command_recv) = mpsc::channel(1);
+        let (callback_sender, _callback_recv) = mpsc::channel(1);
+        let (br_sender, _br_recv) = broadcast::channel(64);
+        let mut state = ServerState::new(storage, command_sender, callback_sender, br_sender)
+            .await
+            .unwrap();
+
+        // A client that never sends its header must not hold up the others.
+        let _silent = proxy_client(&mut state, listen, b"").await;
+        let mut client = proxy_client(
+            &mut state,
+            listen,
+            b"PROXY TCP4 203.0.113.7 127.0.0.1 40000 443\r\nhello",
+        )
+        .await;
+        let cmd = command_recv.recv().await.unwrap();
+        assert!(matches!(cmd, ServerCommand::ProxyConnection { .. }));
+        state.handle_command(cmd).await;
+        let mut buf = [0; 5];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+        let conns = state.get_port_connections("test").unwrap();
+        assert_eq!(conns.len(), 1);
+        assert_eq!(conns[0].remote, "203.0.113.7:40000");
+
+        let closed = |mut client: TcpStream| async move {
+            let mut buf = Vec::new();
+            tokio::time::timeout(Duration::from_secs(5), client.read_to_end(&mut buf))
+                .await
+                .unwrap()
+                .map_or(true, |_| buf.is_empty())
+        };
+        let missing = proxy_client(&mut state, listen, b"hello").await;
+        assert!(closed(missing).await);
+        let malformed = proxy_client(&mut state, listen, b"PROXY TCP4 203.0.113.7\r\nhello").await;
+        assert!(closed(malformed).await);
+        assert_eq!(state.get_port_connections("test").unwrap().len(), 1);
+    }
+}
@@ -1,5 +1,7 @@
 use crate::proxy::PortContext;
-use taxy_api::port::PortEntry;
+use multiaddr::Multiaddr;
+use std::time::Duration;
+use taxy_api::{error::Error, port::PortEntry, tls::TlsMode};
 
 pub struct ProxyTable {
     contexts: Vec<PortContext>,
@@ -45,6 +47,15 @@ impl ProxyTable {
         }
     }
 
+    pub fn drain_upstream(&self, id: &str, addr: &Multiaddr) -> bool {
+        if let Some(ctx) = self.contexts.iter().find(|p| p.entry().id == *id) {
+            ctx.drain_upstream(addr);
+            true
+        } else {
+            false
+        }
+    }
+
     pub fn reset_port(&mut self, id: &str) -> bool {
         if let Some(index) = self.contexts.iter().position(|p| p.entry().id == *id) {
             self.contexts[index].reset();
@@ -53,4 +64,20 @@ impl ProxyTable {
             false
         }
     }
+
+    pub fn drain_port(&mut self, id: &str, timeout: Duration) -> bool {
+        if let Some(index) = self.contexts.iter().position(|p| p.entry().id == *id) {
+            self.contexts[index].drain(timeout);
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn set_tls_mode(&mut self, id: &str, mode: TlsMode) -> Result<(), Error> {
+        match self.contexts.iter_mut().find(|p| p.entry().id == *id) {
+            Some(ctx) => ctx.set_tls_mode(mode),
+            None => Err(Error::IdNotFound { id: id.to_string() }),
+        }
+    }
 }
@@ -0,0 +1,43 @@
+//! Fixtures shared by tests across modules.
+
+use crate::keyring::certs::Cert;
+use std::str::FromStr;
+use std::sync::Arc;
+use taxy_api::{cert::SelfSignedCertRequest, subject_name::SubjectName};
+use tokio_rustls::rustls::{Certificate, ClientConfig, RootCertStore};
+
+/// Returns a new self-signed certificate for `name`.
+pub fn self_signed(name: &str) -> Arc<Cert> {
+    self_signed_with_sans(&[name])
+}
+
+/// Returns a new self-signed certificate covering all of `names`.
+pub fn self_signed_with_sans(names: &[&str]) -> Arc<Cert> {
+    Arc::new(
+        Cert::new_self_signed(&SelfSignedCertRequest {
+            san: names
+                .iter()
+                .map(|name| SubjectName::from_str(name).unwrap())
+                .collect(),
+        })
+        .unwrap(),
+    )
+}
+
+/// Returns a root store trusting the CA which issued `cert`.
+pub fn root_certs(cert: &Cert) -> RootCertStore {
+    let mut root_certs = RootCertStore::empty();
+    let chain = rustls_pemfile::certs(&mut cert.raw_chain.as_slice()).unwrap();
+    root_certs
+        .add(&Certificate(chain.last().unwrap().clone()))
+        .unwrap();
+    root_certs
+}
+
+/// Returns a client config trusting `cert`, without client authentication.
+pub fn client_config(cert: &Cert) -> ClientConfig {
+    ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(root_certs(cert))
+        .with_no_client_auth()
+}
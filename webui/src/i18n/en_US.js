@@ -28,6 +28,7 @@ export default {
             port_already_in_use: 'Port already in use',
             permission_denied: 'Permission denied',
             address_not_available: 'Address not available',
+            idle_unbound: 'Unbound (idle)',
             no_valid_certificate: 'No valid certificate',
             configuration_failed: 'Configuration failed',
             error: 'Error',